@@ -2,8 +2,12 @@
 
 package net.o137.navelo
 
+import android.annotation.SuppressLint
+import android.bluetooth.BluetoothAdapter
+import android.bluetooth.BluetoothDevice
 import android.bluetooth.le.ScanSettings
 import android.util.Log
+import com.juul.kable.AndroidPeripheral
 import com.juul.kable.ExperimentalApi
 import com.juul.kable.ObsoleteKableApi
 import com.juul.kable.Peripheral
@@ -21,6 +25,7 @@ import kotlinx.coroutines.flow.map
 import kotlinx.coroutines.flow.merge
 import kotlinx.coroutines.isActive
 import kotlinx.coroutines.launch
+import kotlinx.coroutines.withTimeoutOrNull
 import kotlinx.io.IOException
 import kotlin.coroutines.coroutineContext
 import kotlin.time.Duration
@@ -71,6 +76,9 @@ fun Vector3f(data: ByteArray) = Vector3f(
 class NaveloDevice(private val peripheral: Peripheral) {
   companion object {
     private val RssiInterval = 5.seconds
+    /** Long enough to type the passkey shown on the device. */
+    private val BondTimeout = 60.seconds
+    private val BondPollInterval = 200.milliseconds
     val PeriodRange = 100.milliseconds..2550.milliseconds
 
     val scanner by lazy {
@@ -91,6 +99,8 @@ class NaveloDevice(private val peripheral: Peripheral) {
     Log.i(TAG, "Connecting to ${peripheral.name}")
     try {
       peripheral.connect().launch { monitorRssi() }
+      // the movement config and period are only accessible over an encrypted link
+      bond()
       readBatteryLevel()
       readGyroPeriod()
       enableGyro()
@@ -106,6 +116,37 @@ class NaveloDevice(private val peripheral: Peripheral) {
     peripheral.disconnect()
   }
 
+  /** Pair with the passkey shown on the device, unless it is already bonded. */
+  @SuppressLint("MissingPermission")
+  private suspend fun bond() {
+    @Suppress("DEPRECATION")
+    val adapter = BluetoothAdapter.getDefaultAdapter() ?: throw IOException("Bluetooth is not available")
+    val device = adapter.getRemoteDevice((peripheral as AndroidPeripheral).address)
+    if (device.bondState == BluetoothDevice.BOND_BONDED) return
+
+    Log.i(TAG, "Bonding with ${peripheral.name}")
+    if (device.bondState == BluetoothDevice.BOND_NONE && !device.createBond()) {
+      throw IOException("Bonding could not be started")
+    }
+    val bonded = withTimeoutOrNull(BondTimeout) {
+      // BOND_NONE until the stack starts bonding, and again once the pairing failed
+      var started = false
+      while (device.bondState != BluetoothDevice.BOND_BONDED) {
+        when (device.bondState) {
+          BluetoothDevice.BOND_BONDING -> started = true
+          else -> if (started) return@withTimeoutOrNull false
+        }
+        delay(BondPollInterval)
+      }
+      true
+    }
+    when (bonded) {
+      true -> Log.i(TAG, "Bonded")
+      false -> throw IOException("Pairing was rejected")
+      null -> throw IOException("Pairing timed out")
+    }
+  }
+
   private suspend fun monitorRssi() {
     while (coroutineContext.isActive) {
       readRssi()
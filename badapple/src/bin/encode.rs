@@ -3,6 +3,7 @@ use ffmpeg_sidecar::command::FfmpegCommand;
 use ffmpeg_sidecar::event::OutputVideoFrame;
 use flate2::write::DeflateEncoder;
 use flate2::Compression;
+use navelo_protocol::image::{encode_runs, run_lengths};
 use std::fs::File;
 use std::io::Write;
 
@@ -25,8 +26,8 @@ fn main() -> anyhow::Result<()> {
     println!("Processing frame {}", i);
 
     let bits = bitvec_from_frame(&frame);
-    let counts = count_run_lengths(&bits);
-    let bytes = encode_numbers(&counts);
+    let counts = run_lengths(bits.iter().by_vals());
+    let bytes = encode_runs(&counts);
     data.extend_from_slice(&bytes);
   }
 
@@ -73,39 +74,6 @@ fn is_color_bright(red: u8, green: u8, blue: u8) -> bool {
   gray_value > 128
 }
 
-fn count_run_lengths<T: BitStore, O: BitOrder>(bits: &BitVec<T, O>) -> Vec<u32> {
-  let mut counts = Vec::new();
-  let mut current_bit = false;
-  let mut count = 0;
-  for bit in bits {
-    if *bit == current_bit {
-      count += 1;
-    } else {
-      counts.push(count);
-      current_bit = *bit;
-      count = 1;
-    }
-  }
-  counts.push(count);
-  counts
-}
-
-fn encode_numbers(counts: &[u32]) -> Vec<u8> {
-  let mut bytes = Vec::new();
-  for &count in counts {
-    if count <= 250 {
-      bytes.push(count as u8);
-    } else if count < 65536 {
-      bytes.push(251);
-      bytes.extend_from_slice(&(count as u16).to_le_bytes());
-    } else {
-      bytes.push(252);
-      bytes.extend_from_slice(&count.to_le_bytes());
-    }
-  }
-  bytes
-}
-
 fn compress_deflate(data: &[u8]) -> anyhow::Result<Vec<u8>> {
   let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
   encoder.write_all(data)?;
@@ -1,3 +1,62 @@
+use std::{env, fs, path::Path, process::Command};
+
 fn main() {
   embuild::espidf::sysenv::output();
+
+  // shown on the boot screen
+  let hash = Command::new("git")
+    .args(["rev-parse", "--short", "HEAD"])
+    .output()
+    .ok()
+    .filter(|output| output.status.success())
+    .and_then(|output| String::from_utf8(output.stdout).ok())
+    .unwrap_or_else(|| "unknown".into());
+  println!("cargo:rustc-env=NAVELO_BUILD_HASH={}", hash.trim());
+  println!("cargo:rerun-if-changed=../.git/HEAD");
+  println!("cargo:rerun-if-changed=../.git/refs/heads");
+
+  // PEM public key that firmware updates must be signed with, updates are refused without it
+  let key = match env::var("NAVELO_DFU_PUBLIC_KEY") {
+    Ok(path) => {
+      println!("cargo:rerun-if-changed={path}");
+      fs::read(&path).unwrap_or_else(|e| panic!("failed to read {path}: {e}"))
+    }
+    Err(_) => Vec::new(),
+  };
+  let out_dir = env::var("OUT_DIR").unwrap();
+  fs::write(Path::new(&out_dir).join("dfu_public_key.pem"), key).unwrap();
+  println!("cargo:rerun-if-env-changed=NAVELO_DFU_PUBLIC_KEY");
+
+  // the fonts are generated by fontgen, a non-CJK source font leaves the Japanese street names as boxes.
+  // NAVELO_ALLOW_MISSING_GLYPHS=1 builds anyway, e.g. for bring-up before the fonts are regenerated.
+  let allow_missing = env::var("NAVELO_ALLOW_MISSING_GLYPHS").is_ok_and(|v| v == "1");
+  println!("cargo:rerun-if-env-changed=NAVELO_ALLOW_MISSING_GLYPHS");
+  for path in ["src/font12.bin", "src/font16.bin"] {
+    println!("cargo:rerun-if-changed={path}");
+    let font = fs::read(path).unwrap_or_else(|e| panic!("failed to read {path}: {e}"));
+    let missing: String = ['あ', 'ア', '駅']
+      .into_iter()
+      .filter(|&c| !has_glyph(&font, c))
+      .collect();
+    if missing.is_empty() {
+      continue;
+    }
+    let message = format!("{path} has no glyph for {missing}, regenerate it with fontgen from Noto Sans JP");
+    if !allow_missing {
+      panic!("{message}");
+    }
+    println!("cargo:warning={message}");
+  }
+}
+
+/// Whether the codepoint is in the glyph table, see fontgen/src/main.rs for the format
+fn has_glyph(font: &[u8], c: char) -> bool {
+  let Some(count) = font.get(6..8) else {
+    return false;
+  };
+  let count = u16::from_le_bytes([count[0], count[1]]) as usize;
+  let entries = font.get(8..8 + count * 8).unwrap_or_default();
+  entries
+    .chunks_exact(8)
+    .any(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], 0]) == c as u32)
 }
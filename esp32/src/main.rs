@@ -1,7 +1,7 @@
 use embedded_graphics::{
   pixelcolor::BinaryColor,
   prelude::*,
-  primitives::{Circle, PrimitiveStyle},
+  primitives::{Circle, PrimitiveStyle, Rectangle},
 };
 use esp_idf_hal::{
   delay::{Delay, FreeRtos},
@@ -9,15 +9,30 @@ use esp_idf_hal::{
   rmt::{config::TransmitConfig, TxRmtDriver},
   spi::{self, SpiDriver, SpiDriverConfig},
 };
-use esp_idf_hal::{gpio::AnyInputPin, prelude::*};
 use esp_idf_hal::{gpio::PinDriver, spi::SpiDeviceDriver};
+use esp_idf_hal::{
+  gpio::{AnyInputPin, Pull},
+  prelude::*,
+};
 use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
 use esp_idf_svc::sys;
-use log::info;
+use log::{info, warn};
 
+use alert::{
+  AlertKind, AlertManager, AlertOverlay, AlertSound, LOW_BATTERY_LEVEL, OFF_ROUTE_DISTANCE, TURN_ALERT_DISTANCE,
+};
 use audio::*;
+use boot::{BootScreen, FirmwareInfo, InfoScreen};
+use cycling::RideScreen;
 use display::{Weact154Display, HEIGHT, WIDTH};
+use font::{TextBox, TextStyle, FONT_12};
+use minimap::MiniMap;
+use navigation::{format_distance, NavigationBanner, BANNER_HEIGHT};
+use ride_log::RideLog;
 use sensors::Gy87;
+use settings::Lut;
+use ui::{signal_bars, Button, Page, PairingScreen, Status, StatusBar, STATUS_BAR_HEIGHT};
 use utils::spawn_heap_logger;
 
 fn main() -> anyhow::Result<()> {
@@ -30,10 +45,14 @@ fn main() -> anyhow::Result<()> {
   info!("Hello, world!");
   spawn_heap_logger();
 
-  main_display()?;
+  // an error halts the device (CONFIG_ESP_SYSTEM_PANIC_PRINT_HALT), so an unconfirmed update is rolled back first
+  if let Err(e) = main_app() {
+    dfu::reject_boot(&e);
+    return Err(e);
+  }
+  // main_display()?;
   // main_gy87()?;
   // main_audio()?;
-  // bluetooth_example::main()?;
 
   Ok(())
 }
@@ -74,6 +93,12 @@ pub fn main_display() -> anyhow::Result<()> {
     Circle::new(Point::new(x, y), radius as u32)
       .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
       .draw(&mut display)?;
+    TextBox::new(
+      &format!("x: {x}, y: {y}"),
+      Rectangle::new(Point::new(4, 4), Size::new(WIDTH as u32 - 8, FONT_12.height())),
+      TextStyle::new(&FONT_12, BinaryColor::Off),
+    )
+    .draw(&mut display)?;
 
     display.refresh_partial_fast()?;
     // display.refresh_partial_while_awake_fast()?;
@@ -136,650 +161,1931 @@ pub fn main_audio() -> anyhow::Result<()> {
   let mut tx: TxRmtDriver<'static> = TxRmtDriver::new(channel, speaker, &config)?;
 
   loop {
-    play_song_blocking(&mut tx, &ode_to_joy())?;
+    play_song_blocking(&mut tx, &ode_to_joy(), FULL_VOLUME)?;
     FreeRtos::delay_ms(200);
   }
 }
 
-// Based on https://github.com/esp-rs/esp-idf-svc/blob/b42dae55ccfef7c128da0cc8cfdb451f38572a0e/examples/bt_gatt_server.rs
-// Original license: MIT License / Copyright 2019-2020 Contributors to xtensa-lx6-rt
-pub mod bluetooth_example {
-  use std::sync::{Arc, Condvar, Mutex};
-
-  use enumset::enum_set;
+pub fn main_app() -> anyhow::Result<()> {
+  let mut peripherals = Peripherals::take()?;
+  let nvs = EspDefaultNvsPartition::take()?;
+  let delay = Delay::new_default();
 
-  use esp_idf_svc::bt::ble::gap::{AdvConfiguration, BleGapEvent, EspBleGap};
-  use esp_idf_svc::bt::ble::gatt::server::{ConnectionId, EspGatts, GattsEvent, TransferId};
-  use esp_idf_svc::bt::ble::gatt::{
-    AutoResponse, GattCharacteristic, GattDescriptor, GattId, GattInterface, GattResponse, GattServiceId, GattStatus,
-    Handle, Permission, Property,
-  };
-  use esp_idf_svc::bt::{BdAddr, Ble, BtDriver, BtStatus, BtUuid};
-  use esp_idf_svc::hal::delay::FreeRtos;
-  use esp_idf_svc::hal::peripherals::Peripherals;
-  use esp_idf_svc::nvs::EspDefaultNvsPartition;
-  use esp_idf_svc::sys::{EspError, ESP_FAIL};
+  let spi = peripherals.spi2;
+  let sclk = peripherals.pins.gpio19;
+  let sdo = peripherals.pins.gpio18;
+  let sdi = Option::<AnyInputPin>::None;
+  let cs = peripherals.pins.gpio20;
 
-  use log::{info, warn};
+  let reset = PinDriver::output(peripherals.pins.gpio17)?;
+  let busy = PinDriver::input(peripherals.pins.gpio16)?;
+  let dc = PinDriver::output(peripherals.pins.gpio21)?;
 
-  pub fn main() -> anyhow::Result<()> {
-    let peripherals = Peripherals::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
+  let spi_config = spi::config::Config::new().baudrate(20.MHz().into());
+  let spi_driver = SpiDriver::new(spi, sclk, sdo, sdi, &SpiDriverConfig::new())?;
+  let spi_device = SpiDeviceDriver::new(&spi_driver, Some(cs), &spi_config)?;
 
-    let bt = Arc::new(BtDriver::new(peripherals.modem, Some(nvs.clone()))?);
+  let mut display = Weact154Display::new(spi_device, dc, reset, busy, delay);
 
-    let server = ExampleServer::new(
-      Arc::new(EspBleGap::new(bt.clone())?),
-      Arc::new(EspGatts::new(bt.clone())?),
-    );
+  let i2c = peripherals.i2c0;
+  let sda = peripherals.pins.gpio22;
+  let scl = peripherals.pins.gpio23;
 
-    info!("BLE Gap and Gatts initialized");
+  let i2c_config = I2cConfig::new().baudrate(400.kHz().into());
+  let mut i2c_driver = I2cDriver::new(i2c, sda, scl, &i2c_config)?;
 
-    let gap_server = server.clone();
+  let info = FirmwareInfo::collect(&mut i2c_driver);
+  display.clear(BinaryColor::On)?;
+  BootScreen::new(&info).draw(&mut display)?;
+  display.refresh_full()?;
+  display.send_as_previous_pixels()?;
+
+  let storage = EspNvs::new(nvs.clone(), boot::NVS_NAMESPACE, true)?;
+  let mut page = boot::last_page(&storage);
+
+  // sampled before the battery service is registered, so that reads return a real level from the start
+  let battery_level = battery::sample_level(&mut peripherals.adc1, &mut peripherals.pins.gpio2);
+  let server = bluetooth::start(peripherals.modem, nvs, battery_level)?;
+  let map = server.map();
+  let image = server.image();
+  let mut ride_log = RideLog::load(&storage);
+
+  // the display and the BLE stack came up, keep the updated firmware once its services are visible to the phone.
+  // Missing sensors are a hardware fault which rolling back would not fix, they are shown on the boot screen.
+  let missing = info.missing_devices();
+  if !missing.is_empty() {
+    warn!("Missing devices: {}", missing.join(", "));
+  }
+  dfu::confirm_boot(server.wait_registered())?;
 
-    server.gap.subscribe(move |event| {
-      gap_server.check_esp_status(gap_server.on_gap_event(event));
-    })?;
+  movement::spawn_sampler(Gy87::new(i2c_driver, delay), server.clone());
+  battery::spawn_monitor(peripherals.adc1, peripherals.pins.gpio2, server.clone());
+  #[cfg(feature = "standard-profiles")]
+  location::spawn_notifier(server.clone());
 
-    let gatts_server = server.clone();
+  let mut button_pin = PinDriver::input(peripherals.pins.gpio9)?;
+  button_pin.set_pull(Pull::Up)?;
+  let mut button = Button::new(button_pin);
 
-    server
-      .gatts
-      .subscribe(move |(gatt_if, event)| gatts_server.check_esp_status(gatts_server.on_gatts_event(gatt_if, event)))?;
+  let config = TransmitConfig::new();
+  let tx = TxRmtDriver::new(peripherals.rmt.channel0, peripherals.pins.gpio1, &config)?;
+  let sound = AlertSound::spawn(tx);
+
+  let mut alerts = AlertManager::default();
+  let mut off_route = false;
+  let mut low_battery = false;
+  let mut link_lost = false;
+  let mut turn_alerted = None;
+
+  let mut status_bar = StatusBar::default();
+  let page_bounds = Rectangle::new(
+    Point::new(0, STATUS_BAR_HEIGHT as i32),
+    Size::new(WIDTH as u32, HEIGHT as u32 - STATUS_BAR_HEIGHT),
+  );
+
+  // keep the boot screen for a while
+  delay.delay_ms(2000);
+
+  let mut generation = None;
+  let mut last_instruction = None;
+  let mut last_pairing = None;
+  let mut last_settings = None;
+  let mut redraws = 0;
+  let mut image_generation = 0;
+  let mut pushed_image = None;
+  let mut last_ride = None;
 
-    info!("BLE Gap and Gatts subscriptions initialized");
+  loop {
+    let state = map.lock().unwrap().clone();
 
-    server.gatts.register_app(APP_ID)?;
+    let mut redraw = generation != Some(state.generation);
+    generation = Some(state.generation);
 
-    info!("Gatts BTP app registered");
+    let instruction = server.navigation();
+    redraw |= instruction != last_instruction;
+    last_instruction = instruction.clone();
 
-    let mut ind_data = 0_u16;
+    let image_changed = {
+      let image = image.lock().unwrap();
+      let changed = image.generation != image_generation;
+      if changed {
+        image_generation = image.generation;
+        pushed_image = image.image.clone();
+      }
+      changed
+    };
+    // a removed image leaves the page under it to be redrawn
+    redraw |= image_changed && pushed_image.is_none();
 
-    loop {
-      server.indicate(&ind_data.to_le_bytes())?;
-      info!("Broadcasted indication: {ind_data}");
+    let pairing = server.pairing();
+    redraw |= pairing != last_pairing;
+    last_pairing = pairing;
 
-      ind_data = ind_data.wrapping_add(1);
+    let settings = server.settings();
+    if last_settings != Some(settings) {
+      sound.set_volume(settings.effective_volume());
+      redraw = true;
+    }
+    last_settings = Some(settings);
+
+    let metrics = server.ride_metrics();
+    ride_log.poll(&metrics, &storage);
+    // the sensor values only show on the ride page
+    let ride = metrics.values(settings.units);
+    redraw |= page == Page::Ride && last_ride.as_ref() != Some(&ride);
+    last_ride = Some(ride.clone());
+
+    redraw |= alerts.expire();
+    if button.poll() {
+      if !alerts.acknowledge() {
+        page = page.next();
+        if let Err(e) = boot::save_page(&storage, page) {
+          warn!("Failed to save the page: {e}");
+        }
+      }
+      redraw = true;
+    }
 
-      FreeRtos::delay_ms(10000);
+    match state.distance_to_route() {
+      Some(distance) if distance > OFF_ROUTE_DISTANCE && !off_route => {
+        off_route = true;
+        let distance = format_distance(distance as u32, settings.units);
+        if alerts.raise(AlertKind::OffRoute, format!("{distance} away from the route")) {
+          sound.play(AlertKind::OffRoute);
+        }
+        redraw = true;
+      }
+      Some(distance) if distance <= OFF_ROUTE_DISTANCE && off_route => {
+        off_route = false;
+        alerts.clear(AlertKind::OffRoute);
+        redraw = true;
+      }
+      _ => {}
     }
-  }
 
-  const APP_ID: u16 = 0;
-  const MAX_CONNECTIONS: usize = 2;
+    let maneuver = state.maneuver.and_then(|i| state.route.get(i)).copied();
+    if let Some(distance) = state.distance_to_maneuver() {
+      if distance <= TURN_ALERT_DISTANCE && turn_alerted != maneuver {
+        turn_alerted = maneuver;
+        let distance = format_distance(distance as u32, settings.units);
+        if alerts.raise(AlertKind::UpcomingTurn, format!("Turn in {distance}")) {
+          sound.play(AlertKind::UpcomingTurn);
+        }
+        redraw = true;
+      }
+    }
 
-  // TODO: change uuids
+    match server.battery_level() {
+      Some(level) if level <= LOW_BATTERY_LEVEL && !low_battery => {
+        low_battery = true;
+        if alerts.raise(AlertKind::LowBattery, format!("{level}% remaining")) {
+          sound.play(AlertKind::LowBattery);
+        }
+        redraw = true;
+      }
+      Some(level) if level > LOW_BATTERY_LEVEL && low_battery => low_battery = false,
+      _ => {}
+    }
 
-  // Our service UUID
-  pub const SERVICE_UUID: u128 = 0xad91b201734740479e173bed82d75f9d;
+    match server.link_lost() {
+      Some(reason) if !link_lost => {
+        link_lost = true;
+        if alerts.raise(AlertKind::LinkLost, format!("Disconnected: {reason}")) {
+          sound.play(AlertKind::LinkLost);
+        }
+        redraw = true;
+      }
+      None if link_lost => {
+        link_lost = false;
+        alerts.clear(AlertKind::LinkLost);
+        redraw = true;
+      }
+      _ => {}
+    }
 
-  /// Our "recv" characteristic - i.e. where clients can send data.
-  pub const RECV_CHARACTERISTIC_UUID: u128 = 0xb6fccb5087be44f3ae22f85485ea42c4;
-  /// Our "indicate" characteristic - i.e. where clients can receive data if they subscribe to it
-  pub const IND_CHARACTERISTIC_UUID: u128 = 0x503de214868246c4828fd59144da41be;
+    let phone = server.phone_status();
+    let rssi = server.links().iter().filter_map(|link| link.stats.rssi).max();
+    let status = Status {
+      connected: server.connection_count() > 0,
+      signal: rssi.map(signal_bars),
+      phone_battery: phone.battery,
+      device_battery: server.battery_level(),
+      gps_fix: phone.gps_fix,
+      clock: clock::local_time().map(|time| (time.hours, time.minutes)),
+      alerts: alerts.len(),
+    };
 
-  // Name the types as they are used in the example to get shorter type signatures in the various functions below.
-  // note that - rather than `Arc`s, you can use regular references as well, but then you have to deal with lifetimes
-  // and the signatures below will not be `'static`.
-  type ExBtDriver = BtDriver<'static, Ble>;
-  type ExEspBleGap = Arc<EspBleGap<'static, Ble, Arc<ExBtDriver>>>;
-  type ExEspGatts = Arc<EspGatts<'static, Ble, Arc<ExBtDriver>>>;
+    if redraw {
+      display.clear(BinaryColor::On)?;
+      if let Some(pairing) = pairing {
+        PairingScreen::new(pairing.passkey, display.bounding_box()).draw(&mut display)?;
+      } else if let Some(alert) = alerts.current() {
+        AlertOverlay::new(alert, display.bounding_box()).draw(&mut display)?;
+      } else {
+        status_bar.draw(status, &mut display)?;
+        match page {
+          Page::Map => match &instruction {
+            Some(instruction) => {
+              NavigationBanner::new(instruction, page_bounds)
+                .units(settings.units)
+                .now(clock::local_time())
+                .draw(&mut display)?;
+              let map_bounds = Rectangle::new(
+                page_bounds.top_left + Point::new(0, BANNER_HEIGHT as i32),
+                page_bounds.size - Size::new(0, BANNER_HEIGHT),
+              );
+              MiniMap::new(&state, map_bounds).draw(&mut display)?;
+            }
+            None => MiniMap::new(&state, page_bounds).draw(&mut display)?,
+          },
+          Page::Ride => RideScreen::new(&ride, page_bounds).draw(&mut display)?,
+          Page::Info => InfoScreen::new(&info, page_bounds.offset(-8)).draw(&mut display)?,
+        }
+        if let Some(image) = &pushed_image {
+          image.draw(&mut display)?;
+        }
+      }
+      redraws += 1;
+      if settings.full_refresh_every != 0 && redraws >= settings.full_refresh_every {
+        // clear the ghosting left by the partial refreshes
+        redraws = 0;
+        display.refresh_full()?;
+      } else {
+        match settings.lut {
+          Lut::Fast => display.refresh_partial_fast()?,
+          Lut::Normal => display.refresh_partial()?,
+        }
+      }
+      display.send_as_previous_pixels()?;
+    } else if alerts.is_empty() && pairing.is_none() {
+      if let Some(image) = pushed_image.as_ref().filter(|_| image_changed) {
+        // only the pixels of the new image changed
+        image.draw(&mut display)?;
+        display.refresh_partial_fast_area(image.area())?;
+      }
+      if let Some(area) = status_bar.update(status, &mut display)? {
+        // keep the image on top of the status bar
+        if let Some(image) = &pushed_image {
+          image.draw(&mut display)?;
+        }
+        // the display is not put into deep sleep, so only the status bar needs to be sent
+        display.refresh_partial_fast_area(area)?;
+      }
+    }
 
-  #[derive(Debug, Clone)]
-  struct Connection {
-    peer: BdAddr,
-    conn_id: Handle,
-    subscribed: bool,
-    mtu: Option<u16>,
+    delay.delay_ms(50);
   }
+}
 
-  #[derive(Default)]
-  struct State {
-    gatt_if: Option<GattInterface>,
-    service_handle: Option<Handle>,
-    recv_handle: Option<Handle>,
-    ind_handle: Option<Handle>,
-    ind_cccd_handle: Option<Handle>,
-    connections: Vec<Connection>,
-    response: GattResponse,
-    ind_confirmed: Option<BdAddr>,
-  }
+// Based on https://github.com/esp-rs/esp-idf-svc/blob/b42dae55ccfef7c128da0cc8cfdb451f38572a0e/examples/bt_gatt_server.rs
+// Original license: MIT License / Copyright 2019-2020 Contributors to xtensa-lx6-rt
+/**
+ * Declarative GATT server on Bluedroid.
+ *
+ * The server itself is `navelo_gatt`, which also runs on the host against a fake stack. `EspStack` maps its calls and
+ * events to the ESP-IDF ones.
+ */
+pub mod gatt {
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
 
-  #[derive(Clone)]
-  pub struct ExampleServer {
-    gap: ExEspBleGap,
-    gatts: ExEspGatts,
-    state: Arc<Mutex<State>>,
-    condvar: Arc<Condvar>,
-  }
+  use enumset::EnumSet;
 
-  impl ExampleServer {
-    pub fn new(gap: ExEspBleGap, gatts: ExEspGatts) -> Self {
-      Self {
-        gap,
-        gatts,
-        state: Arc::new(Mutex::new(Default::default())),
-        condvar: Arc::new(Condvar::new()),
-      }
-    }
+  use esp_idf_svc::bt::ble::gap::{AdvConfiguration, BleGapEvent, EspBleGap};
+  use esp_idf_svc::bt::ble::gatt::server::{self as esp_gatts, EspGatts};
+  use esp_idf_svc::bt::ble::gatt::{
+    self as esp_gatt, AutoResponse, GattCharacteristic, GattDescriptor, GattId, GattResponse, GattServiceId,
+  };
+  use esp_idf_svc::bt::{self as esp_bt, Ble, BtDriver, BtStatus};
+  use esp_idf_svc::hal::modem::Modem;
+  use esp_idf_svc::nvs::EspDefaultNvsPartition;
+  use esp_idf_svc::sys::{self, esp, EspError, ESP_FAIL};
+
+  use log::{debug, info, warn};
+
+  pub use navelo_gatt::{
+    Activity, Advertisement, AdvertisingConfig, BdAddr, BtUuid, Characteristic, ConnectionId, Descriptor,
+    DisconnectReason, GattStatus, LinkStats, PairingEvent, Peer, ScanEvent, Service, Subscription, MAX_CONNECTIONS,
+  };
+  use navelo_gatt::{
+    AdvertisingParams, Bond, ConnParams, GapEvent, GattInterface, GattsEvent, Handle, LinkRole, Permission, Phy,
+    Property, Response, Stack, TransferId,
+  };
+
+  /// Scan interval and window in 0.625 ms units, scanning half of the time leaves room for advertising
+  const SCAN_INTERVAL: u16 = 0x50;
+  const SCAN_WINDOW: u16 = 0x28;
+  /// Link role of a connection where a central connected to this device, as opposed to a sensor it connected to
+  const LINK_ROLE_PERIPHERAL: u8 = 1;
+
+  pub type BleDriver = BtDriver<'static, Ble>;
+  pub type GattServer = navelo_gatt::GattServer<EspStack>;
+
+  /// The Bluedroid stack of the ESP-IDF
+  pub struct EspStack {
+    bt: Arc<BleDriver>,
+    gap: EspBleGap<'static, Ble, Arc<BleDriver>>,
+    gatts: EspGatts<'static, Ble, Arc<BleDriver>>,
+    response: Mutex<GattResponse>,
   }
 
-  impl ExampleServer {
-    /// Send (indicate) data to all peers that are currently
-    /// subscribed to our indication characteristic
-    ///
-    /// Uses a Mutex + Condvar to wait until indication confirmation
-    /// is received.
+  impl EspStack {
+    /// Initialize the BLE stack
     ///
-    /// This complexity is necessary only when using indications.
-    /// Notifications do not really send confirmation and thus do not
-    /// need this synchronization.
-    fn indicate(&self, data: &[u8]) -> Result<(), EspError> {
-      for peer_index in 0..MAX_CONNECTIONS {
-        // Propagate this data to all clients which are connected
-        // and have subscribed to our indication characteristic
-
-        let mut state = self.state.lock().unwrap();
-
-        loop {
-          if state.connections.len() <= peer_index {
-            // We've send to everybody who is connected
-            break;
-          }
+    /// Bonds are stored in `nvs` by the stack, so paired peers reconnect without a new passkey.
+    pub fn new(modem: Modem, nvs: EspDefaultNvsPartition) -> Result<Self, EspError> {
+      let bt = Arc::new(BtDriver::new(modem, Some(nvs))?);
+
+      let stack = Self {
+        gap: EspBleGap::new(bt.clone())?,
+        gatts: EspGatts::new(bt.clone())?,
+        bt,
+        response: Mutex::default(),
+      };
 
-          let Some(gatt_if) = state.gatt_if else {
-            // We lost the gatt interface in the meantime
-            break;
-          };
+      info!("BLE Gap and Gatts initialized");
 
-          let Some(ind_handle) = state.ind_handle else {
-            // We lost the indication handle in the meantime
-            break;
-          };
+      configure_security()?;
+      configure_scanning()?;
 
-          if state.ind_confirmed.is_none() {
-            let conn = &state.connections[peer_index];
+      Ok(stack)
+    }
 
-            self.gatts.indicate(gatt_if, conn.conn_id, ind_handle, data)?;
+    /// The BLE driver, to run a GATT client next to the server
+    pub fn driver(&self) -> Arc<BleDriver> {
+      self.bt.clone()
+    }
+  }
 
-            state.ind_confirmed = Some(conn.peer);
-            let conn = &state.connections[peer_index];
+  impl Stack for EspStack {
+    type Error = EspError;
 
-            info!("Indicated data to {}", conn.peer);
-            break;
-          } else {
-            state = self.condvar.wait(state).unwrap();
-          }
+    fn subscribe(
+      &self,
+      gap: impl Fn(GapEvent) + Send + Sync + 'static,
+      gatts: impl Fn(GattInterface, GattsEvent) + Send + Sync + 'static,
+    ) -> Result<(), EspError> {
+      self.gap.subscribe(move |event| {
+        if let Some(event) = gap_event(event) {
+          gap(event);
         }
-      }
+      })?;
 
-      Ok(())
+      self.gatts.subscribe(move |(gatt_if, event)| {
+        if let Some(event) = gatts_event(event) {
+          gatts(gatt_if, event);
+        }
+      })
     }
 
-    /// Sample callback where the user code can handle a newly-subscribed client
-    ///
-    /// If the user code just broadcasts the _same_ indication to all subscribed
-    /// clients, this callback might not be necessary.
-    fn on_subscribed(&self, addr: BdAddr) {
-      // Put your custom code here or leave empty
-      // `indicate()` will anyway send to all connected clients
-      warn!("Client {addr} subscribed - put your custom logic here");
+    fn configure_advertising(&self, name: &str, service: Option<&BtUuid>) -> Result<(), EspError> {
+      self.gap.set_device_name(name)?;
+      self.gap.set_adv_conf(&AdvConfiguration {
+        include_name: true,
+        include_txpower: true,
+        flag: 2,
+        service_uuid: service.map(esp_uuid),
+        ..Default::default()
+      })
     }
 
-    /// Sample callback where the user code can handle an unsubscribed client
-    ///
-    /// If the user code just broadcasts the _same_ indication to all subscribed
-    /// clients, this callback might not be necessary.
-    fn on_unsubscribed(&self, addr: BdAddr) {
-      // Put your custom code here
-      // `indicate()` will anyway send to all connected clients
-      warn!("Client {addr} unsubscribed - put your custom logic here");
+    fn start_advertising(&self, params: &AdvertisingParams) -> Result<(), EspError> {
+      let adv_type = match params.directed {
+        Some(_) => sys::esp_ble_adv_type_t_ADV_TYPE_DIRECT_IND_LOW,
+        None => sys::esp_ble_adv_type_t_ADV_TYPE_IND,
+      };
+
+      let mut params = sys::esp_ble_adv_params_t {
+        adv_int_min: interval_units(params.interval.0),
+        adv_int_max: interval_units(params.interval.1),
+        adv_type,
+        own_addr_type: sys::esp_ble_addr_type_t_BLE_ADDR_TYPE_PUBLIC,
+        peer_addr: params.directed.map_or([0; 6], |bond| bond.addr.raw()),
+        peer_addr_type: params
+          .directed
+          .map_or(sys::esp_ble_addr_type_t_BLE_ADDR_TYPE_PUBLIC, |bond| {
+            bond.addr_type.into()
+          }),
+        channel_map: sys::esp_ble_adv_channel_t_ADV_CHNL_ALL,
+        adv_filter_policy: sys::esp_ble_adv_filter_t_ADV_FILTER_ALLOW_SCAN_ANY_CON_ANY,
+      };
+
+      esp!(unsafe { sys::esp_ble_gap_start_advertising(&mut params) })
     }
 
-    /// Sample callback where the user code can handle received data
-    /// For demo purposes, the data is just logged.
-    fn on_recv(&self, addr: BdAddr, data: &[u8], offset: u16, mtu: Option<u16>) {
-      // Put your custom code here
-      warn!("Received data from {addr}: {data:?}, offset: {offset}, mtu: {mtu:?} - put your custom logic here");
+    fn stop_advertising(&self) -> Result<(), EspError> {
+      self.gap.stop_advertising()
     }
 
-    /// The main event handler for the GAP events
-    fn on_gap_event(&self, event: BleGapEvent) -> Result<(), EspError> {
-      info!("Got event: {event:?}");
+    fn bonds(&self) -> Vec<Bond> {
+      bond_list()
+        .iter()
+        .map(|bond| Bond {
+          addr: BdAddr::from_bytes(bond.bd_addr),
+          addr_type: bond.bond_key.pid_key.addr_type as u8,
+        })
+        .collect()
+    }
 
-      if let BleGapEvent::AdvertisingConfigured(status) = event {
-        self.check_bt_status(status)?;
-        self.gap.start_advertising()?;
-      }
+    fn security_response(&self, addr: BdAddr, accept: bool) -> Result<(), EspError> {
+      let mut raw = addr.raw();
+      esp!(unsafe { sys::esp_ble_gap_security_rsp(raw.as_mut_ptr(), accept) })
+    }
 
-      Ok(())
+    fn set_conn_params(&self, addr: BdAddr, params: &ConnParams) -> Result<(), EspError> {
+      let mut params = sys::esp_ble_conn_update_params_t {
+        bda: addr.raw(),
+        min_int: conn_interval_units(params.min_interval),
+        max_int: conn_interval_units(params.max_interval),
+        latency: params.latency,
+        timeout: (params.timeout.as_millis() / 10).clamp(0x0a, 0x0c80) as u16,
+      };
+
+      esp!(unsafe { sys::esp_ble_gap_update_conn_params(&mut params) })
     }
 
-    /// The main event handler for the GATTS events
-    fn on_gatts_event(&self, gatt_if: GattInterface, event: GattsEvent) -> Result<(), EspError> {
-      info!("Got event: {event:?}");
+    fn set_phy(&self, addr: BdAddr, phy: Phy) -> Result<(), EspError> {
+      let mask = match phy {
+        Phy::Le1M => sys::ESP_BLE_GAP_PHY_1M_PREF_MASK,
+        Phy::Le2M => sys::ESP_BLE_GAP_PHY_2M_PREF_MASK,
+      } as u8;
+      let mut raw = addr.raw();
+
+      esp!(unsafe {
+        sys::esp_ble_gap_set_preferred_phy(
+          raw.as_mut_ptr(),
+          0,
+          mask,
+          mask,
+          sys::ESP_BLE_GAP_PHY_OPTIONS_NO_PREF as _,
+        )
+      })
+    }
 
-      match event {
-        GattsEvent::ServiceRegistered { status, app_id } => {
-          self.check_gatt_status(status)?;
-          if APP_ID == app_id {
-            self.create_service(gatt_if)?;
-          }
-        }
-        GattsEvent::ServiceCreated {
-          status, service_handle, ..
-        } => {
-          self.check_gatt_status(status)?;
-          self.configure_and_start_service(service_handle)?;
-        }
-        GattsEvent::CharacteristicAdded {
-          status,
-          attr_handle,
-          service_handle,
-          char_uuid,
-        } => {
-          self.check_gatt_status(status)?;
-          self.register_characteristic(service_handle, attr_handle, char_uuid)?;
-        }
-        GattsEvent::DescriptorAdded {
-          status,
-          attr_handle,
-          service_handle,
-          descr_uuid,
-        } => {
-          self.check_gatt_status(status)?;
-          self.register_cccd_descriptor(service_handle, attr_handle, descr_uuid)?;
-        }
-        GattsEvent::ServiceDeleted { status, service_handle } => {
-          self.check_gatt_status(status)?;
-          self.delete_service(service_handle)?;
-        }
-        GattsEvent::ServiceUnregistered {
-          status, service_handle, ..
-        } => {
-          self.check_gatt_status(status)?;
-          self.unregister_service(service_handle)?;
-        }
-        GattsEvent::Mtu { conn_id, mtu } => {
-          self.register_conn_mtu(conn_id, mtu)?;
-        }
-        GattsEvent::PeerConnected { conn_id, addr, .. } => {
-          self.create_conn(conn_id, addr)?;
-        }
-        GattsEvent::PeerDisconnected { addr, .. } => {
-          self.delete_conn(addr)?;
-        }
-        GattsEvent::Write {
-          conn_id,
-          trans_id,
-          addr,
-          handle,
-          offset,
-          need_rsp,
-          is_prep,
-          value,
-        } => {
-          let handled = self.recv(
-            gatt_if, conn_id, trans_id, addr, handle, offset, need_rsp, is_prep, value,
-          )?;
+    fn set_data_length(&self, addr: BdAddr, tx_octets: u16) -> Result<(), EspError> {
+      let mut raw = addr.raw();
+      esp!(unsafe { sys::esp_ble_gap_set_pkt_data_len(raw.as_mut_ptr(), tx_octets) })
+    }
 
-          if handled {
-            self.send_write_response(gatt_if, conn_id, trans_id, handle, offset, need_rsp, is_prep, value)?;
-          }
-        }
-        GattsEvent::Confirm { status, .. } => {
-          self.check_gatt_status(status)?;
-          self.confirm_indication()?;
-        }
-        _ => (),
-      }
+    fn read_rssi(&self, addr: BdAddr) -> Result<(), EspError> {
+      let mut raw = addr.raw();
+      esp!(unsafe { sys::esp_ble_gap_read_rssi(raw.as_mut_ptr()) })
+    }
 
-      Ok(())
+    fn start_scanning(&self, duration: Duration) -> Result<(), EspError> {
+      esp!(unsafe { sys::esp_ble_gap_start_scanning(duration.as_secs().max(1) as u32) })
     }
 
-    /// Create the service and start advertising
-    /// Called from within the event callback once we are notified that the GATTS app is registered
-    fn create_service(&self, gatt_if: GattInterface) -> Result<(), EspError> {
-      self.state.lock().unwrap().gatt_if = Some(gatt_if);
+    fn stop_scanning(&self) -> Result<(), EspError> {
+      esp!(unsafe { sys::esp_ble_gap_stop_scanning() })
+    }
 
-      self.gap.set_device_name("ESP32")?;
-      self.gap.set_adv_conf(&AdvConfiguration {
-        include_name: true,
-        include_txpower: true,
-        flag: 2,
-        service_uuid: Some(BtUuid::uuid128(SERVICE_UUID)),
-        // service_data: todo!(),
-        // manufacturer_data: todo!(),
-        ..Default::default()
-      })?;
+    fn register_app(&self, app_id: u16) -> Result<(), EspError> {
+      self.gatts.register_app(app_id)
+    }
+
+    fn create_service(&self, gatt_if: GattInterface, uuid: &BtUuid, num_handles: u16) -> Result<(), EspError> {
       self.gatts.create_service(
         gatt_if,
         &GattServiceId {
           id: GattId {
-            uuid: BtUuid::uuid128(SERVICE_UUID),
+            uuid: esp_uuid(uuid),
             inst_id: 0,
           },
           is_primary: true,
         },
-        8,
-      )?;
-
-      Ok(())
-    }
-
-    /// Delete the service
-    /// Called from within the event callback once we are notified that the GATTS app is deleted
-    fn delete_service(&self, service_handle: Handle) -> Result<(), EspError> {
-      let mut state = self.state.lock().unwrap();
-
-      if state.service_handle == Some(service_handle) {
-        state.recv_handle = None;
-        state.ind_handle = None;
-        state.ind_cccd_handle = None;
-      }
-
-      Ok(())
-    }
-
-    /// Unregister the service
-    /// Called from within the event callback once we are notified that the GATTS app is unregistered
-    fn unregister_service(&self, service_handle: Handle) -> Result<(), EspError> {
-      let mut state = self.state.lock().unwrap();
-
-      if state.service_handle == Some(service_handle) {
-        state.gatt_if = None;
-        state.service_handle = None;
-      }
-
-      Ok(())
+        num_handles,
+      )
     }
 
-    /// Configure and start the service
-    /// Called from within the event callback once we are notified that the service is created
-    fn configure_and_start_service(&self, service_handle: Handle) -> Result<(), EspError> {
-      self.state.lock().unwrap().service_handle = Some(service_handle);
-
-      self.gatts.start_service(service_handle)?;
-      self.add_characteristics(service_handle)?;
-
-      Ok(())
+    fn start_service(&self, service_handle: Handle) -> Result<(), EspError> {
+      self.gatts.start_service(service_handle)
     }
 
-    /// Add our two characteristics to the service
-    /// Called from within the event callback once we are notified that the service is created
-    fn add_characteristics(&self, service_handle: Handle) -> Result<(), EspError> {
+    fn add_characteristic(
+      &self,
+      service_handle: Handle,
+      uuid: &BtUuid,
+      properties: EnumSet<Property>,
+      permissions: EnumSet<Permission>,
+      max_len: usize,
+    ) -> Result<(), EspError> {
       self.gatts.add_characteristic(
         service_handle,
         &GattCharacteristic {
-          uuid: BtUuid::uuid128(RECV_CHARACTERISTIC_UUID),
-          permissions: enum_set!(Permission::Write),
-          properties: enum_set!(Property::Write),
-          max_len: 200, // Max recv data
+          uuid: esp_uuid(uuid),
+          permissions: esp_permissions(permissions),
+          properties: properties.iter().map(esp_property).collect(),
+          max_len,
           auto_rsp: AutoResponse::ByApp,
         },
         &[],
-      )?;
+      )
+    }
 
-      self.gatts.add_characteristic(
+    fn add_descriptor(
+      &self,
+      service_handle: Handle,
+      uuid: &BtUuid,
+      permissions: EnumSet<Permission>,
+    ) -> Result<(), EspError> {
+      self.gatts.add_descriptor(
         service_handle,
-        &GattCharacteristic {
-          uuid: BtUuid::uuid128(IND_CHARACTERISTIC_UUID),
-          permissions: enum_set!(Permission::Write | Permission::Read),
-          properties: enum_set!(Property::Indicate),
-          max_len: 200, // Mac iondicate data
-          auto_rsp: AutoResponse::ByApp,
+        &GattDescriptor {
+          uuid: esp_uuid(uuid),
+          permissions: esp_permissions(permissions),
         },
-        &[],
-      )?;
-
-      Ok(())
+      )
     }
 
-    /// Add the CCCD descriptor
-    /// Called from within the event callback once we are notified that a char descriptor is added,
-    /// however the method will do something only if the added char is the "indicate" characteristics of course
-    fn register_characteristic(
+    fn send_response(
       &self,
-      service_handle: Handle,
-      attr_handle: Handle,
-      char_uuid: BtUuid,
+      gatt_if: GattInterface,
+      conn_id: ConnectionId,
+      trans_id: TransferId,
+      status: GattStatus,
+      response: Option<&Response>,
     ) -> Result<(), EspError> {
-      let indicate_char = {
-        let mut state = self.state.lock().unwrap();
-
-        if state.service_handle != Some(service_handle) {
-          false
-        } else if char_uuid == BtUuid::uuid128(RECV_CHARACTERISTIC_UUID) {
-          state.recv_handle = Some(attr_handle);
-
-          false
-        } else if char_uuid == BtUuid::uuid128(IND_CHARACTERISTIC_UUID) {
-          state.ind_handle = Some(attr_handle);
-
-          true
-        } else {
-          false
-        }
+      let status = esp_status(status);
+      let Some(response) = response else {
+        return self.gatts.send_response(gatt_if, conn_id, trans_id, status, None);
       };
 
-      if indicate_char {
-        self.gatts.add_descriptor(
-          service_handle,
-          &GattDescriptor {
-            uuid: BtUuid::uuid16(0x2902), // CCCD
-            permissions: enum_set!(Permission::Read | Permission::Write),
-          },
-        )?;
-      }
+      let mut buffer = self.response.lock().unwrap();
+      buffer
+        .attr_handle(response.handle)
+        .auth_req(0)
+        .offset(response.offset)
+        .value(response.value)
+        .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+      self
+        .gatts
+        .send_response(gatt_if, conn_id, trans_id, status, Some(&buffer))
+    }
 
-      Ok(())
+    fn notify(
+      &self,
+      gatt_if: GattInterface,
+      conn_id: ConnectionId,
+      handle: Handle,
+      data: &[u8],
+    ) -> Result<(), EspError> {
+      self.gatts.notify(gatt_if, conn_id, handle, data)
     }
 
-    /// Register the CCCD descriptor
-    /// Called from within the event callback once we are notified that a descriptor is added,
-    fn register_cccd_descriptor(
+    fn indicate(
       &self,
-      service_handle: Handle,
-      attr_handle: Handle,
-      descr_uuid: BtUuid,
+      gatt_if: GattInterface,
+      conn_id: ConnectionId,
+      handle: Handle,
+      data: &[u8],
     ) -> Result<(), EspError> {
-      let mut state = self.state.lock().unwrap();
+      self.gatts.indicate(gatt_if, conn_id, handle, data)
+    }
+  }
 
-      if descr_uuid == BtUuid::uuid16(0x2902) // CCCD UUID
-              && state.service_handle == Some(service_handle)
-      {
-        state.ind_cccd_handle = Some(attr_handle);
+  /// The GAP events the server handles
+  fn gap_event(event: BleGapEvent) -> Option<GapEvent> {
+    let success = |status| matches!(status, BtStatus::Success);
+
+    Some(match event {
+      BleGapEvent::AdvertisingConfigured(status) => GapEvent::AdvertisingConfigured {
+        success: success(status),
+      },
+      BleGapEvent::AdvertisingStarted(status) => GapEvent::AdvertisingStarted {
+        success: success(status),
+      },
+      BleGapEvent::AdvertisingStopped(status) => GapEvent::AdvertisingStopped {
+        success: success(status),
+      },
+      BleGapEvent::SecurityRequest(addr) => GapEvent::SecurityRequest(bd_addr(addr)),
+      BleGapEvent::PasskeyNotification { addr, passkey } => GapEvent::PasskeyNotification {
+        addr: bd_addr(addr),
+        passkey,
+      },
+      BleGapEvent::AuthenticationComplete { bd_addr: addr, status } => {
+        debug!("Authentication with {addr}: {status:?}");
+        GapEvent::AuthenticationComplete {
+          addr: bd_addr(addr),
+          success: success(status),
+        }
       }
+      BleGapEvent::ScanResult(result) => match result.search_evt {
+        sys::esp_gap_search_evt_t_ESP_GAP_SEARCH_INQ_RES_EVT => {
+          let len = result.adv_data_len as usize + result.scan_rsp_len as usize;
+          GapEvent::ScanResult(Advertisement {
+            addr: BdAddr::from_bytes(result.bda),
+            addr_type: result.ble_addr_type as u8,
+            rssi: result.rssi,
+            data: result.ble_adv[..len.min(result.ble_adv.len())].to_vec(),
+          })
+        }
+        sys::esp_gap_search_evt_t_ESP_GAP_SEARCH_INQ_CMPL_EVT => GapEvent::ScanComplete,
+        _ => return None,
+      },
+      BleGapEvent::ReadRssiConfigured {
+        bd_addr: addr,
+        rssi,
+        status,
+      } => GapEvent::Rssi {
+        addr: bd_addr(addr),
+        rssi: success(status).then_some(rssi),
+      },
+      _ => return None,
+    })
+  }
 
-      Ok(())
-    }
+  /// The GATTS events the server handles, with the borrowed data copied
+  fn gatts_event(event: esp_gatts::GattsEvent) -> Option<GattsEvent> {
+    use esp_gatts::GattsEvent as Event;
+
+    Some(match event {
+      Event::ServiceRegistered { status, app_id } => GattsEvent::ServiceRegistered {
+        status: gatt_status(status),
+        app_id,
+      },
+      Event::ServiceCreated {
+        status,
+        service_handle,
+        service_id,
+      } => GattsEvent::ServiceCreated {
+        status: gatt_status(status),
+        service_handle,
+        service_uuid: uuid(&service_id.id.uuid)?,
+      },
+      Event::CharacteristicAdded {
+        status,
+        attr_handle,
+        service_handle,
+        char_uuid,
+      } => GattsEvent::CharacteristicAdded {
+        status: gatt_status(status),
+        attr_handle,
+        service_handle,
+        char_uuid: uuid(&char_uuid)?,
+      },
+      Event::DescriptorAdded {
+        status,
+        attr_handle,
+        service_handle,
+        descr_uuid,
+      } => GattsEvent::DescriptorAdded {
+        status: gatt_status(status),
+        attr_handle,
+        service_handle,
+        descr_uuid: uuid(&descr_uuid)?,
+      },
+      Event::ServiceDeleted { status, service_handle } => GattsEvent::ServiceDeleted {
+        status: gatt_status(status),
+        service_handle,
+      },
+      Event::ServiceUnregistered { status, .. } => GattsEvent::ServiceUnregistered {
+        status: gatt_status(status),
+      },
+      Event::Mtu { conn_id, mtu } => GattsEvent::Mtu { conn_id, mtu },
+      Event::PeerConnected {
+        conn_id,
+        addr,
+        link_role,
+        ..
+      } => GattsEvent::PeerConnected {
+        conn_id,
+        addr: bd_addr(addr),
+        link_role: match link_role {
+          LINK_ROLE_PERIPHERAL => LinkRole::Peripheral,
+          _ => LinkRole::Central,
+        },
+      },
+      Event::PeerDisconnected { conn_id, addr, reason } => GattsEvent::PeerDisconnected {
+        conn_id,
+        addr: bd_addr(addr),
+        // HCI error codes, apart from a few Bluedroid ones like L2CAP failures
+        reason: DisconnectReason::from_code(reason),
+      },
+      Event::Write {
+        conn_id,
+        trans_id,
+        handle,
+        offset,
+        need_rsp,
+        is_prep,
+        value,
+        ..
+      } => GattsEvent::Write {
+        conn_id,
+        trans_id,
+        handle,
+        offset,
+        need_rsp,
+        is_prep,
+        value: value.to_vec(),
+      },
+      Event::ExecWrite {
+        conn_id,
+        trans_id,
+        canceled,
+        ..
+      } => GattsEvent::ExecWrite {
+        conn_id,
+        trans_id,
+        canceled,
+      },
+      Event::Read {
+        conn_id,
+        trans_id,
+        handle,
+        offset,
+        need_rsp,
+        ..
+      } => GattsEvent::Read {
+        conn_id,
+        trans_id,
+        handle,
+        offset,
+        need_rsp,
+      },
+      Event::Confirm {
+        status,
+        conn_id,
+        handle,
+        ..
+      } => GattsEvent::Confirm {
+        status: gatt_status(status),
+        conn_id,
+        handle,
+      },
+      Event::Congest { conn_id, congested } => GattsEvent::Congest { conn_id, congested },
+      _ => return None,
+    })
+  }
 
-    /// Receive data from a client
-    /// Called from within the event callback once we are notified for the connection MTU
-    fn register_conn_mtu(&self, conn_id: ConnectionId, mtu: u16) -> Result<(), EspError> {
-      let mut state = self.state.lock().unwrap();
+  fn bd_addr(addr: esp_bt::BdAddr) -> BdAddr {
+    BdAddr::from_bytes(addr.raw())
+  }
 
-      if let Some(conn) = state.connections.iter_mut().find(|conn| conn.conn_id == conn_id) {
-        conn.mtu = Some(mtu);
-      }
+  pub fn esp_addr(addr: BdAddr) -> esp_bt::BdAddr {
+    esp_bt::BdAddr::from_bytes(addr.raw())
+  }
 
-      Ok(())
+  fn uuid(uuid: &esp_bt::BtUuid) -> Option<BtUuid> {
+    match *uuid.as_bytes() {
+      [a, b] => Some(BtUuid::uuid16(u16::from_le_bytes([a, b]))),
+      [a, b, c, d] => Some(BtUuid::uuid32(u32::from_le_bytes([a, b, c, d]))),
+      ref bytes => match bytes.try_into() {
+        Ok(bytes) => Some(BtUuid::uuid128(u128::from_le_bytes(bytes))),
+        Err(_) => {
+          warn!("Invalid UUID: {uuid:?}");
+          None
+        }
+      },
     }
+  }
 
-    /// Create a new connection
-    /// Called from within the event callback once we are notified for a new connection
-    fn create_conn(&self, conn_id: ConnectionId, addr: BdAddr) -> Result<(), EspError> {
-      let added = {
-        let mut state = self.state.lock().unwrap();
+  fn esp_uuid(uuid: &BtUuid) -> esp_bt::BtUuid {
+    match *uuid {
+      BtUuid::Uuid16(uuid) => esp_bt::BtUuid::uuid16(uuid),
+      BtUuid::Uuid32(uuid) => esp_bt::BtUuid::uuid32(uuid),
+      BtUuid::Uuid128(uuid) => esp_bt::BtUuid::uuid128(uuid),
+    }
+  }
 
-        if state.connections.len() < MAX_CONNECTIONS {
-          state.connections.push(Connection {
-            peer: addr,
-            conn_id,
-            subscribed: false,
-            mtu: None,
-          });
+  fn gatt_status(status: esp_gatt::GattStatus) -> GattStatus {
+    match status {
+      esp_gatt::GattStatus::Ok => GattStatus::Ok,
+      esp_gatt::GattStatus::InvalidHandle => GattStatus::InvalidHandle,
+      esp_gatt::GattStatus::ReadNotPermit => GattStatus::ReadNotPermit,
+      esp_gatt::GattStatus::WriteNotPermit => GattStatus::WriteNotPermit,
+      esp_gatt::GattStatus::InvalidOffset => GattStatus::InvalidOffset,
+      esp_gatt::GattStatus::PrepareQFull => GattStatus::PrepareQFull,
+      esp_gatt::GattStatus::InvalidAttrLen => GattStatus::InvalidAttrLen,
+      esp_gatt::GattStatus::ErrUnlikely => GattStatus::ErrUnlikely,
+      _ => GattStatus::Error,
+    }
+  }
 
-          true
-        } else {
-          false
-        }
-      };
+  fn esp_status(status: GattStatus) -> esp_gatt::GattStatus {
+    match status {
+      GattStatus::Ok => esp_gatt::GattStatus::Ok,
+      GattStatus::InvalidHandle => esp_gatt::GattStatus::InvalidHandle,
+      GattStatus::ReadNotPermit => esp_gatt::GattStatus::ReadNotPermit,
+      GattStatus::WriteNotPermit => esp_gatt::GattStatus::WriteNotPermit,
+      GattStatus::InvalidOffset => esp_gatt::GattStatus::InvalidOffset,
+      GattStatus::PrepareQFull => esp_gatt::GattStatus::PrepareQFull,
+      GattStatus::InvalidAttrLen => esp_gatt::GattStatus::InvalidAttrLen,
+      GattStatus::ErrUnlikely => esp_gatt::GattStatus::ErrUnlikely,
+      GattStatus::Error => esp_gatt::GattStatus::Error,
+    }
+  }
 
-      if added {
-        self.gap.set_conn_params_conf(addr, 10, 20, 0, 400)?;
-      }
+  fn esp_property(property: Property) -> esp_gatt::Property {
+    match property {
+      Property::Read => esp_gatt::Property::Read,
+      Property::WriteWithoutResponse => esp_gatt::Property::WriteWithoutResponse,
+      Property::Write => esp_gatt::Property::Write,
+      Property::Notify => esp_gatt::Property::Notify,
+      Property::Indicate => esp_gatt::Property::Indicate,
+    }
+  }
 
-      Ok(())
+  fn esp_permissions(permissions: EnumSet<Permission>) -> EnumSet<esp_gatt::Permission> {
+    let permission = |permission| match permission {
+      Permission::Read => esp_gatt::Permission::Read,
+      Permission::ReadEncryptedMitm => esp_gatt::Permission::ReadEncryptedMitm,
+      Permission::Write => esp_gatt::Permission::Write,
+      Permission::WriteEncryptedMitm => esp_gatt::Permission::WriteEncryptedMitm,
+    };
+    permissions.iter().map(permission).collect()
+  }
+
+  /// LE Secure Connections with MITM protection and bonding; the device can only display a passkey
+  fn configure_security() -> Result<(), EspError> {
+    fn set(param: sys::esp_ble_sm_param_t, value: u8) -> Result<(), EspError> {
+      let mut value = value;
+      esp!(unsafe { sys::esp_ble_gap_set_security_param(param, &mut value as *mut u8 as *mut _, 1) })
     }
 
-    /// Delete a connection
-    /// Called from within the event callback once we are notified for a disconnected peer
-    fn delete_conn(&self, addr: BdAddr) -> Result<(), EspError> {
-      let mut state = self.state.lock().unwrap();
+    let keys = (sys::ESP_BLE_ENC_KEY_MASK | sys::ESP_BLE_ID_KEY_MASK) as u8;
+
+    set(
+      sys::esp_ble_sm_param_t_ESP_BLE_SM_AUTHEN_REQ_MODE,
+      sys::ESP_LE_AUTH_REQ_SC_MITM_BOND as u8,
+    )?;
+    set(sys::esp_ble_sm_param_t_ESP_BLE_SM_IOCAP_MODE, sys::ESP_IO_CAP_OUT as u8)?;
+    set(sys::esp_ble_sm_param_t_ESP_BLE_SM_MAX_KEY_SIZE, 16)?;
+    set(
+      sys::esp_ble_sm_param_t_ESP_BLE_SM_ONLY_ACCEPT_SPECIFIED_SEC_AUTH,
+      sys::ESP_BLE_ONLY_ACCEPT_SPECIFIED_AUTH_ENABLE as u8,
+    )?;
+    set(sys::esp_ble_sm_param_t_ESP_BLE_SM_SET_INIT_KEY, keys)?;
+    set(sys::esp_ble_sm_param_t_ESP_BLE_SM_SET_RSP_KEY, keys)?;
 
-      if let Some(index) = state
-        .connections
-        .iter()
-        .position(|Connection { peer, .. }| *peer == addr)
-      {
-        state.connections.swap_remove(index);
-      }
+    Ok(())
+  }
 
-      Ok(())
+  /// Passive scanning, the sensors put their services into the advertising data
+  fn configure_scanning() -> Result<(), EspError> {
+    let mut params = sys::esp_ble_scan_params_t {
+      scan_type: sys::esp_ble_scan_type_t_BLE_SCAN_TYPE_PASSIVE,
+      own_addr_type: sys::esp_ble_addr_type_t_BLE_ADDR_TYPE_PUBLIC,
+      scan_filter_policy: sys::esp_ble_scan_filter_t_BLE_SCAN_FILTER_ALLOW_ALL,
+      scan_interval: SCAN_INTERVAL,
+      scan_window: SCAN_WINDOW,
+      scan_duplicate: sys::esp_ble_scan_duplicate_t_BLE_SCAN_DUPLICATE_ENABLE,
+    };
+    esp!(unsafe { sys::esp_ble_gap_set_scan_params(&mut params) })
+  }
+
+  /// Peers bonded with this device, as stored in NVS
+  pub fn bonded_devices() -> Vec<BdAddr> {
+    bond_list()
+      .iter()
+      .map(|device| BdAddr::from_bytes(device.bd_addr))
+      .collect()
+  }
+
+  fn bond_list() -> Vec<sys::esp_ble_bond_dev_t> {
+    let mut count = unsafe { sys::esp_ble_get_bond_device_num() };
+    if count <= 0 {
+      return Vec::new();
     }
 
-    /// A helper method to process a client sending us data to the "recv" characteristic
-    #[allow(clippy::too_many_arguments)]
-    fn recv(
-      &self,
-      _gatt_if: GattInterface,
-      conn_id: ConnectionId,
-      _trans_id: TransferId,
-      addr: BdAddr,
-      handle: Handle,
-      offset: u16,
-      _need_rsp: bool,
-      _is_prep: bool,
-      value: &[u8],
-    ) -> Result<bool, EspError> {
-      let mut state = self.state.lock().unwrap();
+    let mut list: Vec<sys::esp_ble_bond_dev_t> = Vec::with_capacity(count as usize);
+    let result = unsafe { sys::esp_ble_get_bond_device_list(&mut count, list.as_mut_ptr()) };
+    if esp!(result).is_err() {
+      return Vec::new();
+    }
+    unsafe { list.set_len(count.max(0) as usize) };
+
+    list
+  }
 
-      let recv_handle = state.recv_handle;
-      let ind_cccd_handle = state.ind_cccd_handle;
+  /// Advertising interval in units of 0.625 ms, within the range allowed by the spec
+  fn interval_units(interval: Duration) -> u16 {
+    (interval.as_micros() / 625).clamp(0x20, 0x4000) as u16
+  }
+
+  /// Connection interval in units of 1.25 ms, within the range allowed by the spec
+  fn conn_interval_units(interval: Duration) -> u16 {
+    (interval.as_micros() / 1250).clamp(0x06, 0x0c80) as u16
+  }
+
+  /// Forget a bonded peer, it has to pair again with a new passkey
+  pub fn remove_bond(addr: BdAddr) -> Result<(), EspError> {
+    let mut raw = addr.raw();
+    esp!(unsafe { sys::esp_ble_remove_bond_device(raw.as_mut_ptr()) })
+  }
+}
+
+/**
+ * Navelo GATT services, see `gatt` for the server itself.
+ */
+pub mod bluetooth {
+  use std::collections::HashMap;
+  use std::sync::atomic::{AtomicU8, Ordering};
+  use std::sync::{Arc, Mutex, OnceLock};
+  use std::thread::{sleep, spawn};
+  use std::time::{Duration, Instant};
+
+  use esp_idf_svc::hal::modem::Modem;
+  use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+  use esp_idf_svc::sys::EspError;
+
+  use log::{debug, info, warn};
+
+  use crate::boot::{self, BUILD_HASH, HARDWARE_REVISION, MAX_NAME_LEN, VERSION};
+  use crate::central::SensorCentral;
+  use crate::clock::{self, DateTime, CURRENT_TIME_LEN, LOCAL_TIME_INFO_LEN, MANUAL_TIME_UPDATE, TIME_ZONE_CHANGE};
+  use crate::cycling::RideMetrics;
+  use crate::dfu::{Dfu, DfuState};
+  #[cfg(feature = "standard-profiles")]
+  use crate::gatt::Descriptor;
+  use crate::gatt::{
+    Activity, BdAddr, BtUuid, Characteristic, ConnectionId, DisconnectReason, EspStack, GattServer, GattStatus,
+    LinkStats, PairingEvent, Peer, Service,
+  };
+  use crate::image::{self, ImageMessage, ImageState};
+  #[cfg(feature = "standard-profiles")]
+  use crate::location::{
+    control_response, ControlResult, LocationAndSpeed, NavigationControl, NavigationData, FEATURES,
+    LN_CONTROL_POINT_CHARACTERISTIC_UUID, LN_FEATURE_CHARACTERISTIC_UUID, LN_NAVIGATION_CHARACTERISTIC_UUID,
+    LOCATION_AND_SPEED_CHARACTERISTIC_UUID, LOCATION_NAVIGATION_SERVICE_UUID,
+  };
+  use crate::minimap::{MapMessage, MapState};
+  #[cfg(feature = "standard-profiles")]
+  use crate::movement::ENVIRONMENT_INTERVAL;
+  use crate::movement::{MovementConfig, SAMPLE_LEN};
+  use crate::navigation::{fragment, Instruction, Reassembler};
+  use crate::sensors::BmpValues;
+  use crate::settings::{self, Settings, RECORD_LEN};
+  use crate::ui::PhoneStatus;
+  use crate::utils::ble_address;
+
+  /// Movement service, see `movement`
+  pub const MOVEMENT_SERVICE_UUID: u128 = 0x01379a00_7b58_4dda_af7b_4b87d25b4296;
+  /// IMU samples, notified at the configured period
+  pub const MOVEMENT_DATA_CHARACTERISTIC_UUID: u128 = 0x01379a01_7b58_4dda_af7b_4b87d25b4296;
+  /// Bitmask of the enabled axes
+  pub const MOVEMENT_CONFIG_CHARACTERISTIC_UUID: u128 = 0x01379a02_7b58_4dda_af7b_4b87d25b4296;
+  /// Sampling period in 10 ms units
+  pub const MOVEMENT_PERIOD_CHARACTERISTIC_UUID: u128 = 0x01379a03_7b58_4dda_af7b_4b87d25b4296;
+
+  /// Data pushed by the phone while navigating
+  pub const NAVIGATION_SERVICE_UUID: u128 = 0x01379b00_7b58_4dda_af7b_4b87d25b4296;
+  /// Route geometry and position for the mini-map, see `minimap::MapMessage`
+  pub const MAP_CHARACTERISTIC_UUID: u128 = 0x01379b01_7b58_4dda_af7b_4b87d25b4296;
+  /// Phone battery, GPS fix and time for the status bar, see `ui::PhoneStatus`
+  pub const PHONE_STATUS_CHARACTERISTIC_UUID: u128 = 0x01379b02_7b58_4dda_af7b_4b87d25b4296;
+  /// Fragmented turn-by-turn instructions, see `navigation::Instruction`
+  /// Instructions from the navigation source are notified to the other peers, fragmented for their MTU.
+  pub const NAVIGATION_CHARACTERISTIC_UUID: u128 = 0x01379b03_7b58_4dda_af7b_4b87d25b4296;
+
+  /// Bitmaps drawn on the display, see `image`
+  pub const IMAGE_SERVICE_UUID: u128 = 0x01379d00_7b58_4dda_af7b_4b87d25b4296;
+  /// Fragmented image messages, see `image::ImageMessage`
+  pub const IMAGE_CHARACTERISTIC_UUID: u128 = 0x01379d01_7b58_4dda_af7b_4b87d25b4296;
+
+  /// Standard Battery Service
+  pub const BATTERY_SERVICE_UUID: u16 = 0x180f;
+  /// Battery level in percent
+  pub const BATTERY_LEVEL_CHARACTERISTIC_UUID: u16 = 0x2a19;
+
+  /// Standard Environmental Sensing Service, from the barometer
+  pub const ENVIRONMENTAL_SENSING_SERVICE_UUID: u16 = 0x181a;
+  /// Pressure in 0.1 Pa
+  pub const PRESSURE_CHARACTERISTIC_UUID: u16 = 0x2a6d;
+  /// Temperature in 0.01 degrees Celsius
+  pub const TEMPERATURE_CHARACTERISTIC_UUID: u16 = 0x2a6e;
+  /// When the ESS characteristics are notified
+  pub const ES_TRIGGER_SETTING_DESCRIPTOR_UUID: u16 = 0x290d;
+  /// ES Trigger Setting condition: fixed time interval, followed by the interval in seconds as a u24
+  pub const TRIGGER_FIXED_INTERVAL: u8 = 0x01;
+
+  /// Configuration of the device from the phone
+  pub const SETTINGS_SERVICE_UUID: u128 = 0x01379c00_7b58_4dda_af7b_4b87d25b4296;
+  /// UTF-8 device name, persisted and advertised
+  pub const DEVICE_NAME_CHARACTERISTIC_UUID: u128 = 0x01379c01_7b58_4dda_af7b_4b87d25b4296;
+  /// Display, audio and sensor settings, see `settings::Settings`
+  pub const SETTINGS_CHARACTERISTIC_UUID: u128 = 0x01379c02_7b58_4dda_af7b_4b87d25b4296;
+  /// 1 if the reading peer is the primary navigation source; write 1 to become it, 0 to give it up
+  pub const ROLE_CHARACTERISTIC_UUID: u128 = 0x01379c03_7b58_4dda_af7b_4b87d25b4296;
+
+  /// Firmware update, see `dfu`
+  pub const DFU_SERVICE_UUID: u128 = 0x01379e00_7b58_4dda_af7b_4b87d25b4296;
+  /// Commands written by the phone, the update state is notified back
+  pub const DFU_CONTROL_CHARACTERISTIC_UUID: u128 = 0x01379e01_7b58_4dda_af7b_4b87d25b4296;
+  /// Image chunks prefixed by their offset
+  pub const DFU_DATA_CHARACTERISTIC_UUID: u128 = 0x01379e02_7b58_4dda_af7b_4b87d25b4296;
+
+  /// Standard Current Time Service, the phone sets the clock through it
+  pub const CURRENT_TIME_SERVICE_UUID: u16 = 0x1805;
+  /// Local date and time, see `clock::DateTime::encode_current_time`
+  pub const CURRENT_TIME_CHARACTERISTIC_UUID: u16 = 0x2a2b;
+  /// Time zone and DST offset
+  pub const LOCAL_TIME_INFORMATION_CHARACTERISTIC_UUID: u16 = 0x2a0f;
+
+  /// Standard Device Information Service
+  pub const DEVICE_INFORMATION_SERVICE_UUID: u16 = 0x180a;
+  pub const MODEL_NUMBER_CHARACTERISTIC_UUID: u16 = 0x2a24;
+  pub const SERIAL_NUMBER_CHARACTERISTIC_UUID: u16 = 0x2a25;
+  pub const FIRMWARE_REVISION_CHARACTERISTIC_UUID: u16 = 0x2a26;
+  pub const HARDWARE_REVISION_CHARACTERISTIC_UUID: u16 = 0x2a27;
+  pub const MANUFACTURER_NAME_CHARACTERISTIC_UUID: u16 = 0x2a29;
+
+  const MANUFACTURER_NAME: &str = "Navelo";
+  const MODEL_NUMBER: &str = "Navelo Bike Computer";
+
+  /// How often the link profile is matched to what the device is doing
+  const ACTIVITY_PERIOD: Duration = Duration::from_secs(1);
+  /// The phone sends an instruction at least every few seconds while a route is followed
+  const NAVIGATION_TIMEOUT: Duration = Duration::from_secs(30);
+  /// How often the RSSI of the links is read, the app reads it as often on its side
+  const RSSI_PERIOD: Duration = Duration::from_secs(5);
+  /// A navigating phone which stays disconnected this long is reported lost
+  const LINK_LOST_TIMEOUT: Duration = Duration::from_secs(10);
+  /// The stack adds the attributes one by one, which takes well under a second
+  const REGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+  #[derive(Clone)]
+  pub struct NaveloServer {
+    gatt: GattServer,
+    map: Arc<Mutex<MapState>>,
+    phone_status: Arc<Mutex<PhoneStatus>>,
+    navigation: Arc<Mutex<Option<Instruction>>>,
+    /// When each peer sent its last navigation instruction
+    navigation_at: Arc<Mutex<HashMap<BdAddr, Instant>>>,
+    image: Arc<Mutex<ImageState>>,
+    movement: Arc<Mutex<MovementConfig>>,
+    movement_sample: Arc<Mutex<[u8; SAMPLE_LEN]>>,
+    battery_level: Arc<Mutex<Option<u8>>>,
+    pairing: Arc<Mutex<Option<Pairing>>>,
+    dfu: Dfu,
+    settings: Arc<Mutex<Settings>>,
+    central: SensorCentral,
+    environment: Arc<Mutex<Option<BmpValues>>>,
+    /// Peers which started the navigation through the LN Control Point
+    #[cfg(feature = "standard-profiles")]
+    ln_navigating: Arc<Mutex<Vec<ConnectionId>>>,
+  }
+
+  /// Which connected central the navigation data is taken from
+  ///
+  /// While the primary central is connected, the navigation writes of the other centrals (e.g. a logger) are
+  /// rejected. Without a connected primary, any central may navigate.
+  #[derive(Clone)]
+  struct Roles {
+    primary: Arc<Mutex<Option<BdAddr>>>,
+    server: Arc<OnceLock<GattServer>>,
+  }
+
+  impl Roles {
+    fn is_primary(&self, peer: &Peer) -> bool {
+      *self.primary.lock().unwrap() == Some(peer.addr)
+    }
 
-      let Some(conn) = state.connections.iter_mut().find(|conn| conn.conn_id == conn_id) else {
-        return Ok(false);
+    fn is_navigation_source(&self, peer: &Peer) -> bool {
+      let Some(primary) = *self.primary.lock().unwrap() else {
+        return true;
       };
+      let connected = |server: &GattServer| server.peers().iter().any(|peer| peer.addr == primary);
+      peer.addr == primary || !self.server.get().is_some_and(connected)
+    }
+
+    /// Reject a navigation write of a peer which is not the source
+    fn check_source(&self, peer: &Peer) -> Result<(), GattStatus> {
+      if !self.is_navigation_source(peer) {
+        info!(
+          "Ignored navigation data from {}, it is not the primary central",
+          peer.addr
+        );
+        return Err(GattStatus::WriteNotPermit);
+      }
+      Ok(())
+    }
+  }
+
+  /// Health of the link to a connected peer
+  #[derive(Debug, Clone, Copy)]
+  pub struct PeerLink {
+    pub addr: BdAddr,
+    pub stats: LinkStats,
+    /// Time since the peer sent the last navigation instruction
+    pub since_navigation: Option<Duration>,
+  }
+
+  /// Pairing in progress, the passkey is shown until it completes
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub struct Pairing {
+    pub addr: BdAddr,
+    pub passkey: u32,
+  }
+
+  /// Initialize the BLE stack and register the Navelo services
+  ///
+  /// `battery_level` is the level read before starting, see `battery::sample_level`
+  pub fn start(modem: Modem, nvs: EspDefaultNvsPartition, battery_level: Option<u8>) -> anyhow::Result<NaveloServer> {
+    let map = Arc::new(Mutex::new(MapState::default()));
+    let phone_status = Arc::new(Mutex::new(PhoneStatus::default()));
+    let navigation = Arc::new(Mutex::new(None));
+    let navigation_at = Arc::new(Mutex::new(HashMap::new()));
+    let image = Arc::new(Mutex::new(ImageState::default()));
+    let storage = EspNvs::new(nvs.clone(), boot::NVS_NAMESPACE, true)?;
+    let name = boot::device_name(&storage);
+    let settings = settings::load(&storage);
+    clock::load_utc_offset(&storage);
+    let storage = Arc::new(Mutex::new(storage));
+
+    let movement = Arc::new(Mutex::new(MovementConfig {
+      period: settings.imu_period,
+      ..Default::default()
+    }));
+    let settings = Arc::new(Mutex::new(settings));
+    let movement_sample = Arc::new(Mutex::new([0; SAMPLE_LEN]));
+    let battery_level = Arc::new(Mutex::new(battery_level));
+    let pairing = Arc::new(Mutex::new(None));
+    let environment = Arc::new(Mutex::new(None));
+    #[cfg(feature = "standard-profiles")]
+    let ln_navigating = Arc::new(Mutex::new(Vec::new()));
+
+    // the name handler renames the server it belongs to, so the server is set once it is started
+    let server = Arc::new(OnceLock::<GattServer>::new());
+    let roles = Roles {
+      primary: Arc::new(Mutex::new(boot::primary_central(&storage.lock().unwrap()))),
+      server: server.clone(),
+    };
 
-      if Some(handle) == ind_cccd_handle {
-        // Subscribe or unsubscribe to our indication characteristic
+    let dfu_server = server.clone();
+    let dfu = Dfu::spawn(move |state| {
+      if let Some(server) = dfu_server.get() {
+        let _ = server.notify(&BtUuid::uuid128(DFU_CONTROL_CHARACTERISTIC_UUID), &state.encode());
+      }
+    });
 
-        if offset == 0 && value.len() == 2 {
-          let value = u16::from_le_bytes([value[0], value[1]]);
-          if value == 0x02 {
-            if !conn.subscribed {
-              conn.subscribed = true;
-              self.on_subscribed(conn.peer);
+    let pairing_handler = pairing.clone();
+    let builder = GattServer::builder()
+      .name(&name)
+      .advertise(BtUuid::uuid128(MOVEMENT_SERVICE_UUID))
+      .service(device_information_service())
+      .service(settings_service(&storage, name, &settings, &movement, &roles))
+      .service(dfu_service(&dfu))
+      .service(time_service(&storage, &server))
+      .service(movement_service(&storage, &settings, &movement, &movement_sample))
+      .service(battery_service(&battery_level))
+      .service(navigation_service(
+        &storage,
+        &map,
+        &phone_status,
+        &navigation,
+        &navigation_at,
+        &roles,
+      ))
+      .service(image_service(&image, &roles));
+    // for third-party apps, next to the Navelo services
+    #[cfg(feature = "standard-profiles")]
+    let builder = builder
+      .service(location_service(&ln_navigating, &server))
+      .service(environmental_service(&environment));
+    let gatt = builder
+      .on_pairing(move |event| {
+        let mut pairing = pairing_handler.lock().unwrap();
+        match event {
+          PairingEvent::Passkey { addr, passkey } => *pairing = Some(Pairing { addr, passkey }),
+          PairingEvent::Complete { addr, .. } => {
+            if pairing.is_some_and(|pairing| pairing.addr == addr) {
+              *pairing = None;
             }
-          } else if conn.subscribed {
-            conn.subscribed = false;
-            self.on_unsubscribed(conn.peer);
           }
         }
-      } else if Some(handle) == recv_handle {
-        // Receive data on the recv characteristic
+      })
+      .start(EspStack::new(modem, nvs)?)?;
+    let _ = server.set(gatt.clone());
+    let central = SensorCentral::start(&gatt)?;
+
+    let server = NaveloServer {
+      gatt,
+      map,
+      phone_status,
+      navigation,
+      navigation_at,
+      image,
+      movement,
+      movement_sample,
+      battery_level,
+      pairing,
+      dfu,
+      settings,
+      central,
+      environment,
+      #[cfg(feature = "standard-profiles")]
+      ln_navigating,
+    };
 
-        self.on_recv(addr, value, offset, conn.mtu);
-      } else {
-        return Ok(false);
+    let ticker = server.clone();
+    spawn(move || {
+      let mut rssi_at = Instant::now();
+      loop {
+        sleep(ACTIVITY_PERIOD);
+        if let Err(e) = ticker.gatt.set_activity(ticker.activity()) {
+          warn!("Failed to update the link profile: {e:?}");
+        }
+
+        if rssi_at.elapsed() >= RSSI_PERIOD {
+          rssi_at = Instant::now();
+          // the values are reported later, these are the ones of the previous read
+          for link in ticker.links() {
+            debug!(
+              "Link to {}: {:?}, last navigation {:?} ago",
+              link.addr, link.stats, link.since_navigation
+            );
+          }
+          if let Err(e) = ticker.gatt.read_rssi() {
+            warn!("Failed to read the RSSI: {e:?}");
+          }
+        }
       }
+    });
+
+    Ok(server)
+  }
+
+  fn movement_service(
+    storage: &Arc<Mutex<EspNvs<NvsDefault>>>,
+    settings: &Arc<Mutex<Settings>>,
+    movement: &Arc<Mutex<MovementConfig>>,
+    sample: &Arc<Mutex<[u8; SAMPLE_LEN]>>,
+  ) -> Service {
+    let (config_read, config_write) = (movement.clone(), movement.clone());
+    let (period_read, period_write) = (movement.clone(), movement.clone());
+    let (settings, settings_storage) = (settings.clone(), storage.clone());
+    let sample = sample.clone();
+
+    Service::new(BtUuid::uuid128(MOVEMENT_SERVICE_UUID))
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(MOVEMENT_DATA_CHARACTERISTIC_UUID))
+          .max_len(SAMPLE_LEN)
+          .on_read(move |_| sample.lock().unwrap().to_vec())
+          .notify(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(MOVEMENT_CONFIG_CHARACTERISTIC_UUID))
+          .max_len(2)
+          .on_read(move |_| config_read.lock().unwrap().enabled.to_le_bytes().to_vec())
+          .on_write(move |peer, data| {
+            if !config_write.lock().unwrap().write_enabled(data) {
+              warn!("Invalid movement config from {}: {data:?}", peer.addr);
+              return Err(GattStatus::InvalidAttrLen);
+            }
+            Ok(())
+          })
+          .encrypted(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(MOVEMENT_PERIOD_CHARACTERISTIC_UUID))
+          .max_len(1)
+          .on_read(move |_| vec![period_read.lock().unwrap().period])
+          .on_write(move |peer, data| {
+            let mut movement = period_write.lock().unwrap();
+            if !movement.write_period(data) {
+              warn!("Invalid movement period from {}: {data:?}", peer.addr);
+              return Err(GattStatus::InvalidAttrLen);
+            }
+
+            // the period is part of the settings record, so that it survives a reboot
+            let mut settings = settings.lock().unwrap();
+            if settings.imu_period != movement.period {
+              settings.imu_period = movement.period;
+              if let Err(e) = settings::save(&settings_storage.lock().unwrap(), &settings) {
+                warn!("Failed to save the settings: {e}");
+              }
+            }
+            Ok(())
+          })
+          .encrypted(),
+      )
+  }
+
+  fn device_information_service() -> Service {
+    let serial = ble_address().map(|b| format!("{b:02X}")).concat();
+    let firmware = format!("{VERSION} ({BUILD_HASH})");
+
+    Service::new(BtUuid::uuid16(DEVICE_INFORMATION_SERVICE_UUID))
+      .characteristic(read_only(MANUFACTURER_NAME_CHARACTERISTIC_UUID, MANUFACTURER_NAME))
+      .characteristic(read_only(MODEL_NUMBER_CHARACTERISTIC_UUID, MODEL_NUMBER))
+      .characteristic(read_only(SERIAL_NUMBER_CHARACTERISTIC_UUID, &serial))
+      .characteristic(read_only(HARDWARE_REVISION_CHARACTERISTIC_UUID, HARDWARE_REVISION))
+      .characteristic(read_only(FIRMWARE_REVISION_CHARACTERISTIC_UUID, &firmware))
+  }
+
+  fn read_only(uuid: u16, value: &str) -> Characteristic {
+    let value = value.as_bytes().to_vec();
+    Characteristic::new(BtUuid::uuid16(uuid))
+      .max_len(value.len())
+      .on_read(move |_| value.clone())
+  }
+
+  fn settings_service(
+    storage: &Arc<Mutex<EspNvs<NvsDefault>>>,
+    name: String,
+    settings: &Arc<Mutex<Settings>>,
+    movement: &Arc<Mutex<MovementConfig>>,
+    roles: &Roles,
+  ) -> Service {
+    let name = Arc::new(Mutex::new(name));
+    let name_read = name.clone();
+    let name_storage = storage.clone();
+    let server = roles.server.clone();
+    let (settings_read, settings_write) = (settings.clone(), settings.clone());
+    let settings_storage = storage.clone();
+    let movement = movement.clone();
+    let (role_read, role_write) = (roles.clone(), roles.clone());
+    let role_storage = storage.clone();
+
+    Service::new(BtUuid::uuid128(SETTINGS_SERVICE_UUID))
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(DEVICE_NAME_CHARACTERISTIC_UUID))
+          .max_len(MAX_NAME_LEN)
+          .on_read(move |_| name_read.lock().unwrap().as_bytes().to_vec())
+          .on_write(move |peer, data| {
+            let new_name = match std::str::from_utf8(data) {
+              Ok(new_name) if !new_name.is_empty() && new_name.len() <= MAX_NAME_LEN => new_name,
+              _ => {
+                warn!("Invalid device name from {}: {data:?}", peer.addr);
+                return Err(GattStatus::InvalidAttrLen);
+              }
+            };
+
+            info!("Device renamed to {new_name:?} by {}", peer.addr);
+            if let Err(e) = boot::save_device_name(&name_storage.lock().unwrap(), new_name) {
+              warn!("Failed to save the device name: {e}");
+            }
+            if let Some(Err(e)) = server.get().map(|server| server.set_name(new_name)) {
+              warn!("Failed to apply the device name: {e}");
+            }
+            *name.lock().unwrap() = new_name.to_string();
+            Ok(())
+          })
+          .encrypted(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(SETTINGS_CHARACTERISTIC_UUID))
+          .max_len(RECORD_LEN)
+          .on_read(move |_| settings_read.lock().unwrap().encode().to_vec())
+          .on_write(move |peer, data| {
+            let new_settings = Settings::decode(data).map_err(|e| {
+              warn!("Invalid settings from {}: {e}", peer.addr);
+              GattStatus::InvalidAttrLen
+            })?;
+
+            info!("Settings changed by {}: {new_settings:?}", peer.addr);
+            if let Err(e) = settings::save(&settings_storage.lock().unwrap(), &new_settings) {
+              warn!("Failed to save the settings: {e}");
+            }
+            movement.lock().unwrap().period = new_settings.imu_period;
+            *settings_write.lock().unwrap() = new_settings;
+            Ok(())
+          })
+          .encrypted(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(ROLE_CHARACTERISTIC_UUID))
+          .max_len(1)
+          .on_read(move |peer| vec![u8::from(role_read.is_primary(peer))])
+          .on_write(move |peer, data| {
+            let mut primary = role_write.primary.lock().unwrap();
+            let new_primary = match *data {
+              [1] => Some(peer.addr),
+              [0] if *primary == Some(peer.addr) => None,
+              [0] => return Ok(()),
+              _ => {
+                warn!("Invalid role from {}: {data:?}", peer.addr);
+                return Err(GattStatus::InvalidAttrLen);
+              }
+            };
+
+            info!("Primary central changed to {new_primary:?} by {}", peer.addr);
+            if let Err(e) = boot::save_primary_central(&role_storage.lock().unwrap(), new_primary) {
+              warn!("Failed to save the primary central: {e}");
+            }
+            *primary = new_primary;
+            Ok(())
+          })
+          .encrypted(),
+      )
+  }
+
+  fn dfu_service(dfu: &Dfu) -> Service {
+    let (control_read, control_write, data) = (dfu.clone(), dfu.clone(), dfu.clone());
+
+    Service::new(BtUuid::uuid128(DFU_SERVICE_UUID))
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(DFU_CONTROL_CHARACTERISTIC_UUID))
+          .max_len(128) // start command with the signature
+          .on_read(move |_| control_read.state().encode().to_vec())
+          .on_write(move |peer, data| {
+            control_write.command(data).map_err(|e| {
+              warn!("Invalid DFU command from {}: {e}", peer.addr);
+              GattStatus::InvalidAttrLen
+            })
+          })
+          .notify()
+          .encrypted(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(DFU_DATA_CHARACTERISTIC_UUID))
+          .max_len(512) // Max attribute length, chunks are limited by the MTU
+          .on_write(move |peer, chunk| {
+            data.chunk(chunk).map_err(|e| {
+              warn!("Invalid DFU chunk from {}: {e}", peer.addr);
+              GattStatus::InvalidAttrLen
+            })
+          })
+          .write_without_response()
+          .encrypted(),
+      )
+  }
+
+  #[cfg(feature = "standard-profiles")]
+  fn location_service(navigating: &Arc<Mutex<Vec<ConnectionId>>>, server: &Arc<OnceLock<GattServer>>) -> Service {
+    let navigating = navigating.clone();
+    let server = server.clone();
+
+    Service::new(BtUuid::uuid16(LOCATION_NAVIGATION_SERVICE_UUID))
+      .characteristic(
+        Characteristic::new(BtUuid::uuid16(LN_FEATURE_CHARACTERISTIC_UUID))
+          .max_len(4)
+          .on_read(|_| FEATURES.to_le_bytes().to_vec()),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid16(LOCATION_AND_SPEED_CHARACTERISTIC_UUID))
+          .max_len(14)
+          .notify(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid16(LN_NAVIGATION_CHARACTERISTIC_UUID))
+          .max_len(16)
+          .notify(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid16(LN_CONTROL_POINT_CHARACTERISTIC_UUID))
+          .max_len(20)
+          .on_write(move |peer, data| {
+            let result = NavigationControl::decode(data).map(|control| {
+              let mut navigating = navigating.lock().unwrap();
+              navigating.retain(|&conn_id| conn_id != peer.conn_id);
+              if control == NavigationControl::Start {
+                navigating.push(peer.conn_id);
+              }
+            });
+            let response = control_response(data, result.err().unwrap_or(ControlResult::Success));
+            if let Some(server) = server.get() {
+              let uuid = BtUuid::uuid16(LN_CONTROL_POINT_CHARACTERISTIC_UUID);
+              if let Err(e) = server.indicate_peer(peer.conn_id, &uuid, &response) {
+                warn!("Failed to indicate the LN control point response: {e}");
+              }
+            }
+            Ok(())
+          })
+          .indicate(),
+      )
+  }
+
+  #[cfg(feature = "standard-profiles")]
+  fn environmental_service(environment: &Arc<Mutex<Option<BmpValues>>>) -> Service {
+    let (pressure, temperature) = (environment.clone(), environment.clone());
+
+    Service::new(BtUuid::uuid16(ENVIRONMENTAL_SENSING_SERVICE_UUID))
+      .characteristic(
+        Characteristic::new(BtUuid::uuid16(PRESSURE_CHARACTERISTIC_UUID))
+          .max_len(4)
+          // 0 Pa would be taken for a reading, so reads are rejected until the barometer is sampled
+          .on_try_read(move |_| match *pressure.lock().unwrap() {
+            Some(values) => Ok(pressure_value(values).to_vec()),
+            None => Err(GattStatus::ReadNotPermit),
+          })
+          .notify()
+          .descriptor(es_trigger_setting()),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid16(TEMPERATURE_CHARACTERISTIC_UUID))
+          .max_len(2)
+          .on_try_read(move |_| match *temperature.lock().unwrap() {
+            Some(values) => Ok(temperature_value(values).to_vec()),
+            None => Err(GattStatus::ReadNotPermit),
+          })
+          .notify()
+          .descriptor(es_trigger_setting()),
+      )
+  }
+
+  /// The values are notified every time the barometer is read
+  #[cfg(feature = "standard-profiles")]
+  fn es_trigger_setting() -> Descriptor {
+    let [a, b, c, _] = (ENVIRONMENT_INTERVAL.as_secs() as u32).to_le_bytes();
+    Descriptor::new(
+      BtUuid::uuid16(ES_TRIGGER_SETTING_DESCRIPTOR_UUID),
+      [TRIGGER_FIXED_INTERVAL, a, b, c],
+    )
+  }
+
+  fn pressure_value(values: BmpValues) -> [u8; 4] {
+    ((values.pressure * 10.0).round().max(0.0) as u32).to_le_bytes()
+  }
+
+  fn temperature_value(values: BmpValues) -> [u8; 2] {
+    ((values.temperature * 100.0).round() as i16).to_le_bytes()
+  }
+
+  fn time_service(storage: &Arc<Mutex<EspNvs<NvsDefault>>>, server: &Arc<OnceLock<GattServer>>) -> Service {
+    let storage = storage.clone();
+    let (time_server, zone_server) = (server.clone(), server.clone());
+
+    Service::new(BtUuid::uuid16(CURRENT_TIME_SERVICE_UUID))
+      .characteristic(
+        Characteristic::new(BtUuid::uuid16(CURRENT_TIME_CHARACTERISTIC_UUID))
+          .max_len(CURRENT_TIME_LEN)
+          .on_read(|_| match clock::local_time() {
+            Some(time) => time.encode_current_time(0).to_vec(),
+            None => vec![0; CURRENT_TIME_LEN], // year 0 is unknown
+          })
+          .on_write(move |peer, data| {
+            let time = DateTime::decode_current_time(data).map_err(|e| {
+              warn!("Invalid current time from {}: {e}", peer.addr);
+              GattStatus::InvalidAttrLen
+            })?;
+
+            info!("Time set to {time:?} by {}", peer.addr);
+            // the characteristic holds the local time
+            clock::set_unix_time(time.to_unix() - clock::utc_offset() as i64 * 60);
+            notify_time(&time_server, MANUAL_TIME_UPDATE);
+            Ok(())
+          })
+          .notify()
+          .encrypted(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid16(LOCAL_TIME_INFORMATION_CHARACTERISTIC_UUID))
+          .max_len(LOCAL_TIME_INFO_LEN)
+          .on_read(|_| clock::encode_local_time_info(clock::utc_offset()).to_vec())
+          .on_write(move |peer, data| {
+            let utc_offset = clock::decode_local_time_info(data).map_err(|e| {
+              warn!("Invalid local time information from {}: {e}", peer.addr);
+              GattStatus::InvalidAttrLen
+            })?;
+
+            info!("UTC offset set to {utc_offset} min by {}", peer.addr);
+            save_utc_offset(&storage, utc_offset);
+            // the phone moved to another time zone, the local time it wrote before is still right
+            clock::change_utc_offset(utc_offset);
+            notify_time(&zone_server, TIME_ZONE_CHANGE);
+            Ok(())
+          })
+          .encrypted(),
+      )
+  }
 
-      Ok(true)
+  /// Save the UTC offset if it changed, so that the clock shows the local time after a reboot
+  fn save_utc_offset(storage: &Mutex<EspNvs<NvsDefault>>, utc_offset: i32) {
+    if utc_offset == clock::utc_offset() {
+      return;
+    }
+    if let Err(e) = clock::save_utc_offset(&storage.lock().unwrap(), utc_offset) {
+      warn!("Failed to save the UTC offset: {e}");
     }
+  }
 
-    /// A helper method that sends a response to the peer that just sent us some data on the "recv"
-    /// characteristic.
-    ///
-    /// This is only necessary, because we support write confirmation
-    /// (which is the more complex case as compared to unconfirmed writes).
-    #[allow(clippy::too_many_arguments)]
-    fn send_write_response(
-      &self,
-      gatt_if: GattInterface,
-      conn_id: ConnectionId,
-      trans_id: TransferId,
-      handle: Handle,
-      offset: u16,
-      need_rsp: bool,
-      is_prep: bool,
-      value: &[u8],
-    ) -> Result<(), EspError> {
-      if !need_rsp {
-        return Ok(());
+  fn notify_time(server: &OnceLock<GattServer>, adjust_reason: u8) {
+    if let (Some(server), Some(time)) = (server.get(), clock::local_time()) {
+      let value = time.encode_current_time(adjust_reason);
+      let _ = server.notify(&BtUuid::uuid16(CURRENT_TIME_CHARACTERISTIC_UUID), &value);
+    }
+  }
+
+  fn battery_service(battery_level: &Arc<Mutex<Option<u8>>>) -> Service {
+    let battery_level = battery_level.clone();
+
+    Service::new(BtUuid::uuid16(BATTERY_SERVICE_UUID)).characteristic(
+      Characteristic::new(BtUuid::uuid16(BATTERY_LEVEL_CHARACTERISTIC_UUID))
+        .max_len(1)
+        // the level is sampled before the service is registered, it is only missing if the ADC failed
+        .on_read(move |_| vec![battery_level.lock().unwrap().unwrap_or(0)])
+        .notify(),
+    )
+  }
+
+  fn navigation_service(
+    storage: &Arc<Mutex<EspNvs<NvsDefault>>>,
+    map: &Arc<Mutex<MapState>>,
+    phone_status: &Arc<Mutex<PhoneStatus>>,
+    navigation: &Arc<Mutex<Option<Instruction>>>,
+    navigation_at: &Arc<Mutex<HashMap<BdAddr, Instant>>>,
+    roles: &Roles,
+  ) -> Service {
+    let storage = storage.clone();
+    let map = map.clone();
+    let phone_status = phone_status.clone();
+    let navigation = navigation.clone();
+    let navigation_at = navigation_at.clone();
+    let (map_roles, status_roles, navigation_roles) = (roles.clone(), roles.clone(), roles.clone());
+    let reassemblers = Arc::new(Mutex::new(HashMap::<ConnectionId, Reassembler>::new()));
+    let disconnected = reassemblers.clone();
+    let message_id = AtomicU8::new(0);
+
+    Service::new(BtUuid::uuid128(NAVIGATION_SERVICE_UUID))
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(MAP_CHARACTERISTIC_UUID))
+          .max_len(512) // Max attribute length
+          .on_write(move |peer, data| {
+            map_roles.check_source(peer)?;
+            match MapMessage::decode(data) {
+              Ok(message) => map.lock().unwrap().apply(message),
+              Err(e) => warn!("Invalid map message from {}: {e}", peer.addr),
+            }
+            Ok(())
+          })
+          .encrypted(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(PHONE_STATUS_CHARACTERISTIC_UUID))
+          .max_len(8)
+          .on_write(move |peer, data| {
+            status_roles.check_source(peer)?;
+            match PhoneStatus::decode(data) {
+              Ok(status) => {
+                if let Some((time, utc_offset)) = status.time {
+                  save_utc_offset(&storage, utc_offset);
+                  clock::set_unix_time(time);
+                  clock::set_utc_offset(utc_offset);
+                }
+                *phone_status.lock().unwrap() = status;
+              }
+              Err(e) => warn!("Invalid phone status from {}: {e}", peer.addr),
+            }
+            Ok(())
+          })
+          .encrypted(),
+      )
+      .characteristic(
+        Characteristic::new(BtUuid::uuid128(NAVIGATION_CHARACTERISTIC_UUID))
+          .max_len(512) // Max attribute length, fragments are limited by the MTU
+          .on_write(move |peer, data| {
+            navigation_roles.check_source(peer)?;
+            let message = match reassemblers.lock().unwrap().entry(peer.conn_id).or_default().push(data) {
+              Ok(Some(message)) => message,
+              Ok(None) => return Ok(()),
+              Err(e) => {
+                warn!(
+                  "Invalid navigation fragment from {} (mtu: {}): {e}",
+                  peer.addr,
+                  peer.mtu()
+                );
+                return Ok(());
+              }
+            };
+
+            match Instruction::decode(&message) {
+              Ok(instruction) => {
+                *navigation.lock().unwrap() = Some(instruction);
+                navigation_at.lock().unwrap().insert(peer.addr, Instant::now());
+              }
+              Err(e) => {
+                warn!("Invalid navigation instruction from {}: {e}", peer.addr);
+                return Ok(());
+              }
+            }
+
+            if let Some(server) = navigation_roles.server.get() {
+              let id = message_id.fetch_add(1, Ordering::Relaxed);
+              let uuid = BtUuid::uuid128(NAVIGATION_CHARACTERISTIC_UUID);
+              if let Err(e) = server.notify_fragmented(&uuid, |mtu| fragment(&message, id, mtu)) {
+                warn!("Failed to forward the navigation instruction: {e}");
+              }
+            }
+            Ok(())
+          })
+          // a half received message of a peer is dropped with its connection, the id is reused by the next peer
+          .on_disconnect(move |peer| {
+            disconnected.lock().unwrap().remove(&peer.conn_id);
+          })
+          .write_without_response()
+          .notify()
+          .encrypted(),
+      )
+  }
+
+  fn image_service(image: &Arc<Mutex<ImageState>>, roles: &Roles) -> Service {
+    let image = image.clone();
+    let roles = roles.clone();
+    let reassemblers = Arc::new(Mutex::new(HashMap::<ConnectionId, Reassembler>::new()));
+    let disconnected = reassemblers.clone();
+
+    Service::new(BtUuid::uuid128(IMAGE_SERVICE_UUID)).characteristic(
+      Characteristic::new(BtUuid::uuid128(IMAGE_CHARACTERISTIC_UUID))
+        .max_len(512) // Max attribute length, fragments are limited by the MTU
+        .on_write(move |peer, data| {
+          roles.check_source(peer)?;
+          let message = match reassemblers
+            .lock()
+            .unwrap()
+            .entry(peer.conn_id)
+            .or_insert_with(|| Reassembler::with_limit(image::MAX_MESSAGE_LEN))
+            .push(data)
+          {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+              warn!("Invalid image fragment from {}: {e}", peer.addr);
+              return Ok(());
+            }
+          };
+
+          match ImageMessage::decode(&message) {
+            Ok(message) => image.lock().unwrap().apply(message),
+            Err(e) => warn!("Invalid image from {}: {e}", peer.addr),
+          }
+          Ok(())
+        })
+        .on_disconnect(move |peer| {
+          disconnected.lock().unwrap().remove(&peer.conn_id);
+        })
+        .write_without_response()
+        .encrypted(),
+    )
+  }
+
+  impl NaveloServer {
+    /// Mini-map state, updated by the phone
+    pub fn map(&self) -> Arc<Mutex<MapState>> {
+      self.map.clone()
+    }
+
+    /// Image pushed by the phone, drawn over the current page
+    pub fn image(&self) -> Arc<Mutex<ImageState>> {
+      self.image.clone()
+    }
+
+    /// Latest status reported by the phone
+    pub fn phone_status(&self) -> PhoneStatus {
+      *self.phone_status.lock().unwrap()
+    }
+
+    /// Latest turn-by-turn instruction from the phone
+    pub fn navigation(&self) -> Option<Instruction> {
+      self.navigation.lock().unwrap().clone()
+    }
+
+    /// Number of currently connected peers
+    pub fn connection_count(&self) -> usize {
+      self.gatt.connection_count()
+    }
+
+    /// Wait until every service is registered with the stack, false if that did not happen in time
+    pub fn wait_registered(&self) -> bool {
+      let started = Instant::now();
+      while !self.gatt.is_registered() {
+        if started.elapsed() > REGISTER_TIMEOUT {
+          warn!("GATT services were not registered in {REGISTER_TIMEOUT:?}");
+          return false;
+        }
+        sleep(Duration::from_millis(100));
       }
+      true
+    }
 
-      if is_prep {
-        let mut state = self.state.lock().unwrap();
+    /// Speed, cadence, heart rate and power from the connected cycling sensors
+    pub fn ride_metrics(&self) -> RideMetrics {
+      self.central.metrics()
+    }
 
-        state
-          .response
-          .attr_handle(handle)
-          .auth_req(0)
-          .offset(offset)
-          .value(value)
-          .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+    /// Movement sensors and period requested by the peers
+    pub fn movement_config(&self) -> MovementConfig {
+      *self.movement.lock().unwrap()
+    }
 
+    /// Notify a movement sample to all peers subscribed to the movement data characteristic
+    pub fn notify_movement(&self, data: &[u8; SAMPLE_LEN]) -> Result<(), EspError> {
+      *self.movement_sample.lock().unwrap() = *data;
+      self
+        .gatt
+        .notify(&BtUuid::uuid128(MOVEMENT_DATA_CHARACTERISTIC_UUID), data)
+    }
+
+    /// Whether notifications are piling up because a link is congested
+    pub fn is_congested(&self) -> bool {
+      self.gatt.is_congested()
+    }
+
+    /// Latest battery level of this device, in percent
+    pub fn battery_level(&self) -> Option<u8> {
+      *self.battery_level.lock().unwrap()
+    }
+
+    /// Update the battery level and notify it to all peers subscribed to the battery level characteristic
+    pub fn set_battery_level(&self, level: u8) -> Result<(), EspError> {
+      *self.battery_level.lock().unwrap() = Some(level);
+      self
+        .gatt
+        .notify(&BtUuid::uuid16(BATTERY_LEVEL_CHARACTERISTIC_UUID), &[level])
+    }
+
+    /// Update the barometer reading and notify it to the Environmental Sensing subscribers
+    pub fn set_environment(&self, values: BmpValues) -> Result<(), EspError> {
+      *self.environment.lock().unwrap() = Some(values);
+      self
+        .gatt
+        .notify(&BtUuid::uuid16(PRESSURE_CHARACTERISTIC_UUID), &pressure_value(values))?;
+      self.gatt.notify(
+        &BtUuid::uuid16(TEMPERATURE_CHARACTERISTIC_UUID),
+        &temperature_value(values),
+      )
+    }
+
+    /// Notify the LNS location to all subscribers, and the navigation to the peers which started it
+    #[cfg(feature = "standard-profiles")]
+    pub fn notify_location(
+      &self,
+      location: &LocationAndSpeed,
+      navigation: Option<&NavigationData>,
+    ) -> Result<(), EspError> {
+      self.gatt.notify(
+        &BtUuid::uuid16(LOCATION_AND_SPEED_CHARACTERISTIC_UUID),
+        &location.encode(),
+      )?;
+
+      let peers = self.gatt.peers();
+      let mut navigating = self.ln_navigating.lock().unwrap();
+      navigating.retain(|&conn_id| peers.iter().any(|peer| peer.conn_id == conn_id));
+      let Some(navigation) = navigation else {
+        return Ok(());
+      };
+      let data = navigation.encode();
+      for &conn_id in navigating.iter() {
         self
-          .gatts
-          .send_response(gatt_if, conn_id, trans_id, GattStatus::Ok, Some(&state.response))?;
-      } else {
-        self
-          .gatts
-          .send_response(gatt_if, conn_id, trans_id, GattStatus::Ok, None)?;
+          .gatt
+          .notify_peer(conn_id, &BtUuid::uuid16(LN_NAVIGATION_CHARACTERISTIC_UUID), &data)?;
       }
-
       Ok(())
     }
 
-    /// A helper method to handle an indication confirmation.
-    /// Basically, we need to notify the "indicate" method that sending the indication was
-    /// confirmed, so that it is free to send the next indication.
-    fn confirm_indication(&self) -> Result<(), EspError> {
-      let mut state = self.state.lock().unwrap();
-      if state.ind_confirmed.is_none() {
-        // Should not happen: means we have received a confirmation for
-        // an indication we did not send.
-        unreachable!();
-      }
+    /// Current settings, they may change at any time
+    pub fn settings(&self) -> Settings {
+      *self.settings.lock().unwrap()
+    }
 
-      state.ind_confirmed = None; // So that the main loop can send the next indication
-      self.condvar.notify_all();
+    /// Progress of a firmware update
+    pub fn dfu_state(&self) -> DfuState {
+      self.dfu.state()
+    }
 
-      Ok(())
+    /// Passkey to show while a phone is pairing
+    pub fn pairing(&self) -> Option<Pairing> {
+      *self.pairing.lock().unwrap()
     }
 
-    fn check_esp_status(&self, status: Result<(), EspError>) {
-      if let Err(e) = status {
-        warn!("Got status: {:?}", e);
-      }
+    /// Connected peers and the health of their links
+    pub fn links(&self) -> Vec<PeerLink> {
+      let navigation_at = self.navigation_at.lock().unwrap();
+
+      let links = self.gatt.links().into_iter();
+      links
+        .map(|(peer, stats)| PeerLink {
+          addr: peer.addr,
+          stats,
+          since_navigation: navigation_at.get(&peer.addr).map(Instant::elapsed),
+        })
+        .collect()
     }
 
-    fn check_bt_status(&self, status: BtStatus) -> Result<(), EspError> {
-      if !matches!(status, BtStatus::Success) {
-        warn!("Got status: {:?}", status);
-        Err(EspError::from_infallible::<ESP_FAIL>())
-      } else {
-        Ok(())
-      }
+    /// Why the phone which was navigating disconnected, if it did not come back in time
+    ///
+    /// Cleared once it reconnects, or once any peer navigates again.
+    pub fn link_lost(&self) -> Option<DisconnectReason> {
+      let navigation_at = self.navigation_at.lock().unwrap();
+
+      let navigating = |addr, at: Instant| {
+        let last = navigation_at.get(&addr);
+        last.is_some_and(|&last| at.saturating_duration_since(last) < NAVIGATION_TIMEOUT)
+      };
+      let disconnects = self.gatt.disconnects();
+      let lost = disconnects.iter().rev().find(|d| navigating(d.addr, d.at))?;
+
+      let resumed = navigation_at.values().any(|&at| at > lost.at);
+      let back = self.gatt.peers().iter().any(|peer| peer.addr == lost.addr);
+      (!resumed && !back && lost.at.elapsed() > LINK_LOST_TIMEOUT).then_some(lost.reason)
     }
 
-    fn check_gatt_status(&self, status: GattStatus) -> Result<(), EspError> {
-      if !matches!(status, GattStatus::Ok) {
-        warn!("Got status: {:?}", status);
-        Err(EspError::from_infallible::<ESP_FAIL>())
+    /// What the links are tuned for: a firmware update, then a route being followed, else the dashboard
+    fn activity(&self) -> Activity {
+      if matches!(self.dfu_state(), DfuState::Receiving { .. }) {
+        return Activity::Transfer;
+      }
+      let recent = |at: &Instant| at.elapsed() < NAVIGATION_TIMEOUT;
+      let navigating = self.navigation_at.lock().unwrap().values().any(recent);
+      #[cfg(feature = "standard-profiles")]
+      let navigating = navigating || !self.ln_navigating.lock().unwrap().is_empty();
+      if navigating {
+        Activity::Navigating
       } else {
-        Ok(())
+        Activity::Idle
       }
     }
   }
@@ -794,6 +2100,7 @@ pub mod display {
   use embedded_graphics::{
     pixelcolor::BinaryColor,
     prelude::{DrawTarget, OriginDimensions, Size},
+    primitives::Rectangle,
     Pixel,
   };
   use embedded_hal::{
@@ -927,6 +2234,39 @@ pub mod display {
       self.refresh(0b0000_0100)?; // display with current lut, without sleep
       Ok(())
     }
+    /**
+     Refresh only the pixels inside `area` with the fast lut. The area is widened to multiples of 8 in x.
+     The controller ram must still hold the previous pixels, i.e. no deep sleep since the last refresh and
+     `send_as_previous_pixels` after a full frame refresh.
+    */
+    pub fn refresh_partial_fast_area(&mut self, area: Rectangle) -> Result<(), DisplayError> {
+      let Some(bottom_right) = area.bottom_right() else {
+        return Ok(());
+      };
+      let x = (area.top_left.x.clamp(0, WIDTH as i32 - 1) as u8) & !0b111;
+      let y = area.top_left.y.clamp(0, HEIGHT as i32 - 1) as u8;
+      let x_end = (bottom_right.x.clamp(0, WIDTH as i32 - 1) as u8) | 0b111;
+      let y_end = bottom_right.y.clamp(0, HEIGHT as i32 - 1) as u8;
+      let (width, height) = (x_end - x + 1, y_end - y + 1);
+
+      self.init()?;
+      self.wait_until_idle()?;
+      self.send_command(WRITE_LUT_REGISTER)?;
+      self.send_data(&FAST_LUT)?;
+
+      self.set_ram_area(x, y, width, height)?;
+      self.send_command(WRITE_RAM)?;
+      self.send_pixels_area_data(x, y, width, height)?;
+      self.activate(0b1100_0111)?; // display with current lut, then sleep
+      self.state = DisplayState::Sleep;
+
+      // keep the previous pixels in sync for the next partial refresh
+      self.wait_until_idle()?;
+      self.set_ram_area(x, y, width, height)?;
+      self.send_command(WRITE_RAM_RED)?;
+      self.send_pixels_area_data(x, y, width, height)?;
+      Ok(())
+    }
     /**
      Send the current pixels as the previous pixels.
      Required for partial refresh after deep dleep.
@@ -992,6 +2332,9 @@ pub mod display {
 
       // self.pixels_prev.copy_from_slice(&self.pixels);
 
+      self.activate(mode)
+    }
+    fn activate(&mut self, mode: u8) -> Result<(), DisplayError> {
       self.send_command(DISPLAY_UPDATE_CONTROL_1)?;
       self.send_data(&[0x00])?; // display ram content
       self.send_command(DISPLAY_UPDATE_CONTROL_2)?;
@@ -1034,6 +2377,17 @@ pub mod display {
       self.spi.write(&self.pixels).unwrap();
       Ok(())
     }
+    fn send_pixels_area_data(&mut self, x: u8, y: u8, width: u8, height: u8) -> Result<(), DisplayError> {
+      let stride = WIDTH as usize / 8;
+      let (x, width) = (x as usize / 8, width as usize / 8);
+      let data: Vec<u8> = (y as usize..y as usize + height as usize)
+        .flat_map(|row| &self.pixels[row * stride + x..row * stride + x + width])
+        .copied()
+        .collect();
+      self.dc.set_high().unwrap();
+      self.spi.write(&data).unwrap();
+      Ok(())
+    }
 
     pub fn is_busy(&mut self) -> Result<bool, DisplayError> {
       Ok(self.busy.is_high().unwrap())
@@ -1148,204 +2502,1864 @@ pub mod display {
   }
 }
 
-pub mod audio {
-  use std::{cmp, time::Duration};
+/**
+ * Bitmap fonts packed into flash, and text boxes for the 200x200 epaper.
+ * Font files are generated by `fontgen`, see fontgen/src/main.rs for the format.
+ * Line breaking and bidi reordering are in `navelo-text`, which only needs the glyph widths.
+ */
+pub mod font {
+  use std::cmp::Ordering;
 
-  use esp_idf_hal::{
-    rmt::{PinState, Pulse, PulseTicks, Symbol, TxRmtDriver},
-    units::Hertz,
+  use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
   };
+  use navelo_text::Metrics;
+  pub use navelo_text::{Direction, Wrap};
 
-  #[derive(Debug, Clone, Copy, Eq, PartialEq)]
-  pub enum Letter {
-    C,
-    Cs,
-    D,
-    Ds,
-    E,
-    F,
-    Fs,
-    G,
-    Gs,
-    A,
-    As0,
-    B,
-  }
+  pub static FONT_12: Font = Font::new(include_bytes!("font12.bin"));
+  pub static FONT_16: Font = Font::new(include_bytes!("font16.bin"));
 
-  #[derive(Debug, Clone, Copy, Eq, PartialEq)]
-  pub struct LetterOctave(Letter, u8);
-  impl LetterOctave {
-    pub fn new(letter: Letter, octave: u8) -> Self {
-      Self(letter, octave)
-    }
+  const MAGIC: &[u8; 4] = b"NVF1";
+  const HEADER_LEN: usize = 8;
+  const ENTRY_LEN: usize = 8;
+
+  #[derive(Debug, Clone, Copy)]
+  pub struct Glyph {
+    width: u32,
+    height: u32,
+    bits: &'static [u8],
   }
-  impl From<(Letter, u8)> for LetterOctave {
-    fn from((letter, octave): (Letter, u8)) -> Self {
-      Self(letter, octave)
+
+  impl Glyph {
+    pub fn width(&self) -> u32 {
+      self.width
     }
-  }
-  impl From<LetterOctave> for Hertz {
-    fn from(note: LetterOctave) -> Self {
-      // TODO: cache result or calculate on compile time?
-      let LetterOctave(letter, octave) = note;
-      let step = letter as u8 + 12 * (octave + 1);
-      let freq = 440.0 * 2.0_f32.powf((step as f32 - 49.0) / 12.0);
-      Hertz(freq as u32)
+    fn pixel(&self, x: u32, y: u32) -> bool {
+      let index = (y * self.width + x) as usize;
+      self.bits[index / 8] & (0b1000_0000 >> (index % 8)) != 0
     }
   }
 
-  pub fn play_song_blocking(tx: &mut TxRmtDriver<'static>, notes: &[Note]) -> anyhow::Result<()> {
-    // insert short rest between same notes
-    let mut new_notes = Vec::<Note>::new();
-    for note in notes {
-      if let Some(prev) = new_notes.last_mut() {
-        if prev.pitch == note.pitch {
-          prev.duration -= Duration::from_millis(20);
-          new_notes.push(note!(20));
+  pub struct Font {
+    data: &'static [u8],
+  }
+
+  impl Font {
+    pub const fn new(data: &'static [u8]) -> Self {
+      Self { data }
+    }
+
+    /** Line height in pixels */
+    pub fn height(&self) -> u32 {
+      self.data[4] as u32
+    }
+    pub fn baseline(&self) -> u32 {
+      self.data[5] as u32
+    }
+    fn count(&self) -> usize {
+      if !self.data.starts_with(MAGIC) {
+        return 0;
+      }
+      u16::from_le_bytes([self.data[6], self.data[7]]) as usize
+    }
+    fn entry(&self, index: usize) -> (u32, u32, usize) {
+      let e = &self.data[HEADER_LEN + index * ENTRY_LEN..][..ENTRY_LEN];
+      let codepoint = u32::from_le_bytes([e[0], e[1], e[2], 0]);
+      let offset = u32::from_le_bytes([e[4], e[5], e[6], e[7]]) as usize;
+      (codepoint, e[3] as u32, offset)
+    }
+
+    pub fn glyph(&self, c: char) -> Option<Glyph> {
+      let count = self.count();
+      let (mut lo, mut hi) = (0, count);
+      while lo < hi {
+        let mid = (lo + hi) / 2;
+        let (codepoint, width, offset) = self.entry(mid);
+        match codepoint.cmp(&(c as u32)) {
+          Ordering::Less => lo = mid + 1,
+          Ordering::Greater => hi = mid,
+          Ordering::Equal => {
+            let start = HEADER_LEN + count * ENTRY_LEN + offset;
+            let len = (width * self.height()) as usize;
+            return Some(Glyph {
+              width,
+              height: self.height(),
+              bits: &self.data[start..start + len.div_ceil(8)],
+            });
+          }
         }
       }
-      new_notes.push(*note);
+      None
     }
 
-    // precalculate all notes to avoid unwanted delays
-    let ticks_hz = tx.counter_clock()?;
-    let iter = new_notes.iter().map(|note| note.iter(ticks_hz));
-    let iter = iter.collect::<Vec<NoteRmtIter>>().into_iter().flatten();
+    pub fn char_width(&self, c: char) -> u32 {
+      if c.is_control() {
+        return 0;
+      }
+      match self.glyph(c) {
+        Some(glyph) => glyph.width,
+        None => self.tofu_width(),
+      }
+    }
+    pub fn text_width(&self, text: &str) -> u32 {
+      Metrics::text_width(self, text)
+    }
+    fn tofu_width(&self) -> u32 {
+      self.height() / 2 + 2
+    }
 
-    tx.start_iter_blocking(iter)?;
-    Ok(())
+    /** Draw a single character with its top-left corner at `pos`, and return its advance */
+    pub fn draw_char<D>(&self, c: char, pos: Point, color: BinaryColor, target: &mut D) -> Result<u32, D::Error>
+    where
+      D: DrawTarget<Color = BinaryColor>,
+    {
+      if c.is_control() {
+        return Ok(0);
+      }
+      let Some(glyph) = self.glyph(c) else {
+        // draw a box for missing glyphs
+        let width = self.tofu_width();
+        Rectangle::new(pos + Point::new(1, 1), Size::new(width - 2, self.baseline() - 1))
+          .into_styled(PrimitiveStyle::with_stroke(color, 1))
+          .draw(target)?;
+        return Ok(width);
+      };
+      let pixels = (0..glyph.height)
+        .flat_map(|y| (0..glyph.width).map(move |x| (x, y)))
+        .filter(|&(x, y)| glyph.pixel(x, y))
+        .map(|(x, y)| Pixel(pos + Point::new(x as i32, y as i32), color));
+      target.draw_iter(pixels)?;
+      Ok(glyph.width)
+    }
+
+    /** Draw a single line of text as is, without layout */
+    pub fn draw_str<D>(&self, text: &str, pos: Point, color: BinaryColor, target: &mut D) -> Result<u32, D::Error>
+    where
+      D: DrawTarget<Color = BinaryColor>,
+    {
+      let mut x = 0;
+      for c in text.chars() {
+        x += self.draw_char(c, pos + Point::new(x as i32, 0), color, target)?;
+      }
+      Ok(x)
+    }
   }
 
-  #[derive(Debug, Clone, Copy)]
-  pub struct Note {
-    pitch: Option<LetterOctave>,
-    duration: Duration,
+  impl Metrics for Font {
+    fn char_width(&self, c: char) -> u32 {
+      Font::char_width(self, c)
+    }
   }
-  impl Note {
-    pub fn new(pitch: impl Into<LetterOctave>, duration: Duration) -> Self {
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub enum Align {
+    /** Left for left-to-right text, right for right-to-left text */
+    Start,
+    Left,
+    Center,
+    Right,
+  }
+
+  #[derive(Clone, Copy)]
+  pub struct TextStyle<'a> {
+    pub font: &'a Font,
+    pub color: BinaryColor,
+    pub align: Align,
+    pub wrap: Wrap,
+    pub max_lines: Option<usize>,
+    pub line_gap: u32,
+  }
+
+  impl<'a> TextStyle<'a> {
+    pub fn new(font: &'a Font, color: BinaryColor) -> Self {
       Self {
-        pitch: Some(pitch.into()),
-        duration,
+        font,
+        color,
+        align: Align::Start,
+        wrap: Wrap::Word,
+        max_lines: None,
+        line_gap: 0,
       }
     }
-    pub fn new_rest(duration: Duration) -> Self {
-      Self { pitch: None, duration }
+    pub fn align(self, align: Align) -> Self {
+      Self { align, ..self }
     }
-    pub fn play_blocking(&self, tx: &mut TxRmtDriver<'static>) -> anyhow::Result<()> {
-      let ticks_hz = tx.counter_clock()?;
-      tx.start_iter_blocking(self.iter(ticks_hz))?;
+    pub fn wrap(self, wrap: Wrap) -> Self {
+      Self { wrap, ..self }
+    }
+    pub fn max_lines(self, max_lines: usize) -> Self {
+      Self {
+        max_lines: Some(max_lines),
+        ..self
+      }
+    }
+    pub fn line_gap(self, line_gap: u32) -> Self {
+      Self { line_gap, ..self }
+    }
+  }
+
+  /** Text wrapped into a rectangle. Lines that do not fit are dropped, and the last line gets an ellipsis. */
+  pub struct TextBox<'a, 'b> {
+    pub text: &'b str,
+    pub bounds: Rectangle,
+    pub style: TextStyle<'a>,
+  }
+
+  impl<'a, 'b> TextBox<'a, 'b> {
+    pub fn new(text: &'b str, bounds: Rectangle, style: TextStyle<'a>) -> Self {
+      Self { text, bounds, style }
+    }
+  }
+
+  impl Drawable for TextBox<'_, '_> {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+      D: DrawTarget<Color = Self::Color>,
+    {
+      let font = self.style.font;
+      let line_height = font.height() + self.style.line_gap;
+      let fit_lines = ((self.bounds.size.height + self.style.line_gap) / line_height).max(1) as usize;
+      let max_lines = self.style.max_lines.map_or(fit_lines, |n| n.min(fit_lines));
+      let style = &self.style;
+
+      let left = self.bounds.top_left.x;
+      let width = self.bounds.size.width;
+      let mut y = self.bounds.top_left.y;
+      for line in navelo_text::layout(self.text, width, font, style.wrap, Some(max_lines)) {
+        let align = match (style.align, line.direction) {
+          (Align::Start, Direction::Ltr) => Align::Left,
+          (Align::Start, Direction::Rtl) => Align::Right,
+          (align, _) => align,
+        };
+        let x = match align {
+          Align::Center => left + (width.saturating_sub(line.width) / 2) as i32,
+          Align::Right => left + width.saturating_sub(line.width) as i32,
+          _ => left,
+        };
+        font.draw_str(&line.text, Point::new(x, y), style.color, target)?;
+        y += line_height as i32;
+      }
       Ok(())
     }
-    pub fn iter(&self, ticks_hz: Hertz) -> NoteRmtIter {
-      let duration_ms = self.duration.as_millis();
-      match self.pitch {
-        Some(pitch) => {
-          let cycles_per_second: Hertz = pitch.into();
-          let cycles = (cycles_per_second.0 as u128 * duration_ms) / 1000;
-          let ticks_per_cycle = ticks_hz.0 / cycles_per_second.0;
-          let ticks = PulseTicks::new((ticks_per_cycle / 2) as u16).unwrap();
-          // info!("{:?} -> {} ticks, {} cycles", self, ticks_per_cycle, cycles);
-          NoteRmtIter::new_tone(ticks, cycles as u32)
+  }
+}
+
+/**
+ * Mini-map of the upcoming route, sent by the phone as a polyline.
+ * Coordinates are projected around the current position with an equirectangular projection,
+ * which is accurate enough for a few kilometers.
+ */
+pub mod minimap {
+  use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Circle, Line, PrimitiveStyle, Rectangle, Triangle},
+  };
+  pub use navelo_protocol::map::{GeoPoint, MapMessage};
+
+  use crate::font::{Align, TextBox, TextStyle, FONT_12};
+
+  const METERS_PER_DEGREE: f32 = 111_320.0;
+  const SCALE_BAR_STEPS: [u32; 10] = [10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000];
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub enum Orientation {
+    NorthUp,
+    HeadingUp,
+  }
+
+  #[derive(Debug, Clone, Default)]
+  pub struct MapState {
+    pub route: Vec<GeoPoint>,
+    /** Index into `route` of the next maneuver point */
+    pub maneuver: Option<usize>,
+    pub position: Option<GeoPoint>,
+    /** Degrees clockwise from north */
+    pub heading: Option<f32>,
+    /** Incremented on every update, to detect changes */
+    pub generation: u32,
+  }
+
+  impl MapState {
+    pub fn apply(&mut self, message: MapMessage) {
+      match message {
+        MapMessage::Route { points, maneuver } => {
+          self.maneuver = maneuver.filter(|&i| i < points.len());
+          self.route = points;
         }
-        None => {
-          let total_ticks = (duration_ms * ticks_hz.0 as u128) / 1000;
-          // info!("{:?} -> {} ticks", self, total_ticks);
-          NoteRmtIter::new_rest(total_ticks as u32)
+        MapMessage::Position { position, heading } => {
+          self.position = Some(position);
+          self.heading = heading;
         }
       }
+      self.generation = self.generation.wrapping_add(1);
+    }
+
+    /** Distance in meters from the current position to the next maneuver point */
+    pub fn distance_to_maneuver(&self) -> Option<f32> {
+      let position = self.position?;
+      let maneuver = self.route.get(self.maneuver?)?;
+      let (x, y) = local_meters(position, *maneuver);
+      Some((x * x + y * y).sqrt())
+    }
+
+    /** Degrees clockwise from north from the current position to the last point of the route */
+    pub fn bearing_to_destination(&self) -> Option<f32> {
+      let (x, y) = local_meters(self.position?, *self.route.last()?);
+      Some(x.atan2(y).to_degrees().rem_euclid(360.0))
+    }
+
+    /** Distance in meters from the current position to the nearest point on the route */
+    pub fn distance_to_route(&self) -> Option<f32> {
+      let position = self.position?;
+      let points: Vec<(f32, f32)> = self.route.iter().map(|&p| local_meters(position, p)).collect();
+      match points.as_slice() {
+        [] => None,
+        [p] => Some((p.0 * p.0 + p.1 * p.1).sqrt()),
+        points => points
+          .windows(2)
+          .map(|segment| segment_distance((0.0, 0.0), segment[0], segment[1]))
+          .min_by(f32::total_cmp),
+      }
     }
   }
 
-  pub enum NoteRmtIter {
-    Tone { ticks: PulseTicks, cycles: u32 },
-    Rest { ticks: u32 },
+  /** East and north offset in meters of `point` from `origin` */
+  fn local_meters(origin: GeoPoint, point: GeoPoint) -> (f32, f32) {
+    let cos_lat = (origin.lat as f32 * 1e-6).to_radians().cos();
+    // in i64 so that the difference of any two points fits
+    let x = (point.lon as i64 - origin.lon as i64) as f32 * 1e-6 * METERS_PER_DEGREE * cos_lat;
+    let y = (point.lat as i64 - origin.lat as i64) as f32 * 1e-6 * METERS_PER_DEGREE;
+    (x, y)
   }
-  impl NoteRmtIter {
-    pub fn new_tone(ticks: PulseTicks, cycles: u32) -> Self {
-      Self::Tone { ticks, cycles }
+
+  struct Projection {
+    origin: GeoPoint,
+    /** Pixels per meter */
+    scale: f32,
+    sin: f32,
+    cos: f32,
+    center: (f32, f32),
+  }
+
+  impl Projection {
+    /** `rotation` is the direction (degrees clockwise from north) that should point up */
+    fn new(origin: GeoPoint, meters_per_pixel: f32, rotation: f32, center: Point) -> Self {
+      let (sin, cos) = rotation.to_radians().sin_cos();
+      Self {
+        origin,
+        scale: 1.0 / meters_per_pixel,
+        sin,
+        cos,
+        center: (center.x as f32, center.y as f32),
+      }
     }
-    pub fn new_rest(ticks: u32) -> Self {
-      Self::Rest { ticks }
+    fn project(&self, point: GeoPoint) -> (f32, f32) {
+      let (x, y) = local_meters(self.origin, point);
+      let (x, y) = self.rotate(x, y);
+      (self.center.0 + x * self.scale, self.center.1 - y * self.scale)
+    }
+    /** Rotate a vector in east-north coordinates into screen orientation (y up) */
+    fn rotate(&self, x: f32, y: f32) -> (f32, f32) {
+      (x * self.cos - y * self.sin, x * self.sin + y * self.cos)
     }
   }
-  impl Iterator for NoteRmtIter {
-    type Item = Symbol;
 
-    fn next(&mut self) -> Option<Self::Item> {
-      match self {
-        Self::Tone { ticks, cycles } => {
-          if *cycles > 0 {
-            *cycles -= 1;
-            Some(Symbol::new(
-              Pulse::new(PinState::High, *ticks),
-              Pulse::new(PinState::Low, *ticks),
-            ))
-          } else {
-            None
-          }
+  pub struct MiniMap<'a> {
+    pub state: &'a MapState,
+    pub bounds: Rectangle,
+    pub orientation: Orientation,
+    pub meters_per_pixel: f32,
+  }
+
+  impl<'a> MiniMap<'a> {
+    pub fn new(state: &'a MapState, bounds: Rectangle) -> Self {
+      Self {
+        state,
+        bounds,
+        orientation: Orientation::HeadingUp,
+        meters_per_pixel: 2.0,
+      }
+    }
+  }
+
+  impl Drawable for MiniMap<'_> {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+      D: DrawTarget<Color = Self::Color>,
+    {
+      let color = BinaryColor::Off;
+      let target = &mut target.clipped(&self.bounds);
+
+      let Some(origin) = self.state.position.or(self.state.route.first().copied()) else {
+        let style = TextStyle::new(&FONT_12, color).align(Align::Center);
+        return TextBox::new("No route", self.bounds, style).draw(target);
+      };
+
+      let heading_up = self.orientation == Orientation::HeadingUp && self.state.heading.is_some();
+      let rotation = if heading_up { self.state.heading.unwrap() } else { 0.0 };
+      let center = if heading_up {
+        // leave more room ahead of the rider
+        self.bounds.center() + Point::new(0, self.bounds.size.height as i32 / 4)
+      } else {
+        self.bounds.center()
+      };
+      let projection = Projection::new(origin, self.meters_per_pixel, rotation, center);
+
+      // route
+      let points: Vec<(f32, f32)> = self.state.route.iter().map(|&p| projection.project(p)).collect();
+      let keep = simplify(&points, 1.0);
+      let points: Vec<(f32, f32)> = points
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, k)| *k)
+        .map(|(p, _)| p)
+        .collect();
+      let rect = (
+        self.bounds.top_left.x as f32,
+        self.bounds.top_left.y as f32,
+        (self.bounds.top_left.x + self.bounds.size.width as i32) as f32,
+        (self.bounds.top_left.y + self.bounds.size.height as i32) as f32,
+      );
+      for segment in points.windows(2) {
+        if let Some((a, b)) = clip(segment[0], segment[1], rect) {
+          Line::new(to_point(a), to_point(b))
+            .into_styled(PrimitiveStyle::with_stroke(color, 3))
+            .draw(target)?;
         }
-        Self::Rest { ticks } => {
-          if *ticks > 0 {
-            let t = cmp::min(*ticks, 0xff);
-            *ticks -= t;
-            Some(Symbol::new(
-              Pulse::new(PinState::Low, PulseTicks::new(t as u16 / 2).unwrap()),
-              Pulse::new(PinState::Low, PulseTicks::new(t as u16 / 2).unwrap()),
-            ))
-          } else {
-            None
+      }
+
+      // next maneuver point
+      if let Some(maneuver) = self.state.maneuver.and_then(|i| self.state.route.get(i)) {
+        let p = to_point(projection.project(*maneuver));
+        Circle::with_center(p, 11)
+          .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+          .draw(target)?;
+        Circle::with_center(p, 11)
+          .into_styled(PrimitiveStyle::with_stroke(color, 2))
+          .draw(target)?;
+      }
+
+      // current position
+      if let Some(position) = self.state.position {
+        let p = projection.project(position);
+        match self.state.heading {
+          Some(heading) => {
+            let (sin, cos) = (heading - rotation).to_radians().sin_cos();
+            let vertex = |forward: f32, right: f32| {
+              to_point((p.0 + forward * sin + right * cos, p.1 - forward * cos + right * sin))
+            };
+            Triangle::new(vertex(8.0, 0.0), vertex(-5.0, -6.0), vertex(-5.0, 6.0))
+              .into_styled(PrimitiveStyle::with_fill(color))
+              .draw(target)?;
+          }
+          None => {
+            Circle::with_center(to_point(p), 9)
+              .into_styled(PrimitiveStyle::with_fill(color))
+              .draw(target)?;
           }
         }
       }
+
+      // north arrow
+      if heading_up {
+        let c = self.bounds.top_left + Point::new(self.bounds.size.width as i32 - 12, 12);
+        let (x, y) = projection.rotate(0.0, 8.0);
+        let tip = c + Point::new(x as i32, -y as i32);
+        Line::new(c - (tip - c), tip)
+          .into_styled(PrimitiveStyle::with_stroke(color, 1))
+          .draw(target)?;
+        Circle::with_center(tip, 5)
+          .into_styled(PrimitiveStyle::with_fill(color))
+          .draw(target)?;
+      }
+
+      self.draw_scale_bar(target)
     }
   }
 
-  macro_rules! note {
-    ($duration: expr) => {
-      Note::new_rest(::std::time::Duration::from_millis($duration))
+  impl MiniMap<'_> {
+    fn draw_scale_bar<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+      D: DrawTarget<Color = BinaryColor>,
+    {
+      let color = BinaryColor::Off;
+      let max_px = self.bounds.size.width as f32 / 3.0;
+      let Some(meters) = SCALE_BAR_STEPS
+        .into_iter()
+        .rev()
+        .find(|&m| m as f32 / self.meters_per_pixel <= max_px)
+      else {
+        return Ok(());
+      };
+      let length = (meters as f32 / self.meters_per_pixel) as i32;
+
+      let left = self.bounds.top_left + Point::new(4, self.bounds.size.height as i32 - 4);
+      let right = left + Point::new(length, 0);
+      let style = PrimitiveStyle::with_stroke(color, 2);
+      Line::new(left, right).into_styled(style).draw(target)?;
+      Line::new(left, left - Point::new(0, 4))
+        .into_styled(style)
+        .draw(target)?;
+      Line::new(right, right - Point::new(0, 4))
+        .into_styled(style)
+        .draw(target)?;
+
+      let label = if meters >= 1000 {
+        format!("{} km", meters / 1000)
+      } else {
+        format!("{} m", meters)
+      };
+      let pos = right + Point::new(4, -(FONT_12.height() as i32));
+      FONT_12.draw_str(&label, pos, color, target)?;
+      Ok(())
+    }
+  }
+
+  fn to_point((x, y): (f32, f32)) -> Point {
+    Point::new(x.round() as i32, y.round() as i32)
+  }
+
+  /** Douglas-Peucker line simplification, returns which points to keep */
+  fn simplify(points: &[(f32, f32)], epsilon: f32) -> Vec<bool> {
+    let mut keep = vec![false; points.len()];
+    if points.len() <= 2 {
+      keep.fill(true);
+      return keep;
+    }
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut stack = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+      let (a, b) = (points[first], points[last]);
+      let farthest = (first + 1..last)
+        .map(|i| (i, segment_distance(points[i], a, b)))
+        .max_by(|x, y| x.1.total_cmp(&y.1));
+      if let Some((i, distance)) = farthest {
+        if distance > epsilon {
+          keep[i] = true;
+          stack.push((first, i));
+          stack.push((i, last));
+        }
+      }
+    }
+    keep
+  }
+
+  fn segment_distance(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length2 = dx * dx + dy * dy;
+    let t = if length2 > 0.0 {
+      (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length2).clamp(0.0, 1.0)
+    } else {
+      0.0
     };
-    ($letter: ident, $octave: expr, $duration: expr) => {
-      Note::new(
-        (Letter::$letter, $octave),
-        ::std::time::Duration::from_millis($duration),
-      )
+    let (x, y) = (a.0 + t * dx - p.0, a.1 + t * dy - p.1);
+    (x * x + y * y).sqrt()
+  }
+
+  /** Cohen-Sutherland line clipping against `(left, top, right, bottom)` */
+  #[allow(clippy::type_complexity)]
+  fn clip(
+    mut a: (f32, f32),
+    mut b: (f32, f32),
+    (left, top, right, bottom): (f32, f32, f32, f32),
+  ) -> Option<((f32, f32), (f32, f32))> {
+    let outcode = |(x, y): (f32, f32)| {
+      (u8::from(x < left)) | (u8::from(x > right) << 1) | (u8::from(y < top) << 2) | (u8::from(y > bottom) << 3)
     };
+    let (mut code_a, mut code_b) = (outcode(a), outcode(b));
+    loop {
+      if code_a | code_b == 0 {
+        return Some((a, b));
+      }
+      if code_a & code_b != 0 {
+        return None;
+      }
+      let code = if code_a != 0 { code_a } else { code_b };
+      let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+      let p = if code & 0b1000 != 0 {
+        (a.0 + dx * (bottom - a.1) / dy, bottom)
+      } else if code & 0b0100 != 0 {
+        (a.0 + dx * (top - a.1) / dy, top)
+      } else if code & 0b0010 != 0 {
+        (right, a.1 + dy * (right - a.0) / dx)
+      } else {
+        (left, a.1 + dy * (left - a.0) / dx)
+      };
+      if code == code_a {
+        a = p;
+        code_a = outcode(a);
+      } else {
+        b = p;
+        code_b = outcode(b);
+      }
+    }
   }
+}
 
-  pub fn ode_to_joy() -> [Note; 63] {
-    [
-      note!(Fs, 5, 400),
-      note!(Fs, 5, 400),
-      note!(G, 5, 400),
-      note!(A, 5, 400),
-      note!(A, 5, 400),
-      note!(G, 5, 400),
-      note!(Fs, 5, 400),
-      note!(E, 5, 400),
-      note!(D, 5, 400),
-      note!(D, 5, 400),
-      note!(E, 5, 400),
-      note!(Fs, 5, 400),
-      note!(Fs, 5, 600),
-      note!(E, 5, 200),
-      note!(E, 5, 800),
-      //
-      note!(Fs, 5, 400),
-      note!(Fs, 5, 400),
-      note!(G, 5, 400),
-      note!(A, 5, 400),
-      note!(A, 5, 400),
-      note!(G, 5, 400),
-      note!(Fs, 5, 400),
-      note!(E, 5, 400),
-      note!(D, 5, 400),
-      note!(D, 5, 400),
-      note!(E, 5, 400),
-      note!(Fs, 5, 400),
-      note!(E, 5, 600),
-      note!(D, 5, 200),
-      note!(D, 5, 800),
-      //
+/**
+ * Turn-by-turn navigation instructions sent by the phone, and the banner that shows them.
+ * The codec lives in `navelo_protocol` so that it can be tested on the host.
+ */
+pub mod navigation {
+  use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Circle, Line, PrimitiveStyle, Rectangle, Triangle},
+  };
+
+  use crate::clock::DateTime;
+  use crate::font::{TextBox, TextStyle, Wrap, FONT_12, FONT_16};
+  use crate::settings::Units;
+
+  pub use navelo_protocol::navigation::{fragment, Instruction, Maneuver, Modifier, Reassembler};
+
+  pub const BANNER_HEIGHT: u32 = 48;
+
+  const FEET_PER_METER: f32 = 3.28084;
+  const METERS_PER_MILE: f32 = 1609.344;
+
+  /** Degrees clockwise from straight ahead */
+  fn modifier_angle(modifier: Modifier) -> f32 {
+    match modifier {
+      Modifier::None | Modifier::Straight => 0.0,
+      Modifier::SlightLeft => -45.0,
+      Modifier::Left => -90.0,
+      Modifier::SharpLeft => -135.0,
+      Modifier::SlightRight => 45.0,
+      Modifier::Right => 90.0,
+      Modifier::SharpRight => 135.0,
+      Modifier::UTurn => 180.0,
+    }
+  }
+
+  pub fn format_distance(meters: u32, units: Units) -> String {
+    match units {
+      Units::Metric => match meters {
+        0..1000 => format!("{meters} m"),
+        _ => format!("{:.1} km", meters as f32 / 1000.0),
+      },
+      Units::Imperial => match (meters as f32 * FEET_PER_METER) as u32 {
+        feet @ 0..1000 => format!("{feet} ft"),
+        _ => format!("{:.1} mi", meters as f32 / METERS_PER_MILE),
+      },
+    }
+  }
+
+  pub fn format_duration(seconds: u32) -> String {
+    let minutes = seconds.div_ceil(60);
+    match minutes {
+      0..60 => format!("{minutes} min"),
+      _ => format!("{} h {} min", minutes / 60, minutes % 60),
+    }
+  }
+
+  /** Maneuver arrow, distance, street and remaining distance at the top of the map page */
+  pub struct NavigationBanner<'a> {
+    pub instruction: &'a Instruction,
+    pub bounds: Rectangle,
+    pub units: Units,
+    /** Local time, to show the arrival time instead of the remaining duration */
+    pub now: Option<DateTime>,
+  }
+
+  impl<'a> NavigationBanner<'a> {
+    /** Uses the top `BANNER_HEIGHT` pixels of `bounds` */
+    pub fn new(instruction: &'a Instruction, bounds: Rectangle) -> Self {
+      let bounds = Rectangle::new(bounds.top_left, Size::new(bounds.size.width, BANNER_HEIGHT));
+      Self {
+        instruction,
+        bounds,
+        units: Units::Metric,
+        now: None,
+      }
+    }
+    pub fn units(self, units: Units) -> Self {
+      Self { units, ..self }
+    }
+    pub fn now(self, now: Option<DateTime>) -> Self {
+      Self { now, ..self }
+    }
+
+    fn draw_icon<D>(&self, center: Point, target: &mut D) -> Result<(), D::Error>
+    where
+      D: DrawTarget<Color = BinaryColor>,
+    {
+      let color = BinaryColor::Off;
+      let stroke = PrimitiveStyle::with_stroke(color, 3);
+
+      if self.instruction.maneuver == Maneuver::Arrive {
+        Circle::with_center(center, 20).into_styled(stroke).draw(target)?;
+        return Circle::with_center(center, 8)
+          .into_styled(PrimitiveStyle::with_fill(color))
+          .draw(target);
+      }
+      if self.instruction.maneuver == Maneuver::Roundabout {
+        Circle::with_center(center, 12).into_styled(stroke).draw(target)?;
+      }
+
+      let bend = center + Point::new(0, 2);
+      Line::new(center + Point::new(0, 18), bend)
+        .into_styled(stroke)
+        .draw(target)?;
+
+      let (sin, cos) = modifier_angle(self.instruction.modifier).to_radians().sin_cos();
+      let at = |length: f32, side: f32| {
+        bend
+          + Point::new(
+            (sin * length + cos * side).round() as i32,
+            (-cos * length + sin * side).round() as i32,
+          )
+      };
+      Line::new(bend, at(10.0, 0.0)).into_styled(stroke).draw(target)?;
+      Triangle::new(at(18.0, 0.0), at(9.0, -6.0), at(9.0, 6.0))
+        .into_styled(PrimitiveStyle::with_fill(color))
+        .draw(target)
+    }
+  }
+
+  impl Drawable for NavigationBanner<'_> {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+      D: DrawTarget<Color = Self::Color>,
+    {
+      let color = BinaryColor::Off;
+      let Point { x, y } = self.bounds.top_left;
+      let width = self.bounds.size.width;
+
+      self
+        .bounds
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(target)?;
+      Line::new(
+        Point::new(x, y + BANNER_HEIGHT as i32 - 1),
+        Point::new(x + width as i32 - 1, y + BANNER_HEIGHT as i32 - 1),
+      )
+      .into_styled(PrimitiveStyle::with_stroke(color, 1))
+      .draw(target)?;
+
+      self.draw_icon(Point::new(x + 22, y + 22), target)?;
+
+      let text_x = x + 46;
+      let text_width = width.saturating_sub(48);
+      let line = |top: i32, height: u32| Rectangle::new(Point::new(text_x, y + top), Size::new(text_width, height));
+
+      let heading = match self.instruction.maneuver {
+        Maneuver::Arrive => "Arrive".to_string(),
+        _ => format_distance(self.instruction.distance, self.units),
+      };
+      let style = TextStyle::new(&FONT_16, color).wrap(Wrap::None).max_lines(1);
+      TextBox::new(&heading, line(1, FONT_16.height()), style).draw(target)?;
+
+      let style = TextStyle::new(&FONT_12, color).wrap(Wrap::None).max_lines(1);
+      let street_top = 1 + FONT_16.height() as i32;
+      TextBox::new(&self.instruction.street, line(street_top, FONT_12.height()), style).draw(target)?;
+
+      let mut remaining = format_distance(self.instruction.remaining, self.units);
+      if let Some(eta) = self.instruction.eta {
+        remaining = match self.now {
+          Some(now) => {
+            let arrival = DateTime::from_unix(now.to_unix() + eta as i64);
+            format!("{remaining}, ETA {:02}:{:02}", arrival.hours, arrival.minutes)
+          }
+          None => format!("{remaining}, {}", format_duration(eta)),
+        };
+      }
+      let remaining_top = street_top + FONT_12.height() as i32;
+      TextBox::new(&remaining, line(remaining_top, FONT_12.height()), style).draw(target)?;
+
+      Ok(())
+    }
+  }
+}
+
+/**
+ * Bitmaps pushed by the phone for content the firmware cannot render itself, such as map snapshots or QR codes.
+ * The codec lives in `navelo_protocol::image`.
+ */
+pub mod image {
+  use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle};
+  use navelo_protocol::image::Bitmap;
+
+  pub use navelo_protocol::image::{ImageMessage, MAX_MESSAGE_LEN};
+
+  /** Bitmap placed on the display */
+  #[derive(Debug, Clone, PartialEq, Eq)]
+  pub struct Image(Bitmap);
+
+  impl From<Bitmap> for Image {
+    fn from(bitmap: Bitmap) -> Self {
+      Self(bitmap)
+    }
+  }
+
+  impl Image {
+    pub fn area(&self) -> Rectangle {
+      let (x, y) = self.0.position();
+      let (width, height) = self.0.size();
+      Rectangle::new(Point::new(x as i32, y as i32), Size::new(width as u32, height as u32))
+    }
+  }
+
+  impl Drawable for Image {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+      D: DrawTarget<Color = Self::Color>,
+    {
+      target.fill_contiguous(&self.area(), self.0.pixels().map(BinaryColor::from))
+    }
+  }
+
+  /** Image currently pushed by the phone */
+  #[derive(Debug, Clone, Default)]
+  pub struct ImageState {
+    pub image: Option<Image>,
+    /** Incremented on every update, to detect changes */
+    pub generation: u32,
+  }
+
+  impl ImageState {
+    pub fn apply(&mut self, message: ImageMessage) {
+      self.image = match message {
+        ImageMessage::Show(bitmap) => Some(bitmap.into()),
+        ImageMessage::Clear => None,
+      };
+      self.generation = self.generation.wrapping_add(1);
+    }
+  }
+}
+
+/**
+ * Widgets shared by the on-device screens.
+ */
+pub mod ui {
+  use std::time::{Duration, Instant};
+
+  use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Circle, Line, PrimitiveStyle, Rectangle, Triangle},
+  };
+  use embedded_hal::digital::InputPin;
+  pub use navelo_protocol::status::{GpsFix, PhoneStatus};
+
+  use crate::display::WIDTH;
+  use crate::font::{Align, TextBox, TextStyle, FONT_12, FONT_16};
+
+  pub const STATUS_BAR_HEIGHT: u32 = 16;
+
+  const DEBOUNCE: Duration = Duration::from_millis(30);
+
+  /** Screens the user can switch between with the button */
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+  pub enum Page {
+    #[default]
+    Map,
+    Info,
+    Ride,
+  }
+
+  impl Page {
+    pub fn next(self) -> Self {
+      match self {
+        Self::Map => Self::Ride,
+        Self::Ride => Self::Info,
+        Self::Info => Self::Map,
+      }
+    }
+    pub fn from_u8(value: u8) -> Option<Self> {
+      match value {
+        0 => Some(Self::Map),
+        1 => Some(Self::Info),
+        2 => Some(Self::Ride),
+        _ => None,
+      }
+    }
+  }
+
+  /** Push button connected between the pin and ground */
+  pub struct Button<P> {
+    pin: P,
+    pressed: bool,
+    changed: Instant,
+  }
+
+  impl<P: InputPin> Button<P> {
+    pub fn new(pin: P) -> Self {
+      Self {
+        pin,
+        pressed: false,
+        changed: Instant::now(),
+      }
+    }
+
+    /** Returns true once per press */
+    pub fn poll(&mut self) -> bool {
+      let pressed = self.pin.is_low().unwrap_or(false);
+      if pressed != self.pressed && self.changed.elapsed() >= DEBOUNCE {
+        self.pressed = pressed;
+        self.changed = Instant::now();
+        return pressed;
+      }
+      false
+    }
+  }
+
+  /** Everything shown in the status bar. The status bar is redrawn only when this changes. */
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+  pub struct Status {
+    pub connected: bool,
+    /** Bars of the best link, see `signal_bars` */
+    pub signal: Option<u8>,
+    pub phone_battery: Option<u8>,
+    pub device_battery: Option<u8>,
+    pub gps_fix: GpsFix,
+    pub clock: Option<(u8, u8)>,
+    pub alerts: usize,
+  }
+
+  #[derive(Default)]
+  pub struct StatusBar {
+    last: Option<Status>,
+  }
+
+  impl StatusBar {
+    pub fn bounds() -> Rectangle {
+      Rectangle::new(Point::zero(), Size::new(WIDTH as u32, STATUS_BAR_HEIGHT))
+    }
+
+    /** Draw the status bar if it changed since the last draw, and return the area to refresh */
+    pub fn update<D>(&mut self, status: Status, target: &mut D) -> Result<Option<Rectangle>, D::Error>
+    where
+      D: DrawTarget<Color = BinaryColor>,
+    {
+      if self.last == Some(status) {
+        return Ok(None);
+      }
+      self.draw(status, target).map(Some)
+    }
+
+    /** Draw the status bar unconditionally, e.g. after the whole screen was cleared */
+    pub fn draw<D>(&mut self, status: Status, target: &mut D) -> Result<Rectangle, D::Error>
+    where
+      D: DrawTarget<Color = BinaryColor>,
+    {
+      let color = BinaryColor::Off;
+      let bounds = Self::bounds();
+      let stroke = PrimitiveStyle::with_stroke(color, 1);
+      let fill = PrimitiveStyle::with_fill(color);
+
+      bounds
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(target)?;
+      Line::new(
+        Point::new(0, bounds.size.height as i32 - 1),
+        bounds.bottom_right().unwrap(),
+      )
+      .into_styled(stroke)
+      .draw(target)?;
+
+      // ble link
+      let o = Point::new(3, 1);
+      for (a, b) in [
+        ((4, 0), (4, 12)),
+        ((4, 0), (7, 3)),
+        ((7, 3), (1, 9)),
+        ((1, 3), (7, 9)),
+        ((7, 9), (4, 12)),
+      ] {
+        Line::new(o + Point::new(a.0, a.1), o + Point::new(b.0, b.1))
+          .into_styled(stroke)
+          .draw(target)?;
+      }
+      if !status.connected {
+        Line::new(o + Point::new(0, 12), o + Point::new(8, 0))
+          .into_styled(PrimitiveStyle::with_stroke(color, 2))
+          .draw(target)?;
+      }
+
+      // signal strength
+      if let Some(bars) = status.signal {
+        for i in 0..3 {
+          let height = 4 + 3 * i as u32;
+          let bar = Rectangle::new(Point::new(46 + 4 * i, 13 - height as i32), Size::new(3, height));
+          let style = if (i as u8) < bars { fill } else { stroke };
+          bar.into_styled(style).draw(target)?;
+        }
+      }
+
+      // gps fix
+      let c = Point::new(20, 7);
+      match status.gps_fix {
+        GpsFix::Unknown => {}
+        GpsFix::NoFix => {
+          Circle::with_center(c, 11).into_styled(stroke).draw(target)?;
+        }
+        GpsFix::Fix => {
+          Circle::with_center(c, 11).into_styled(stroke).draw(target)?;
+          Circle::with_center(c, 5).into_styled(fill).draw(target)?;
+        }
+      }
+
+      // alerts
+      if status.alerts > 0 {
+        Triangle::new(Point::new(36, 1), Point::new(30, 13), Point::new(42, 13))
+          .into_styled(fill)
+          .draw(target)?;
+        Line::new(Point::new(36, 5), Point::new(36, 9))
+          .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+          .draw(target)?;
+        Pixel(Point::new(36, 11), BinaryColor::On).draw(target)?;
+      }
+
+      // clock
+      let clock = match status.clock {
+        Some((hours, minutes)) => format!("{hours:02}:{minutes:02}"),
+        None => "--:--".into(),
+      };
+      let x = (WIDTH as i32 - FONT_12.text_width(&clock) as i32) / 2;
+      FONT_12.draw_str(&clock, Point::new(x, 1), color, target)?;
+
+      // batteries
+      let mut x = WIDTH as i32 - 22;
+      if let Some(level) = status.device_battery {
+        draw_battery(Point::new(x, 3), level, target)?;
+        x -= 32;
+      }
+      if let Some(level) = status.phone_battery {
+        Rectangle::new(Point::new(x + 1, 2), Size::new(6, 11))
+          .into_styled(stroke)
+          .draw(target)?;
+        draw_battery(Point::new(x + 10, 3), level, target)?;
+      }
+
+      self.last = Some(status);
+      Ok(bounds)
+    }
+  }
+
+  /** 0 to 3 bars for an RSSI in dBm, a link below -90 dBm is about to drop */
+  pub fn signal_bars(rssi: i8) -> u8 {
+    match rssi {
+      -60.. => 3,
+      -75..=-61 => 2,
+      -90..=-76 => 1,
+      _ => 0,
+    }
+  }
+
+  fn draw_battery<D>(pos: Point, level: u8, target: &mut D) -> Result<(), D::Error>
+  where
+    D: DrawTarget<Color = BinaryColor>,
+  {
+    let color = BinaryColor::Off;
+    Rectangle::new(pos, Size::new(16, 9))
+      .into_styled(PrimitiveStyle::with_stroke(color, 1))
+      .draw(target)?;
+    Rectangle::new(pos + Point::new(16, 2), Size::new(2, 5))
+      .into_styled(PrimitiveStyle::with_fill(color))
+      .draw(target)?;
+    let width = (level.min(100) as u32 * 12).div_ceil(100);
+    Rectangle::new(pos + Point::new(2, 2), Size::new(width, 5))
+      .into_styled(PrimitiveStyle::with_fill(color))
+      .draw(target)?;
+    Ok(())
+  }
+
+  /** Passkey to be entered on the phone while it is pairing */
+  pub struct PairingScreen {
+    pub passkey: u32,
+    pub bounds: Rectangle,
+  }
+
+  impl PairingScreen {
+    pub fn new(passkey: u32, bounds: Rectangle) -> Self {
+      Self { passkey, bounds }
+    }
+  }
+
+  impl Drawable for PairingScreen {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+      D: DrawTarget<Color = Self::Color>,
+    {
+      let color = BinaryColor::Off;
+      let bounds = self.bounds;
+      bounds
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(target)?;
+
+      let inner = bounds.offset(-8);
+      let left = inner.top_left.x;
+      let width = inner.size.width;
+      let center = inner.center().y;
+      let line = |top: i32, height: u32| Rectangle::new(Point::new(left, top), Size::new(width, height));
+      let style = |font| TextStyle::new(font, color).align(Align::Center).max_lines(1);
+
+      let passkey = format!("{:06}", self.passkey % 1_000_000);
+      let passkey_top = center - FONT_16.height() as i32 / 2;
+      TextBox::new("Pairing", line(inner.top_left.y, FONT_16.height()), style(&FONT_16)).draw(target)?;
+      TextBox::new(&passkey, line(passkey_top, FONT_16.height()), style(&FONT_16)).draw(target)?;
+
+      // box around the passkey so that it stands out
+      line(passkey_top, FONT_16.height())
+        .offset(4)
+        .into_styled(PrimitiveStyle::with_stroke(color, 2))
+        .draw(target)?;
+
+      let hint_top = passkey_top + FONT_16.height() as i32 + 16;
+      TextBox::new(
+        "Enter this code on the phone",
+        line(hint_top, FONT_12.height()),
+        style(&FONT_12),
+      )
+      .draw(target)?;
+      Ok(())
+    }
+  }
+}
+
+/**
+ * Alerts shown as a full-screen overlay with a tone sequence, e.g. for off-route and upcoming turns.
+ * Only the alert with the highest priority is shown, the others wait until it is acknowledged or expires.
+ */
+pub mod alert {
+  use std::{
+    sync::{
+      atomic::{AtomicU8, Ordering},
+      mpsc::{channel, Sender},
+      Arc,
+    },
+    thread::spawn,
+    time::{Duration, Instant},
+  };
+
+  use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Circle, Line, PrimitiveStyle, Rectangle, Triangle},
+  };
+  use esp_idf_hal::rmt::TxRmtDriver;
+  use log::warn;
+
+  use crate::audio::{note, play_song_blocking, Letter, Note, FULL_VOLUME};
+  use crate::font::{Align, TextBox, TextStyle, FONT_12, FONT_16};
+
+  /** Distance from the route in meters before the rider is considered off route */
+  pub const OFF_ROUTE_DISTANCE: f32 = 50.0;
+  /** Distance to the next maneuver in meters when the turn alert is raised */
+  pub const TURN_ALERT_DISTANCE: f32 = 100.0;
+  /** Device battery level in percent when the low battery alert is raised */
+  pub const LOW_BATTERY_LEVEL: u8 = 15;
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub enum AlertKind {
+    OffRoute,
+    UpcomingTurn,
+    LinkLost,
+    LowBattery,
+  }
+
+  impl AlertKind {
+    /** Higher is more important */
+    pub fn priority(self) -> u8 {
+      match self {
+        Self::OffRoute => 3,
+        Self::UpcomingTurn => 2,
+        Self::LinkLost => 1,
+        Self::LowBattery => 0,
+      }
+    }
+
+    /** `None` means the alert stays until it is acknowledged or cleared */
+    pub fn timeout(self) -> Option<Duration> {
+      match self {
+        Self::OffRoute => None,
+        Self::UpcomingTurn => Some(Duration::from_secs(15)),
+        Self::LinkLost => None,
+        Self::LowBattery => Some(Duration::from_secs(10)),
+      }
+    }
+
+    pub fn title(self) -> &'static str {
+      match self {
+        Self::OffRoute => "Off route",
+        Self::UpcomingTurn => "Turn ahead",
+        Self::LinkLost => "Phone link lost",
+        Self::LowBattery => "Low battery",
+      }
+    }
+
+    pub fn melody(self) -> Vec<Note> {
+      match self {
+        Self::OffRoute => vec![note!(A, 5, 200), note!(F, 5, 200), note!(D, 5, 400)],
+        Self::UpcomingTurn => vec![note!(E, 6, 100), note!(50), note!(E, 6, 100)],
+        Self::LinkLost => vec![note!(G, 5, 150), note!(50), note!(C, 5, 400)],
+        Self::LowBattery => vec![note!(C, 5, 300), note!(C, 4, 500)],
+      }
+    }
+
+    /** Draw the icon centered at `center`, about 48x48 pixels */
+    pub fn draw_icon<D>(self, center: Point, target: &mut D) -> Result<(), D::Error>
+    where
+      D: DrawTarget<Color = BinaryColor>,
+    {
+      let color = BinaryColor::Off;
+      let thick = PrimitiveStyle::with_stroke(color, 5);
+      let at = |x: i32, y: i32| center + Point::new(x, y);
+      match self {
+        Self::OffRoute => {
+          Triangle::new(at(0, -22), at(-24, 20), at(24, 20))
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(target)?;
+          Line::new(at(0, -8), at(0, 6))
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 5))
+            .draw(target)?;
+          Circle::with_center(at(0, 13), 5)
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(target)?;
+        }
+        Self::UpcomingTurn => {
+          Line::new(at(-10, 22), at(-10, -6)).into_styled(thick).draw(target)?;
+          Line::new(at(-10, -6), at(12, -6)).into_styled(thick).draw(target)?;
+          Triangle::new(at(24, -6), at(10, -18), at(10, 6))
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(target)?;
+        }
+        Self::LinkLost => {
+          Rectangle::new(at(-13, -22), Size::new(26, 44))
+            .into_styled(PrimitiveStyle::with_stroke(color, 3))
+            .draw(target)?;
+          Line::new(at(-20, -20), at(20, 20)).into_styled(thick).draw(target)?;
+        }
+        Self::LowBattery => {
+          Rectangle::new(at(-24, -12), Size::new(44, 24))
+            .into_styled(PrimitiveStyle::with_stroke(color, 3))
+            .draw(target)?;
+          Rectangle::new(at(20, -5), Size::new(5, 10))
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(target)?;
+          Rectangle::new(at(-19, -7), Size::new(6, 14))
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(target)?;
+        }
+      }
+      Ok(())
+    }
+  }
+
+  #[derive(Debug, Clone)]
+  pub struct Alert {
+    pub kind: AlertKind,
+    pub message: String,
+    pub raised: Instant,
+  }
+
+  impl Alert {
+    fn is_expired(&self) -> bool {
+      self
+        .kind
+        .timeout()
+        .is_some_and(|timeout| self.raised.elapsed() >= timeout)
+    }
+  }
+
+  #[derive(Default)]
+  pub struct AlertManager {
+    alerts: Vec<Alert>,
+  }
+
+  impl AlertManager {
+    /**
+     Raise an alert, replacing the pending alert of the same kind.
+     Returns true if it became the current alert, i.e. the cue should be played.
+    */
+    pub fn raise(&mut self, kind: AlertKind, message: impl Into<String>) -> bool {
+      self.alerts.retain(|alert| alert.kind != kind);
+      self.alerts.push(Alert {
+        kind,
+        message: message.into(),
+        raised: Instant::now(),
+      });
+      // stable sort keeps the older alert first among the same priority
+      self
+        .alerts
+        .sort_by_key(|alert| std::cmp::Reverse(alert.kind.priority()));
+      self.current().is_some_and(|alert| alert.kind == kind)
+    }
+
+    /** Remove an alert whose condition is resolved, e.g. back on route */
+    pub fn clear(&mut self, kind: AlertKind) {
+      self.alerts.retain(|alert| alert.kind != kind);
+    }
+
+    /** Dismiss the current alert, e.g. by a button press. Returns false if there was nothing to dismiss. */
+    pub fn acknowledge(&mut self) -> bool {
+      if self.alerts.is_empty() {
+        return false;
+      }
+      self.alerts.remove(0);
+      true
+    }
+
+    /** Remove expired alerts. Returns true if the current alert changed. */
+    pub fn expire(&mut self) -> bool {
+      let current = self.current().map(|alert| alert.kind);
+      self.alerts.retain(|alert| !alert.is_expired());
+      current != self.current().map(|alert| alert.kind)
+    }
+
+    pub fn current(&self) -> Option<&Alert> {
+      self.alerts.first()
+    }
+
+    pub fn contains(&self, kind: AlertKind) -> bool {
+      self.alerts.iter().any(|alert| alert.kind == kind)
+    }
+
+    pub fn len(&self) -> usize {
+      self.alerts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+      self.alerts.is_empty()
+    }
+  }
+
+  /** Plays alert cues on a background thread, so that the caller is not blocked */
+  pub struct AlertSound {
+    sender: Sender<AlertKind>,
+    volume: Arc<AtomicU8>,
+  }
+
+  impl AlertSound {
+    pub fn spawn(mut tx: TxRmtDriver<'static>) -> Self {
+      let (sender, receiver) = channel::<AlertKind>();
+      let volume = Arc::new(AtomicU8::new(FULL_VOLUME));
+      let player_volume = volume.clone();
+      spawn(move || {
+        for kind in receiver {
+          let volume = player_volume.load(Ordering::Relaxed);
+          if volume == 0 {
+            continue;
+          }
+          if let Err(e) = play_song_blocking(&mut tx, &kind.melody(), volume) {
+            warn!("Failed to play alert cue: {e}");
+          }
+        }
+      });
+      Self { sender, volume }
+    }
+
+    pub fn play(&self, kind: AlertKind) {
+      let _ = self.sender.send(kind);
+    }
+
+    /** Volume of the following cues in percent, 0 mutes them */
+    pub fn set_volume(&self, volume: u8) {
+      self.volume.store(volume, Ordering::Relaxed);
+    }
+  }
+
+  pub struct AlertOverlay<'a> {
+    pub alert: &'a Alert,
+    pub bounds: Rectangle,
+  }
+
+  impl<'a> AlertOverlay<'a> {
+    pub fn new(alert: &'a Alert, bounds: Rectangle) -> Self {
+      Self { alert, bounds }
+    }
+  }
+
+  impl Drawable for AlertOverlay<'_> {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+      D: DrawTarget<Color = Self::Color>,
+    {
+      let color = BinaryColor::Off;
+      let bounds = self.bounds;
+      bounds
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(target)?;
+      bounds
+        .offset(-2)
+        .into_styled(PrimitiveStyle::with_stroke(color, 3))
+        .draw(target)?;
+
+      let inner = bounds.offset(-8);
+      let left = inner.top_left.x;
+      let width = inner.size.width;
+      let mut y = inner.top_left.y;
+
+      self
+        .alert
+        .kind
+        .draw_icon(Point::new(inner.center().x, y + 26), target)?;
+      y += 58;
+
+      let title = TextStyle::new(&FONT_16, color).align(Align::Center).max_lines(1);
+      let title_bounds = Rectangle::new(Point::new(left, y), Size::new(width, FONT_16.height()));
+      TextBox::new(self.alert.kind.title(), title_bounds, title).draw(target)?;
+      y += FONT_16.height() as i32 + 6;
+
+      let hint = "Press to dismiss";
+      let bottom = inner.top_left.y + inner.size.height as i32;
+      let message = TextStyle::new(&FONT_12, color).align(Align::Center).line_gap(2);
+      let message_height = (bottom - FONT_12.height() as i32 - 4 - y).max(0) as u32;
+      let message_bounds = Rectangle::new(Point::new(left, y), Size::new(width, message_height));
+      TextBox::new(&self.alert.message, message_bounds, message).draw(target)?;
+
+      let hint_bounds = Rectangle::new(
+        Point::new(left, bottom - FONT_12.height() as i32),
+        Size::new(width, FONT_12.height()),
+      );
+      TextBox::new(hint, hint_bounds, TextStyle::new(&FONT_12, color).align(Align::Center)).draw(target)?;
+      Ok(())
+    }
+  }
+}
+
+/**
+ * Boot splash and firmware info screen.
+ */
+pub mod boot {
+  use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Circle, Line, PrimitiveStyle, Rectangle, Triangle},
+  };
+  use esp_idf_hal::i2c::I2cDriver;
+  use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+  use esp_idf_svc::sys::EspError;
+
+  use crate::font::{Align, TextBox, TextStyle, FONT_12, FONT_16};
+  use crate::gatt::BdAddr;
+  use crate::sensors;
+  use crate::ui::Page;
+  use crate::utils::{ble_address, scan_i2c};
+
+  pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+  pub const BUILD_HASH: &str = env!("NAVELO_BUILD_HASH");
+
+  /** Hardware revision of the board, reported through the Device Information Service */
+  pub const HARDWARE_REVISION: &str = "ESP32-C6 rev1";
+
+  /** NVS namespace of all the settings */
+  pub const NVS_NAMESPACE: &str = "navelo";
+  const PAGE_KEY: &str = "page";
+  const NAME_KEY: &str = "name";
+  const PRIMARY_KEY: &str = "primary";
+  /** Longest device name, so that it still fits in the scan response */
+  pub const MAX_NAME_LEN: usize = 20;
+
+  #[derive(Debug, Clone)]
+  pub struct FirmwareInfo {
+    pub ble_address: [u8; 6],
+    pub i2c_devices: Vec<u8>,
+  }
+
+  impl FirmwareInfo {
+    pub fn collect(i2c: &mut I2cDriver) -> Self {
+      Self {
+        ble_address: ble_address(),
+        i2c_devices: scan_i2c(i2c),
+      }
+    }
+
+    /** Expected devices which did not respond */
+    pub fn missing_devices(&self) -> Vec<&'static str> {
+      sensors::DEVICES
+        .iter()
+        .filter(|(addr, _)| !self.i2c_devices.contains(addr))
+        .map(|(_, name)| *name)
+        .collect()
+    }
+
+    fn lines(&self) -> Vec<String> {
+      let address = self.ble_address.map(|b| format!("{b:02x}")).join(":");
+      let missing = self.missing_devices();
+      let check = if missing.is_empty() {
+        format!("Sensors OK ({} devices)", self.i2c_devices.len())
+      } else {
+        format!("Missing: {}", missing.join(", "))
+      };
+      vec![format!("v{VERSION} ({BUILD_HASH})"), format!("BLE {address}"), check]
+    }
+  }
+
+  /** Firmware version, BLE address and self-check summary */
+  pub struct InfoScreen<'a> {
+    pub info: &'a FirmwareInfo,
+    pub bounds: Rectangle,
+  }
+
+  impl<'a> InfoScreen<'a> {
+    pub fn new(info: &'a FirmwareInfo, bounds: Rectangle) -> Self {
+      Self { info, bounds }
+    }
+  }
+
+  impl Drawable for InfoScreen<'_> {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+      D: DrawTarget<Color = Self::Color>,
+    {
+      let style = TextStyle::new(&FONT_12, BinaryColor::Off)
+        .align(Align::Center)
+        .max_lines(2);
+      let line_height = FONT_12.height() as i32 + 4;
+      let mut y = self.bounds.top_left.y;
+      for line in self.info.lines() {
+        let bounds = Rectangle::new(
+          Point::new(self.bounds.top_left.x, y),
+          Size::new(self.bounds.size.width, FONT_12.height() * 2),
+        );
+        TextBox::new(&line, bounds, style).draw(target)?;
+        y += line_height * (FONT_12.text_width(&line) / self.bounds.size.width.max(1) + 1) as i32;
+      }
+      Ok(())
+    }
+  }
+
+  /** Logo, name and firmware info, shown while booting */
+  pub struct BootScreen<'a> {
+    pub info: &'a FirmwareInfo,
+  }
+
+  impl<'a> BootScreen<'a> {
+    pub fn new(info: &'a FirmwareInfo) -> Self {
+      Self { info }
+    }
+  }
+
+  impl Drawable for BootScreen<'_> {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+      D: DrawTarget<Color = Self::Color>,
+    {
+      let bounds = target.bounding_box();
+      let center_x = bounds.center().x;
+
+      draw_logo(Point::new(center_x, 52), target)?;
+
+      let title = Rectangle::new(Point::new(0, 100), Size::new(bounds.size.width, FONT_16.height()));
+      let style = TextStyle::new(&FONT_16, BinaryColor::Off).align(Align::Center);
+      TextBox::new("Navelo", title, style).draw(target)?;
+
+      let info = Rectangle::new(Point::new(8, 130), Size::new(bounds.size.width - 16, 64));
+      InfoScreen::new(self.info, info).draw(target)
+    }
+  }
+
+  /** A bicycle wheel with a navigation arrow, about 80x60 pixels */
+  fn draw_logo<D>(center: Point, target: &mut D) -> Result<(), D::Error>
+  where
+    D: DrawTarget<Color = BinaryColor>,
+  {
+    let color = BinaryColor::Off;
+    let stroke = PrimitiveStyle::with_stroke(color, 4);
+    let at = |x: i32, y: i32| center + Point::new(x, y);
+
+    Circle::with_center(at(-22, 12), 36).into_styled(stroke).draw(target)?;
+    Circle::with_center(at(22, 12), 36).into_styled(stroke).draw(target)?;
+    Line::new(at(-22, 12), at(-6, -12)).into_styled(stroke).draw(target)?;
+    Line::new(at(-6, -12), at(22, 12)).into_styled(stroke).draw(target)?;
+    Triangle::new(at(0, -30), at(-12, -6), at(12, -6))
+      .into_styled(PrimitiveStyle::with_fill(color))
+      .draw(target)?;
+    Ok(())
+  }
+
+  /** The page shown before the last power off */
+  pub fn last_page(nvs: &EspNvs<NvsDefault>) -> Page {
+    nvs
+      .get_u8(PAGE_KEY)
+      .ok()
+      .flatten()
+      .and_then(Page::from_u8)
+      .unwrap_or_default()
+  }
+
+  pub fn save_page(nvs: &EspNvs<NvsDefault>, page: Page) -> Result<(), EspError> {
+    nvs.set_u8(PAGE_KEY, page as u8)
+  }
+
+  /** Name set from the phone, or one derived from the BLE address so that units can be told apart */
+  pub fn device_name(nvs: &EspNvs<NvsDefault>) -> String {
+    let mut buf = [0; MAX_NAME_LEN + 1];
+    match nvs.get_str(NAME_KEY, &mut buf) {
+      Ok(Some(name)) if !name.is_empty() => name.to_string(),
+      _ => {
+        let address = ble_address();
+        format!("Navelo-{:02X}{:02X}", address[4], address[5])
+      }
+    }
+  }
+
+  pub fn save_device_name(nvs: &EspNvs<NvsDefault>, name: &str) -> Result<(), EspError> {
+    nvs.set_str(NAME_KEY, name)
+  }
+
+  /** Central chosen as the navigation source, it keeps the role across reconnections */
+  pub fn primary_central(nvs: &EspNvs<NvsDefault>) -> Option<BdAddr> {
+    let mut buf = [0; 6];
+    match nvs.get_raw(PRIMARY_KEY, &mut buf) {
+      Ok(Some(&[a, b, c, d, e, f])) => Some(BdAddr::from_bytes([a, b, c, d, e, f])),
+      _ => None,
+    }
+  }
+
+  pub fn save_primary_central(nvs: &EspNvs<NvsDefault>, addr: Option<BdAddr>) -> Result<(), EspError> {
+    match addr {
+      Some(addr) => nvs.set_raw(PRIMARY_KEY, &addr.raw()).map(|_| ()),
+      None => nvs.remove(PRIMARY_KEY).map(|_| ()),
+    }
+  }
+}
+
+pub mod audio {
+  use std::{cmp, time::Duration};
+
+  use esp_idf_hal::{
+    rmt::{PinState, Pulse, PulseTicks, Symbol, TxRmtDriver},
+    units::Hertz,
+  };
+
+  #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+  pub enum Letter {
+    C,
+    Cs,
+    D,
+    Ds,
+    E,
+    F,
+    Fs,
+    G,
+    Gs,
+    A,
+    As0,
+    B,
+  }
+
+  #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+  pub struct LetterOctave(Letter, u8);
+  impl LetterOctave {
+    pub fn new(letter: Letter, octave: u8) -> Self {
+      Self(letter, octave)
+    }
+  }
+  impl From<(Letter, u8)> for LetterOctave {
+    fn from((letter, octave): (Letter, u8)) -> Self {
+      Self(letter, octave)
+    }
+  }
+  impl From<LetterOctave> for Hertz {
+    fn from(note: LetterOctave) -> Self {
+      // TODO: cache result or calculate on compile time?
+      let LetterOctave(letter, octave) = note;
+      let step = letter as u8 + 12 * (octave + 1);
+      let freq = 440.0 * 2.0_f32.powf((step as f32 - 49.0) / 12.0);
+      Hertz(freq as u32)
+    }
+  }
+
+  pub const FULL_VOLUME: u8 = 100;
+
+  /** `volume` in percent */
+  pub fn play_song_blocking(tx: &mut TxRmtDriver<'static>, notes: &[Note], volume: u8) -> anyhow::Result<()> {
+    // insert short rest between same notes
+    let mut new_notes = Vec::<Note>::new();
+    for note in notes {
+      if let Some(prev) = new_notes.last_mut() {
+        if prev.pitch == note.pitch {
+          prev.duration -= Duration::from_millis(20);
+          new_notes.push(note!(20));
+        }
+      }
+      new_notes.push(*note);
+    }
+
+    // precalculate all notes to avoid unwanted delays
+    let ticks_hz = tx.counter_clock()?;
+    let iter = new_notes.iter().map(|note| note.iter_with_volume(ticks_hz, volume));
+    let iter = iter.collect::<Vec<NoteRmtIter>>().into_iter().flatten();
+
+    tx.start_iter_blocking(iter)?;
+    Ok(())
+  }
+
+  #[derive(Debug, Clone, Copy)]
+  pub struct Note {
+    pitch: Option<LetterOctave>,
+    duration: Duration,
+  }
+  impl Note {
+    pub fn new(pitch: impl Into<LetterOctave>, duration: Duration) -> Self {
+      Self {
+        pitch: Some(pitch.into()),
+        duration,
+      }
+    }
+    pub fn new_rest(duration: Duration) -> Self {
+      Self { pitch: None, duration }
+    }
+    pub fn play_blocking(&self, tx: &mut TxRmtDriver<'static>) -> anyhow::Result<()> {
+      let ticks_hz = tx.counter_clock()?;
+      tx.start_iter_blocking(self.iter(ticks_hz))?;
+      Ok(())
+    }
+    pub fn iter(&self, ticks_hz: Hertz) -> NoteRmtIter {
+      self.iter_with_volume(ticks_hz, FULL_VOLUME)
+    }
+    /** The buzzer gets quieter as the duty cycle of the square wave is reduced from 50 % */
+    pub fn iter_with_volume(&self, ticks_hz: Hertz, volume: u8) -> NoteRmtIter {
+      let duration_ms = self.duration.as_millis();
+      match self.pitch {
+        Some(pitch) => {
+          let cycles_per_second: Hertz = pitch.into();
+          let cycles = (cycles_per_second.0 as u128 * duration_ms) / 1000;
+          let ticks_per_cycle = ticks_hz.0 / cycles_per_second.0;
+          let high = (ticks_per_cycle / 2 * volume.min(FULL_VOLUME) as u32 / FULL_VOLUME as u32).max(1);
+          let low = (ticks_per_cycle - high).min(0x7fff); // max ticks of a pulse
+          let high = PulseTicks::new(high as u16).unwrap();
+          let low = PulseTicks::new(low as u16).unwrap();
+          // info!("{:?} -> {} ticks, {} cycles", self, ticks_per_cycle, cycles);
+          NoteRmtIter::new_tone(high, low, cycles as u32)
+        }
+        None => {
+          let total_ticks = (duration_ms * ticks_hz.0 as u128) / 1000;
+          // info!("{:?} -> {} ticks", self, total_ticks);
+          NoteRmtIter::new_rest(total_ticks as u32)
+        }
+      }
+    }
+  }
+
+  pub enum NoteRmtIter {
+    Tone {
+      high: PulseTicks,
+      low: PulseTicks,
+      cycles: u32,
+    },
+    Rest {
+      ticks: u32,
+    },
+  }
+  impl NoteRmtIter {
+    pub fn new_tone(high: PulseTicks, low: PulseTicks, cycles: u32) -> Self {
+      Self::Tone { high, low, cycles }
+    }
+    pub fn new_rest(ticks: u32) -> Self {
+      Self::Rest { ticks }
+    }
+  }
+  impl Iterator for NoteRmtIter {
+    type Item = Symbol;
+
+    fn next(&mut self) -> Option<Self::Item> {
+      match self {
+        Self::Tone { high, low, cycles } => {
+          if *cycles > 0 {
+            *cycles -= 1;
+            Some(Symbol::new(
+              Pulse::new(PinState::High, *high),
+              Pulse::new(PinState::Low, *low),
+            ))
+          } else {
+            None
+          }
+        }
+        Self::Rest { ticks } => {
+          if *ticks > 0 {
+            let t = cmp::min(*ticks, 0xff);
+            *ticks -= t;
+            Some(Symbol::new(
+              Pulse::new(PinState::Low, PulseTicks::new(t as u16 / 2).unwrap()),
+              Pulse::new(PinState::Low, PulseTicks::new(t as u16 / 2).unwrap()),
+            ))
+          } else {
+            None
+          }
+        }
+      }
+    }
+  }
+
+  macro_rules! note {
+    ($duration: expr) => {
+      Note::new_rest(::std::time::Duration::from_millis($duration))
+    };
+    ($letter: ident, $octave: expr, $duration: expr) => {
+      Note::new(
+        (Letter::$letter, $octave),
+        ::std::time::Duration::from_millis($duration),
+      )
+    };
+  }
+
+  pub fn ode_to_joy() -> [Note; 63] {
+    [
+      note!(Fs, 5, 400),
+      note!(Fs, 5, 400),
+      note!(G, 5, 400),
+      note!(A, 5, 400),
+      note!(A, 5, 400),
+      note!(G, 5, 400),
+      note!(Fs, 5, 400),
+      note!(E, 5, 400),
+      note!(D, 5, 400),
+      note!(D, 5, 400),
+      note!(E, 5, 400),
+      note!(Fs, 5, 400),
+      note!(Fs, 5, 600),
+      note!(E, 5, 200),
+      note!(E, 5, 800),
+      //
+      note!(Fs, 5, 400),
+      note!(Fs, 5, 400),
+      note!(G, 5, 400),
+      note!(A, 5, 400),
+      note!(A, 5, 400),
+      note!(G, 5, 400),
+      note!(Fs, 5, 400),
+      note!(E, 5, 400),
+      note!(D, 5, 400),
+      note!(D, 5, 400),
+      note!(E, 5, 400),
+      note!(Fs, 5, 400),
+      note!(E, 5, 600),
+      note!(D, 5, 200),
+      note!(D, 5, 800),
+      //
       note!(E, 5, 400),
       note!(E, 5, 400),
       note!(Fs, 5, 400),
@@ -1383,213 +4397,2144 @@ pub mod audio {
     ]
   }
 
-  pub(crate) use note;
+  pub(crate) use note;
+}
+
+/**
+ * GY87 IMU Module (MPU6050 + HMC5883L + BMP180)
+ * MPU6050 Spec: https://www.alldatasheet.com/datasheet-pdf/view/517746/ETC1/MPU6050.html
+ * MPU6050 Register Map: https://www.alldatasheet.com/datasheet-pdf/view/1132809/TDK/MPU6050.html
+ * HMC5883L Datasheet: https://www.alldatasheet.com/datasheet-pdf/view/428790/HONEYWELL/HMC5883L.html
+ * BMP180 Datasheet: https://www.alldatasheet.com/datasheet-pdf/view/1132068/BOSCH/BMP180.html
+ */
+pub mod sensors {
+  use esp_idf_hal::{
+    delay::{Delay, BLOCK},
+    i2c::I2cDriver,
+  };
+
+  pub const MPU6050_ADDR: u8 = 0x68;
+  pub const HMC5883L_ADDR: u8 = 0x1e;
+  pub const BMP180_ADDR: u8 = 0x77;
+
+  /** MPU6050 gyroscope: LSB per °/s at ±250 °/s */
+  pub const GYRO_SENSITIVITY: f32 = 131.0;
+  /** MPU6050 accelerometer: LSB per g at ±2 g */
+  pub const ACC_SENSITIVITY: f32 = 16384.0;
+  /** HMC5883L magnetometer: LSB per gauss at ±1.3 Ga */
+  pub const MAG_SENSITIVITY: f32 = 1090.0;
+
+  /** MPU6050 digital low pass filter setting: 0 is 260 Hz, 5 is 10 Hz, 6 is 5 Hz */
+  pub use navelo_protocol::settings::{DEFAULT_LOW_PASS, MAX_LOW_PASS};
+
+  /** I2C devices on the GY87 module */
+  pub const DEVICES: [(u8, &str); 3] = [
+    (MPU6050_ADDR, "MPU6050"),
+    (HMC5883L_ADDR, "HMC5883L"),
+    (BMP180_ADDR, "BMP180"),
+  ];
+
+  #[derive(Debug, Clone, Copy)]
+  struct Bmp180CalibrationData {
+    ac1: i64,
+    ac2: i64,
+    ac3: i64,
+    ac4: i64,
+    ac5: i64,
+    ac6: i64,
+    b1: i64,
+    b2: i64,
+    mc: i64,
+    md: i64,
+  }
+
+  #[derive(Debug, Clone, Copy)]
+  pub struct MpuValues {
+    pub acc_x: f32,
+    pub acc_y: f32,
+    pub acc_z: f32,
+    pub temp: f32,
+    pub gyro_x: f32,
+    pub gyro_y: f32,
+    pub gyro_z: f32,
+  }
+
+  #[derive(Debug, Clone, Copy)]
+  pub struct HmcValues {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+  }
+
+  #[derive(Debug, Clone, Copy)]
+  pub struct BmpValues {
+    pub temperature: f32,
+    pub pressure: f32,
+  }
+
+  pub struct Gy87<'a> {
+    i2c: I2cDriver<'a>,
+    delay: Delay,
+    mpu_addr: u8,
+    hmc_addr: u8,
+    bmp_addr: u8,
+    initialized: bool,
+    low_pass: u8,
+    hmc_gain: Option<f32>,
+    bmp_calib: Option<Bmp180CalibrationData>,
+  }
+
+  impl<'a> Gy87<'a> {
+    pub fn new(i2c: I2cDriver<'a>, delay: Delay) -> Self {
+      Self {
+        i2c,
+        delay,
+        mpu_addr: MPU6050_ADDR,
+        hmc_addr: HMC5883L_ADDR,
+        bmp_addr: BMP180_ADDR,
+        initialized: false,
+        low_pass: DEFAULT_LOW_PASS,
+        hmc_gain: None,
+        bmp_calib: None,
+      }
+    }
+
+    /** Change the MPU6050 low pass filter, see `DEFAULT_LOW_PASS` */
+    pub fn set_low_pass(&mut self, low_pass: u8) -> anyhow::Result<()> {
+      let low_pass = low_pass.min(MAX_LOW_PASS);
+      if low_pass == self.low_pass {
+        return Ok(());
+      }
+      if self.initialized {
+        self.write(self.mpu_addr, &[0x1a, low_pass])?;
+      }
+      self.low_pass = low_pass;
+      Ok(())
+    }
+
+    pub fn init(&mut self) -> anyhow::Result<()> {
+      if self.initialized {
+        return Ok(());
+      }
+
+      // === MPU6050 === //
+      self.write(self.mpu_addr, &[0x6b, 0b0000_0001])?; // wake up and set clock source
+      self.write(self.mpu_addr, &[0x1a, self.low_pass])?; // low pass filter
+      self.write(self.mpu_addr, &[0x37, 0b0000_0010])?; // enable i2c bypass
+
+      // === HMC5883L === //
+      self.write(self.hmc_addr, &[0x00, 0b1111_0000])?; // 8 samples average, 15 Hz
+      self.write(self.hmc_addr, &[0x01, 0b0010_0000])?; // range 1.3 Ga
+      self.write(self.hmc_addr, &[0x02, 0b0000_0000])?; // continuous mode
+      self.hmc_gain = Some(1.0 / MAG_SENSITIVITY);
+
+      // === BMP180 === //
+      self.write(self.bmp_addr, &[0xaa])?;
+      let calib = self.read::<[u8; 22]>(self.bmp_addr, 0xaa)?;
+      self.bmp_calib = Some(Bmp180CalibrationData {
+        ac1: i16::from_be_bytes([calib[0], calib[1]]) as i64,
+        ac2: i16::from_be_bytes([calib[2], calib[3]]) as i64,
+        ac3: i16::from_be_bytes([calib[4], calib[5]]) as i64,
+        ac4: u16::from_be_bytes([calib[6], calib[7]]) as i64,
+        ac5: u16::from_be_bytes([calib[8], calib[9]]) as i64,
+        ac6: u16::from_be_bytes([calib[10], calib[11]]) as i64,
+        b1: i16::from_be_bytes([calib[12], calib[13]]) as i64,
+        b2: i16::from_be_bytes([calib[14], calib[15]]) as i64,
+        mc: i16::from_be_bytes([calib[18], calib[19]]) as i64,
+        md: i16::from_be_bytes([calib[20], calib[21]]) as i64,
+      });
+
+      self.initialized = true;
+      Ok(())
+    }
+
+    // TODO: self test
+
+    pub fn read_mpu(&mut self) -> anyhow::Result<MpuValues> {
+      self.init()?;
+
+      let reg = self.read::<[u8; 14]>(self.mpu_addr, 0x3b)?;
+
+      Ok(MpuValues {
+        acc_x: i16::from_be_bytes([reg[0], reg[1]]) as f32 / ACC_SENSITIVITY,
+        acc_y: i16::from_be_bytes([reg[2], reg[3]]) as f32 / ACC_SENSITIVITY,
+        acc_z: i16::from_be_bytes([reg[4], reg[5]]) as f32 / ACC_SENSITIVITY,
+        temp: i16::from_be_bytes([reg[6], reg[7]]) as f32 / 340.0 + 36.53,
+        gyro_x: i16::from_be_bytes([reg[8], reg[9]]) as f32 / GYRO_SENSITIVITY,
+        gyro_y: i16::from_be_bytes([reg[10], reg[11]]) as f32 / GYRO_SENSITIVITY,
+        gyro_z: i16::from_be_bytes([reg[12], reg[13]]) as f32 / GYRO_SENSITIVITY,
+      })
+    }
+    pub fn read_hmc(&mut self) -> anyhow::Result<HmcValues> {
+      self.init()?;
+
+      let reg = self.read::<[u8; 6]>(self.hmc_addr, 0x03)?;
+
+      Ok(HmcValues {
+        x: i16::from_be_bytes([reg[0], reg[1]]) as f32 * self.hmc_gain.unwrap(),
+        y: i16::from_be_bytes([reg[2], reg[3]]) as f32 * self.hmc_gain.unwrap(),
+        z: i16::from_be_bytes([reg[4], reg[5]]) as f32 * self.hmc_gain.unwrap(),
+      })
+    }
+    pub fn read_bmp(&mut self) -> anyhow::Result<BmpValues> {
+      let oss = 3; // 8 samples, 26 ms
+      let sleep = [5, 8, 14, 26][oss as usize];
+
+      self.init()?;
+
+      self.write(self.bmp_addr, &[0xf4, 0x2e])?;
+      self.delay.delay_ms(5); // at least 4.5 ms
+      let ut = self.read::<[u8; 2]>(self.bmp_addr, 0xf6)?;
+      let ut = u16::from_be_bytes([ut[0], ut[1]]) as i64;
+
+      self.write(self.bmp_addr, &[0xf4, 0x34 + (oss << 6)])?;
+      self.delay.delay_ms(sleep);
+      let up = self.read::<[u8; 3]>(self.bmp_addr, 0xf6)?;
+      let up = u32::from_be_bytes([0, up[0], up[1], up[2]]) as i64;
+      let up = up >> (8 - oss);
+
+      let Bmp180CalibrationData {
+        ac1,
+        ac2,
+        ac3,
+        ac4,
+        ac5,
+        ac6,
+        b1,
+        b2,
+        mc,
+        md,
+      } = self.bmp_calib.unwrap();
+
+      let x1 = ((ut - ac6) * ac5) >> 15;
+      let x2 = (mc << 11) / (x1 + md);
+      let b5 = x1 + x2;
+      let temperature = (b5 + 8) as f32 / 160.0;
+
+      let b6 = b5 - 4000;
+      let x1 = (b2 * ((b6 * b6) >> 12)) >> 11;
+      let x2 = (ac2 * b6) >> 11;
+      let x3 = x1 + x2;
+      let b3 = (((ac1 * 4 + x3) << oss) + 2) / 4;
+      let x1 = (ac3 * b6) >> 13;
+      let x2 = (b1 * ((b6 * b6) >> 12)) >> 16;
+      let x3 = ((x1 + x2) + 2) >> 2;
+      let b4 = (ac4 * (x3 + 32768)) >> 15;
+      let b7 = (up - b3) * (50000 >> oss);
+      let p = b7 * 2 / b4;
+      let x1 = (p >> 8) * (p >> 8);
+      let x1 = (x1 * 3038) >> 16;
+      let x2 = (-7357 * p) >> 16;
+      let pressure = p + ((x1 + x2 + 3791) >> 4);
+      let pressure = pressure as f32;
+
+      Ok(BmpValues { temperature, pressure })
+    }
+
+    fn read<T: Default + AsMut<[u8]>>(&mut self, addr: u8, reg: u8) -> anyhow::Result<T> {
+      let mut data = T::default();
+      self.i2c.write(addr, &[reg], BLOCK)?;
+      self.i2c.read(addr, data.as_mut(), BLOCK)?;
+      Ok(data)
+    }
+    fn write(&mut self, addr: u8, data: &[u8]) -> anyhow::Result<()> {
+      self.i2c.write(addr, data, BLOCK)?;
+      Ok(())
+    }
+  }
+}
+
+/**
+ * Navelo Movement service, modelled after the TI SensorTag movement service.
+ *
+ * Data (9a01): gyro x, y, z / acc x, y, z / mag x, y, z as i16 LE raw sensor units (18 bytes)
+ * Config (9a02): u16 LE bitmask of the enabled axes, disabled axes read as zero
+ * Period (9a03): u8 in 10 ms units, saved as `Settings::imu_period`
+ */
+pub mod movement {
+  use std::{
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+  };
+
+  use log::warn;
+
+  use crate::bluetooth::NaveloServer;
+  use crate::sensors::{Gy87, ACC_SENSITIVITY, GYRO_SENSITIVITY, MAG_SENSITIVITY};
+
+  pub use navelo_protocol::movement::{
+    MovementConfig, MovementSample, ACC_X, ACC_Y, ACC_Z, GYRO_X, GYRO_Y, GYRO_Z, MAG, SAMPLE_LEN,
+  };
+
+  /** The pressure and temperature change slowly, and a BMP180 conversion takes 30 ms */
+  pub const ENVIRONMENT_INTERVAL: Duration = Duration::from_secs(10);
+
+  /** How often to check the config while no axis is enabled */
+  const IDLE_POLL: Duration = Duration::from_millis(100);
+
+  /** Read the enabled sensors and encode them as a data characteristic value */
+  pub fn sample(gy87: &mut Gy87, enabled: u16) -> anyhow::Result<[u8; SAMPLE_LEN]> {
+    let mut values = [0.0; 9];
+
+    if enabled & (GYRO_X | GYRO_Y | GYRO_Z | ACC_X | ACC_Y | ACC_Z) != 0 {
+      let mpu = gy87.read_mpu()?;
+      let axes = [
+        (GYRO_X, mpu.gyro_x * GYRO_SENSITIVITY),
+        (GYRO_Y, mpu.gyro_y * GYRO_SENSITIVITY),
+        (GYRO_Z, mpu.gyro_z * GYRO_SENSITIVITY),
+        (ACC_X, mpu.acc_x * ACC_SENSITIVITY),
+        (ACC_Y, mpu.acc_y * ACC_SENSITIVITY),
+        (ACC_Z, mpu.acc_z * ACC_SENSITIVITY),
+      ];
+      for (i, (bit, value)) in axes.into_iter().enumerate() {
+        if enabled & bit != 0 {
+          values[i] = value;
+        }
+      }
+    }
+
+    if enabled & MAG != 0 {
+      let hmc = gy87.read_hmc()?;
+      values[6] = hmc.x * MAG_SENSITIVITY;
+      values[7] = hmc.y * MAG_SENSITIVITY;
+      values[8] = hmc.z * MAG_SENSITIVITY;
+    }
+
+    let raw = values.map(|value| value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+    let sample = MovementSample {
+      gyro: [raw[0], raw[1], raw[2]],
+      acc: [raw[3], raw[4], raw[5]],
+      mag: [raw[6], raw[7], raw[8]],
+    };
+    Ok(sample.encode())
+  }
+
+  /**
+   * Sample the IMU at the configured period and notify the subscribed peers.
+   * The barometer is read every `ENVIRONMENT_INTERVAL` whether or not the IMU is enabled.
+   */
+  pub fn spawn_sampler(mut gy87: Gy87<'static>, server: NaveloServer) {
+    let mut environment_read: Option<Instant> = None;
+    // the filter is written to the IMU only when the setting changes, and again after a failed write
+    let mut low_pass = None;
+    spawn(move || loop {
+      let wanted = server.settings().imu_low_pass;
+      if low_pass != Some(wanted) {
+        match gy87.set_low_pass(wanted) {
+          Ok(()) => low_pass = Some(wanted),
+          Err(e) => warn!("Failed to set the IMU low pass filter: {e}"),
+        }
+      }
+
+      if environment_read.is_none_or(|read| read.elapsed() >= ENVIRONMENT_INTERVAL) {
+        environment_read = Some(Instant::now());
+        match gy87.read_bmp() {
+          Ok(values) => {
+            if let Err(e) = server.set_environment(values) {
+              warn!("Failed to notify the environment: {e}");
+            }
+          }
+          Err(e) => warn!("Failed to read the barometer: {e}"),
+        }
+      }
+
+      let config = server.movement_config();
+      if !config.is_enabled() {
+        sleep(IDLE_POLL);
+        continue;
+      }
+      if server.is_congested() {
+        // the previous sample has not been sent yet, skip this one
+        sleep(config.period());
+        continue;
+      }
+
+      match sample(&mut gy87, config.enabled) {
+        Ok(data) => {
+          if let Err(e) = server.notify_movement(&data) {
+            warn!("Failed to notify movement data: {e}");
+          }
+        }
+        Err(e) => warn!("Failed to read the IMU: {e}"),
+      }
+      sleep(config.period());
+    });
+  }
+}
+
+/**
+ * Li-ion battery monitor.
+ * The cell is connected to GPIO2 (ADC1 channel 2) through a 1:2 resistor divider.
+ */
+pub mod battery {
+  use std::{
+    thread::{sleep, spawn},
+    time::Duration,
+  };
+
+  use esp_idf_hal::{
+    adc::{
+      attenuation::DB_11,
+      oneshot::{
+        config::{AdcChannelConfig, Calibration},
+        AdcChannelDriver, AdcDriver,
+      },
+      ADC1,
+    },
+    gpio::Gpio2,
+    peripheral::Peripheral,
+  };
+  use log::{info, warn};
+
+  use crate::bluetooth::NaveloServer;
+
+  const VOLTAGE_DIVIDER: f32 = 2.0;
+  const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+  /** Weight of a new sample in the moving average */
+  const SMOOTHING: f32 = 0.2;
+  /** Ignore changes smaller than this to avoid flapping between two levels */
+  const HYSTERESIS: f32 = 0.7;
+
+  /** Open circuit voltage (mV) to state of charge (%) of a typical Li-ion cell, in descending order */
+  const DISCHARGE_CURVE: [(u16, u8); 21] = [
+    (4200, 100),
+    (4150, 95),
+    (4110, 90),
+    (4080, 85),
+    (4020, 80),
+    (3980, 75),
+    (3950, 70),
+    (3910, 65),
+    (3870, 60),
+    (3850, 55),
+    (3840, 50),
+    (3820, 45),
+    (3800, 40),
+    (3790, 35),
+    (3770, 30),
+    (3750, 25),
+    (3730, 20),
+    (3710, 15),
+    (3690, 10),
+    (3610, 5),
+    (3270, 0),
+  ];
+
+  /** State of charge (%) for a cell voltage (mV) */
+  pub fn percent(millivolts: f32) -> f32 {
+    let (top, _) = DISCHARGE_CURVE[0];
+    let (bottom, _) = DISCHARGE_CURVE[DISCHARGE_CURVE.len() - 1];
+    if millivolts >= top as f32 {
+      return 100.0;
+    }
+    if millivolts <= bottom as f32 {
+      return 0.0;
+    }
+
+    DISCHARGE_CURVE
+      .windows(2)
+      .find_map(|pair| {
+        let [(v1, p1), (v0, p0)] = [pair[0], pair[1]];
+        let (v0, v1, p0, p1) = (v0 as f32, v1 as f32, p0 as f32, p1 as f32);
+        (millivolts >= v0).then(|| p0 + (millivolts - v0) / (v1 - v0) * (p1 - p0))
+      })
+      .unwrap_or(0.0)
+  }
+
+  /** Smooths the measured voltage and reports the level when it changes */
+  #[derive(Debug, Default)]
+  pub struct BatteryFilter {
+    millivolts: Option<f32>,
+    level: Option<u8>,
+  }
+
+  impl BatteryFilter {
+    /** Feed a cell voltage (mV), returns the new level if it changed */
+    pub fn push(&mut self, millivolts: f32) -> Option<u8> {
+      let filtered = match self.millivolts {
+        Some(prev) => prev + (millivolts - prev) * SMOOTHING,
+        None => millivolts,
+      };
+      self.millivolts = Some(filtered);
+
+      let percent = percent(filtered);
+      let changed = match self.level {
+        Some(level) => (percent - level as f32).abs() >= HYSTERESIS,
+        None => true,
+      };
+      if !changed {
+        return None;
+      }
+
+      let level = percent.round() as u8;
+      self.level = Some(level);
+      Some(level)
+    }
+
+    pub fn level(&self) -> Option<u8> {
+      self.level
+    }
+  }
+
+  /** Level from a single blocking sample, for the battery service to start with */
+  pub fn sample_level(adc: impl Peripheral<P = ADC1>, pin: impl Peripheral<P = Gpio2>) -> Option<u8> {
+    match read_once(adc, pin) {
+      Ok(millivolts) => Some(percent(millivolts as f32 * VOLTAGE_DIVIDER).round() as u8),
+      Err(e) => {
+        warn!("Failed to read the battery voltage: {e}");
+        None
+      }
+    }
+  }
+
+  fn read_once(adc: impl Peripheral<P = ADC1>, pin: impl Peripheral<P = Gpio2>) -> anyhow::Result<u16> {
+    let adc = AdcDriver::new(adc)?;
+    let mut channel = AdcChannelDriver::new(&adc, pin, &channel_config())?;
+    Ok(adc.read(&mut channel)?)
+  }
+
+  fn channel_config() -> AdcChannelConfig {
+    AdcChannelConfig {
+      attenuation: DB_11,
+      calibration: Calibration::Curve,
+      ..Default::default()
+    }
+  }
+
+  /** Sample the battery periodically and publish the level through the battery service */
+  pub fn spawn_monitor(adc: ADC1, pin: Gpio2, server: NaveloServer) {
+    spawn(move || {
+      if let Err(e) = run_monitor(adc, pin, &server) {
+        warn!("Battery monitor stopped: {e}");
+      }
+    });
+  }
+
+  fn run_monitor(adc: ADC1, pin: Gpio2, server: &NaveloServer) -> anyhow::Result<()> {
+    let adc = AdcDriver::new(adc)?;
+    let mut channel = AdcChannelDriver::new(&adc, pin, &channel_config())?;
+    let mut filter = BatteryFilter::default();
+
+    loop {
+      match adc.read(&mut channel) {
+        Ok(millivolts) => {
+          if let Some(level) = filter.push(millivolts as f32 * VOLTAGE_DIVIDER) {
+            info!("battery: {level}%");
+            if let Err(e) = server.set_battery_level(level) {
+              warn!("Failed to notify battery level: {e}");
+            }
+          }
+        }
+        Err(e) => warn!("Failed to read the battery voltage: {e}"),
+      }
+      sleep(SAMPLE_INTERVAL);
+    }
+  }
+}
+
+/**
+ * Standard cycling sensors: Cycling Speed and Cadence, Heart Rate and Cycling Power.
+ *
+ * The measurements are notified by the sensors connected by `central`, and collected into `RideMetrics` which is
+ * shown on the ride page.
+ */
+pub mod cycling {
+  use std::time::{Duration, Instant};
+
+  use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+  };
+  use thiserror::Error;
+
+  use crate::font::{Align, TextBox, TextStyle, FONT_12, FONT_16};
+  use crate::settings::Units;
+
+  pub const CSC_SERVICE_UUID: u16 = 0x1816;
+  pub const CSC_MEASUREMENT_UUID: u16 = 0x2a5b;
+  pub const HEART_RATE_SERVICE_UUID: u16 = 0x180d;
+  pub const HEART_RATE_MEASUREMENT_UUID: u16 = 0x2a37;
+  pub const CYCLING_POWER_SERVICE_UUID: u16 = 0x1818;
+  pub const CYCLING_POWER_MEASUREMENT_UUID: u16 = 0x2a63;
+
+  /** 700x25C in meters, the sensors only count wheel revolutions */
+  const WHEEL_CIRCUMFERENCE: f32 = 2.105;
+  /** Event times are in 1/1024 s */
+  const EVENT_TIME_HZ: f32 = 1024.0;
+  /** No new revolution within this time means that the wheel or the crank stopped */
+  const STOPPED_AFTER: Duration = Duration::from_secs(3);
+  /** A value not refreshed within this time is dropped, e.g. the sensor went out of range */
+  const STALE_AFTER: Duration = Duration::from_secs(5);
+
+  const KMH_PER_MPS: f32 = 3.6;
+  const MPH_PER_MPS: f32 = 2.236_936;
+
+  const CSC_WHEEL: u8 = 1 << 0;
+  const CSC_CRANK: u8 = 1 << 1;
+  const HEART_RATE_U16: u8 = 1 << 0;
+  const POWER_PEDAL_BALANCE: u16 = 1 << 0;
+  const POWER_ACCUMULATED_TORQUE: u16 = 1 << 2;
+  const POWER_WHEEL: u16 = 1 << 4;
+  const POWER_CRANK: u16 = 1 << 5;
+
+  #[derive(Error, Debug, PartialEq, Eq)]
+  pub enum MeasurementError {
+    #[error("measurement is truncated")]
+    Truncated,
+  }
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  pub enum SensorKind {
+    SpeedCadence,
+    HeartRate,
+    Power,
+  }
+
+  impl SensorKind {
+    pub const ALL: [Self; 3] = [Self::SpeedCadence, Self::HeartRate, Self::Power];
+
+    pub fn service_uuid(self) -> u16 {
+      match self {
+        Self::SpeedCadence => CSC_SERVICE_UUID,
+        Self::HeartRate => HEART_RATE_SERVICE_UUID,
+        Self::Power => CYCLING_POWER_SERVICE_UUID,
+      }
+    }
+    pub fn measurement_uuid(self) -> u16 {
+      match self {
+        Self::SpeedCadence => CSC_MEASUREMENT_UUID,
+        Self::HeartRate => HEART_RATE_MEASUREMENT_UUID,
+        Self::Power => CYCLING_POWER_MEASUREMENT_UUID,
+      }
+    }
+    pub fn from_service_uuid(uuid: u16) -> Option<Self> {
+      Self::ALL.into_iter().find(|kind| kind.service_uuid() == uuid)
+    }
+
+    pub fn decode(self, data: &[u8]) -> Result<Measurement, MeasurementError> {
+      match self {
+        Self::SpeedCadence => Measurement::decode_csc(data),
+        Self::HeartRate => Measurement::decode_heart_rate(data),
+        Self::Power => Measurement::decode_power(data),
+      }
+    }
+  }
+
+  /** Cumulative revolutions and the time of the last one in 1/1024 s, both wrap around */
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub struct Revolutions {
+    pub count: u32,
+    pub time: u16,
+  }
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+  pub struct Measurement {
+    pub wheel: Option<Revolutions>,
+    /** The crank count is only 16 bits */
+    pub crank: Option<Revolutions>,
+    /** Beats per minute */
+    pub heart_rate: Option<u16>,
+    /** Watts */
+    pub power: Option<i16>,
+  }
+
+  impl Measurement {
+    /**
+     * CSC Measurement (0x2a5b), little endian:
+     * flags: u8, wheel revolutions: u32 and time: u16 if bit 0, crank revolutions: u16 and time: u16 if bit 1
+     */
+    pub fn decode_csc(data: &[u8]) -> Result<Self, MeasurementError> {
+      let mut data = data;
+      let [flags] = *read::<1>(&mut data)?;
+      let mut measurement = Self::default();
+      if flags & CSC_WHEEL != 0 {
+        let count = u32::from_le_bytes(*read(&mut data)?);
+        let time = u16::from_le_bytes(*read(&mut data)?);
+        measurement.wheel = Some(Revolutions { count, time });
+      }
+      if flags & CSC_CRANK != 0 {
+        let count = u16::from_le_bytes(*read(&mut data)?) as u32;
+        let time = u16::from_le_bytes(*read(&mut data)?);
+        measurement.crank = Some(Revolutions { count, time });
+      }
+      Ok(measurement)
+    }
+
+    /**
+     * Heart Rate Measurement (0x2a37), little endian:
+     * flags: u8, heart rate: u16 if bit 0 else u8, the energy expended and RR intervals are ignored
+     */
+    pub fn decode_heart_rate(data: &[u8]) -> Result<Self, MeasurementError> {
+      let mut data = data;
+      let [flags] = *read::<1>(&mut data)?;
+      let heart_rate = match flags & HEART_RATE_U16 {
+        0 => read::<1>(&mut data)?[0] as u16,
+        _ => u16::from_le_bytes(*read(&mut data)?),
+      };
+      Ok(Self {
+        heart_rate: Some(heart_rate),
+        ..Default::default()
+      })
+    }
+
+    /**
+     * Cycling Power Measurement (0x2a63), little endian:
+     * flags: u16, power: i16, pedal power balance: u8 if bit 0, accumulated torque: u16 if bit 2,
+     * wheel revolutions: u32 and time: u16 (1/2048 s) if bit 4, crank revolutions: u16 and time: u16 if bit 5.
+     * The wheel revolutions and the fields after the crank revolutions are ignored.
+     */
+    pub fn decode_power(data: &[u8]) -> Result<Self, MeasurementError> {
+      let mut data = data;
+      let flags = u16::from_le_bytes(*read(&mut data)?);
+      let power = i16::from_le_bytes(*read(&mut data)?);
+      let mut measurement = Self {
+        power: Some(power),
+        ..Default::default()
+      };
+      if flags & POWER_PEDAL_BALANCE != 0 {
+        read::<1>(&mut data)?;
+      }
+      if flags & POWER_ACCUMULATED_TORQUE != 0 {
+        read::<2>(&mut data)?;
+      }
+      if flags & POWER_WHEEL != 0 {
+        read::<6>(&mut data)?;
+      }
+      if flags & POWER_CRANK != 0 {
+        let count = u16::from_le_bytes(*read(&mut data)?) as u32;
+        let time = u16::from_le_bytes(*read(&mut data)?);
+        measurement.crank = Some(Revolutions { count, time });
+      }
+      Ok(measurement)
+    }
+  }
+
+  fn read<'a, const N: usize>(data: &mut &'a [u8]) -> Result<&'a [u8; N], MeasurementError> {
+    let (bytes, rest) = data.split_first_chunk::<N>().ok_or(MeasurementError::Truncated)?;
+    *data = rest;
+    Ok(bytes)
+  }
+
+  /** Revolutions per second from the cumulative counts */
+  #[derive(Debug, Clone)]
+  struct RevolutionRate {
+    /** The count wraps around at this mask */
+    mask: u32,
+    /** Last revolutions and when they changed */
+    last: Option<(Revolutions, Instant)>,
+    rate: Option<f32>,
+    updated: Option<Instant>,
+  }
+
+  impl RevolutionRate {
+    fn new(mask: u32) -> Self {
+      Self {
+        mask,
+        last: None,
+        rate: None,
+        updated: None,
+      }
+    }
+
+    fn update(&mut self, revolutions: Revolutions, now: Instant) {
+      self.updated = Some(now);
+      match self.last {
+        // sensors repeat the last event while nothing turns
+        Some((last, changed)) if last.time == revolutions.time => {
+          if now.duration_since(changed) > STOPPED_AFTER {
+            self.rate = Some(0.0);
+          }
+        }
+        Some((last, _)) => {
+          let count = revolutions.count.wrapping_sub(last.count) & self.mask;
+          let elapsed = revolutions.time.wrapping_sub(last.time) as f32 / EVENT_TIME_HZ;
+          self.rate = Some(count as f32 / elapsed);
+          self.last = Some((revolutions, now));
+        }
+        None => self.last = Some((revolutions, now)),
+      }
+    }
+
+    fn rate(&self) -> Option<f32> {
+      self
+        .rate
+        .filter(|_| self.updated.is_some_and(|updated| updated.elapsed() < STALE_AFTER))
+    }
+  }
+
+  /**
+   * Latest values from all the connected sensors
+   *
+   * Both a speed and cadence sensor and a power meter may count crank revolutions, with their own counts and event
+   * times, so each gets its own rate. The cadence comes from the speed and cadence sensor when it has one.
+   */
+  #[derive(Debug, Clone)]
+  pub struct RideMetrics {
+    wheel: RevolutionRate,
+    csc_crank: RevolutionRate,
+    power_crank: RevolutionRate,
+    heart_rate: Option<(u16, Instant)>,
+    power: Option<(i16, Instant)>,
+  }
+
+  impl Default for RideMetrics {
+    fn default() -> Self {
+      Self {
+        wheel: RevolutionRate::new(u32::MAX),
+        csc_crank: RevolutionRate::new(u16::MAX as u32),
+        power_crank: RevolutionRate::new(u16::MAX as u32),
+        heart_rate: None,
+        power: None,
+      }
+    }
+  }
+
+  impl RideMetrics {
+    pub fn apply(&mut self, kind: SensorKind, measurement: Measurement) {
+      let now = Instant::now();
+      if let Some(wheel) = measurement.wheel {
+        self.wheel.update(wheel, now);
+      }
+      if let Some(crank) = measurement.crank {
+        match kind {
+          SensorKind::Power => self.power_crank.update(crank, now),
+          _ => self.csc_crank.update(crank, now),
+        }
+      }
+      if let Some(heart_rate) = measurement.heart_rate {
+        self.heart_rate = Some((heart_rate, now));
+      }
+      if let Some(power) = measurement.power {
+        self.power = Some((power, now));
+      }
+    }
+
+    /** Meters per second */
+    pub fn speed(&self) -> Option<f32> {
+      self.wheel.rate().map(|rate| rate * WHEEL_CIRCUMFERENCE)
+    }
+    /** Crank revolutions per minute */
+    pub fn cadence(&self) -> Option<f32> {
+      self
+        .csc_crank
+        .rate()
+        .or_else(|| self.power_crank.rate())
+        .map(|rate| rate * 60.0)
+    }
+    pub fn heart_rate(&self) -> Option<u16> {
+      fresh(self.heart_rate)
+    }
+    pub fn power(&self) -> Option<i16> {
+      fresh(self.power)
+    }
+
+    /** Values as shown on the ride page, the page is only redrawn when they change */
+    pub fn values(&self, units: Units) -> RideValues {
+      RideValues {
+        speed: self.speed().map(|speed| format_speed(speed, units)),
+        cadence: self.cadence().map(|cadence| cadence.round() as u16),
+        heart_rate: self.heart_rate(),
+        power: self.power(),
+      }
+    }
+  }
+
+  fn fresh<T: Copy>(value: Option<(T, Instant)>) -> Option<T> {
+    value
+      .filter(|(_, updated)| updated.elapsed() < STALE_AFTER)
+      .map(|(value, _)| value)
+  }
+
+  pub fn format_speed(meters_per_second: f32, units: Units) -> String {
+    match units {
+      Units::Metric => format!("{:.1} km/h", meters_per_second * KMH_PER_MPS),
+      Units::Imperial => format!("{:.1} mph", meters_per_second * MPH_PER_MPS),
+    }
+  }
+
+  #[derive(Debug, Clone, PartialEq, Eq, Default)]
+  pub struct RideValues {
+    pub speed: Option<String>,
+    pub cadence: Option<u16>,
+    pub heart_rate: Option<u16>,
+    pub power: Option<i16>,
+  }
+
+  /** Speed, cadence, heart rate and power in a 2x2 grid, `--` without a sensor */
+  pub struct RideScreen<'a> {
+    pub values: &'a RideValues,
+    pub bounds: Rectangle,
+  }
+
+  impl<'a> RideScreen<'a> {
+    pub fn new(values: &'a RideValues, bounds: Rectangle) -> Self {
+      Self { values, bounds }
+    }
+  }
+
+  impl Drawable for RideScreen<'_> {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+      D: DrawTarget<Color = Self::Color>,
+    {
+      let color = BinaryColor::Off;
+      let Point { x, y } = self.bounds.top_left;
+      let Size { width, height } = self.bounds.size;
+      let stroke = PrimitiveStyle::with_stroke(color, 1);
+      let (center_x, center_y) = (x + width as i32 / 2, y + height as i32 / 2);
+
+      Line::new(Point::new(center_x, y), Point::new(center_x, y + height as i32 - 1))
+        .into_styled(stroke)
+        .draw(target)?;
+      Line::new(Point::new(x, center_y), Point::new(x + width as i32 - 1, center_y))
+        .into_styled(stroke)
+        .draw(target)?;
+
+      let values = self.values;
+      let or_none = |value: Option<String>| value.unwrap_or_else(|| "--".to_string());
+      let tiles = [
+        ("Speed", or_none(values.speed.clone())),
+        (
+          "Cadence",
+          or_none(values.cadence.map(|cadence| format!("{cadence} rpm"))),
+        ),
+        ("Heart rate", or_none(values.heart_rate.map(|bpm| format!("{bpm} bpm")))),
+        ("Power", or_none(values.power.map(|power| format!("{power} W")))),
+      ];
+
+      let tile_size = Size::new(width / 2, height / 2);
+      for (i, (label, value)) in tiles.iter().enumerate() {
+        let top_left = Point::new(
+          x + (i % 2) as i32 * tile_size.width as i32,
+          y + (i / 2) as i32 * tile_size.height as i32,
+        );
+        let tile = Rectangle::new(top_left, tile_size).offset(-4);
+        let line =
+          |top: i32, height: u32| Rectangle::new(Point::new(tile.top_left.x, top), Size::new(tile.size.width, height));
+        let style = |font| TextStyle::new(font, color).align(Align::Center).max_lines(1);
+
+        let value_top = tile.center().y - FONT_16.height() as i32 / 2;
+        TextBox::new(label, line(tile.top_left.y, FONT_12.height()), style(&FONT_12)).draw(target)?;
+        TextBox::new(value, line(value_top, FONT_16.height()), style(&FONT_16)).draw(target)?;
+      }
+      Ok(())
+    }
+  }
+}
+
+/**
+ * BLE central role: finds the cycling sensors, connects to them and subscribes to their measurements.
+ *
+ * Runs as a GATT client next to the server, on the same BLE driver. One sensor of each kind is connected; while a kind
+ * is missing the sensors are scanned for periodically, and a sensor that disconnects is scanned for again.
+ */
+pub mod central {
+  use std::collections::HashMap;
+  use std::sync::{Arc, Mutex};
+  use std::thread::{sleep, spawn};
+  use std::time::Duration;
+
+  use esp_idf_svc::bt::ble::gatt::client::{
+    CharacteristicElement, ConnectionId, DescriptorElement, EspGattc, GattAuthReq, GattWriteType, GattcEvent,
+  };
+  use esp_idf_svc::bt::ble::gatt::{GattInterface, GattStatus, Handle};
+  use esp_idf_svc::bt::{BdAddr, Ble, BtUuid};
+  use esp_idf_svc::sys::EspError;
+
+  use log::{info, warn};
+
+  use crate::cycling::{RideMetrics, SensorKind};
+  use crate::gatt::{esp_addr, Advertisement, BleDriver, GattServer, ScanEvent};
+
+  /** The server is registered as app 0 */
+  const APP_ID: u16 = 1;
+  /** How long each scan for the missing sensors lasts */
+  const SCAN_DURATION: Duration = Duration::from_secs(10);
+  /** How often to scan while a sensor is missing */
+  const SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+  const CCCD_UUID: u16 = 0x2902;
+  const CCCD_NOTIFY: [u8; 2] = [0x01, 0x00];
+
+  type BleGattc = Arc<EspGattc<'static, Ble, Arc<BleDriver>>>;
+
+  struct Sensor {
+    addr: BdAddr,
+    conn_id: ConnectionId,
+    /** Kinds advertised by the sensor and missing when it was found */
+    kinds: Vec<SensorKind>,
+    /** Handle ranges of the services found by the discovery */
+    services: Vec<(SensorKind, Handle, Handle)>,
+    /** Measurement value handles */
+    measurements: HashMap<Handle, SensorKind>,
+  }
+
+  #[derive(Default)]
+  struct State {
+    gattc_if: Option<GattInterface>,
+    sensors: Vec<Sensor>,
+    /** The sensor being connected, only one at a time */
+    connecting: Option<(BdAddr, Vec<SensorKind>)>,
+    scanning: bool,
+  }
+
+  impl State {
+    fn missing(&self) -> Vec<SensorKind> {
+      let connected = self.sensors.iter().flat_map(|sensor| &sensor.kinds);
+      let connecting = self.connecting.iter().flat_map(|(_, kinds)| kinds);
+      let present: Vec<_> = connected.chain(connecting).collect();
+      SensorKind::ALL
+        .into_iter()
+        .filter(|kind| !present.contains(&kind))
+        .collect()
+    }
+
+    fn sensor(&mut self, conn_id: ConnectionId) -> Option<&mut Sensor> {
+      self.sensors.iter_mut().find(|sensor| sensor.conn_id == conn_id)
+    }
+  }
+
+  #[derive(Clone)]
+  pub struct SensorCentral {
+    gattc: BleGattc,
+    server: GattServer,
+    metrics: Arc<Mutex<RideMetrics>>,
+    state: Arc<Mutex<State>>,
+  }
+
+  impl SensorCentral {
+    /** Register the GATT client and start looking for sensors */
+    pub fn start(server: &GattServer) -> anyhow::Result<Self> {
+      let central = Self {
+        gattc: Arc::new(EspGattc::new(server.stack().driver())?),
+        server: server.clone(),
+        metrics: Arc::new(Mutex::new(RideMetrics::default())),
+        state: Arc::new(Mutex::new(State::default())),
+      };
+
+      let events = central.clone();
+      central.gattc.subscribe(move |(gattc_if, event)| {
+        events.check(events.on_gattc_event(gattc_if, event));
+      })?;
+
+      let scans = central.clone();
+      server.set_scan_handler(move |event| scans.check(scans.on_scan_event(event)));
+
+      central.gattc.register_app(APP_ID)?;
+
+      let ticker = central.clone();
+      spawn(move || loop {
+        sleep(SCAN_INTERVAL);
+        ticker.check(ticker.scan_for_missing());
+      });
+
+      Ok(central)
+    }
+
+    /** Values from the connected sensors */
+    pub fn metrics(&self) -> RideMetrics {
+      self.metrics.lock().unwrap().clone()
+    }
+
+    fn check(&self, result: Result<(), EspError>) {
+      if let Err(e) = result {
+        warn!("Sensor central error: {e:?}");
+      }
+    }
+
+    fn scan_for_missing(&self) -> Result<(), EspError> {
+      let mut state = self.state.lock().unwrap();
+      if state.gattc_if.is_none() || state.scanning || state.connecting.is_some() || state.missing().is_empty() {
+        return Ok(());
+      }
+      state.scanning = true;
+      self.server.start_scanning(SCAN_DURATION)
+    }
+
+    fn on_scan_event(&self, event: ScanEvent) -> Result<(), EspError> {
+      match event {
+        ScanEvent::Found(advertisement) => self.on_advertisement(advertisement),
+        ScanEvent::Complete => {
+          self.state.lock().unwrap().scanning = false;
+          Ok(())
+        }
+      }
+    }
+
+    /** Connect to the first sensor advertising a missing kind */
+    fn on_advertisement(&self, advertisement: Advertisement) -> Result<(), EspError> {
+      let mut state = self.state.lock().unwrap();
+      let Some(gattc_if) = state.gattc_if else {
+        return Ok(());
+      };
+      if state.connecting.is_some() {
+        return Ok(());
+      }
+      let missing = state.missing();
+      let kinds: Vec<_> = advertisement
+        .service_uuids16()
+        .into_iter()
+        .filter_map(SensorKind::from_service_uuid)
+        .filter(|kind| missing.contains(kind))
+        .collect();
+      if kinds.is_empty() {
+        return Ok(());
+      }
+
+      info!(
+        "Connecting to {:?} sensor {} (RSSI {})",
+        kinds, advertisement.addr, advertisement.rssi
+      );
+      let addr = esp_addr(advertisement.addr);
+      state.connecting = Some((addr, kinds));
+      state.scanning = false;
+      self.server.stop_scanning()?;
+      self.gattc.open(gattc_if, addr, advertisement.addr_type.into(), true)
+    }
+
+    fn on_gattc_event(&self, gattc_if: GattInterface, event: GattcEvent) -> Result<(), EspError> {
+      match event {
+        GattcEvent::ClientRegistered { status, app_id } if app_id == APP_ID => {
+          if status != GattStatus::Ok {
+            warn!("Failed to register the sensor client: {status:?}");
+            return Ok(());
+          }
+          self.state.lock().unwrap().gattc_if = Some(gattc_if);
+          self.scan_for_missing()?;
+        }
+        GattcEvent::Open {
+          status, conn_id, addr, ..
+        } => {
+          let mut state = self.state.lock().unwrap();
+          let Some((_, kinds)) = state.connecting.take_if(|(connecting, _)| *connecting == addr) else {
+            return Ok(());
+          };
+          if status != GattStatus::Ok {
+            warn!("Failed to connect to sensor {addr}: {status:?}");
+            return Ok(());
+          }
+          state.sensors.push(Sensor {
+            addr,
+            conn_id,
+            kinds,
+            services: Vec::new(),
+            measurements: HashMap::new(),
+          });
+          self.gattc.search_service(gattc_if, conn_id, None)?;
+        }
+        GattcEvent::SearchResult {
+          conn_id,
+          srvc_id,
+          start_handle,
+          end_handle,
+          ..
+        } => {
+          let mut state = self.state.lock().unwrap();
+          let Some(sensor) = state.sensor(conn_id) else {
+            return Ok(());
+          };
+          let kind = sensor
+            .kinds
+            .iter()
+            .find(|kind| srvc_id.uuid == BtUuid::uuid16(kind.service_uuid()));
+          if let Some(&kind) = kind {
+            sensor.services.push((kind, start_handle, end_handle));
+          }
+        }
+        GattcEvent::SearchComplete { status, conn_id, .. } => {
+          if status != GattStatus::Ok {
+            warn!("Failed to discover the sensor services: {status:?}");
+            return Ok(());
+          }
+          self.subscribe(gattc_if, conn_id)?;
+        }
+        GattcEvent::Notify {
+          conn_id, handle, value, ..
+        } => {
+          let kind = self
+            .state
+            .lock()
+            .unwrap()
+            .sensor(conn_id)
+            .and_then(|sensor| sensor.measurements.get(&handle).copied());
+          if let Some(kind) = kind {
+            match kind.decode(value) {
+              Ok(measurement) => self.metrics.lock().unwrap().apply(kind, measurement),
+              Err(e) => warn!("Invalid {kind:?} measurement: {e}"),
+            }
+          }
+        }
+        GattcEvent::WriteDescriptor { status, .. } | GattcEvent::RegisterNotify { status, .. }
+          if status != GattStatus::Ok =>
+        {
+          warn!("Failed to subscribe to a sensor: {status:?}");
+        }
+        GattcEvent::Disconnected { conn_id, addr, .. } | GattcEvent::Close { conn_id, addr, .. } => {
+          let mut state = self.state.lock().unwrap();
+          if state
+            .connecting
+            .as_ref()
+            .is_some_and(|(connecting, _)| *connecting == addr)
+          {
+            state.connecting = None;
+          }
+          state.sensors.retain(|sensor| sensor.conn_id != conn_id);
+          drop(state);
+          info!("Sensor {addr} disconnected");
+          self.scan_for_missing()?;
+        }
+        _ => (),
+      }
+
+      Ok(())
+    }
+
+    /** Enable the notifications of the measurement of every discovered service */
+    fn subscribe(&self, gattc_if: GattInterface, conn_id: ConnectionId) -> Result<(), EspError> {
+      let Some((addr, services)) = self
+        .state
+        .lock()
+        .unwrap()
+        .sensor(conn_id)
+        .map(|sensor| (sensor.addr, sensor.services.clone()))
+      else {
+        return Ok(());
+      };
+
+      for (kind, start_handle, end_handle) in services {
+        let mut characteristics = [CharacteristicElement::new()];
+        let uuid = BtUuid::uuid16(kind.measurement_uuid());
+        let found = self.gattc.get_characteristic_by_uuid(
+          gattc_if,
+          conn_id,
+          start_handle,
+          end_handle,
+          &uuid,
+          &mut characteristics,
+        )?;
+        if found == 0 {
+          warn!("Sensor {addr} has no {kind:?} measurement");
+          continue;
+        }
+        let handle = characteristics[0].handle();
+
+        let mut descriptors = [DescriptorElement::new()];
+        let cccd = BtUuid::uuid16(CCCD_UUID);
+        if self
+          .gattc
+          .get_descriptor_by_char_handle(gattc_if, conn_id, handle, &cccd, &mut descriptors)?
+          == 0
+        {
+          warn!("Sensor {addr} does not notify its {kind:?} measurement");
+          continue;
+        }
+
+        self.gattc.register_for_notify(gattc_if, addr, handle)?;
+        self.gattc.write_descriptor(
+          gattc_if,
+          conn_id,
+          descriptors[0].handle(),
+          &CCCD_NOTIFY,
+          GattWriteType::RequireResponse,
+          GattAuthReq::None,
+        )?;
+
+        if let Some(sensor) = self.state.lock().unwrap().sensor(conn_id) {
+          sensor.measurements.insert(handle, kind);
+        }
+        info!("Subscribed to the {kind:?} measurement of {addr}");
+      }
+
+      Ok(())
+    }
+  }
+}
+
+/**
+ * Location and Navigation Service (0x1819), so that third-party apps get the position and the route progress without
+ * the Navelo protocol.
+ *
+ * The values are built from what the phone pushes for the mini-map and the banner, and the speed of the cycling
+ * sensors. Compiled with the `standard-profiles` feature.
+ */
+#[cfg(feature = "standard-profiles")]
+pub mod location {
+  use std::thread::{sleep, spawn};
+  use std::time::Duration;
+
+  use log::warn;
+
+  use crate::bluetooth::NaveloServer;
+  use crate::clock::{self, DateTime};
+  use crate::minimap::{GeoPoint, MapState};
+  use crate::navigation::Instruction;
+  use crate::ui::GpsFix;
+
+  pub const LOCATION_NAVIGATION_SERVICE_UUID: u16 = 0x1819;
+  pub const LOCATION_AND_SPEED_CHARACTERISTIC_UUID: u16 = 0x2a67;
+  pub const LN_NAVIGATION_CHARACTERISTIC_UUID: u16 = 0x2a68;
+  pub const LN_FEATURE_CHARACTERISTIC_UUID: u16 = 0x2a6a;
+  pub const LN_CONTROL_POINT_CHARACTERISTIC_UUID: u16 = 0x2a6b;
+
+  const NOTIFY_INTERVAL: Duration = Duration::from_secs(1);
+
+  const FEATURE_SPEED: u32 = 1 << 0;
+  const FEATURE_LOCATION: u32 = 1 << 2;
+  const FEATURE_HEADING: u32 = 1 << 4;
+  const FEATURE_REMAINING_DISTANCE: u32 = 1 << 7;
+  const FEATURE_ETA: u32 = 1 << 9;
+  const FEATURE_POSITION_STATUS: u32 = 1 << 15;
+  /** LN Feature characteristic value */
+  pub const FEATURES: u32 = FEATURE_SPEED
+    | FEATURE_LOCATION
+    | FEATURE_HEADING
+    | FEATURE_REMAINING_DISTANCE
+    | FEATURE_ETA
+    | FEATURE_POSITION_STATUS;
+
+  const LOCATION_SPEED: u16 = 1 << 0;
+  const LOCATION_LOCATION: u16 = 1 << 2;
+  const LOCATION_HEADING: u16 = 1 << 4;
+  const LOCATION_STATUS_SHIFT: u16 = 7;
+  const NAVIGATION_REMAINING_DISTANCE: u16 = 1 << 0;
+  const NAVIGATION_ETA: u16 = 1 << 2;
+  const NAVIGATION_STATUS_SHIFT: u16 = 3;
+  const NAVIGATION_TO_DESTINATION: u16 = 1 << 6;
+
+  const RESPONSE_CODE: u8 = 0x20;
+  const NAVIGATION_CONTROL: u8 = 0x03;
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub enum PositionStatus {
+    None = 0,
+    Ok = 1,
+    Estimated = 2,
+    LastKnown = 3,
+  }
+
+  impl PositionStatus {
+    fn new(position: Option<GeoPoint>, fix: GpsFix) -> Self {
+      match (position, fix) {
+        (None, _) => Self::None,
+        (Some(_), GpsFix::NoFix) => Self::LastKnown,
+        (Some(_), _) => Self::Ok,
+      }
+    }
+  }
+
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub struct LocationAndSpeed {
+    /** Meters per second */
+    pub speed: Option<f32>,
+    pub position: Option<GeoPoint>,
+    /** Degrees clockwise from north */
+    pub heading: Option<f32>,
+    pub status: PositionStatus,
+  }
+
+  impl LocationAndSpeed {
+    pub fn new(map: &MapState, fix: GpsFix, speed: Option<f32>) -> Self {
+      Self {
+        speed,
+        position: map.position,
+        heading: map.heading,
+        status: PositionStatus::new(map.position, fix),
+      }
+    }
+
+    /**
+     * Location and Speed (0x2a67), little endian:
+     * flags: u16, speed: u16 (1/100 m/s), latitude: i32 and longitude: i32 (1e-7 degrees), heading: u16 (1/100 degrees)
+     */
+    pub fn encode(&self) -> Vec<u8> {
+      let mut flags = (self.status as u16) << LOCATION_STATUS_SHIFT;
+      let mut data = Vec::with_capacity(14);
+      if let Some(speed) = self.speed {
+        flags |= LOCATION_SPEED;
+        data.extend_from_slice(&hundredths(speed).to_le_bytes());
+      }
+      if let Some(position) = self.position {
+        flags |= LOCATION_LOCATION;
+        data.extend_from_slice(&position.lat.saturating_mul(10).to_le_bytes());
+        data.extend_from_slice(&position.lon.saturating_mul(10).to_le_bytes());
+      }
+      if let Some(heading) = self.heading {
+        flags |= LOCATION_HEADING;
+        data.extend_from_slice(&hundredths(heading.rem_euclid(360.0)).to_le_bytes());
+      }
+      [flags.to_le_bytes().as_slice(), &data].concat()
+    }
+  }
+
+  /** Progress to the destination, only sent to the peers which started the navigation */
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub struct NavigationData {
+    /** Degrees clockwise from north to the destination */
+    pub bearing: f32,
+    pub heading: f32,
+    /** Meters */
+    pub remaining: u32,
+    pub eta: Option<DateTime>,
+    pub status: PositionStatus,
+  }
+
+  impl NavigationData {
+    /** None without a position or a route */
+    pub fn new(map: &MapState, fix: GpsFix, instruction: &Instruction) -> Option<Self> {
+      let bearing = map.bearing_to_destination()?;
+      let eta = instruction
+        .eta
+        .zip(clock::unix_time())
+        .map(|(eta, now)| DateTime::from_unix(now + eta as i64 + clock::utc_offset() as i64 * 60));
+      Some(Self {
+        bearing,
+        // the heading is mandatory, assume heading to the destination while it is unknown
+        heading: map.heading.unwrap_or(bearing),
+        remaining: instruction.remaining,
+        eta,
+        status: PositionStatus::new(map.position, fix),
+      })
+    }
+
+    /**
+     * Navigation (0x2a68), little endian:
+     * flags: u16, bearing: u16 and heading: u16 (1/100 degrees), remaining distance: u24 (1/10 m), ETA: date time
+     */
+    pub fn encode(&self) -> Vec<u8> {
+      let mut flags =
+        NAVIGATION_REMAINING_DISTANCE | NAVIGATION_TO_DESTINATION | (self.status as u16) << NAVIGATION_STATUS_SHIFT;
+      let mut data = Vec::with_capacity(16);
+      data.extend_from_slice(&hundredths(self.bearing.rem_euclid(360.0)).to_le_bytes());
+      data.extend_from_slice(&hundredths(self.heading.rem_euclid(360.0)).to_le_bytes());
+      let remaining = self.remaining.saturating_mul(10).min(0xff_ffff);
+      data.extend_from_slice(&remaining.to_le_bytes()[..3]);
+      if let Some(eta) = self.eta {
+        flags |= NAVIGATION_ETA;
+        data.extend_from_slice(&eta.encode_date_time());
+      }
+      [flags.to_le_bytes().as_slice(), &data].concat()
+    }
+  }
+
+  fn hundredths(value: f32) -> u16 {
+    (value * 100.0).round().clamp(0.0, u16::MAX as f32) as u16
+  }
+
+  /** Result codes of the LN Control Point */
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub enum ControlResult {
+    Success = 1,
+    OpCodeNotSupported = 2,
+    InvalidParameter = 3,
+  }
+
+  /** Navigation Control procedure, the route itself is chosen on the phone */
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub enum NavigationControl {
+    /** Stop or pause the notifications of the Navigation characteristic */
+    Stop,
+    /** Start or continue them */
+    Start,
+  }
+
+  impl NavigationControl {
+    /** Parse a write to the LN Control Point, only the Navigation Control procedure is supported */
+    pub fn decode(data: &[u8]) -> Result<Self, ControlResult> {
+      match *data {
+        [NAVIGATION_CONTROL, 0x00 | 0x02] => Ok(Self::Stop),
+        [NAVIGATION_CONTROL, 0x01 | 0x03] => Ok(Self::Start),
+        [NAVIGATION_CONTROL, ..] => Err(ControlResult::InvalidParameter),
+        _ => Err(ControlResult::OpCodeNotSupported),
+      }
+    }
+  }
+
+  /** Response indicated after a write to the LN Control Point */
+  pub fn control_response(request: &[u8], result: ControlResult) -> [u8; 3] {
+    [RESPONSE_CODE, request.first().copied().unwrap_or(0), result as u8]
+  }
+
+  /** Notify the location and the navigation progress every second */
+  pub fn spawn_notifier(server: NaveloServer) {
+    spawn(move || loop {
+      sleep(NOTIFY_INTERVAL);
+
+      let map = server.map().lock().unwrap().clone();
+      let fix = server.phone_status().gps_fix;
+      let location = LocationAndSpeed::new(&map, fix, server.ride_metrics().speed());
+      let navigation = server
+        .navigation()
+        .and_then(|instruction| NavigationData::new(&map, fix, &instruction));
+
+      if let Err(e) = server.notify_location(&location, navigation.as_ref()) {
+        warn!("Failed to notify the location: {e}");
+      }
+    });
+  }
+}
+
+/**
+ * Wall-clock time set by the phone.
+ *
+ * The time is kept by the system time, which ESP-IDF counts with the RTC timer so that it keeps running in light sleep.
+ * Only the UTC offset is kept here. Before the first sync the system time starts at the epoch and is reported as unknown.
+ */
+pub mod clock {
+  use std::sync::atomic::{AtomicI32, Ordering};
+  use std::time::{SystemTime, UNIX_EPOCH};
+
+  use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+  use esp_idf_svc::sys::{self, EspError};
+  use thiserror::Error;
+
+  /** 2024-01-01, anything earlier was never synced */
+  const MIN_VALID_TIME: i64 = 1_704_067_200;
+  const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+  pub const CURRENT_TIME_LEN: usize = 10;
+  pub const DATE_TIME_LEN: usize = 7;
+  pub const LOCAL_TIME_INFO_LEN: usize = 2;
+  /** Adjust reason flags of the Current Time characteristic */
+  pub const MANUAL_TIME_UPDATE: u8 = 1 << 0;
+  pub const TIME_ZONE_CHANGE: u8 = 1 << 2;
+  const TIME_ZONE_UNKNOWN: i8 = -128;
+  const DST_OFFSET_UNKNOWN: u8 = 255;
+  const UTC_OFFSET_KEY: &str = "utc_offset";
+
+  /** UTC offset in minutes */
+  static UTC_OFFSET: AtomicI32 = AtomicI32::new(0);
+
+  #[derive(Error, Debug, PartialEq, Eq)]
+  pub enum ClockError {
+    #[error("value is truncated")]
+    Truncated,
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+  }
+
+  pub fn set_unix_time(seconds: i64) {
+    let time = sys::timeval {
+      tv_sec: seconds as _,
+      tv_usec: 0,
+    };
+    unsafe { sys::settimeofday(&time, std::ptr::null()) };
+  }
+
+  pub fn set_utc_offset(minutes: i32) {
+    UTC_OFFSET.store(minutes, Ordering::Relaxed);
+  }
+
+  pub fn utc_offset() -> i32 {
+    UTC_OFFSET.load(Ordering::Relaxed)
+  }
+
+  /** Move to another time zone without changing the local time, the UTC time is derived from it */
+  pub fn change_utc_offset(minutes: i32) {
+    let local = local_time();
+    set_utc_offset(minutes);
+    if let Some(local) = local {
+      set_unix_time(local.to_unix() - minutes as i64 * 60);
+    }
+  }
+
+  /** Restore the saved UTC offset, the time itself is lost on reboot until the phone sets it again */
+  pub fn load_utc_offset(nvs: &EspNvs<NvsDefault>) {
+    if let Ok(Some(minutes)) = nvs.get_i32(UTC_OFFSET_KEY) {
+      set_utc_offset(minutes);
+    }
+  }
+
+  pub fn save_utc_offset(nvs: &EspNvs<NvsDefault>, minutes: i32) -> Result<(), EspError> {
+    nvs.set_i32(UTC_OFFSET_KEY, minutes)
+  }
+
+  /** Seconds since the unix epoch, None until the phone set the time */
+  pub fn unix_time() -> Option<i64> {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    (seconds >= MIN_VALID_TIME).then_some(seconds)
+  }
+
+  pub fn local_time() -> Option<DateTime> {
+    Some(DateTime::from_unix(unix_time()? + utc_offset() as i64 * 60))
+  }
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+  pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+  }
+
+  impl DateTime {
+    pub fn from_unix(seconds: i64) -> Self {
+      let days = seconds.div_euclid(SECONDS_PER_DAY);
+      let time = seconds.rem_euclid(SECONDS_PER_DAY);
+
+      // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+      let days = days + 719_468;
+      let era = days.div_euclid(146_097);
+      let day_of_era = days - era * 146_097;
+      let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+      let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+      let month = (5 * day_of_year + 2) / 153;
+      let day = day_of_year - (153 * month + 2) / 5 + 1;
+      let month = if month < 10 { month + 3 } else { month - 9 };
+      let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+      Self {
+        year: year as u16,
+        month: month as u8,
+        day: day as u8,
+        hours: (time / 3600) as u8,
+        minutes: (time / 60 % 60) as u8,
+        seconds: (time % 60) as u8,
+      }
+    }
+
+    pub fn to_unix(&self) -> i64 {
+      // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+      let month = self.month as i64;
+      let year = self.year as i64 - (month <= 2) as i64;
+      let era = year.div_euclid(400);
+      let year_of_era = year - era * 400;
+      let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+      let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+      let days = era * 146_097 + day_of_era - 719_468;
+
+      days * SECONDS_PER_DAY + self.hours as i64 * 3600 + self.minutes as i64 * 60 + self.seconds as i64
+    }
+
+    /** 1 for Monday to 7 for Sunday */
+    pub fn weekday(&self) -> u8 {
+      // 1970-01-01 was a Thursday
+      ((self.to_unix().div_euclid(SECONDS_PER_DAY) + 3).rem_euclid(7) + 1) as u8
+    }
+
+    /**
+     * Current Time characteristic (0x2a2b), little endian:
+     * year: u16, month: u8, day: u8, hours: u8, minutes: u8, seconds: u8, day of week: u8, fractions256: u8,
+     * adjust reason: u8
+     */
+    pub fn encode_current_time(&self, adjust_reason: u8) -> [u8; CURRENT_TIME_LEN] {
+      let mut data = [0; CURRENT_TIME_LEN];
+      data[..DATE_TIME_LEN].copy_from_slice(&self.encode_date_time());
+      data[DATE_TIME_LEN] = self.weekday();
+      data[CURRENT_TIME_LEN - 1] = adjust_reason;
+      data
+    }
+
+    /** Date Time (0x2a08), the first fields of the Current Time */
+    pub fn encode_date_time(&self) -> [u8; DATE_TIME_LEN] {
+      let [year_low, year_high] = self.year.to_le_bytes();
+      [
+        year_low,
+        year_high,
+        self.month,
+        self.day,
+        self.hours,
+        self.minutes,
+        self.seconds,
+      ]
+    }
+
+    /** The day of week, fractions and adjust reason are ignored */
+    pub fn decode_current_time(data: &[u8]) -> Result<Self, ClockError> {
+      let data = data.get(..7).ok_or(ClockError::Truncated)?;
+      Ok(Self {
+        year: match u16::from_le_bytes([data[0], data[1]]) {
+          year @ 1582..=9999 => year,
+          _ => return Err(ClockError::Invalid("year")),
+        },
+        month: match data[2] {
+          month @ 1..=12 => month,
+          _ => return Err(ClockError::Invalid("month")),
+        },
+        day: match data[3] {
+          day @ 1..=31 => day,
+          _ => return Err(ClockError::Invalid("day")),
+        },
+        hours: match data[4] {
+          hours @ 0..=23 => hours,
+          _ => return Err(ClockError::Invalid("hours")),
+        },
+        minutes: match data[5] {
+          minutes @ 0..=59 => minutes,
+          _ => return Err(ClockError::Invalid("minutes")),
+        },
+        seconds: match data[6] {
+          seconds @ 0..=59 => seconds,
+          _ => return Err(ClockError::Invalid("seconds")),
+        },
+      })
+    }
+  }
+
+  /** Local Time Information characteristic (0x2a0f): time zone: i8, DST offset: u8, both in 15 minutes */
+  pub fn encode_local_time_info(utc_offset: i32) -> [u8; LOCAL_TIME_INFO_LEN] {
+    [(utc_offset / 15) as i8 as u8, 0]
+  }
+
+  /** UTC offset in minutes, including the DST offset */
+  pub fn decode_local_time_info(data: &[u8]) -> Result<i32, ClockError> {
+    let &[time_zone, dst_offset] = data.first_chunk::<2>().ok_or(ClockError::Truncated)?;
+    let time_zone = match time_zone as i8 {
+      time_zone @ -48..=56 => time_zone as i32,
+      TIME_ZONE_UNKNOWN => 0,
+      _ => return Err(ClockError::Invalid("time zone")),
+    };
+    let dst_offset = match dst_offset {
+      0 | 2 | 4 | 8 => dst_offset as i32,
+      DST_OFFSET_UNKNOWN => 0,
+      _ => return Err(ClockError::Invalid("DST offset")),
+    };
+    Ok((time_zone + dst_offset) * 15)
+  }
 }
 
 /**
- * GY87 IMU Module (MPU6050 + HMC5883L + BMP180)
- * MPU6050 Spec: https://www.alldatasheet.com/datasheet-pdf/view/517746/ETC1/MPU6050.html
- * MPU6050 Register Map: https://www.alldatasheet.com/datasheet-pdf/view/1132809/TDK/MPU6050.html
- * HMC5883L Datasheet: https://www.alldatasheet.com/datasheet-pdf/view/428790/HONEYWELL/HMC5883L.html
- * BMP180 Datasheet: https://www.alldatasheet.com/datasheet-pdf/view/1132068/BOSCH/BMP180.html
+ * Device settings configured from the phone.
+ *
+ * Exchanged as a compact versioned record, validated on the device, persisted in NVS and applied live by the display,
+ * audio and sensor code which read them every time they are used.
  */
-pub mod sensors {
-  use esp_idf_hal::{
-    delay::{Delay, BLOCK},
-    i2c::I2cDriver,
-  };
+pub mod settings {
+  use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+  use esp_idf_svc::sys::EspError;
+  use log::warn;
+
+  pub use navelo_protocol::settings::{Lut, Settings, Units, RECORD_LEN};
+
+  const SETTINGS_KEY: &str = "settings";
+
+  /** Saved settings, or the defaults if there are none or they are from an incompatible version */
+  pub fn load(nvs: &EspNvs<NvsDefault>) -> Settings {
+    let mut buf = [0; RECORD_LEN];
+    match nvs.get_raw(SETTINGS_KEY, &mut buf) {
+      Ok(Some(data)) => Settings::decode(data).unwrap_or_else(|e| {
+        warn!("Ignoring saved settings: {e}");
+        Settings::default()
+      }),
+      _ => Settings::default(),
+    }
+  }
 
-  #[derive(Debug, Clone, Copy)]
-  struct Bmp180CalibrationData {
-    ac1: i64,
-    ac2: i64,
-    ac3: i64,
-    ac4: i64,
-    ac5: i64,
-    ac6: i64,
-    b1: i64,
-    b2: i64,
-    mc: i64,
-    md: i64,
+  pub fn save(nvs: &EspNvs<NvsDefault>, settings: &Settings) -> Result<(), EspError> {
+    nvs.set_raw(SETTINGS_KEY, &settings.encode()).map(|_| ())
   }
+}
 
-  #[derive(Debug, Clone, Copy)]
-  pub struct MpuValues {
-    pub acc_x: f32,
-    pub acc_y: f32,
-    pub acc_z: f32,
-    pub temp: f32,
-    pub gyro_x: f32,
-    pub gyro_y: f32,
-    pub gyro_z: f32,
+/**
+ * Ride log: the ride metrics sampled every `SAMPLE_INTERVAL` while a sensor reports anything.
+ *
+ * Only the last `MAX_SAMPLES` are kept, in NVS so that they survive a reset. They are written every
+ * `FLUSH_INTERVAL` rather than on every sample to spare the flash, so a reset loses the samples since the last write.
+ */
+pub mod ride_log {
+  use std::collections::VecDeque;
+  use std::time::{Duration, Instant};
+
+  use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+  use esp_idf_svc::sys::EspError;
+  use log::warn;
+  use navelo_protocol::ride::{self, RideSample, SAMPLE_LEN};
+
+  use crate::clock;
+  use crate::cycling::RideMetrics;
+
+  const LOG_KEY: &str = "ride_log";
+  const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+  const FLUSH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+  /** The last hour, the NVS partition is only 24 KB */
+  const MAX_SAMPLES: usize = 360;
+
+  pub struct RideLog {
+    samples: VecDeque<RideSample>,
+    last_sample: Option<Instant>,
+    last_flush: Instant,
+    unsaved: bool,
   }
 
-  #[derive(Debug, Clone, Copy)]
-  pub struct HmcValues {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
+  impl RideLog {
+    /** Continues the saved log, or starts an empty one if there is none or it is corrupted */
+    pub fn load(nvs: &EspNvs<NvsDefault>) -> Self {
+      let mut buf = vec![0; MAX_SAMPLES * SAMPLE_LEN];
+      let samples = match nvs.get_raw(LOG_KEY, &mut buf) {
+        Ok(Some(data)) => ride::decode_log(data).unwrap_or_else(|e| {
+          warn!("Ignoring the saved ride log: {e}");
+          Vec::new()
+        }),
+        _ => Vec::new(),
+      };
+      Self {
+        samples: samples.into(),
+        last_sample: None,
+        last_flush: Instant::now(),
+        unsaved: false,
+      }
+    }
+
+    /** Called from the main loop, records a sample when it is due and saves the log when that is due */
+    pub fn poll(&mut self, metrics: &RideMetrics, nvs: &EspNvs<NvsDefault>) {
+      let now = Instant::now();
+      if self.last_sample.is_some_and(|last| now - last < SAMPLE_INTERVAL) {
+        return;
+      }
+      self.last_sample = Some(now);
+
+      let sample = sample(metrics);
+      if !sample.is_empty() {
+        if self.samples.len() == MAX_SAMPLES {
+          self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.unsaved = true;
+      }
+
+      if self.unsaved && now - self.last_flush >= FLUSH_INTERVAL {
+        // retried at the next flush on failure
+        self.last_flush = now;
+        match self.save(nvs) {
+          Ok(()) => self.unsaved = false,
+          Err(e) => warn!("Failed to save the ride log: {e}"),
+        }
+      }
+    }
+
+    fn save(&self, nvs: &EspNvs<NvsDefault>) -> Result<(), EspError> {
+      nvs.set_raw(LOG_KEY, &ride::encode_log(&self.samples)).map(|_| ())
+    }
   }
 
-  #[derive(Debug, Clone, Copy)]
-  pub struct BmpValues {
-    pub temperature: f32,
-    pub pressure: f32,
+  fn sample(metrics: &RideMetrics) -> RideSample {
+    RideSample {
+      time: clock::unix_time().and_then(|time| u32::try_from(time).ok()),
+      speed: metrics.speed().map(|speed| (speed * 100.0).round() as u16),
+      cadence: metrics.cadence().map(|cadence| cadence.round() as u8),
+      heart_rate: metrics.heart_rate().map(|bpm| bpm.min(u8::MAX as u16) as u8),
+      power: metrics.power(),
+    }
   }
+}
 
-  pub struct Gy87<'a> {
-    i2c: I2cDriver<'a>,
-    delay: Delay,
-    mpu_addr: u8,
-    hmc_addr: u8,
-    bmp_addr: u8,
-    initialized: bool,
-    hmc_gain: Option<f32>,
-    bmp_calib: Option<Bmp180CalibrationData>,
+/**
+ * Firmware update over BLE.
+ *
+ * The phone writes `Start` with the image size, its SHA-256 and an ECDSA P-256 signature of the hash, then the image
+ * in chunks, then `Finish`. The image is written to the inactive OTA partition, which becomes the boot partition only
+ * if the hash matches. The new firmware has to confirm itself with `confirm_boot`, otherwise the bootloader rolls
+ * back to the previous one on the next reset.
+ */
+pub mod dfu {
+  use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+  use std::sync::{Arc, Mutex};
+  use std::thread::{sleep, spawn};
+  use std::time::Duration;
+
+  use esp_idf_svc::sys::{self, esp, EspError};
+  use log::{info, warn};
+  use navelo_protocol::dfu;
+  pub use navelo_protocol::dfu::{Command, DfuError, DfuState};
+
+  /** PEM public key given by `NAVELO_DFU_PUBLIC_KEY` at build time, empty if none */
+  const PUBLIC_KEY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/dfu_public_key.pem"));
+  /** Give the phone time to receive the final state before rebooting */
+  const REBOOT_DELAY: Duration = Duration::from_secs(2);
+  /** Progress is reported every this many bytes */
+  const PROGRESS_STEP: u32 = 4096;
+  /** Chunks waiting for the flash writes, about 8 KB with the largest MTU */
+  const MAX_QUEUED: usize = 16;
+
+  enum Message {
+    Command(Command),
+    /** Offset and image bytes, see `dfu::decode_chunk` */
+    Chunk(u32, Vec<u8>),
   }
 
-  impl<'a> Gy87<'a> {
-    pub fn new(i2c: I2cDriver<'a>, delay: Delay) -> Self {
-      Self {
-        i2c,
-        delay,
-        mpu_addr: 0x68,
-        hmc_addr: 0x1e,
-        bmp_addr: 0x77,
-        initialized: false,
-        hmc_gain: None,
-        bmp_calib: None,
+  /**
+   * Hands the commands and chunks from the BLE callbacks to a thread, which does the slow flash writes
+   *
+   * The queue is bounded: once it is full, the callback waits for the flash, which holds up the BLE task. The stack
+   * then stops acknowledging packets and the phone has to wait, instead of the queue eating the heap.
+   */
+  #[derive(Clone)]
+  pub struct Dfu {
+    sender: SyncSender<Message>,
+    state: Arc<Mutex<DfuState>>,
+  }
+
+  impl Dfu {
+    pub fn spawn(on_state: impl Fn(DfuState) + Send + 'static) -> Self {
+      let (sender, receiver) = sync_channel(MAX_QUEUED);
+      let state = Arc::new(Mutex::new(DfuState::Idle));
+
+      let worker_state = state.clone();
+      spawn(move || run(receiver, worker_state, on_state));
+
+      Self { sender, state }
+    }
+
+    pub fn command(&self, data: &[u8]) -> Result<(), DfuError> {
+      let command = Command::decode(data)?;
+      let _ = self.sender.send(Message::Command(command));
+      Ok(())
+    }
+
+    pub fn chunk(&self, data: &[u8]) -> Result<(), DfuError> {
+      let (offset, data) = dfu::decode_chunk(data)?;
+      let _ = self.sender.send(Message::Chunk(offset, data.to_vec()));
+      Ok(())
+    }
+
+    pub fn state(&self) -> DfuState {
+      *self.state.lock().unwrap()
+    }
+  }
+
+  fn run(receiver: Receiver<Message>, state: Arc<Mutex<DfuState>>, on_state: impl Fn(DfuState)) {
+    let mut update: Option<Update> = None;
+
+    for message in receiver {
+      let result = match message {
+        Message::Command(Command::Start { size, hash, signature }) => {
+          if let Some(update) = update.take() {
+            update.abort();
+          }
+          verify_signature(&hash, &signature)
+            .and_then(|_| Update::begin(size, hash))
+            .map(|started| {
+              info!("Firmware update started: {size} bytes");
+              update = Some(started);
+              DfuState::Receiving { received: 0, size }
+            })
+        }
+        Message::Command(Command::Abort) => {
+          if let Some(update) = update.take() {
+            info!("Firmware update aborted");
+            update.abort();
+          }
+          Ok(DfuState::Idle)
+        }
+        Message::Command(Command::Finish) => match update.take() {
+          Some(update) => update.finish().map(|_| DfuState::Done),
+          None => Err(DfuError::NotStarted),
+        },
+        Message::Chunk(offset, data) => match &mut update {
+          Some(update) => update.write(offset, &data).map(|_| DfuState::Receiving {
+            received: update.received,
+            size: update.size,
+          }),
+          None => Err(DfuError::NotStarted),
+        },
+      };
+
+      let new_state = result.unwrap_or_else(|e| {
+        warn!("Firmware update failed: {e}");
+        if let Some(update) = update.take() {
+          update.abort();
+        }
+        DfuState::Failed(e)
+      });
+
+      let previous = std::mem::replace(&mut *state.lock().unwrap(), new_state);
+      let report = match (previous, new_state) {
+        (DfuState::Receiving { received: before, .. }, DfuState::Receiving { received, size }) => {
+          received / PROGRESS_STEP != before / PROGRESS_STEP || received == size
+        }
+        _ => true,
+      };
+      if report {
+        on_state(new_state);
+      }
+
+      if new_state == DfuState::Done {
+        info!("Firmware update done, rebooting");
+        sleep(REBOOT_DELAY);
+        unsafe { sys::esp_restart() };
       }
     }
+  }
 
-    pub fn init(&mut self) -> anyhow::Result<()> {
-      if self.initialized {
-        return Ok(());
+  /** Image being written to the inactive OTA partition */
+  struct Update {
+    handle: sys::esp_ota_handle_t,
+    partition: *const sys::esp_partition_t,
+    size: u32,
+    hash: [u8; 32],
+    received: u32,
+    sha: Sha256,
+  }
+
+  impl Update {
+    fn begin(size: u32, hash: [u8; 32]) -> Result<Self, DfuError> {
+      let partition = unsafe { sys::esp_ota_get_next_update_partition(std::ptr::null()) };
+      if partition.is_null() {
+        return Err(DfuError::Flash);
+      }
+      if size > unsafe { (*partition).size } {
+        return Err(DfuError::TooLarge);
       }
 
-      // === MPU6050 === //
-      self.write(self.mpu_addr, &[0x6b, 0b0000_0001])?; // wake up and set clock source
-      self.write(self.mpu_addr, &[0x1a, 0b0000_0101])?; // 10 Hz low pass filter
-      self.write(self.mpu_addr, &[0x37, 0b0000_0010])?; // enable i2c bypass
+      // erase while writing instead of up front, so that the first chunk is not delayed by seconds
+      let mut handle = 0;
+      esp!(unsafe { sys::esp_ota_begin(partition, sys::OTA_WITH_SEQUENTIAL_WRITES as usize, &mut handle) })
+        .map_err(flash_error)?;
+
+      Ok(Self {
+        handle,
+        partition,
+        size,
+        hash,
+        received: 0,
+        sha: Sha256::new(),
+      })
+    }
 
-      // === HMC5883L === //
-      self.write(self.hmc_addr, &[0x00, 0b1111_0000])?; // 8 samples average, 15 Hz
-      self.write(self.hmc_addr, &[0x01, 0b0010_0000])?; // range 1.3 Ga
-      self.write(self.hmc_addr, &[0x02, 0b0000_0000])?; // continuous mode
-      self.hmc_gain = Some(1.0 / 1090.0); // for 1.3 Ga
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), DfuError> {
+      if offset != self.received {
+        return Err(DfuError::UnexpectedOffset);
+      }
+      if self.received as usize + data.len() > self.size as usize {
+        return Err(DfuError::SizeMismatch);
+      }
 
-      // === BMP180 === //
-      self.write(self.bmp_addr, &[0xaa])?;
-      let calib = self.read::<[u8; 22]>(self.bmp_addr, 0xaa)?;
-      self.bmp_calib = Some(Bmp180CalibrationData {
-        ac1: i16::from_be_bytes([calib[0], calib[1]]) as i64,
-        ac2: i16::from_be_bytes([calib[2], calib[3]]) as i64,
-        ac3: i16::from_be_bytes([calib[4], calib[5]]) as i64,
-        ac4: u16::from_be_bytes([calib[6], calib[7]]) as i64,
-        ac5: u16::from_be_bytes([calib[8], calib[9]]) as i64,
-        ac6: u16::from_be_bytes([calib[10], calib[11]]) as i64,
-        b1: i16::from_be_bytes([calib[12], calib[13]]) as i64,
-        b2: i16::from_be_bytes([calib[14], calib[15]]) as i64,
-        mc: i16::from_be_bytes([calib[18], calib[19]]) as i64,
-        md: i16::from_be_bytes([calib[20], calib[21]]) as i64,
-      });
+      esp!(unsafe { sys::esp_ota_write(self.handle, data.as_ptr() as *const _, data.len()) }).map_err(flash_error)?;
+      self.sha.update(data);
+      self.received += data.len() as u32;
+      Ok(())
+    }
 
-      self.initialized = true;
+    /** Check the image and make it the boot partition */
+    fn finish(mut self) -> Result<(), DfuError> {
+      if self.received != self.size {
+        self.abort();
+        return Err(DfuError::SizeMismatch);
+      }
+      if self.sha.finish() != self.hash {
+        self.abort();
+        return Err(DfuError::HashMismatch);
+      }
+
+      // also validates the image header and checksum
+      esp!(unsafe { sys::esp_ota_end(self.handle) }).map_err(flash_error)?;
+      esp!(unsafe { sys::esp_ota_set_boot_partition(self.partition) }).map_err(flash_error)?;
       Ok(())
     }
 
-    // TODO: self test
+    fn abort(self) {
+      unsafe { sys::esp_ota_abort(self.handle) };
+    }
+  }
 
-    pub fn read_mpu(&mut self) -> anyhow::Result<MpuValues> {
-      self.init()?;
+  fn flash_error(e: EspError) -> DfuError {
+    warn!("OTA error: {e}");
+    DfuError::Flash
+  }
 
-      let reg = self.read::<[u8; 14]>(self.mpu_addr, 0x3b)?;
+  fn verify_signature(hash: &[u8; 32], signature: &[u8]) -> Result<(), DfuError> {
+    if PUBLIC_KEY.is_empty() {
+      return Err(DfuError::NoPublicKey);
+    }
 
-      Ok(MpuValues {
-        acc_x: i16::from_be_bytes([reg[0], reg[1]]) as f32 / 16384.0,
-        acc_y: i16::from_be_bytes([reg[2], reg[3]]) as f32 / 16384.0,
-        acc_z: i16::from_be_bytes([reg[4], reg[5]]) as f32 / 16384.0,
-        temp: i16::from_be_bytes([reg[6], reg[7]]) as f32 / 340.0 + 36.53,
-        gyro_x: i16::from_be_bytes([reg[8], reg[9]]) as f32 / 131.0,
-        gyro_y: i16::from_be_bytes([reg[10], reg[11]]) as f32 / 131.0,
-        gyro_z: i16::from_be_bytes([reg[12], reg[13]]) as f32 / 131.0,
-      })
+    // mbedtls expects the terminating NUL to be part of a PEM key
+    let mut key = PUBLIC_KEY.to_vec();
+    key.push(0);
+
+    unsafe {
+      let mut pk = std::mem::zeroed();
+      sys::mbedtls_pk_init(&mut pk);
+      let result = if sys::mbedtls_pk_parse_public_key(&mut pk, key.as_ptr(), key.len()) != 0 {
+        Err(DfuError::NoPublicKey)
+      } else if sys::mbedtls_pk_verify(
+        &mut pk,
+        sys::mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+        hash.as_ptr(),
+        hash.len(),
+        signature.as_ptr(),
+        signature.len(),
+      ) != 0
+      {
+        Err(DfuError::InvalidSignature)
+      } else {
+        Ok(())
+      };
+      sys::mbedtls_pk_free(&mut pk);
+      result
     }
-    pub fn read_hmc(&mut self) -> anyhow::Result<HmcValues> {
-      self.init()?;
+  }
 
-      let reg = self.read::<[u8; 6]>(self.hmc_addr, 0x03)?;
+  struct Sha256(sys::mbedtls_sha256_context);
 
-      Ok(HmcValues {
-        x: i16::from_be_bytes([reg[0], reg[1]]) as f32 * self.hmc_gain.unwrap(),
-        y: i16::from_be_bytes([reg[2], reg[3]]) as f32 * self.hmc_gain.unwrap(),
-        z: i16::from_be_bytes([reg[4], reg[5]]) as f32 * self.hmc_gain.unwrap(),
-      })
+  impl Sha256 {
+    fn new() -> Self {
+      let mut ctx = unsafe { std::mem::zeroed() };
+      unsafe {
+        sys::mbedtls_sha256_init(&mut ctx);
+        sys::mbedtls_sha256_starts(&mut ctx, 0);
+      }
+      Self(ctx)
     }
-    pub fn read_bmp(&mut self) -> anyhow::Result<BmpValues> {
-      let oss = 3; // 8 samples, 26 ms
-      let sleep = [5, 8, 14, 26][oss as usize];
 
-      self.init()?;
-
-      self.write(self.bmp_addr, &[0xf4, 0x2e])?;
-      self.delay.delay_ms(5); // at least 4.5 ms
-      let ut = self.read::<[u8; 2]>(self.bmp_addr, 0xf6)?;
-      let ut = u16::from_be_bytes([ut[0], ut[1]]) as i64;
+    fn update(&mut self, data: &[u8]) {
+      unsafe { sys::mbedtls_sha256_update(&mut self.0, data.as_ptr(), data.len()) };
+    }
 
-      self.write(self.bmp_addr, &[0xf4, 0x34 + (oss << 6)])?;
-      self.delay.delay_ms(sleep);
-      let up = self.read::<[u8; 3]>(self.bmp_addr, 0xf6)?;
-      let up = u32::from_be_bytes([0, up[0], up[1], up[2]]) as i64;
-      let up = up >> (8 - oss);
+    fn finish(&mut self) -> [u8; 32] {
+      let mut hash = [0; 32];
+      unsafe { sys::mbedtls_sha256_finish(&mut self.0, hash.as_mut_ptr()) };
+      hash
+    }
+  }
 
-      let Bmp180CalibrationData {
-        ac1,
-        ac2,
-        ac3,
-        ac4,
-        ac5,
-        ac6,
-        b1,
-        b2,
-        mc,
-        md,
-      } = self.bmp_calib.unwrap();
+  impl Drop for Sha256 {
+    fn drop(&mut self) {
+      unsafe { sys::mbedtls_sha256_free(&mut self.0) };
+    }
+  }
 
-      let x1 = ((ut - ac6) * ac5) >> 15;
-      let x2 = (mc << 11) / (x1 + md);
-      let b5 = x1 + x2;
-      let temperature = (b5 + 8) as f32 / 160.0;
+  /** Whether this is the first boot of an updated firmware, which has to be confirmed */
+  pub fn is_pending_verify() -> bool {
+    let mut state = 0;
+    let result = unsafe { sys::esp_ota_get_state_partition(sys::esp_ota_get_running_partition(), &mut state) };
+    result == sys::ESP_OK && state == sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+  }
 
-      let b6 = b5 - 4000;
-      let x1 = (b2 * ((b6 * b6) >> 12)) >> 11;
-      let x2 = (ac2 * b6) >> 11;
-      let x3 = x1 + x2;
-      let b3 = (((ac1 * 4 + x3) << oss) + 2) / 4;
-      let x1 = (ac3 * b6) >> 13;
-      let x2 = (b1 * ((b6 * b6) >> 12)) >> 16;
-      let x3 = ((x1 + x2) + 2) >> 2;
-      let b4 = (ac4 * (x3 + 32768)) >> 15;
-      let b7 = (up - b3) * (50000 >> oss);
-      let p = b7 * 2 / b4;
-      let x1 = (p >> 8) * (p >> 8);
-      let x1 = (x1 * 3038) >> 16;
-      let x2 = (-7357 * p) >> 16;
-      let pressure = p + ((x1 + x2 + 3791) >> 4);
-      let pressure = pressure as f32;
+  /**
+  Keep the updated firmware if it is healthy, otherwise roll back to the previous one and reboot.
+  Does nothing if the running firmware was already confirmed.
+  */
+  pub fn confirm_boot(healthy: bool) -> Result<(), EspError> {
+    if !is_pending_verify() {
+      return Ok(());
+    }
 
-      Ok(BmpValues { temperature, pressure })
+    if healthy {
+      info!("Updated firmware confirmed");
+      esp!(unsafe { sys::esp_ota_mark_app_valid_cancel_rollback() })
+    } else {
+      warn!("Updated firmware failed the health check, rolling back");
+      esp!(unsafe { sys::esp_ota_mark_app_invalid_rollback_and_reboot() })
     }
+  }
 
-    fn read<T: Default + AsMut<[u8]>>(&mut self, addr: u8, reg: u8) -> anyhow::Result<T> {
-      let mut data = T::default();
-      self.i2c.write(addr, &[reg], BLOCK)?;
-      self.i2c.read(addr, data.as_mut(), BLOCK)?;
-      Ok(data)
+  /**
+  Roll back an updated firmware which failed to start, before the error halts the device.
+  Does nothing if the running firmware was already confirmed, the error is then returned as usual.
+  */
+  pub fn reject_boot(error: &anyhow::Error) {
+    if !is_pending_verify() {
+      return;
     }
-    fn write(&mut self, addr: u8, data: &[u8]) -> anyhow::Result<()> {
-      self.i2c.write(addr, data, BLOCK)?;
-      Ok(())
+
+    warn!("Updated firmware failed to start: {error:?}");
+    if let Err(e) = confirm_boot(false) {
+      warn!("Failed to roll back: {e:?}");
     }
   }
 }
@@ -1617,11 +6562,22 @@ pub mod utils {
     });
   }
 
-  pub fn scan_i2c(i2c: &mut I2cDriver) {
+  pub fn scan_i2c(i2c: &mut I2cDriver) -> Vec<u8> {
+    let mut found = Vec::new();
     for addr in 0x00..=0x7f {
       if i2c.write(addr, &[], BLOCK).is_ok() {
         info!("found device at 0x{:02x}", addr);
+        found.push(addr);
       }
     }
+    found
+  }
+
+  pub fn ble_address() -> [u8; 6] {
+    let mut mac = [0; 6];
+    unsafe {
+      sys::esp_read_mac(mac.as_mut_ptr(), sys::esp_mac_type_t_ESP_MAC_BT);
+    }
+    mac
   }
 }
@@ -0,0 +1,142 @@
+//! Rasterizes a TTF/OTF font into the 1bpp bitmap font format used by the firmware.
+//!
+//! Usage: `cargo run --release -- <font.ttf|otf> <pixel size> <output>`
+//! e.g. `cargo run --release -- data/NotoSansJP-Regular.otf 16 ../esp32/src/font16.bin`
+//!
+//! Format (all integers little endian):
+//!
+//! ```text
+//! header : b"NVF1", height: u8, baseline: u8, count: u16
+//! entries: count x (codepoint: u24, width: u8, offset: u32), sorted by codepoint
+//! bitmaps: width x height bits per glyph, row-major, MSB first, padded to a byte per glyph
+//! ```
+//!
+//! `offset` is relative to the start of the bitmap area.
+
+use ab_glyph::{point, Font, FontVec, PxScale, ScaleFont};
+use anyhow::{bail, Context};
+use std::collections::BTreeSet;
+use std::fs::{read, read_to_string, File};
+use std::io::Write;
+
+const MAGIC: &[u8; 4] = b"NVF1";
+
+/// Always included, in addition to the characters listed in `charset.txt`.
+const RANGES: &[(u32, u32)] = &[
+  (0x0020, 0x007e), // ASCII
+  (0x00a0, 0x00ff), // Latin-1 Supplement
+  (0x2010, 0x2027), // General Punctuation (dashes, quotes, ellipsis)
+  (0x2190, 0x2193), // Arrows
+  (0x3000, 0x303f), // CJK Symbols and Punctuation
+  (0x3040, 0x309f), // Hiragana
+  (0x30a0, 0x30ff), // Katakana
+  (0xff01, 0xff5e), // Fullwidth Forms
+];
+
+/// A kana and kanji each, street names are mostly Japanese so a font without them is useless
+const REQUIRED: &[char] = &['あ', 'ア', '駅'];
+
+fn main() -> anyhow::Result<()> {
+  let args: Vec<String> = std::env::args().collect();
+  let [_, font_path, size, output] = args.as_slice() else {
+    bail!("usage: fontgen <font.ttf|otf> <pixel size> <output>");
+  };
+  let size: f32 = size.parse().context("invalid pixel size")?;
+
+  let font = FontVec::try_from_vec(read(font_path)?)?;
+  let font = font.as_scaled(PxScale::from(size));
+
+  let charset = read_charset("charset.txt")?;
+
+  let missing_required: String = REQUIRED.iter().filter(|&&c| font.glyph_id(c).0 == 0).collect();
+  if !missing_required.is_empty() {
+    bail!("{font_path} has no glyph for {missing_required}, use a CJK font like Noto Sans JP");
+  }
+
+  let ascent = font.ascent().ceil();
+  let height = (ascent - font.descent().floor()) as u32;
+  let baseline = ascent as u32;
+  if height > u8::MAX as u32 {
+    bail!("font is too large: {} px", height);
+  }
+
+  let mut entries = Vec::new();
+  let mut bitmaps = Vec::new();
+  let mut missing = 0;
+
+  for c in charset {
+    let glyph_id = font.glyph_id(c);
+    if glyph_id.0 == 0 {
+      missing += 1;
+      continue;
+    }
+
+    let width = font.h_advance(glyph_id).round().clamp(1.0, u8::MAX as f32) as u32;
+    let mut bits = vec![false; (width * height) as usize];
+
+    let glyph = glyph_id.with_scale_and_position(font.scale(), point(0.0, ascent));
+    if let Some(outlined) = font.outline_glyph(glyph) {
+      let bounds = outlined.px_bounds();
+      outlined.draw(|x, y, coverage| {
+        let x = x as i32 + bounds.min.x as i32;
+        let y = y as i32 + bounds.min.y as i32;
+        if coverage >= 0.5 && (0..width as i32).contains(&x) && (0..height as i32).contains(&y) {
+          bits[(y as u32 * width + x as u32) as usize] = true;
+        }
+      });
+    }
+
+    entries.push((c as u32, width as u8, bitmaps.len() as u32));
+    bitmaps.extend(pack_bits(&bits));
+  }
+
+  if entries.len() > u16::MAX as usize {
+    bail!("too many glyphs: {}", entries.len());
+  }
+
+  let mut data = Vec::new();
+  data.extend_from_slice(MAGIC);
+  data.push(height as u8);
+  data.push(baseline as u8);
+  data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+  for (codepoint, width, offset) in &entries {
+    data.extend_from_slice(&codepoint.to_le_bytes()[..3]);
+    data.push(*width);
+    data.extend_from_slice(&offset.to_le_bytes());
+  }
+  data.extend_from_slice(&bitmaps);
+
+  let mut outfile = File::create(output)?;
+  outfile.write_all(&data)?;
+
+  println!(
+    "{} glyphs ({} missing), {}px line height, {} bytes",
+    entries.len(),
+    missing,
+    height,
+    data.len()
+  );
+  Ok(())
+}
+
+fn read_charset(path: &str) -> anyhow::Result<BTreeSet<char>> {
+  let mut charset = BTreeSet::new();
+  for &(start, end) in RANGES {
+    charset.extend((start..=end).filter_map(char::from_u32));
+  }
+  let text = read_to_string(path).with_context(|| format!("failed to read {}", path))?;
+  charset.extend(text.chars().filter(|c| !c.is_whitespace()));
+  Ok(charset)
+}
+
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+  bits
+    .chunks(8)
+    .map(|chunk| {
+      chunk
+        .iter()
+        .enumerate()
+        .fold(0u8, |byte, (i, &bit)| byte | (u8::from(bit) << (7 - i)))
+    })
+    .collect()
+}
@@ -0,0 +1,769 @@
+/*!
+ * An in-memory stack and a scripted client, to run the server without a radio
+ *
+ * `FakeStack` keeps an attribute table and records everything the server sends. Its events are queued and only
+ * delivered when the `Harness` pumps them, so a test sees the state after the server handled a request and
+ * everything it triggered.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use enumset::EnumSet;
+
+use crate::link::*;
+use crate::server::*;
+use crate::stack::*;
+
+const GATT_IF: GattInterface = 3;
+const CCCD_UUID: BtUuid = BtUuid::uuid16(0x2902);
+
+type GapHandler = Box<dyn Fn(GapEvent) + Send + Sync>;
+type GattsHandler = Box<dyn Fn(GattInterface, GattsEvent) + Send + Sync>;
+
+/// Never returned by the fake stack, calls always succeed
+#[derive(Debug)]
+pub enum Never {}
+
+/// An attribute added by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeAttribute {
+  pub service_handle: Handle,
+  pub handle: Handle,
+  pub uuid: BtUuid,
+  /// Empty for descriptors
+  pub properties: EnumSet<Property>,
+  pub permissions: EnumSet<Permission>,
+}
+
+/// A notification or an indication sent by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sent {
+  pub conn_id: ConnectionId,
+  pub handle: Handle,
+  pub data: Vec<u8>,
+  pub indication: bool,
+}
+
+/// A response sent by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentResponse {
+  pub conn_id: ConnectionId,
+  pub trans_id: TransferId,
+  pub status: GattStatus,
+  pub value: Option<Vec<u8>>,
+}
+
+enum Event {
+  Gap(GapEvent),
+  Gatts(GattsEvent),
+}
+
+#[derive(Default)]
+struct Inner {
+  events: VecDeque<Event>,
+  next_handle: Handle,
+  services: Vec<FakeService>,
+  attributes: Vec<FakeAttribute>,
+  advertising_data: Option<(String, Option<BtUuid>)>,
+  advertising: Option<AdvertisingParams>,
+  scanning: bool,
+  bonds: Vec<Bond>,
+  conn_params: HashMap<BdAddr, ConnParams>,
+  phys: HashMap<BdAddr, Phy>,
+  data_lengths: HashMap<BdAddr, u16>,
+  rssi: HashMap<BdAddr, i8>,
+  responses: Vec<SentResponse>,
+  sent: Vec<Sent>,
+}
+
+struct FakeService {
+  handle: Handle,
+  uuid: BtUuid,
+  started: bool,
+  /// Last handle used within the range reserved for the service
+  last: Handle,
+  end: Handle,
+}
+
+impl Inner {
+  /// Next handle within the range reserved for the service
+  fn allocate(&mut self, service_handle: Handle) -> Handle {
+    let service = self.services.iter_mut().find(|s| s.handle == service_handle);
+    let service = service.expect("Unknown service");
+    service.last += 1;
+    assert!(
+      service.last <= service.end,
+      "Out of handles in service {service_handle}"
+    );
+    service.last
+  }
+}
+
+#[derive(Default)]
+pub struct FakeStack {
+  inner: Mutex<Inner>,
+  handlers: OnceLock<(GapHandler, GattsHandler)>,
+}
+
+impl FakeStack {
+  /// A stack with these peers already bonded, the server advertises directed to the first one if configured to
+  pub fn with_bonds(bonds: &[Bond]) -> Self {
+    let stack = Self::default();
+    stack.inner.lock().unwrap().bonds = bonds.to_vec();
+    stack
+  }
+
+  /// Deliver the queued events, including the ones queued while handling them
+  pub fn pump(&self) {
+    let Some((gap, gatts)) = self.handlers.get() else {
+      return;
+    };
+
+    loop {
+      // released before the handler runs, it calls back into the stack
+      let event = self.inner.lock().unwrap().events.pop_front();
+      match event {
+        Some(Event::Gap(event)) => gap(event),
+        Some(Event::Gatts(event)) => gatts(GATT_IF, event),
+        None => break,
+      }
+    }
+  }
+
+  /// Queue an event as if it came from the radio, delivered by the next `pump`
+  pub fn push_gap(&self, event: GapEvent) {
+    self.push(Event::Gap(event));
+  }
+  pub fn push_gatts(&self, event: GattsEvent) {
+    if let GattsEvent::PeerConnected {
+      link_role: LinkRole::Peripheral,
+      ..
+    } = event
+    {
+      // advertising stops once a central connects
+      self.inner.lock().unwrap().advertising = None;
+    }
+    self.push(Event::Gatts(event));
+  }
+
+  pub fn attributes(&self) -> Vec<FakeAttribute> {
+    self.inner.lock().unwrap().attributes.clone()
+  }
+  /// Services in the order they were created, and whether they were started
+  pub fn services(&self) -> Vec<(BtUuid, bool)> {
+    let inner = self.inner.lock().unwrap();
+
+    inner.services.iter().map(|s| (s.uuid.clone(), s.started)).collect()
+  }
+  /// Device name and advertised service UUID
+  pub fn advertising_data(&self) -> Option<(String, Option<BtUuid>)> {
+    self.inner.lock().unwrap().advertising_data.clone()
+  }
+  /// Parameters of the running advertising
+  pub fn advertising(&self) -> Option<AdvertisingParams> {
+    self.inner.lock().unwrap().advertising
+  }
+  pub fn is_scanning(&self) -> bool {
+    self.inner.lock().unwrap().scanning
+  }
+  /// Connection parameters last requested for a peer
+  pub fn conn_params(&self, addr: BdAddr) -> Option<ConnParams> {
+    self.inner.lock().unwrap().conn_params.get(&addr).copied()
+  }
+  /// PHY last preferred for a peer
+  pub fn phy(&self, addr: BdAddr) -> Option<Phy> {
+    self.inner.lock().unwrap().phys.get(&addr).copied()
+  }
+  /// Link layer payload last requested for a peer
+  pub fn data_length(&self, addr: BdAddr) -> Option<u16> {
+    self.inner.lock().unwrap().data_lengths.get(&addr).copied()
+  }
+  /// RSSI reported when the server reads it, it fails for the other peers
+  pub fn set_rssi(&self, addr: BdAddr, rssi: i8) {
+    self.inner.lock().unwrap().rssi.insert(addr, rssi);
+  }
+  /// Take the responses sent since the last call
+  pub fn take_responses(&self) -> Vec<SentResponse> {
+    std::mem::take(&mut self.inner.lock().unwrap().responses)
+  }
+  /// Take the notifications and indications sent since the last call
+  pub fn take_sent(&self) -> Vec<Sent> {
+    std::mem::take(&mut self.inner.lock().unwrap().sent)
+  }
+
+  fn push(&self, event: Event) {
+    self.inner.lock().unwrap().events.push_back(event);
+  }
+}
+
+impl Stack for FakeStack {
+  type Error = Never;
+
+  fn subscribe(
+    &self,
+    gap: impl Fn(GapEvent) + Send + Sync + 'static,
+    gatts: impl Fn(GattInterface, GattsEvent) + Send + Sync + 'static,
+  ) -> Result<(), Never> {
+    if self.handlers.set((Box::new(gap), Box::new(gatts))).is_err() {
+      panic!("Subscribed twice");
+    }
+    Ok(())
+  }
+
+  fn configure_advertising(&self, name: &str, service: Option<&BtUuid>) -> Result<(), Never> {
+    self.inner.lock().unwrap().advertising_data = Some((name.to_string(), service.cloned()));
+    self.push_gap(GapEvent::AdvertisingConfigured { success: true });
+    Ok(())
+  }
+
+  fn start_advertising(&self, params: &AdvertisingParams) -> Result<(), Never> {
+    self.inner.lock().unwrap().advertising = Some(*params);
+    self.push_gap(GapEvent::AdvertisingStarted { success: true });
+    Ok(())
+  }
+
+  fn stop_advertising(&self) -> Result<(), Never> {
+    self.inner.lock().unwrap().advertising = None;
+    self.push_gap(GapEvent::AdvertisingStopped { success: true });
+    Ok(())
+  }
+
+  fn bonds(&self) -> Vec<Bond> {
+    self.inner.lock().unwrap().bonds.clone()
+  }
+
+  fn security_response(&self, _addr: BdAddr, _accept: bool) -> Result<(), Never> {
+    Ok(())
+  }
+
+  fn set_conn_params(&self, addr: BdAddr, params: &ConnParams) -> Result<(), Never> {
+    self.inner.lock().unwrap().conn_params.insert(addr, *params);
+    Ok(())
+  }
+
+  fn set_phy(&self, addr: BdAddr, phy: Phy) -> Result<(), Never> {
+    self.inner.lock().unwrap().phys.insert(addr, phy);
+    Ok(())
+  }
+
+  fn set_data_length(&self, addr: BdAddr, tx_octets: u16) -> Result<(), Never> {
+    self.inner.lock().unwrap().data_lengths.insert(addr, tx_octets);
+    Ok(())
+  }
+
+  fn read_rssi(&self, addr: BdAddr) -> Result<(), Never> {
+    let rssi = self.inner.lock().unwrap().rssi.get(&addr).copied();
+    self.push_gap(GapEvent::Rssi { addr, rssi });
+    Ok(())
+  }
+
+  fn start_scanning(&self, _duration: Duration) -> Result<(), Never> {
+    self.inner.lock().unwrap().scanning = true;
+    Ok(())
+  }
+
+  fn stop_scanning(&self) -> Result<(), Never> {
+    self.inner.lock().unwrap().scanning = false;
+    Ok(())
+  }
+
+  fn register_app(&self, app_id: u16) -> Result<(), Never> {
+    self.push_gatts(GattsEvent::ServiceRegistered {
+      status: GattStatus::Ok,
+      app_id,
+    });
+    Ok(())
+  }
+
+  fn create_service(&self, _gatt_if: GattInterface, uuid: &BtUuid, num_handles: u16) -> Result<(), Never> {
+    let service_handle = {
+      let mut inner = self.inner.lock().unwrap();
+      let service_handle = inner.next_handle + 1;
+      // the handles of the characteristics and descriptors are reserved right away, like in a real stack
+      let end = inner.next_handle + num_handles;
+      inner.next_handle = end;
+      inner.services.push(FakeService {
+        handle: service_handle,
+        uuid: uuid.clone(),
+        started: false,
+        last: service_handle,
+        end,
+      });
+      service_handle
+    };
+
+    self.push_gatts(GattsEvent::ServiceCreated {
+      status: GattStatus::Ok,
+      service_handle,
+      service_uuid: uuid.clone(),
+    });
+    Ok(())
+  }
+
+  fn start_service(&self, service_handle: Handle) -> Result<(), Never> {
+    let mut inner = self.inner.lock().unwrap();
+    if let Some(service) = inner.services.iter_mut().find(|s| s.handle == service_handle) {
+      service.started = true;
+    }
+    Ok(())
+  }
+
+  fn add_characteristic(
+    &self,
+    service_handle: Handle,
+    uuid: &BtUuid,
+    properties: EnumSet<Property>,
+    permissions: EnumSet<Permission>,
+    _max_len: usize,
+  ) -> Result<(), Never> {
+    let attr_handle = self.add_attribute(service_handle, uuid, properties, permissions, true);
+
+    self.push_gatts(GattsEvent::CharacteristicAdded {
+      status: GattStatus::Ok,
+      attr_handle,
+      service_handle,
+      char_uuid: uuid.clone(),
+    });
+    Ok(())
+  }
+
+  fn add_descriptor(
+    &self,
+    service_handle: Handle,
+    uuid: &BtUuid,
+    permissions: EnumSet<Permission>,
+  ) -> Result<(), Never> {
+    let attr_handle = self.add_attribute(service_handle, uuid, EnumSet::empty(), permissions, false);
+
+    self.push_gatts(GattsEvent::DescriptorAdded {
+      status: GattStatus::Ok,
+      attr_handle,
+      service_handle,
+      descr_uuid: uuid.clone(),
+    });
+    Ok(())
+  }
+
+  fn send_response(
+    &self,
+    _gatt_if: GattInterface,
+    conn_id: ConnectionId,
+    trans_id: TransferId,
+    status: GattStatus,
+    response: Option<&Response>,
+  ) -> Result<(), Never> {
+    self.inner.lock().unwrap().responses.push(SentResponse {
+      conn_id,
+      trans_id,
+      status,
+      value: response.map(|response| response.value.to_vec()),
+    });
+    Ok(())
+  }
+
+  fn notify(&self, _gatt_if: GattInterface, conn_id: ConnectionId, handle: Handle, data: &[u8]) -> Result<(), Never> {
+    self.inner.lock().unwrap().sent.push(Sent {
+      conn_id,
+      handle,
+      data: data.to_vec(),
+      indication: false,
+    });
+    // Bluedroid reports every notification as sent
+    self.push_gatts(GattsEvent::Confirm {
+      status: GattStatus::Ok,
+      conn_id,
+      handle,
+    });
+    Ok(())
+  }
+
+  fn indicate(&self, _gatt_if: GattInterface, conn_id: ConnectionId, handle: Handle, data: &[u8]) -> Result<(), Never> {
+    // confirmed by the client, see `Harness::confirm`
+    self.inner.lock().unwrap().sent.push(Sent {
+      conn_id,
+      handle,
+      data: data.to_vec(),
+      indication: true,
+    });
+    Ok(())
+  }
+}
+
+impl FakeStack {
+  fn add_attribute(
+    &self,
+    service_handle: Handle,
+    uuid: &BtUuid,
+    properties: EnumSet<Property>,
+    permissions: EnumSet<Permission>,
+    declaration: bool,
+  ) -> Handle {
+    let mut inner = self.inner.lock().unwrap();
+
+    if declaration {
+      inner.allocate(service_handle);
+    }
+    let handle = inner.allocate(service_handle);
+    inner.attributes.push(FakeAttribute {
+      service_handle,
+      handle,
+      uuid: uuid.clone(),
+      properties,
+      permissions,
+    });
+
+    handle
+  }
+}
+
+/// A scripted client connected through the fake stack
+///
+/// Every request pumps the events, so the server has handled it (and everything it triggered) once a call returns.
+pub struct Harness {
+  server: GattServer<FakeStack>,
+  next_conn_id: ConnectionId,
+  next_trans_id: TransferId,
+  /// Negotiated ATT_MTU of each connection
+  mtus: HashMap<ConnectionId, u16>,
+  /// Peer address of each connection
+  addrs: HashMap<ConnectionId, BdAddr>,
+}
+
+impl Harness {
+  pub fn start(builder: GattServerBuilder<FakeStack>) -> Self {
+    Self::start_with(builder, FakeStack::default())
+  }
+
+  pub fn start_with(builder: GattServerBuilder<FakeStack>, stack: FakeStack) -> Self {
+    let Ok(server) = builder.start(stack);
+    server.stack().pump();
+
+    Self {
+      server,
+      next_conn_id: 0,
+      next_trans_id: 0,
+      mtus: HashMap::new(),
+      addrs: HashMap::new(),
+    }
+  }
+
+  pub fn server(&self) -> &GattServer<FakeStack> {
+    &self.server
+  }
+  pub fn stack(&self) -> &FakeStack {
+    self.server.stack()
+  }
+
+  /// Value handle of a characteristic
+  pub fn handle(&self, uuid: &BtUuid) -> Handle {
+    let attributes = self.stack().attributes();
+    let attribute = attributes.iter().find(|a| !a.properties.is_empty() && a.uuid == *uuid);
+
+    attribute.unwrap_or_else(|| panic!("No characteristic {uuid:?}")).handle
+  }
+
+  /// Handle of the CCCD of a characteristic
+  pub fn cccd(&self, uuid: &BtUuid) -> Handle {
+    let value = self.handle(uuid);
+    let attributes = self.stack().attributes();
+    let descriptors = attributes.iter().skip_while(|a| a.handle != value).skip(1);
+    let mut descriptors = descriptors.take_while(|a| a.properties.is_empty());
+
+    let cccd = descriptors.find(|a| a.uuid == CCCD_UUID);
+    cccd.unwrap_or_else(|| panic!("No CCCD for {uuid:?}")).handle
+  }
+
+  /// Connect a phone, connection ids are assigned in order starting from 0
+  pub fn connect(&mut self, addr: BdAddr) -> ConnectionId {
+    self.connect_as(addr, LinkRole::Peripheral)
+  }
+
+  /// Connect a link on which this device is the central, like the one to a sensor
+  pub fn connect_sensor(&mut self, addr: BdAddr) -> ConnectionId {
+    self.connect_as(addr, LinkRole::Central)
+  }
+
+  pub fn exchange_mtu(&mut self, conn_id: ConnectionId, mtu: u16) {
+    self.mtus.insert(conn_id, mtu);
+    self.pump(GattsEvent::Mtu { conn_id, mtu });
+  }
+
+  /// Write the CCCD of a characteristic
+  pub fn subscribe(&mut self, conn_id: ConnectionId, uuid: &BtUuid, subscription: Subscription) -> GattStatus {
+    let cccd = self.cccd(uuid);
+    self.write_handle(conn_id, cccd, &subscription.to_cccd().to_le_bytes())
+  }
+
+  /// Write Request, returns the status of the response
+  pub fn write(&mut self, conn_id: ConnectionId, uuid: &BtUuid, value: &[u8]) -> GattStatus {
+    let handle = self.handle(uuid);
+    self.write_handle(conn_id, handle, value)
+  }
+
+  pub fn write_handle(&mut self, conn_id: ConnectionId, handle: Handle, value: &[u8]) -> GattStatus {
+    let trans_id = self.request(GattsEvent::Write {
+      conn_id,
+      trans_id: self.next_trans_id,
+      handle,
+      offset: 0,
+      need_rsp: true,
+      is_prep: false,
+      value: value.to_vec(),
+    });
+    self.response(trans_id).status
+  }
+
+  /// Write Command, which is not answered
+  pub fn write_command(&mut self, conn_id: ConnectionId, uuid: &BtUuid, value: &[u8]) {
+    let handle = self.handle(uuid);
+    self.request(GattsEvent::Write {
+      conn_id,
+      trans_id: self.next_trans_id,
+      handle,
+      offset: 0,
+      need_rsp: false,
+      is_prep: false,
+      value: value.to_vec(),
+    });
+  }
+
+  /// Prepare Write Requests of the largest parts the MTU allows, then an Execute Write Request
+  /// Returns the status of the first failed request, the prepared parts must be echoed back.
+  pub fn long_write(&mut self, conn_id: ConnectionId, uuid: &BtUuid, value: &[u8]) -> GattStatus {
+    let handle = self.handle(uuid);
+    // ATT opcode, handle and offset
+    let len = self.mtu(conn_id) as usize - 5;
+
+    for (i, part) in value.chunks(len).enumerate() {
+      let offset = (i * len) as u16;
+      let trans_id = self.request(GattsEvent::Write {
+        conn_id,
+        trans_id: self.next_trans_id,
+        handle,
+        offset,
+        need_rsp: true,
+        is_prep: true,
+        value: part.to_vec(),
+      });
+      let response = self.response(trans_id);
+      if response.status != GattStatus::Ok {
+        return response.status;
+      }
+      assert_eq!(
+        response.value.as_deref(),
+        Some(part),
+        "Prepared write at {offset} not echoed"
+      );
+    }
+
+    let trans_id = self.request(GattsEvent::ExecWrite {
+      conn_id,
+      trans_id: self.next_trans_id,
+      canceled: false,
+    });
+    self.response(trans_id).status
+  }
+
+  /// Read Request, or Read Blob Request with an offset
+  pub fn read(&mut self, conn_id: ConnectionId, uuid: &BtUuid) -> Result<Vec<u8>, GattStatus> {
+    let handle = self.handle(uuid);
+    self.read_handle(conn_id, handle, 0)
+  }
+
+  pub fn read_handle(&mut self, conn_id: ConnectionId, handle: Handle, offset: u16) -> Result<Vec<u8>, GattStatus> {
+    let trans_id = self.request(GattsEvent::Read {
+      conn_id,
+      trans_id: self.next_trans_id,
+      handle,
+      offset,
+      need_rsp: true,
+    });
+
+    match self.response(trans_id) {
+      SentResponse {
+        status: GattStatus::Ok,
+        value: Some(value),
+        ..
+      } => Ok(value),
+      SentResponse { status, .. } => Err(status),
+    }
+  }
+
+  /// The peer closes the link
+  pub fn disconnect(&mut self, conn_id: ConnectionId) {
+    self.disconnect_for(conn_id, DisconnectReason::RemoteUser);
+  }
+
+  pub fn disconnect_for(&mut self, conn_id: ConnectionId, reason: DisconnectReason) {
+    let addr = self.addrs.remove(&conn_id).expect("Not connected");
+    self.mtus.remove(&conn_id);
+    self.pump(GattsEvent::PeerDisconnected { conn_id, addr, reason });
+  }
+
+  /// Poll the RSSI of the links, as set with `FakeStack::set_rssi`
+  pub fn read_rssi(&mut self) {
+    let Ok(()) = self.server.read_rssi();
+    self.stack().pump();
+  }
+
+  /// Report the link buffers full or drained
+  pub fn congest(&mut self, conn_id: ConnectionId, congested: bool) {
+    self.pump(GattsEvent::Congest { conn_id, congested });
+  }
+
+  /// Confirm the last indication sent on a connection
+  pub fn confirm(&mut self, conn_id: ConnectionId, handle: Handle) {
+    self.pump(GattsEvent::Confirm {
+      status: GattStatus::Ok,
+      conn_id,
+      handle,
+    });
+  }
+
+  /// Take the notifications and indications sent since the last call
+  pub fn take_sent(&self) -> Vec<Sent> {
+    self.stack().take_sent()
+  }
+
+  /// Replay a script, panics with the index of the first step that does not hold
+  pub fn replay(&mut self, steps: &[Step]) {
+    for (i, step) in steps.iter().enumerate() {
+      let step_panic = |message: String| -> ! { panic!("Step {i} ({step:?}): {message}") };
+
+      match step {
+        Step::Connect(addr) => {
+          self.connect(*addr);
+        }
+        Step::ExchangeMtu(conn_id, mtu) => self.exchange_mtu(*conn_id, *mtu),
+        Step::Subscribe(conn_id, uuid, subscription) => {
+          let status = self.subscribe(*conn_id, uuid, *subscription);
+          if status != GattStatus::Ok {
+            step_panic(format!("got {status:?}"));
+          }
+        }
+        Step::Write(conn_id, uuid, value, expected) => {
+          let status = self.write(*conn_id, uuid, value);
+          if status != *expected {
+            step_panic(format!("got {status:?}"));
+          }
+        }
+        Step::LongWrite(conn_id, uuid, value, expected) => {
+          let status = self.long_write(*conn_id, uuid, value);
+          if status != *expected {
+            step_panic(format!("got {status:?}"));
+          }
+        }
+        Step::Read(conn_id, uuid, expected) => {
+          let value = self.read(*conn_id, uuid);
+          if value != *expected {
+            step_panic(format!("got {value:?}"));
+          }
+        }
+        Step::Disconnect(conn_id) => self.disconnect(*conn_id),
+        Step::Congest(conn_id, congested) => self.congest(*conn_id, *congested),
+        Step::Confirm(conn_id, uuid) => {
+          let handle = self.handle(uuid);
+          self.confirm(*conn_id, handle);
+        }
+        Step::Run(run) => {
+          run(&self.server);
+          self.stack().pump();
+        }
+        Step::ExpectSent(expected) => {
+          let sent = self.take_sent();
+          let sent = sent.iter().map(|sent| {
+            let characteristic = self.stack().attributes().into_iter().find(|a| a.handle == sent.handle);
+            let uuid = characteristic.map_or(BtUuid::uuid16(0), |a| a.uuid);
+            (sent.conn_id, uuid, sent.data.clone(), sent.indication)
+          });
+          let sent = sent.collect::<Vec<_>>();
+          let expected = expected
+            .iter()
+            .map(|(conn_id, uuid, data, indication)| (*conn_id, uuid.clone(), data.clone(), *indication))
+            .collect::<Vec<_>>();
+          if sent != expected {
+            step_panic(format!("sent {sent:?}"));
+          }
+        }
+        Step::ExpectAdvertising(expected) => {
+          let advertising = self.stack().advertising().is_some();
+          if advertising != *expected {
+            step_panic(format!("advertising is {advertising}"));
+          }
+        }
+        Step::ExpectConnections(expected) => {
+          let count = self.server.connection_count();
+          if count != *expected {
+            step_panic(format!("{count} connections"));
+          }
+        }
+      }
+    }
+  }
+
+  fn connect_as(&mut self, addr: BdAddr, link_role: LinkRole) -> ConnectionId {
+    let conn_id = self.next_conn_id;
+    self.next_conn_id += 1;
+    self.addrs.insert(conn_id, addr);
+    self.pump(GattsEvent::PeerConnected {
+      conn_id,
+      addr,
+      link_role,
+    });
+    conn_id
+  }
+
+  fn mtu(&self, conn_id: ConnectionId) -> u16 {
+    self.mtus.get(&conn_id).copied().unwrap_or(DEFAULT_MTU)
+  }
+
+  /// Deliver a request and return its transfer id
+  fn request(&mut self, event: GattsEvent) -> TransferId {
+    let trans_id = self.next_trans_id;
+    self.next_trans_id += 1;
+    self.pump(event);
+    trans_id
+  }
+
+  fn response(&self, trans_id: TransferId) -> SentResponse {
+    let responses = self.stack().take_responses();
+    let mut responses = responses.into_iter().filter(|response| response.trans_id == trans_id);
+
+    let response = responses.next().unwrap_or_else(|| panic!("No response to {trans_id}"));
+    assert!(responses.next().is_none(), "Answered {trans_id} twice");
+    response
+  }
+
+  fn pump(&self, event: GattsEvent) {
+    self.stack().push_gatts(event);
+    self.stack().pump();
+  }
+}
+
+/// A step of a script for `Harness::replay`
+///
+/// Connections are numbered in the order of the `Connect` steps, starting from 0.
+#[derive(Debug, Clone)]
+pub enum Step {
+  Connect(BdAddr),
+  ExchangeMtu(ConnectionId, u16),
+  /// Write the CCCD, which must succeed
+  Subscribe(ConnectionId, BtUuid, Subscription),
+  /// Write Request and the expected status
+  Write(ConnectionId, BtUuid, Vec<u8>, GattStatus),
+  LongWrite(ConnectionId, BtUuid, Vec<u8>, GattStatus),
+  /// Read Request and the expected value or status
+  Read(ConnectionId, BtUuid, Result<Vec<u8>, GattStatus>),
+  Disconnect(ConnectionId),
+  Congest(ConnectionId, bool),
+  /// Confirm the indication of the characteristic
+  Confirm(ConnectionId, BtUuid),
+  /// Act on the server, e.g. to notify a value
+  Run(fn(&GattServer<FakeStack>)),
+  /// The notifications (`false`) and indications (`true`) sent since the last expectation, in order
+  ExpectSent(Vec<(ConnectionId, BtUuid, Vec<u8>, bool)>),
+  ExpectAdvertising(bool),
+  ExpectConnections(usize),
+}
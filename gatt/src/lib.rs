@@ -0,0 +1,18 @@
+/*!
+ * Declarative GATT server, independent of the BLE stack.
+ *
+ * Services are described up front with `Service` / `Characteristic` / `Descriptor`, and `GattServer` registers them,
+ * resolves the attribute handles from the stack events and dispatches reads, writes and subscriptions to the handlers.
+ *
+ * The stack is behind the `Stack` trait: the firmware implements it on top of Bluedroid, and `fake::FakeStack` plays
+ * scripted peers on the host so that the server can be tested without a phone.
+ */
+
+pub mod fake;
+mod link;
+mod server;
+mod stack;
+
+pub use link::*;
+pub use server::*;
+pub use stack::*;
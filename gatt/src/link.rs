@@ -0,0 +1,164 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::stack::{BdAddr, ConnParams, Phy};
+
+/// Link layer payload without Data Length Extension
+pub const DEFAULT_DATA_LENGTH: u16 = 27;
+/// Largest link layer payload with Data Length Extension
+pub const MAX_DATA_LENGTH: u16 = 251;
+
+/// What the device is doing, the connected peers are asked for the link settings that suit it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Activity {
+  /// Showing the dashboard, the phone rarely writes
+  #[default]
+  Idle,
+  /// Following a route, the instructions should show up right away
+  Navigating,
+  /// Receiving a firmware update
+  Transfer,
+}
+
+/// Link settings requested from a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkProfile {
+  pub params: ConnParams,
+  pub phy: Phy,
+  /// Max link layer payload sent to the peer
+  pub data_length: u16,
+}
+
+impl Activity {
+  /// The intervals follow the Apple accessory guidelines, which Android accepts too: the interval is a multiple of
+  /// 15 ms, the max is at least 15 ms above the min, and `max * (latency + 1)` stays under 2 s.
+  pub fn profile(self) -> LinkProfile {
+    match self {
+      // the device may skip 4 connection events, which is 750 ms at most between two of them
+      Self::Idle => LinkProfile {
+        params: ConnParams {
+          min_interval: Duration::from_millis(135),
+          max_interval: Duration::from_millis(150),
+          latency: 4,
+          timeout: Duration::from_secs(6),
+        },
+        phy: Phy::Le1M,
+        data_length: DEFAULT_DATA_LENGTH,
+      },
+      Self::Navigating => LinkProfile {
+        params: ConnParams {
+          min_interval: Duration::from_millis(15),
+          max_interval: Duration::from_millis(30),
+          latency: 0,
+          timeout: Duration::from_secs(2),
+        },
+        phy: Phy::Le1M,
+        data_length: DEFAULT_DATA_LENGTH,
+      },
+      // several packets fit in a connection event, so the shortest interval and the largest packets win
+      Self::Transfer => LinkProfile {
+        params: ConnParams {
+          min_interval: Duration::from_millis(15),
+          max_interval: Duration::from_millis(30),
+          latency: 0,
+          timeout: Duration::from_secs(2),
+        },
+        phy: Phy::Le2M,
+        data_length: MAX_DATA_LENGTH,
+      },
+    }
+  }
+}
+
+/// Why a link was closed, from the HCI error code reported by the stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+  /// The supervision timeout elapsed without hearing from the peer, e.g. it went out of range
+  Timeout,
+  /// The peer closed the link, e.g. the app disconnected
+  RemoteUser,
+  RemoteLowResources,
+  RemotePowerOff,
+  /// This device closed the link
+  Local,
+  /// The peer did not answer a link layer procedure in time
+  LinkLayerTimeout,
+  /// The encrypted packets could not be authenticated, e.g. the bond was removed on one side
+  MicFailure,
+  /// The peer never answered after the connection request
+  FailedToEstablish,
+  Other(u16),
+}
+
+impl DisconnectReason {
+  pub fn from_code(code: u16) -> Self {
+    match code {
+      0x08 => Self::Timeout,
+      0x13 => Self::RemoteUser,
+      0x14 => Self::RemoteLowResources,
+      0x15 => Self::RemotePowerOff,
+      0x16 => Self::Local,
+      0x22 => Self::LinkLayerTimeout,
+      0x3d => Self::MicFailure,
+      0x3e => Self::FailedToEstablish,
+      code => Self::Other(code),
+    }
+  }
+
+  /// Whether the link dropped on its own instead of being closed by either side
+  pub fn is_link_loss(self) -> bool {
+    matches!(self, Self::Timeout | Self::LinkLayerTimeout | Self::FailedToEstablish)
+  }
+}
+
+impl fmt::Display for DisconnectReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Timeout => write!(f, "connection timeout"),
+      Self::RemoteUser => write!(f, "closed by the peer"),
+      Self::RemoteLowResources => write!(f, "peer low on resources"),
+      Self::RemotePowerOff => write!(f, "peer powered off"),
+      Self::Local => write!(f, "closed by this device"),
+      Self::LinkLayerTimeout => write!(f, "link layer response timeout"),
+      Self::MicFailure => write!(f, "MIC failure"),
+      Self::FailedToEstablish => write!(f, "failed to establish"),
+      Self::Other(code) => write!(f, "reason {code:#04x}"),
+    }
+  }
+}
+
+/// Health of the link to a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkStats {
+  pub connected_at: Instant,
+  /// Last RSSI in dBm, see `GattServer::read_rssi`
+  pub rssi: Option<i8>,
+  /// Times the stack reported the link congested
+  ///
+  /// The controller retransmits unacknowledged packets by itself and the host cannot count them, but they pile up
+  /// in its buffers until the link congests. This is the closest measure of retransmissions the stack offers.
+  pub congestions: u32,
+  /// Notifications the stack failed to send, and indications failed or not confirmed in time
+  pub failed_sends: u32,
+}
+
+impl LinkStats {
+  pub fn new(connected_at: Instant) -> Self {
+    Self {
+      connected_at,
+      rssi: None,
+      congestions: 0,
+      failed_sends: 0,
+    }
+  }
+}
+
+/// A peer which disconnected, kept for debugging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnect {
+  pub addr: BdAddr,
+  pub reason: DisconnectReason,
+  pub at: Instant,
+  /// The link as it was when it closed
+  pub stats: LinkStats,
+}
@@ -0,0 +1,1516 @@
+// Based on https://github.com/esp-rs/esp-idf-svc/blob/b42dae55ccfef7c128da0cc8cfdb451f38572a0e/examples/bt_gatt_server.rs
+// Original license: MIT License / Copyright 2019-2020 Contributors to xtensa-lx6-rt
+
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use enumset::{enum_set, EnumSet};
+use log::{debug, info, warn};
+
+use crate::link::*;
+use crate::stack::*;
+
+const APP_ID: u16 = 0;
+pub const MAX_CONNECTIONS: usize = 2;
+/// Max attribute length, and thus the max size of a long write
+const MAX_PREPARED_LEN: usize = 512;
+/// Prepared writes not executed within this time are discarded
+const PREPARE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Outgoing notifications and indications buffered per peer while the link is congested
+const MAX_QUEUED: usize = 8;
+/// ATT transaction timeout, an indication not confirmed within this time is given up
+const INDICATION_TIMEOUT: Duration = Duration::from_secs(30);
+/// Disconnects kept for debugging, the oldest ones are dropped
+const MAX_DISCONNECTS: usize = 8;
+
+/// How often the advertising phase is checked for timeouts
+const ADVERTISING_TICK: Duration = Duration::from_secs(1);
+
+const CCCD_UUID: u16 = 0x2902;
+const CCCD_NOTIFY: u16 = 0x0001;
+const CCCD_INDICATE: u16 = 0x0002;
+
+type ReadHandler = Arc<dyn Fn(&Peer) -> Result<Vec<u8>, GattStatus> + Send + Sync>;
+type WriteHandler = Arc<dyn Fn(&Peer, &[u8]) -> Result<(), GattStatus> + Send + Sync>;
+type SubscribeHandler = Arc<dyn Fn(&Peer, Subscription) + Send + Sync>;
+type DisconnectHandler = Arc<dyn Fn(&Peer) + Send + Sync>;
+type PairingHandler = Arc<dyn Fn(PairingEvent) + Send + Sync>;
+type ScanHandler = Arc<dyn Fn(ScanEvent) + Send + Sync>;
+
+/// Progress of a pairing, the passkey has to be shown to the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingEvent {
+  Passkey { addr: BdAddr, passkey: u32 },
+  Complete { addr: BdAddr, success: bool },
+}
+
+/// Reported while scanning, see `GattServer::start_scanning`
+#[derive(Debug, Clone)]
+pub enum ScanEvent {
+  Found(Advertisement),
+  /// The scan duration elapsed
+  Complete,
+}
+
+/// Advertising after a disconnect: optionally directed to the bonded peer, then fast, then slow to save power
+#[derive(Debug, Clone, Copy)]
+pub struct AdvertisingConfig {
+  /// How long to advertise directed to the bonded peer, `None` to skip directed advertising
+  ///
+  /// Directed advertising targets the identity address of the bond. Phones connect from a resolvable private
+  /// address, which the controller only matches against the identity address with privacy and the resolving list
+  /// enabled, so this is off by default.
+  pub directed_window: Option<Duration>,
+  /// Min and max interval while advertising fast, also used for directed advertising
+  pub fast_interval: (Duration, Duration),
+  /// How long to advertise fast before falling back to slow
+  pub fast_window: Duration,
+  /// Min and max interval while advertising slow, used until a peer connects
+  pub slow_interval: (Duration, Duration),
+}
+
+impl Default for AdvertisingConfig {
+  fn default() -> Self {
+    Self {
+      directed_window: None,
+      fast_interval: (Duration::from_millis(20), Duration::from_millis(30)),
+      fast_window: Duration::from_secs(30),
+      // one of the intervals recommended by Apple, so that iOS still finds the device in the background
+      slow_interval: (Duration::from_micros(1_022_500), Duration::from_micros(1_285_000)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AdvertisingPhase {
+  Directed(Bond),
+  Fast,
+  Slow,
+}
+
+/// The connected peer a request comes from
+#[derive(Debug, Clone, Copy)]
+pub struct Peer {
+  pub addr: BdAddr,
+  pub conn_id: ConnectionId,
+  pub mtu: Option<u16>,
+}
+
+impl Peer {
+  /// Negotiated ATT_MTU, or the default one before the exchange
+  pub fn mtu(&self) -> u16 {
+    self.mtu.unwrap_or(DEFAULT_MTU)
+  }
+}
+
+/// Notifications and indications enabled by a peer through the CCCD
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Subscription {
+  pub notify: bool,
+  pub indicate: bool,
+}
+
+impl Subscription {
+  pub fn from_cccd(value: u16) -> Self {
+    Self {
+      notify: value & CCCD_NOTIFY != 0,
+      indicate: value & CCCD_INDICATE != 0,
+    }
+  }
+  pub fn to_cccd(self) -> u16 {
+    (if self.notify { CCCD_NOTIFY } else { 0 }) | (if self.indicate { CCCD_INDICATE } else { 0 })
+  }
+}
+
+/// A primary service
+pub struct Service {
+  uuid: BtUuid,
+  characteristics: Vec<Characteristic>,
+}
+
+impl Service {
+  /// Service UUIDs must be unique within a server
+  pub fn new(uuid: BtUuid) -> Self {
+    Self {
+      uuid,
+      characteristics: Vec::new(),
+    }
+  }
+  pub fn characteristic(mut self, characteristic: Characteristic) -> Self {
+    self.characteristics.push(characteristic);
+    self
+  }
+
+  fn num_handles(&self) -> u16 {
+    let characteristics = self
+      .characteristics
+      .iter()
+      .map(|c| 2 + c.descriptor_count())
+      .sum::<usize>();
+    1 + characteristics as u16
+  }
+}
+
+/// A characteristic, its properties are derived from the registered handlers
+pub struct Characteristic {
+  uuid: BtUuid,
+  properties: EnumSet<Property>,
+  permissions: EnumSet<Permission>,
+  max_len: usize,
+  read: Option<ReadHandler>,
+  write: Option<WriteHandler>,
+  subscribe: Option<SubscribeHandler>,
+  disconnect: Option<DisconnectHandler>,
+  descriptors: Vec<Descriptor>,
+  encrypted: bool,
+}
+
+impl Characteristic {
+  /// Characteristic UUIDs must be unique within a server
+  pub fn new(uuid: BtUuid) -> Self {
+    Self {
+      uuid,
+      properties: EnumSet::empty(),
+      permissions: EnumSet::empty(),
+      max_len: 20,
+      read: None,
+      write: None,
+      subscribe: None,
+      disconnect: None,
+      descriptors: Vec::new(),
+      encrypted: false,
+    }
+  }
+  /// Longest value, longer writes are rejected with `InvalidAttrLen` before reaching the handler
+  pub fn max_len(mut self, max_len: usize) -> Self {
+    self.max_len = max_len;
+    self
+  }
+  /// Answer reads with the returned value, long reads are sliced by the server
+  pub fn on_read(self, handler: impl Fn(&Peer) -> Vec<u8> + Send + Sync + 'static) -> Self {
+    self.on_try_read(move |peer| Ok(handler(peer)))
+  }
+  /// Like `on_read`, but the handler can reject the read, e.g. while there is no value yet
+  pub fn on_try_read(mut self, handler: impl Fn(&Peer) -> Result<Vec<u8>, GattStatus> + Send + Sync + 'static) -> Self {
+    self.properties |= Property::Read;
+    self.permissions |= Permission::Read;
+    self.read = Some(Arc::new(handler));
+    self
+  }
+  /// Accept writes, long writes are reassembled by the server before the handler is called
+  pub fn on_write(mut self, handler: impl Fn(&Peer, &[u8]) -> Result<(), GattStatus> + Send + Sync + 'static) -> Self {
+    self.properties |= Property::Write;
+    self.permissions |= Permission::Write;
+    self.write = Some(Arc::new(handler));
+    self
+  }
+  /// Also accept Write Without Response
+  pub fn write_without_response(mut self) -> Self {
+    self.properties |= Property::WriteWithoutResponse;
+    self
+  }
+  /// Allow notifications, adds a CCCD
+  pub fn notify(mut self) -> Self {
+    self.properties |= Property::Notify;
+    self
+  }
+  /// Allow indications, adds a CCCD
+  pub fn indicate(mut self) -> Self {
+    self.properties |= Property::Indicate;
+    self
+  }
+  /// Called when a peer changes its CCCD
+  pub fn on_subscribe(mut self, handler: impl Fn(&Peer, Subscription) + Send + Sync + 'static) -> Self {
+    self.subscribe = Some(Arc::new(handler));
+    self
+  }
+  /// Called when a connected peer disconnects, to drop what the handlers keep for it
+  pub fn on_disconnect(mut self, handler: impl Fn(&Peer) + Send + Sync + 'static) -> Self {
+    self.disconnect = Some(Arc::new(handler));
+    self
+  }
+  pub fn descriptor(mut self, descriptor: Descriptor) -> Self {
+    self.descriptors.push(descriptor);
+    self
+  }
+  /// Only allow access (including the CCCD) over an encrypted link of a peer paired with the passkey
+  pub fn encrypted(mut self) -> Self {
+    self.encrypted = true;
+    self
+  }
+
+  fn attribute_permissions(&self, mut permissions: EnumSet<Permission>) -> EnumSet<Permission> {
+    if self.encrypted {
+      if permissions.remove(Permission::Read) {
+        permissions.insert(Permission::ReadEncryptedMitm);
+      }
+      if permissions.remove(Permission::Write) {
+        permissions.insert(Permission::WriteEncryptedMitm);
+      }
+    }
+    permissions
+  }
+
+  fn has_cccd(&self) -> bool {
+    self.properties.contains(Property::Notify) || self.properties.contains(Property::Indicate)
+  }
+  fn descriptor_count(&self) -> usize {
+    self.descriptors.len() + usize::from(self.has_cccd())
+  }
+}
+
+/// A read-only descriptor with a fixed value, e.g. a Characteristic User Description (0x2901)
+pub struct Descriptor {
+  uuid: BtUuid,
+  value: Vec<u8>,
+}
+
+impl Descriptor {
+  pub fn new(uuid: BtUuid, value: impl Into<Vec<u8>>) -> Self {
+    Self {
+      uuid,
+      value: value.into(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AttributeKind {
+  Value,
+  Cccd,
+  Descriptor(usize),
+}
+
+/// Where an attribute handle points to in the service definitions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Attribute {
+  service: usize,
+  characteristic: usize,
+  kind: AttributeKind,
+}
+
+#[derive(Debug, Clone)]
+struct Connection {
+  peer: Peer,
+  /// Keyed by the characteristic value handle
+  subscriptions: HashMap<Handle, Subscription>,
+  prepared: PreparedWrite,
+  /// Set by the stack when its buffers for this link are full
+  congested: bool,
+  queue: VecDeque<Outgoing>,
+  /// Handle and send time of the indication waiting for its confirmation
+  in_flight: Option<(Handle, Instant)>,
+  stats: LinkStats,
+}
+
+#[derive(Debug, Clone)]
+struct Outgoing {
+  handle: Handle,
+  data: Vec<u8>,
+  indicate: bool,
+}
+
+impl Connection {
+  /// Queue the values (or fragments of one value) of a characteristic
+  /// Only the latest notification of each characteristic is kept, so the queued ones are replaced.
+  fn enqueue(&mut self, handle: Handle, values: Vec<Vec<u8>>, indicate: bool) {
+    if !indicate {
+      self.queue.retain(|queued| queued.indicate || queued.handle != handle);
+    }
+
+    // the fragments of a single value may exceed the limit, they are never dropped for each other
+    while !self.queue.is_empty() && self.queue.len() + values.len() > MAX_QUEUED {
+      // Notifications are dropped first, they are superseded by the next value anyway
+      let index = self.queue.iter().position(|queued| !queued.indicate).unwrap_or(0);
+      if let Some(dropped) = self.queue.remove(index) {
+        warn!(
+          "Queue to {} is full, dropped a value of {}",
+          self.peer.addr, dropped.handle
+        );
+      }
+    }
+
+    self
+      .queue
+      .extend(values.into_iter().map(|data| Outgoing { handle, data, indicate }));
+  }
+
+  /// Next value that can be sent, indications wait for the confirmation of the previous one
+  fn dequeue(&mut self) -> Option<Outgoing> {
+    if self.congested {
+      return None;
+    }
+    if self
+      .in_flight
+      .is_some_and(|(_, sent)| sent.elapsed() > INDICATION_TIMEOUT)
+    {
+      warn!("Indication to {} was not confirmed", self.peer.addr);
+      self.in_flight = None;
+      self.stats.failed_sends += 1;
+    }
+
+    let in_flight = self.in_flight.is_some();
+    let index = self.queue.iter().position(|queued| !queued.indicate || !in_flight)?;
+    self.queue.remove(index)
+  }
+}
+
+/// Prepare Write requests of one peer, queued until the Execute Write request
+///
+/// Only a single attribute can be written at a time, and the parts must arrive in order.
+#[derive(Debug, Clone, Default)]
+struct PreparedWrite {
+  handle: Option<Handle>,
+  value: Vec<u8>,
+  started: Option<Instant>,
+}
+
+impl PreparedWrite {
+  fn push(&mut self, handle: Handle, offset: u16, data: &[u8]) -> Result<(), GattStatus> {
+    if self.is_expired() {
+      self.clear();
+    }
+
+    if offset == 0 {
+      if self.handle.is_some_and(|queued| queued != handle) {
+        return Err(GattStatus::PrepareQFull);
+      }
+      // the client starts the value over, e.g. when it retries a long write which it gave up on
+      self.handle = Some(handle);
+      self.value.clear();
+      self.started = Some(Instant::now());
+    } else if self.handle != Some(handle) || offset as usize != self.value.len() {
+      return Err(GattStatus::InvalidOffset);
+    }
+
+    if self.value.len() + data.len() > MAX_PREPARED_LEN {
+      self.clear();
+      return Err(GattStatus::InvalidAttrLen);
+    }
+
+    self.value.extend_from_slice(data);
+    Ok(())
+  }
+
+  /// Take the queued value, if it has not expired
+  fn take(&mut self) -> Result<Option<(Handle, Vec<u8>)>, GattStatus> {
+    let expired = self.is_expired();
+    let handle = self.handle;
+    let value = std::mem::take(&mut self.value);
+    self.clear();
+
+    match handle {
+      Some(_) if expired => Err(GattStatus::ErrUnlikely),
+      Some(handle) => Ok(Some((handle, value))),
+      None => Ok(None),
+    }
+  }
+
+  fn is_expired(&self) -> bool {
+    self.started.is_some_and(|started| started.elapsed() > PREPARE_TIMEOUT)
+  }
+
+  fn clear(&mut self) {
+    self.handle = None;
+    self.value.clear();
+    self.started = None;
+  }
+}
+
+#[derive(Default)]
+struct State {
+  gatt_if: Option<GattInterface>,
+  /// Indexed like `GattServer::services`
+  service_handles: Vec<Option<Handle>>,
+  /// Attributes added to a service but not yet reported by the stack, in the order they were added
+  pending: HashMap<Handle, VecDeque<Attribute>>,
+  attributes: HashMap<Handle, Attribute>,
+  connections: Vec<Connection>,
+  /// Advertising can only start once the advertising data is configured
+  adv_configured: bool,
+  /// Running advertising phase and when it started
+  advertising: Option<(AdvertisingPhase, Instant)>,
+  /// Phase to start once the running advertising is stopped
+  next_advertising: Option<AdvertisingPhase>,
+  stopping: bool,
+  /// Decides the link settings requested from the peers
+  activity: Activity,
+  /// Latest disconnects of the served peers, oldest first
+  disconnects: VecDeque<Disconnect>,
+}
+
+/// Collects the services and the advertising data before the server is started
+pub struct GattServerBuilder<S> {
+  name: String,
+  advertised: Option<BtUuid>,
+  services: Vec<Service>,
+  pairing: Option<PairingHandler>,
+  advertising: AdvertisingConfig,
+  stack: PhantomData<S>,
+}
+
+impl<S: Stack> GattServerBuilder<S> {
+  pub fn name(mut self, name: &str) -> Self {
+    self.name = name.to_string();
+    self
+  }
+  /// Service UUID put into the advertising data, so that scanners can filter on it
+  pub fn advertise(mut self, uuid: BtUuid) -> Self {
+    self.advertised = Some(uuid);
+    self
+  }
+  /// Advertising intervals and how long each phase lasts
+  pub fn advertising(mut self, config: AdvertisingConfig) -> Self {
+    self.advertising = config;
+    self
+  }
+  pub fn service(mut self, service: Service) -> Self {
+    self.services.push(service);
+    self
+  }
+  /// Called with the passkey to display while a peer pairs, and when the pairing completes
+  pub fn on_pairing(mut self, handler: impl Fn(PairingEvent) + Send + Sync + 'static) -> Self {
+    self.pairing = Some(Arc::new(handler));
+    self
+  }
+
+  /// Subscribe to the stack events and register the services
+  pub fn start(self, stack: S) -> Result<GattServer<S>, S::Error> {
+    let server = GattServer {
+      stack: Arc::new(stack),
+      name: Arc::new(Mutex::new(self.name)),
+      advertised: self.advertised,
+      advertising: self.advertising,
+      pairing: self.pairing,
+      state: Arc::new(Mutex::new(State {
+        service_handles: vec![None; self.services.len()],
+        ..Default::default()
+      })),
+      services: Arc::new(self.services),
+      scan: Arc::new(OnceLock::new()),
+    };
+
+    let gap_server = server.clone();
+    let gatts_server = server.clone();
+
+    server.stack.subscribe(
+      move |event| gap_server.check(gap_server.on_gap_event(event)),
+      move |gatt_if, event| gatts_server.check(gatts_server.on_gatts_event(gatt_if, event)),
+    )?;
+
+    info!("BLE Gap and Gatts subscriptions initialized");
+
+    // started once the advertising data is configured
+    server.set_advertising(Some(server.reconnect_phase(None)))?;
+
+    let ticker = server.clone();
+    spawn(move || loop {
+      sleep(ADVERTISING_TICK);
+      ticker.check(ticker.advance_advertising());
+    });
+
+    server.stack.register_app(APP_ID)?;
+
+    info!("Gatts BTP app registered");
+
+    Ok(server)
+  }
+}
+
+pub struct GattServer<S> {
+  stack: Arc<S>,
+  name: Arc<Mutex<String>>,
+  advertised: Option<BtUuid>,
+  advertising: AdvertisingConfig,
+  pairing: Option<PairingHandler>,
+  services: Arc<Vec<Service>>,
+  state: Arc<Mutex<State>>,
+  scan: Arc<OnceLock<ScanHandler>>,
+}
+
+// derived Clone would require S: Clone
+impl<S> Clone for GattServer<S> {
+  fn clone(&self) -> Self {
+    Self {
+      stack: self.stack.clone(),
+      name: self.name.clone(),
+      advertised: self.advertised.clone(),
+      advertising: self.advertising,
+      pairing: self.pairing.clone(),
+      services: self.services.clone(),
+      state: self.state.clone(),
+      scan: self.scan.clone(),
+    }
+  }
+}
+
+impl<S: Stack> GattServer<S> {
+  pub fn builder() -> GattServerBuilder<S> {
+    GattServerBuilder {
+      name: "Navelo".to_string(),
+      advertised: None,
+      services: Vec::new(),
+      pairing: None,
+      advertising: AdvertisingConfig::default(),
+      stack: PhantomData,
+    }
+  }
+
+  /// The stack the server runs on, e.g. to run a GATT client next to the server
+  pub fn stack(&self) -> &S {
+    &self.stack
+  }
+
+  /// Called with the devices found while scanning, can only be set once
+  pub fn set_scan_handler(&self, handler: impl Fn(ScanEvent) + Send + Sync + 'static) {
+    if self.scan.set(Arc::new(handler)).is_err() {
+      warn!("Scan handler is already set");
+    }
+  }
+
+  /// Scan for advertising devices, `ScanEvent::Complete` is reported once `duration` elapsed
+  pub fn start_scanning(&self, duration: Duration) -> Result<(), S::Error> {
+    self.stack.start_scanning(duration)
+  }
+
+  pub fn stop_scanning(&self) -> Result<(), S::Error> {
+    self.stack.stop_scanning()
+  }
+
+  /// Whether every service is created and every attribute got its handle, so that peers see all of them
+  pub fn is_registered(&self) -> bool {
+    let state = self.state.lock().unwrap();
+
+    let attributes: usize = self
+      .services
+      .iter()
+      .flat_map(|service| &service.characteristics)
+      .map(|characteristic| 1 + characteristic.has_cccd() as usize + characteristic.descriptors.len())
+      .sum();
+    state.service_handles.iter().all(Option::is_some) && state.attributes.len() == attributes
+  }
+
+  /// Number of currently connected peers
+  pub fn connection_count(&self) -> usize {
+    self.state.lock().unwrap().connections.len()
+  }
+
+  /// Currently connected peers
+  pub fn peers(&self) -> Vec<Peer> {
+    let state = self.state.lock().unwrap();
+
+    state.connections.iter().map(|conn| conn.peer).collect()
+  }
+
+  /// Connected peers and the health of their links
+  pub fn links(&self) -> Vec<(Peer, LinkStats)> {
+    let state = self.state.lock().unwrap();
+
+    state.connections.iter().map(|conn| (conn.peer, conn.stats)).collect()
+  }
+
+  /// Latest disconnects of the peers, oldest first
+  pub fn disconnects(&self) -> Vec<Disconnect> {
+    self.state.lock().unwrap().disconnects.iter().copied().collect()
+  }
+
+  /// Ask the stack for the RSSI of every link, `links` returns it once reported
+  pub fn read_rssi(&self) -> Result<(), S::Error> {
+    for peer in self.peers() {
+      self.stack.read_rssi(peer.addr)?;
+    }
+    Ok(())
+  }
+
+  /// Send a notification to all peers that enabled notifications of the characteristic
+  ///
+  /// Does not block: while a link is congested the latest value is kept and sent once the link recovers.
+  pub fn notify(&self, uuid: &BtUuid, data: &[u8]) -> Result<(), S::Error> {
+    self.send(uuid, None, false, |_| vec![data.to_vec()])
+  }
+
+  /// Send an indication to all peers that enabled indications of the characteristic
+  ///
+  /// Use this only for values that must be acknowledged, every indication is queued until the previous one
+  /// to the same peer is confirmed.
+  pub fn indicate(&self, uuid: &BtUuid, data: &[u8]) -> Result<(), S::Error> {
+    self.send(uuid, None, true, |_| vec![data.to_vec()])
+  }
+
+  /// Like `notify`, but only to one peer
+  pub fn notify_peer(&self, conn_id: ConnectionId, uuid: &BtUuid, data: &[u8]) -> Result<(), S::Error> {
+    self.send(uuid, Some(conn_id), false, |_| vec![data.to_vec()])
+  }
+
+  /// Like `indicate`, but only to one peer
+  pub fn indicate_peer(&self, conn_id: ConnectionId, uuid: &BtUuid, data: &[u8]) -> Result<(), S::Error> {
+    self.send(uuid, Some(conn_id), true, |_| vec![data.to_vec()])
+  }
+
+  /// Notify a value that may not fit a single notification
+  ///
+  /// `fragment` splits it for the ATT_MTU of each subscribed peer. The fragments are queued together, and replace
+  /// the fragments of the previous value which were not sent yet.
+  pub fn notify_fragmented(&self, uuid: &BtUuid, fragment: impl Fn(u16) -> Vec<Vec<u8>>) -> Result<(), S::Error> {
+    self.send(uuid, None, false, fragment)
+  }
+
+  /// Change the device name, the advertising data is updated right away
+  pub fn set_name(&self, name: &str) -> Result<(), S::Error> {
+    *self.name.lock().unwrap() = name.to_string();
+
+    if self.state.lock().unwrap().gatt_if.is_none() {
+      // applied once the app is registered
+      return Ok(());
+    }
+    self.configure_advertising()
+  }
+
+  /// What the device is doing, see `set_activity`
+  pub fn activity(&self) -> Activity {
+    self.state.lock().unwrap().activity
+  }
+
+  /// Ask the connected peers (and the ones connecting later) for the link settings that suit the activity
+  ///
+  /// Nothing is requested if the activity did not change, so this can be called whenever the state is polled.
+  pub fn set_activity(&self, activity: Activity) -> Result<(), S::Error> {
+    let peers = {
+      let mut state = self.state.lock().unwrap();
+      if state.activity == activity {
+        return Ok(());
+      }
+      state.activity = activity;
+      state.connections.iter().map(|conn| conn.peer.addr).collect::<Vec<_>>()
+    };
+
+    info!("Activity: {activity:?}");
+
+    let profile = activity.profile();
+    for addr in peers {
+      self.apply_profile(addr, profile)?;
+    }
+
+    Ok(())
+  }
+
+  /// Whether values are piling up for any peer, so that producers can slow down
+  pub fn is_congested(&self) -> bool {
+    let state = self.state.lock().unwrap();
+
+    state
+      .connections
+      .iter()
+      .any(|conn| conn.congested || !conn.queue.is_empty())
+  }
+
+  fn send(
+    &self,
+    uuid: &BtUuid,
+    conn_id: Option<ConnectionId>,
+    indicate: bool,
+    values: impl Fn(u16) -> Vec<Vec<u8>>,
+  ) -> Result<(), S::Error> {
+    let mut state = self.state.lock().unwrap();
+
+    let Some(gatt_if) = state.gatt_if else {
+      return Ok(());
+    };
+    let Some(handle) = self.value_handle(&state, uuid) else {
+      return Ok(());
+    };
+
+    for conn in state.connections.iter_mut() {
+      if conn_id.is_some_and(|conn_id| conn_id != conn.peer.conn_id) {
+        continue;
+      }
+      let subscription = conn.subscriptions.get(&handle).copied().unwrap_or_default();
+      if (indicate && subscription.indicate) || (!indicate && subscription.notify) {
+        conn.enqueue(handle, values(conn.peer.mtu()), indicate);
+        self.flush(gatt_if, conn)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Send queued values until the link is congested or an indication is waiting for its confirmation
+  fn flush(&self, gatt_if: GattInterface, conn: &mut Connection) -> Result<(), S::Error> {
+    while let Some(outgoing) = conn.dequeue() {
+      if outgoing.indicate {
+        self
+          .stack
+          .indicate(gatt_if, conn.peer.conn_id, outgoing.handle, &outgoing.data)?;
+        conn.in_flight = Some((outgoing.handle, Instant::now()));
+      } else {
+        self
+          .stack
+          .notify(gatt_if, conn.peer.conn_id, outgoing.handle, &outgoing.data)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// The central decides in the end, it may keep other settings than the requested ones
+  fn apply_profile(&self, addr: BdAddr, profile: LinkProfile) -> Result<(), S::Error> {
+    self.stack.set_conn_params(addr, &profile.params)?;
+    self.stack.set_phy(addr, profile.phy)?;
+    self.stack.set_data_length(addr, profile.data_length)
+  }
+
+  fn value_handle(&self, state: &State, uuid: &BtUuid) -> Option<Handle> {
+    state.attributes.iter().find_map(|(&handle, attribute)| {
+      let characteristic = &self.services[attribute.service].characteristics[attribute.characteristic];
+      (attribute.kind == AttributeKind::Value && characteristic.uuid == *uuid).then_some(handle)
+    })
+  }
+
+  fn characteristic(&self, attribute: Attribute) -> &Characteristic {
+    &self.services[attribute.service].characteristics[attribute.characteristic]
+  }
+
+  /// The main event handler for the GAP events
+  fn on_gap_event(&self, event: GapEvent) -> Result<(), S::Error> {
+    // every advertisement seen while scanning is an event, and the RSSI is polled
+    if !matches!(event, GapEvent::ScanResult(_) | GapEvent::Rssi { .. }) {
+      info!("Got event: {event:?}");
+    }
+
+    match event {
+      GapEvent::AdvertisingConfigured { success } => {
+        if check_success(success, "configure advertising") {
+          self.state.lock().unwrap().adv_configured = true;
+          self.start_next_advertising()?;
+        }
+      }
+      GapEvent::AdvertisingStarted { success } => {
+        check_success(success, "start advertising");
+      }
+      GapEvent::AdvertisingStopped { success } => {
+        if check_success(success, "stop advertising") {
+          self.state.lock().unwrap().stopping = false;
+          self.start_next_advertising()?;
+        }
+      }
+      GapEvent::SecurityRequest(addr) => {
+        self.stack.security_response(addr, true)?;
+      }
+      GapEvent::PasskeyNotification { addr, passkey } => {
+        info!("Pairing with {addr}");
+        self.on_pairing(PairingEvent::Passkey { addr, passkey });
+      }
+      GapEvent::AuthenticationComplete { addr, success } => {
+        if !success {
+          warn!("Pairing with {addr} failed");
+        }
+        self.on_pairing(PairingEvent::Complete { addr, success });
+      }
+      GapEvent::ScanResult(advertisement) => self.on_scan(ScanEvent::Found(advertisement)),
+      GapEvent::ScanComplete => self.on_scan(ScanEvent::Complete),
+      GapEvent::Rssi { addr, rssi } => self.register_rssi(addr, rssi),
+    }
+
+    Ok(())
+  }
+
+  fn on_scan(&self, event: ScanEvent) {
+    if let Some(handler) = self.scan.get() {
+      handler(event);
+    }
+  }
+
+  /// Set the device name and the advertising data, advertising starts once the stack reports it configured
+  fn configure_advertising(&self) -> Result<(), S::Error> {
+    let name = self.name.lock().unwrap().clone();
+
+    self.stack.configure_advertising(&name, self.advertised.as_ref())
+  }
+
+  /// Switch to another advertising phase, or stop advertising with `None`
+  /// The running advertising is stopped first, the next phase starts once the stack reports it stopped
+  fn set_advertising(&self, phase: Option<AdvertisingPhase>) -> Result<(), S::Error> {
+    let mut state = self.state.lock().unwrap();
+
+    state.next_advertising = phase;
+    if !state.adv_configured || state.stopping {
+      return Ok(());
+    }
+
+    if state.advertising.take().is_some() {
+      state.stopping = true;
+      drop(state);
+      self.stack.stop_advertising()
+    } else {
+      drop(state);
+      self.start_next_advertising()
+    }
+  }
+
+  fn start_next_advertising(&self) -> Result<(), S::Error> {
+    let mut state = self.state.lock().unwrap();
+
+    let Some(phase) = state.next_advertising.take() else {
+      return Ok(());
+    };
+    state.advertising = Some((phase, Instant::now()));
+    drop(state);
+
+    debug!("Advertising: {phase:?}");
+
+    let config = &self.advertising;
+    let params = match phase {
+      AdvertisingPhase::Directed(bond) => AdvertisingParams {
+        interval: config.fast_interval,
+        directed: Some(bond),
+      },
+      AdvertisingPhase::Fast => AdvertisingParams {
+        interval: config.fast_interval,
+        directed: None,
+      },
+      AdvertisingPhase::Slow => AdvertisingParams {
+        interval: config.slow_interval,
+        directed: None,
+      },
+    };
+
+    self.stack.start_advertising(&params)
+  }
+
+  /// Move on to the next advertising phase once the current one timed out
+  fn advance_advertising(&self) -> Result<(), S::Error> {
+    let Some((phase, since)) = self.state.lock().unwrap().advertising else {
+      return Ok(());
+    };
+
+    let config = &self.advertising;
+    let next = match phase {
+      AdvertisingPhase::Directed(_) if since.elapsed() >= config.directed_window.unwrap_or_default() => {
+        AdvertisingPhase::Fast
+      }
+      AdvertisingPhase::Fast if since.elapsed() >= config.fast_window => AdvertisingPhase::Slow,
+      _ => return Ok(()),
+    };
+
+    self.set_advertising(Some(next))
+  }
+
+  /// Advertise directed to the bonded peer so that it reconnects quickly, otherwise fast
+  /// `addr` is the peer that just disconnected, or `None` to pick any bonded peer
+  fn reconnect_phase(&self, addr: Option<BdAddr>) -> AdvertisingPhase {
+    if self.advertising.directed_window.is_none() {
+      return AdvertisingPhase::Fast;
+    }
+
+    let bonds = self.stack.bonds();
+    let bond = match addr {
+      Some(addr) => bonds.into_iter().find(|bond| bond.addr == addr),
+      None => bonds.into_iter().next(),
+    };
+
+    bond.map_or(AdvertisingPhase::Fast, AdvertisingPhase::Directed)
+  }
+
+  fn on_pairing(&self, event: PairingEvent) {
+    if let Some(handler) = &self.pairing {
+      handler(event);
+    }
+  }
+
+  /// The main event handler for the GATTS events
+  fn on_gatts_event(&self, gatt_if: GattInterface, event: GattsEvent) -> Result<(), S::Error> {
+    info!("Got event: {event:?}");
+
+    match event {
+      GattsEvent::ServiceRegistered { status, .. }
+      | GattsEvent::ServiceCreated { status, .. }
+      | GattsEvent::CharacteristicAdded { status, .. }
+      | GattsEvent::DescriptorAdded { status, .. }
+      | GattsEvent::ServiceDeleted { status, .. }
+      | GattsEvent::ServiceUnregistered { status }
+        if status != GattStatus::Ok =>
+      {
+        warn!("Got status: {status:?}");
+      }
+      GattsEvent::ServiceRegistered { app_id: APP_ID, .. } => {
+        self.create_services(gatt_if)?;
+      }
+      GattsEvent::ServiceCreated {
+        service_handle,
+        service_uuid,
+        ..
+      } => {
+        self.configure_and_start_service(service_handle, service_uuid)?;
+      }
+      GattsEvent::CharacteristicAdded {
+        attr_handle,
+        service_handle,
+        char_uuid,
+        ..
+      } => {
+        self.register_attribute(service_handle, attr_handle, char_uuid);
+      }
+      GattsEvent::DescriptorAdded {
+        attr_handle,
+        service_handle,
+        descr_uuid,
+        ..
+      } => {
+        self.register_attribute(service_handle, attr_handle, descr_uuid);
+      }
+      GattsEvent::ServiceDeleted { service_handle, .. } => {
+        self.delete_service(service_handle);
+      }
+      GattsEvent::ServiceUnregistered { .. } => {
+        self.unregister();
+      }
+      GattsEvent::Mtu { conn_id, mtu } => {
+        self.register_conn_mtu(conn_id, mtu);
+      }
+      // the links to the sensors this device connected to as a central are reported here too
+      GattsEvent::PeerConnected {
+        conn_id,
+        addr,
+        link_role: LinkRole::Peripheral,
+      } => {
+        self.create_conn(conn_id, addr)?;
+      }
+      GattsEvent::PeerDisconnected { addr, reason, .. } => {
+        // a sensor disconnecting leaves the advertising alone
+        if let Some(peer) = self.delete_conn(addr, reason) {
+          for characteristic in self.services.iter().flat_map(|service| &service.characteristics) {
+            if let Some(handler) = &characteristic.disconnect {
+              handler(&peer);
+            }
+          }
+          self.set_advertising(Some(self.reconnect_phase(Some(addr))))?;
+        }
+      }
+      GattsEvent::Write {
+        conn_id,
+        trans_id,
+        handle,
+        offset,
+        need_rsp,
+        is_prep,
+        value,
+      } => {
+        if is_prep {
+          self.prepare_write(gatt_if, conn_id, trans_id, handle, offset, need_rsp, &value)?;
+        } else {
+          let status = self.write(conn_id, handle, &value);
+          if need_rsp {
+            self.stack.send_response(gatt_if, conn_id, trans_id, status, None)?;
+          }
+        }
+      }
+      GattsEvent::ExecWrite {
+        conn_id,
+        trans_id,
+        canceled,
+      } => {
+        self.execute_write(gatt_if, conn_id, trans_id, canceled)?;
+      }
+      GattsEvent::Read {
+        conn_id,
+        trans_id,
+        handle,
+        offset,
+        need_rsp: true,
+      } => {
+        self.send_read_response(gatt_if, conn_id, trans_id, handle, offset)?;
+      }
+      GattsEvent::Confirm {
+        status,
+        conn_id,
+        handle,
+      } => {
+        self.confirm(gatt_if, conn_id, handle, status)?;
+      }
+      GattsEvent::Congest { conn_id, congested } => {
+        self.set_congested(gatt_if, conn_id, congested)?;
+      }
+      _ => (),
+    }
+
+    Ok(())
+  }
+
+  /// Create the services and configure advertising
+  /// Called from within the event callback once we are notified that the GATTS app is registered
+  fn create_services(&self, gatt_if: GattInterface) -> Result<(), S::Error> {
+    self.state.lock().unwrap().gatt_if = Some(gatt_if);
+
+    self.configure_advertising()?;
+
+    for service in self.services.iter() {
+      self
+        .stack
+        .create_service(gatt_if, &service.uuid, service.num_handles())?;
+    }
+
+    Ok(())
+  }
+
+  /// Start the service and add its characteristics and descriptors
+  /// Called from within the event callback once we are notified that the service is created
+  ///
+  /// A descriptor belongs to the characteristic added right before it, so everything is added in one go
+  /// and the stack reports the handles back in the same order.
+  fn configure_and_start_service(&self, service_handle: Handle, service_uuid: BtUuid) -> Result<(), S::Error> {
+    let Some(index) = self.services.iter().position(|s| s.uuid == service_uuid) else {
+      warn!("Unknown service created: {service_uuid:?}");
+      return Ok(());
+    };
+    let service = &self.services[index];
+
+    {
+      let mut state = self.state.lock().unwrap();
+      state.service_handles[index] = Some(service_handle);
+
+      let pending = state.pending.entry(service_handle).or_default();
+      for (c, characteristic) in service.characteristics.iter().enumerate() {
+        let attribute = |kind| Attribute {
+          service: index,
+          characteristic: c,
+          kind,
+        };
+        pending.push_back(attribute(AttributeKind::Value));
+        if characteristic.has_cccd() {
+          pending.push_back(attribute(AttributeKind::Cccd));
+        }
+        for d in 0..characteristic.descriptors.len() {
+          pending.push_back(attribute(AttributeKind::Descriptor(d)));
+        }
+      }
+    }
+
+    self.stack.start_service(service_handle)?;
+
+    for characteristic in &service.characteristics {
+      self.stack.add_characteristic(
+        service_handle,
+        &characteristic.uuid,
+        characteristic.properties,
+        characteristic.attribute_permissions(characteristic.permissions),
+        characteristic.max_len,
+      )?;
+
+      if characteristic.has_cccd() {
+        self.stack.add_descriptor(
+          service_handle,
+          &BtUuid::uuid16(CCCD_UUID),
+          characteristic.attribute_permissions(enum_set!(Permission::Read | Permission::Write)),
+        )?;
+      }
+
+      for descriptor in &characteristic.descriptors {
+        self.stack.add_descriptor(
+          service_handle,
+          &descriptor.uuid,
+          characteristic.attribute_permissions(enum_set!(Permission::Read)),
+        )?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Resolve the handle of the next attribute of the service
+  /// Called from within the event callback once we are notified that a characteristic or a descriptor is added
+  fn register_attribute(&self, service_handle: Handle, attr_handle: Handle, uuid: BtUuid) {
+    let mut state = self.state.lock().unwrap();
+
+    let Some(attribute) = state.pending.get_mut(&service_handle).and_then(|p| p.pop_front()) else {
+      warn!("Unexpected attribute {uuid:?} added to service {service_handle}");
+      return;
+    };
+
+    let characteristic = self.characteristic(attribute);
+    let expected = match attribute.kind {
+      AttributeKind::Value => characteristic.uuid.clone(),
+      AttributeKind::Cccd => BtUuid::uuid16(CCCD_UUID),
+      AttributeKind::Descriptor(d) => characteristic.descriptors[d].uuid.clone(),
+    };
+    if expected != uuid {
+      warn!("Attribute {uuid:?} added to service {service_handle}, expected {expected:?}");
+      return;
+    }
+
+    state.attributes.insert(attr_handle, attribute);
+  }
+
+  /// Forget the handles of a deleted service
+  /// Called from within the event callback once we are notified that the service is deleted
+  fn delete_service(&self, service_handle: Handle) {
+    let mut state = self.state.lock().unwrap();
+
+    if let Some(index) = state.service_handles.iter().position(|&h| h == Some(service_handle)) {
+      state.service_handles[index] = None;
+      state.pending.remove(&service_handle);
+      state.attributes.retain(|_, attribute| attribute.service != index);
+    }
+  }
+
+  /// Forget everything about the GATTS app
+  /// Called from within the event callback once we are notified that the GATTS app is unregistered
+  fn unregister(&self) {
+    let mut state = self.state.lock().unwrap();
+
+    state.gatt_if = None;
+    state.service_handles.fill(None);
+    state.pending.clear();
+    state.attributes.clear();
+  }
+
+  /// Called from within the event callback once we are notified for the connection MTU
+  fn register_conn_mtu(&self, conn_id: ConnectionId, mtu: u16) {
+    let mut state = self.state.lock().unwrap();
+
+    if let Some(conn) = state.connections.iter_mut().find(|conn| conn.peer.conn_id == conn_id) {
+      conn.peer.mtu = Some(mtu);
+    }
+  }
+
+  /// Create a new connection
+  /// Called from within the event callback once we are notified for a new connection
+  fn create_conn(&self, conn_id: ConnectionId, addr: BdAddr) -> Result<(), S::Error> {
+    let added = {
+      let mut state = self.state.lock().unwrap();
+
+      // the stack stops advertising once a peer connects
+      state.advertising = None;
+
+      if state.connections.len() < MAX_CONNECTIONS {
+        state.connections.push(Connection {
+          peer: Peer {
+            addr,
+            conn_id,
+            mtu: None,
+          },
+          subscriptions: HashMap::new(),
+          prepared: PreparedWrite::default(),
+          congested: false,
+          queue: VecDeque::new(),
+          in_flight: None,
+          stats: LinkStats::new(Instant::now()),
+        });
+
+        true
+      } else {
+        false
+      }
+    };
+
+    if added {
+      let activity = self.state.lock().unwrap().activity;
+      self.apply_profile(addr, activity.profile())?;
+    }
+
+    // keep advertising slowly for the other peers
+    let full = self.connection_count() >= MAX_CONNECTIONS;
+    self.set_advertising((!full).then_some(AdvertisingPhase::Slow))?;
+
+    Ok(())
+  }
+
+  /// Delete a connection, returns the peer unless it was not connected to the server
+  /// Called from within the event callback once we are notified for a disconnected peer
+  fn delete_conn(&self, addr: BdAddr, reason: DisconnectReason) -> Option<Peer> {
+    let mut state = self.state.lock().unwrap();
+
+    let index = state.connections.iter().position(|conn| conn.peer.addr == addr)?;
+    let Connection { peer, stats, .. } = state.connections.swap_remove(index);
+
+    let connected = stats.connected_at.elapsed();
+    let (rssi, congestions, failed) = (stats.rssi, stats.congestions, stats.failed_sends);
+    if reason.is_link_loss() {
+      warn!("Lost {addr}: {reason}, connected for {connected:?} (rssi: {rssi:?}, congestions: {congestions}, failed sends: {failed})");
+    } else {
+      info!("Client {addr} disconnected: {reason}, connected for {connected:?}");
+    }
+
+    if state.disconnects.len() >= MAX_DISCONNECTS {
+      state.disconnects.pop_front();
+    }
+    state.disconnects.push_back(Disconnect {
+      addr,
+      reason,
+      at: Instant::now(),
+      stats,
+    });
+
+    Some(peer)
+  }
+
+  fn register_rssi(&self, addr: BdAddr, rssi: Option<i8>) {
+    let mut state = self.state.lock().unwrap();
+
+    let Some(conn) = state.connections.iter_mut().find(|conn| conn.peer.addr == addr) else {
+      return;
+    };
+    match rssi {
+      Some(rssi) => conn.stats.rssi = Some(rssi),
+      None => debug!("Failed to read the RSSI of {addr}"),
+    }
+  }
+
+  /// Handle the completion of a notification or an indication
+  /// Called from within the event callback, the stack reports both kinds so a confirmation may not match
+  /// any indication in flight
+  fn confirm(
+    &self,
+    gatt_if: GattInterface,
+    conn_id: ConnectionId,
+    handle: Handle,
+    status: GattStatus,
+  ) -> Result<(), S::Error> {
+    let mut state = self.state.lock().unwrap();
+
+    let Some(conn) = state.connections.iter_mut().find(|conn| conn.peer.conn_id == conn_id) else {
+      return Ok(());
+    };
+
+    if conn.in_flight.is_some_and(|(in_flight, _)| in_flight == handle) {
+      if status != GattStatus::Ok {
+        warn!("Indication of {handle} to {} failed: {status:?}", conn.peer.addr);
+        conn.stats.failed_sends += 1;
+      }
+      conn.in_flight = None;
+      self.flush(gatt_if, conn)?;
+    } else {
+      debug!("Confirmation of {handle} to {}: {status:?}", conn.peer.addr);
+      if status != GattStatus::Ok {
+        conn.stats.failed_sends += 1;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Pause or resume sending to a peer
+  /// Called from within the event callback once the stack reports a change of the link congestion
+  fn set_congested(&self, gatt_if: GattInterface, conn_id: ConnectionId, congested: bool) -> Result<(), S::Error> {
+    let mut state = self.state.lock().unwrap();
+
+    if let Some(conn) = state.connections.iter_mut().find(|conn| conn.peer.conn_id == conn_id) {
+      if congested && !conn.congested {
+        conn.stats.congestions += 1;
+      }
+      conn.congested = congested;
+      if !congested {
+        self.flush(gatt_if, conn)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Dispatch a complete write to the CCCD or to the characteristic handler
+  fn write(&self, conn_id: ConnectionId, handle: Handle, value: &[u8]) -> GattStatus {
+    let mut state = self.state.lock().unwrap();
+
+    let Some(&attribute) = state.attributes.get(&handle) else {
+      return GattStatus::InvalidHandle;
+    };
+    let Some(conn) = state.connections.iter_mut().find(|conn| conn.peer.conn_id == conn_id) else {
+      return GattStatus::InvalidHandle;
+    };
+    let peer = conn.peer;
+    let characteristic = self.characteristic(attribute);
+
+    match attribute.kind {
+      AttributeKind::Value => {
+        drop(state);
+
+        if value.len() > characteristic.max_len {
+          warn!(
+            "Rejected {} byte write to {:?}, max {}",
+            value.len(),
+            characteristic.uuid,
+            characteristic.max_len
+          );
+          return GattStatus::InvalidAttrLen;
+        }
+        match &characteristic.write {
+          Some(handler) => handler(&peer, value),
+          None => Err(GattStatus::WriteNotPermit),
+        }
+        .err()
+        .unwrap_or(GattStatus::Ok)
+      }
+      AttributeKind::Cccd => {
+        let [lo, hi] = *value else {
+          return GattStatus::InvalidAttrLen;
+        };
+        let subscription = Subscription::from_cccd(u16::from_le_bytes([lo, hi]));
+        let Some(value_handle) = self.value_handle(&state, &characteristic.uuid) else {
+          return GattStatus::InvalidHandle;
+        };
+        let conn = state
+          .connections
+          .iter_mut()
+          .find(|conn| conn.peer.conn_id == conn_id)
+          .unwrap();
+        conn.subscriptions.insert(value_handle, subscription);
+        drop(state);
+
+        info!(
+          "Client {} subscription to {:?}: {subscription:?}",
+          peer.addr, characteristic.uuid
+        );
+        if let Some(handler) = &characteristic.subscribe {
+          handler(&peer, subscription);
+        }
+        GattStatus::Ok
+      }
+      AttributeKind::Descriptor(_) => GattStatus::WriteNotPermit,
+    }
+  }
+
+  /// Queue a part of a long write until the peer executes it
+  #[allow(clippy::too_many_arguments)]
+  fn prepare_write(
+    &self,
+    gatt_if: GattInterface,
+    conn_id: ConnectionId,
+    trans_id: TransferId,
+    handle: Handle,
+    offset: u16,
+    need_rsp: bool,
+    value: &[u8],
+  ) -> Result<(), S::Error> {
+    let status = {
+      let mut state = self.state.lock().unwrap();
+
+      match state.connections.iter_mut().find(|conn| conn.peer.conn_id == conn_id) {
+        Some(conn) => conn.prepared.push(handle, offset, value),
+        None => Err(GattStatus::InvalidHandle),
+      }
+    };
+
+    if !need_rsp {
+      return Ok(());
+    }
+
+    match status {
+      // The Prepare Write Response echoes the request
+      Ok(()) => self.stack.send_response(
+        gatt_if,
+        conn_id,
+        trans_id,
+        GattStatus::Ok,
+        Some(&Response { handle, offset, value }),
+      ),
+      Err(status) => {
+        warn!("Rejected prepared write to {handle} at {offset}: {status:?}");
+
+        self.stack.send_response(gatt_if, conn_id, trans_id, status, None)
+      }
+    }
+  }
+
+  /// Apply (or discard) the queued long write as if it was a single write
+  fn execute_write(
+    &self,
+    gatt_if: GattInterface,
+    conn_id: ConnectionId,
+    trans_id: TransferId,
+    canceled: bool,
+  ) -> Result<(), S::Error> {
+    let prepared = {
+      let mut state = self.state.lock().unwrap();
+
+      match state.connections.iter_mut().find(|conn| conn.peer.conn_id == conn_id) {
+        Some(conn) => conn.prepared.take(),
+        None => Ok(None),
+      }
+    };
+
+    let status = match prepared {
+      Ok(Some((handle, value))) if !canceled => self.write(conn_id, handle, &value),
+      Ok(_) => GattStatus::Ok,
+      Err(status) => {
+        warn!("Discarded expired prepared write");
+        status
+      }
+    };
+
+    self.stack.send_response(gatt_if, conn_id, trans_id, status, None)
+  }
+
+  /// Answer a read request from the characteristic handler, the CCCD or a descriptor value
+  fn send_read_response(
+    &self,
+    gatt_if: GattInterface,
+    conn_id: ConnectionId,
+    trans_id: TransferId,
+    handle: Handle,
+    offset: u16,
+  ) -> Result<(), S::Error> {
+    let (attribute, peer, subscription) = {
+      let state = self.state.lock().unwrap();
+
+      let attribute = state.attributes.get(&handle).copied();
+      let conn = state.connections.iter().find(|conn| conn.peer.conn_id == conn_id);
+      let subscription = attribute.and_then(|attribute| {
+        let value_handle = self.value_handle(&state, &self.characteristic(attribute).uuid)?;
+        conn?.subscriptions.get(&value_handle).copied()
+      });
+
+      (attribute, conn.map(|conn| conn.peer), subscription)
+    };
+
+    let (Some(attribute), Some(peer)) = (attribute, peer) else {
+      return self
+        .stack
+        .send_response(gatt_if, conn_id, trans_id, GattStatus::InvalidHandle, None);
+    };
+    let characteristic = self.characteristic(attribute);
+
+    let value = match attribute.kind {
+      AttributeKind::Value => {
+        let value = match &characteristic.read {
+          Some(handler) => handler(&peer),
+          None => Err(GattStatus::ReadNotPermit),
+        };
+        match value {
+          Ok(value) => value,
+          Err(status) => return self.stack.send_response(gatt_if, conn_id, trans_id, status, None),
+        }
+      }
+      AttributeKind::Cccd => subscription.unwrap_or_default().to_cccd().to_le_bytes().to_vec(),
+      AttributeKind::Descriptor(d) => characteristic.descriptors[d].value.clone(),
+    };
+
+    let Some(value) = value.get(offset as usize..) else {
+      return self
+        .stack
+        .send_response(gatt_if, conn_id, trans_id, GattStatus::InvalidOffset, None);
+    };
+
+    self.stack.send_response(
+      gatt_if,
+      conn_id,
+      trans_id,
+      GattStatus::Ok,
+      Some(&Response { handle, offset, value }),
+    )
+  }
+
+  fn check(&self, status: Result<(), S::Error>) {
+    if let Err(e) = status {
+      warn!("Got status: {:?}", e);
+    }
+  }
+}
+
+fn check_success(success: bool, operation: &str) -> bool {
+  if !success {
+    warn!("Failed to {operation}");
+  }
+  success
+}
@@ -0,0 +1,357 @@
+use std::fmt;
+use std::time::Duration;
+
+use enumset::{EnumSet, EnumSetType};
+
+use crate::link::DisconnectReason;
+
+pub type GattInterface = u8;
+pub type Handle = u16;
+pub type ConnectionId = u16;
+pub type TransferId = u32;
+
+/// ATT_MTU before the MTU exchange
+pub const DEFAULT_MTU: u16 = 23;
+
+/// Bluetooth device address
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct BdAddr([u8; 6]);
+
+impl BdAddr {
+  pub const fn from_bytes(bytes: [u8; 6]) -> Self {
+    Self(bytes)
+  }
+  pub fn raw(&self) -> [u8; 6] {
+    self.0
+  }
+}
+
+impl fmt::Display for BdAddr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let [a, b, c, d, e, g] = self.0;
+    write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+  }
+}
+
+impl fmt::Debug for BdAddr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(self, f)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BtUuid {
+  Uuid16(u16),
+  Uuid32(u32),
+  Uuid128(u128),
+}
+
+impl BtUuid {
+  pub const fn uuid16(uuid: u16) -> Self {
+    Self::Uuid16(uuid)
+  }
+  pub const fn uuid32(uuid: u32) -> Self {
+    Self::Uuid32(uuid)
+  }
+  pub const fn uuid128(uuid: u128) -> Self {
+    Self::Uuid128(uuid)
+  }
+}
+
+/// ATT error codes used by the server and the handlers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GattStatus {
+  Ok = 0x00,
+  InvalidHandle = 0x01,
+  ReadNotPermit = 0x02,
+  WriteNotPermit = 0x03,
+  InvalidOffset = 0x07,
+  PrepareQFull = 0x09,
+  InvalidAttrLen = 0x0d,
+  ErrUnlikely = 0x0e,
+  /// Any other failure reported by the stack
+  Error = 0x85,
+}
+
+#[derive(Debug, EnumSetType)]
+pub enum Property {
+  Read,
+  WriteWithoutResponse,
+  Write,
+  Notify,
+  Indicate,
+}
+
+#[derive(Debug, EnumSetType)]
+pub enum Permission {
+  Read,
+  ReadEncryptedMitm,
+  Write,
+  WriteEncryptedMitm,
+}
+
+/// Role of this device on a link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkRole {
+  /// A sensor this device connected to
+  Central,
+  /// A phone which connected to this device
+  Peripheral,
+}
+
+/// A peer bonded with this device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bond {
+  pub addr: BdAddr,
+  /// Public or random, as the stack encodes it
+  pub addr_type: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdvertisingParams {
+  /// Min and max advertising interval
+  pub interval: (Duration, Duration),
+  /// Advertise directed to this peer instead of to anyone
+  pub directed: Option<Bond>,
+}
+
+/// Preferred connection parameters requested from the central
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnParams {
+  pub min_interval: Duration,
+  pub max_interval: Duration,
+  /// Connection events the peripheral may skip
+  pub latency: u16,
+  pub timeout: Duration,
+}
+
+/// Physical layer of a link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phy {
+  Le1M,
+  /// Twice the throughput, with a shorter range
+  Le2M,
+}
+
+/// Advertising data and scan response of a device found while scanning
+#[derive(Debug, Clone)]
+pub struct Advertisement {
+  pub addr: BdAddr,
+  /// Public or random, as the stack encodes it
+  pub addr_type: u8,
+  pub rssi: i32,
+  pub data: Vec<u8>,
+}
+
+const AD_INCOMPLETE_UUID16: u8 = 0x02;
+const AD_COMPLETE_UUID16: u8 = 0x03;
+
+impl Advertisement {
+  /// 16-bit service UUIDs from the (in)complete lists of the AD structures
+  pub fn service_uuids16(&self) -> Vec<u16> {
+    let mut uuids = Vec::new();
+    let mut data = self.data.as_slice();
+    while let Some((&len, rest)) = data.split_first() {
+      let Some(structure) = rest.get(..len as usize) else {
+        break;
+      };
+      if let Some((&(AD_INCOMPLETE_UUID16 | AD_COMPLETE_UUID16), list)) = structure.split_first() {
+        uuids.extend(list.chunks_exact(2).map(|uuid| u16::from_le_bytes([uuid[0], uuid[1]])));
+      }
+      data = &rest[len as usize..];
+    }
+    uuids
+  }
+}
+
+/// Value of a read response, or the echo of a prepared write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Response<'a> {
+  pub handle: Handle,
+  pub offset: u16,
+  pub value: &'a [u8],
+}
+
+#[derive(Debug, Clone)]
+pub enum GapEvent {
+  AdvertisingConfigured {
+    success: bool,
+  },
+  AdvertisingStarted {
+    success: bool,
+  },
+  AdvertisingStopped {
+    success: bool,
+  },
+  /// The peer asks to encrypt the link
+  SecurityRequest(BdAddr),
+  PasskeyNotification {
+    addr: BdAddr,
+    passkey: u32,
+  },
+  AuthenticationComplete {
+    addr: BdAddr,
+    success: bool,
+  },
+  ScanResult(Advertisement),
+  /// The scan duration elapsed
+  ScanComplete,
+  /// Reply to `Stack::read_rssi`, `None` if it could not be read
+  Rssi {
+    addr: BdAddr,
+    rssi: Option<i8>,
+  },
+}
+
+#[derive(Debug, Clone)]
+pub enum GattsEvent {
+  ServiceRegistered {
+    status: GattStatus,
+    app_id: u16,
+  },
+  ServiceCreated {
+    status: GattStatus,
+    service_handle: Handle,
+    service_uuid: BtUuid,
+  },
+  CharacteristicAdded {
+    status: GattStatus,
+    attr_handle: Handle,
+    service_handle: Handle,
+    char_uuid: BtUuid,
+  },
+  DescriptorAdded {
+    status: GattStatus,
+    attr_handle: Handle,
+    service_handle: Handle,
+    descr_uuid: BtUuid,
+  },
+  ServiceDeleted {
+    status: GattStatus,
+    service_handle: Handle,
+  },
+  ServiceUnregistered {
+    status: GattStatus,
+  },
+  Mtu {
+    conn_id: ConnectionId,
+    mtu: u16,
+  },
+  PeerConnected {
+    conn_id: ConnectionId,
+    addr: BdAddr,
+    link_role: LinkRole,
+  },
+  PeerDisconnected {
+    conn_id: ConnectionId,
+    addr: BdAddr,
+    reason: DisconnectReason,
+  },
+  Write {
+    conn_id: ConnectionId,
+    trans_id: TransferId,
+    handle: Handle,
+    offset: u16,
+    need_rsp: bool,
+    is_prep: bool,
+    value: Vec<u8>,
+  },
+  ExecWrite {
+    conn_id: ConnectionId,
+    trans_id: TransferId,
+    canceled: bool,
+  },
+  Read {
+    conn_id: ConnectionId,
+    trans_id: TransferId,
+    handle: Handle,
+    offset: u16,
+    need_rsp: bool,
+  },
+  /// A notification was sent or an indication was confirmed
+  Confirm {
+    status: GattStatus,
+    conn_id: ConnectionId,
+    handle: Handle,
+  },
+  Congest {
+    conn_id: ConnectionId,
+    congested: bool,
+  },
+}
+
+/// The BLE stack the server runs on
+///
+/// Calls only start the operations: their results are reported back as events, which the stack delivers to the
+/// handlers passed to `subscribe` from its own task, never from within a call of the server.
+pub trait Stack: Send + Sync + 'static {
+  type Error: fmt::Debug + Send + Sync + 'static;
+
+  /// Deliver the GAP and GATTS events to the server, called once before the app is registered
+  fn subscribe(
+    &self,
+    gap: impl Fn(GapEvent) + Send + Sync + 'static,
+    gatts: impl Fn(GattInterface, GattsEvent) + Send + Sync + 'static,
+  ) -> Result<(), Self::Error>;
+
+  /// Set the device name and the advertising data, reported with `GapEvent::AdvertisingConfigured`
+  fn configure_advertising(&self, name: &str, service: Option<&BtUuid>) -> Result<(), Self::Error>;
+  fn start_advertising(&self, params: &AdvertisingParams) -> Result<(), Self::Error>;
+  fn stop_advertising(&self) -> Result<(), Self::Error>;
+  /// Peers bonded with this device
+  fn bonds(&self) -> Vec<Bond>;
+  /// Accept or reject the encryption of the link requested by the peer
+  fn security_response(&self, addr: BdAddr, accept: bool) -> Result<(), Self::Error>;
+  fn set_conn_params(&self, addr: BdAddr, params: &ConnParams) -> Result<(), Self::Error>;
+  /// Prefer a PHY on the link, the peer may not support it
+  fn set_phy(&self, addr: BdAddr, phy: Phy) -> Result<(), Self::Error>;
+  /// Max link layer payload sent to the peer, above 27 bytes with Data Length Extension
+  fn set_data_length(&self, addr: BdAddr, tx_octets: u16) -> Result<(), Self::Error>;
+  /// Read the signal strength of a link, reported with `GapEvent::Rssi`
+  fn read_rssi(&self, addr: BdAddr) -> Result<(), Self::Error>;
+  /// Report the advertising devices with `GapEvent::ScanResult` until `duration` elapsed
+  fn start_scanning(&self, duration: Duration) -> Result<(), Self::Error>;
+  fn stop_scanning(&self) -> Result<(), Self::Error>;
+
+  /// Register the GATTS app, reported with `GattsEvent::ServiceRegistered`
+  fn register_app(&self, app_id: u16) -> Result<(), Self::Error>;
+  fn create_service(&self, gatt_if: GattInterface, uuid: &BtUuid, num_handles: u16) -> Result<(), Self::Error>;
+  fn start_service(&self, service_handle: Handle) -> Result<(), Self::Error>;
+  fn add_characteristic(
+    &self,
+    service_handle: Handle,
+    uuid: &BtUuid,
+    properties: EnumSet<Property>,
+    permissions: EnumSet<Permission>,
+    max_len: usize,
+  ) -> Result<(), Self::Error>;
+  fn add_descriptor(
+    &self,
+    service_handle: Handle,
+    uuid: &BtUuid,
+    permissions: EnumSet<Permission>,
+  ) -> Result<(), Self::Error>;
+  fn send_response(
+    &self,
+    gatt_if: GattInterface,
+    conn_id: ConnectionId,
+    trans_id: TransferId,
+    status: GattStatus,
+    response: Option<&Response>,
+  ) -> Result<(), Self::Error>;
+  fn notify(
+    &self,
+    gatt_if: GattInterface,
+    conn_id: ConnectionId,
+    handle: Handle,
+    data: &[u8],
+  ) -> Result<(), Self::Error>;
+  fn indicate(
+    &self,
+    gatt_if: GattInterface,
+    conn_id: ConnectionId,
+    handle: Handle,
+    data: &[u8],
+  ) -> Result<(), Self::Error>;
+}
@@ -0,0 +1,640 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use navelo_gatt::fake::{FakeStack, Harness, Step};
+use navelo_gatt::*;
+use navelo_protocol::navigation::{fragment, Reassembler};
+
+const SERVICE: BtUuid = BtUuid::uuid128(0x01370000_7b58_4dda_af7b_4b87d25b4296);
+const VALUE: BtUuid = BtUuid::uuid128(0x01370001_7b58_4dda_af7b_4b87d25b4296);
+const COMMAND: BtUuid = BtUuid::uuid128(0x01370002_7b58_4dda_af7b_4b87d25b4296);
+const EVENT: BtUuid = BtUuid::uuid128(0x01370003_7b58_4dda_af7b_4b87d25b4296);
+const SECRET: BtUuid = BtUuid::uuid128(0x01370004_7b58_4dda_af7b_4b87d25b4296);
+
+const PHONE: BdAddr = BdAddr::from_bytes([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+const TABLET: BdAddr = BdAddr::from_bytes([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbd]);
+const WATCH: BdAddr = BdAddr::from_bytes([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbe]);
+const SENSOR: BdAddr = BdAddr::from_bytes([0xc0, 0xff, 0xee, 0x00, 0x00, 0x01]);
+
+const NOTIFY: Subscription = Subscription {
+  notify: true,
+  indicate: false,
+};
+const INDICATE: Subscription = Subscription {
+  notify: false,
+  indicate: true,
+};
+
+/// A service like the ones of the firmware: a readable and notified value, a command and an indicated event
+fn server(written: Arc<Mutex<Vec<Vec<u8>>>>) -> GattServerBuilder<FakeStack> {
+  GattServer::builder()
+    .name("Navelo test")
+    .advertise(SERVICE.clone())
+    .service(
+      Service::new(SERVICE.clone())
+        .characteristic(
+          Characteristic::new(VALUE.clone())
+            .on_read(|_| b"hello".to_vec())
+            .notify()
+            .max_len(512)
+            .descriptor(Descriptor::new(BtUuid::uuid16(0x2901), "Value")),
+        )
+        .characteristic(
+          Characteristic::new(COMMAND.clone())
+            .max_len(512)
+            .write_without_response()
+            .on_write(move |_, value| {
+              if value.first() == Some(&0xff) {
+                return Err(GattStatus::WriteNotPermit);
+              }
+              written.lock().unwrap().push(value.to_vec());
+              Ok(())
+            }),
+        )
+        .characteristic(Characteristic::new(EVENT.clone()).indicate())
+        .characteristic(Characteristic::new(SECRET.clone()).on_read(|_| vec![42]).encrypted()),
+    )
+}
+
+fn harness() -> (Harness, Arc<Mutex<Vec<Vec<u8>>>>) {
+  let written = Arc::new(Mutex::new(Vec::new()));
+  (Harness::start(server(written.clone())), written)
+}
+
+#[test]
+fn registers_the_services() {
+  let (harness, _) = harness();
+  let stack = harness.stack();
+
+  assert_eq!(stack.services(), vec![(SERVICE, true)]);
+  assert_eq!(
+    stack.advertising_data(),
+    Some(("Navelo test".to_string(), Some(SERVICE)))
+  );
+  assert!(stack.advertising().is_some());
+
+  let attributes = stack.attributes();
+  let uuids = attributes.iter().map(|a| a.uuid.clone()).collect::<Vec<_>>();
+  assert_eq!(
+    uuids,
+    vec![
+      VALUE,
+      BtUuid::uuid16(0x2902),
+      BtUuid::uuid16(0x2901),
+      COMMAND,
+      EVENT,
+      BtUuid::uuid16(0x2902),
+      SECRET,
+    ]
+  );
+
+  let value = &attributes[0];
+  assert_eq!(value.properties, Property::Read | Property::Notify);
+  let command = &attributes[3];
+  assert_eq!(command.properties, Property::Write | Property::WriteWithoutResponse);
+  let secret = &attributes[6];
+  assert_eq!(secret.permissions, Permission::ReadEncryptedMitm);
+}
+
+#[test]
+fn reports_once_the_services_are_registered() {
+  let Ok(server) = server(Arc::default()).start(FakeStack::default());
+  assert!(!server.is_registered());
+
+  server.stack().pump();
+  assert!(server.is_registered());
+}
+
+#[test]
+fn connect_subscribe_notify_disconnect() {
+  let (mut harness, _) = harness();
+
+  harness.replay(&[
+    Step::ExpectAdvertising(true),
+    Step::Connect(PHONE),
+    Step::ExpectConnections(1),
+    Step::ExchangeMtu(0, 185),
+    // nothing is sent before the peer subscribes
+    Step::Run(|server| server.notify(&VALUE, &[1]).unwrap()),
+    Step::ExpectSent(vec![]),
+    Step::Subscribe(0, VALUE, NOTIFY),
+    Step::Run(|server| server.notify(&VALUE, &[2]).unwrap()),
+    Step::ExpectSent(vec![(0, VALUE, vec![2], false)]),
+    Step::Subscribe(0, VALUE, Subscription::default()),
+    Step::Run(|server| server.notify(&VALUE, &[3]).unwrap()),
+    Step::ExpectSent(vec![]),
+    Step::Disconnect(0),
+    Step::ExpectConnections(0),
+    Step::ExpectAdvertising(true),
+  ]);
+
+  let peer = harness.server().peers();
+  assert!(peer.is_empty());
+}
+
+#[test]
+fn reports_the_mtu_of_each_peer() {
+  let (mut harness, _) = harness();
+
+  let phone = harness.connect(PHONE);
+  let tablet = harness.connect(TABLET);
+  harness.exchange_mtu(tablet, 247);
+
+  let peers = harness.server().peers();
+  let mtu = |conn_id| peers.iter().find(|p| p.conn_id == conn_id).unwrap().mtu();
+  assert_eq!(mtu(phone), DEFAULT_MTU);
+  assert_eq!(mtu(tablet), 247);
+}
+
+#[test]
+fn requests_connection_parameters() {
+  let (mut harness, _) = harness();
+
+  harness.connect(PHONE);
+
+  let params = harness.stack().conn_params(PHONE).unwrap();
+  assert!(params.min_interval <= params.max_interval);
+  assert_eq!(params, Activity::Idle.profile().params);
+}
+
+#[test]
+fn switches_the_link_profile_with_the_activity() {
+  let (mut harness, _) = harness();
+  harness.connect(PHONE);
+  let tablet = harness.connect(TABLET);
+
+  harness.server().set_activity(Activity::Navigating).unwrap();
+  let navigating = Activity::Navigating.profile();
+  for peer in [PHONE, TABLET] {
+    assert_eq!(harness.stack().conn_params(peer), Some(navigating.params));
+    assert_eq!(harness.stack().phy(peer), Some(Phy::Le1M));
+  }
+
+  harness.server().set_activity(Activity::Transfer).unwrap();
+  assert_eq!(harness.server().activity(), Activity::Transfer);
+  for peer in [PHONE, TABLET] {
+    assert_eq!(harness.stack().phy(peer), Some(Phy::Le2M));
+    assert_eq!(harness.stack().data_length(peer), Some(MAX_DATA_LENGTH));
+  }
+
+  // a peer connecting later gets the current profile
+  harness.disconnect(tablet);
+  harness.connect(WATCH);
+  assert_eq!(harness.stack().phy(WATCH), Some(Phy::Le2M));
+
+  harness.server().set_activity(Activity::Idle).unwrap();
+  let idle = Activity::Idle.profile();
+  assert!(idle.params.latency > 0);
+  assert_eq!(harness.stack().conn_params(WATCH), Some(idle.params));
+  assert_eq!(harness.stack().data_length(WATCH), Some(DEFAULT_DATA_LENGTH));
+}
+
+#[test]
+fn keeps_the_link_profile_of_disconnected_peers_alone() {
+  let (mut harness, _) = harness();
+  let phone = harness.connect(PHONE);
+  harness.disconnect(phone);
+
+  harness.server().set_activity(Activity::Transfer).unwrap();
+  assert_eq!(harness.stack().phy(PHONE), Some(Phy::Le1M));
+}
+
+#[test]
+fn reads() {
+  let (mut harness, _) = harness();
+  let phone = harness.connect(PHONE);
+
+  harness.replay(&[
+    Step::Read(0, VALUE, Ok(b"hello".to_vec())),
+    Step::Read(0, COMMAND, Err(GattStatus::ReadNotPermit)),
+  ]);
+
+  // Read Blob
+  let handle = harness.handle(&VALUE);
+  assert_eq!(harness.read_handle(phone, handle, 3), Ok(b"lo".to_vec()));
+  assert_eq!(harness.read_handle(phone, handle, 6), Err(GattStatus::InvalidOffset));
+
+  // descriptors
+  let user_description = handle + 2;
+  assert_eq!(harness.read_handle(phone, user_description, 0), Ok(b"Value".to_vec()));
+  assert_eq!(harness.read_handle(phone, 0x7fff, 0), Err(GattStatus::InvalidHandle));
+}
+
+#[test]
+fn rejects_reads_from_the_handler() {
+  let builder = GattServer::builder().name("Navelo test").service(
+    Service::new(SERVICE.clone()).characteristic(
+      Characteristic::new(VALUE.clone())
+        .on_try_read(|_| Err(GattStatus::ReadNotPermit))
+        .notify(),
+    ),
+  );
+  let mut harness = Harness::start(builder);
+
+  harness.replay(&[
+    Step::Connect(PHONE),
+    Step::Read(0, VALUE, Err(GattStatus::ReadNotPermit)),
+  ]);
+  assert_eq!(
+    harness.stack().attributes()[0].properties,
+    Property::Read | Property::Notify
+  );
+}
+
+#[test]
+fn reads_back_the_cccd() {
+  let (mut harness, _) = harness();
+  let phone = harness.connect(PHONE);
+  let tablet = harness.connect(TABLET);
+  let cccd = harness.cccd(&VALUE);
+
+  harness.subscribe(phone, &VALUE, NOTIFY);
+
+  assert_eq!(harness.read_handle(phone, cccd, 0), Ok(vec![0x01, 0x00]));
+  // subscriptions are per peer
+  assert_eq!(harness.read_handle(tablet, cccd, 0), Ok(vec![0x00, 0x00]));
+}
+
+#[test]
+fn writes() {
+  let (mut harness, written) = harness();
+  let phone = harness.connect(PHONE);
+
+  harness.replay(&[
+    Step::Write(0, COMMAND, vec![1, 2, 3], GattStatus::Ok),
+    Step::Write(0, COMMAND, vec![0xff], GattStatus::WriteNotPermit),
+    Step::Write(0, VALUE, vec![1], GattStatus::WriteNotPermit),
+  ]);
+
+  harness.write_command(phone, &COMMAND, &[4]);
+  assert!(harness.stack().take_responses().is_empty());
+
+  assert_eq!(*written.lock().unwrap(), vec![vec![1, 2, 3], vec![4]]);
+
+  // a CCCD value is two bytes
+  let cccd = harness.cccd(&VALUE);
+  assert_eq!(harness.write_handle(phone, cccd, &[1]), GattStatus::InvalidAttrLen);
+  // other descriptors are read only
+  assert_eq!(harness.write_handle(phone, cccd + 1, &[1]), GattStatus::WriteNotPermit);
+}
+
+#[test]
+fn long_writes() {
+  let (mut harness, written) = harness();
+  let message = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+
+  harness.replay(&[
+    Step::Connect(PHONE),
+    Step::LongWrite(0, COMMAND, message.clone(), GattStatus::Ok),
+    Step::ExchangeMtu(0, 100),
+    Step::LongWrite(0, COMMAND, message.clone(), GattStatus::Ok),
+    Step::LongWrite(0, COMMAND, vec![0; 513], GattStatus::InvalidAttrLen),
+  ]);
+
+  assert_eq!(*written.lock().unwrap(), vec![message.clone(), message]);
+}
+
+#[test]
+fn rejects_values_longer_than_max_len() {
+  let written = Arc::new(Mutex::new(Vec::new()));
+  let handler_written = written.clone();
+  let builder = GattServer::builder().name("Navelo test").service(
+    Service::new(SERVICE.clone()).characteristic(Characteristic::new(COMMAND.clone()).max_len(4).on_write(
+      move |_, value| {
+        handler_written.lock().unwrap().push(value.to_vec());
+        Ok(())
+      },
+    )),
+  );
+  let mut harness = Harness::start(builder);
+
+  harness.replay(&[
+    Step::Connect(PHONE),
+    Step::Write(0, COMMAND, vec![0; 5], GattStatus::InvalidAttrLen),
+    Step::LongWrite(0, COMMAND, vec![0; 30], GattStatus::InvalidAttrLen),
+    Step::Write(0, COMMAND, vec![1; 4], GattStatus::Ok),
+  ]);
+
+  assert_eq!(*written.lock().unwrap(), vec![vec![1; 4]]);
+}
+
+#[test]
+fn rejects_prepared_writes_out_of_order() {
+  let (mut harness, _) = harness();
+  let phone = harness.connect(PHONE);
+  let handle = harness.handle(&COMMAND);
+
+  for (trans_id, offset) in [(100, 0), (101, 5)] {
+    harness.stack().push_gatts(GattsEvent::Write {
+      conn_id: phone,
+      trans_id,
+      handle,
+      offset,
+      need_rsp: true,
+      is_prep: true,
+      value: vec![0; 4],
+    });
+  }
+  harness.stack().pump();
+
+  let statuses = harness.stack().take_responses().into_iter().map(|r| r.status);
+  assert_eq!(
+    statuses.collect::<Vec<_>>(),
+    vec![GattStatus::Ok, GattStatus::InvalidOffset]
+  );
+}
+
+#[test]
+fn restarts_a_prepared_write_from_offset_zero() {
+  let (mut harness, written) = harness();
+  let phone = harness.connect(PHONE);
+  let handle = harness.handle(&COMMAND);
+
+  for (trans_id, offset, value) in [(100, 0, [1; 4]), (101, 4, [2; 4]), (102, 0, [3; 4]), (103, 4, [4; 4])] {
+    harness.stack().push_gatts(GattsEvent::Write {
+      conn_id: phone,
+      trans_id,
+      handle,
+      offset,
+      need_rsp: true,
+      is_prep: true,
+      value: value.to_vec(),
+    });
+  }
+  harness.stack().push_gatts(GattsEvent::ExecWrite {
+    conn_id: phone,
+    trans_id: 104,
+    canceled: false,
+  });
+  harness.stack().pump();
+
+  let statuses = harness.stack().take_responses().into_iter().map(|r| r.status);
+  assert_eq!(statuses.collect::<Vec<_>>(), vec![GattStatus::Ok; 5]);
+  assert_eq!(*written.lock().unwrap(), vec![vec![3, 3, 3, 3, 4, 4, 4, 4]]);
+}
+
+#[test]
+fn reports_disconnected_peers_to_the_characteristics() {
+  let disconnected = Arc::new(Mutex::new(Vec::new()));
+  let handler_disconnected = disconnected.clone();
+  let builder = GattServer::builder().name("Navelo test").service(
+    Service::new(SERVICE.clone()).characteristic(
+      Characteristic::new(COMMAND.clone())
+        .on_write(|_, _| Ok(()))
+        .on_disconnect(move |peer| handler_disconnected.lock().unwrap().push(peer.addr)),
+    ),
+  );
+  let mut harness = Harness::start(builder);
+
+  let phone = harness.connect(PHONE);
+  let _tablet = harness.connect(TABLET);
+  let sensor = harness.connect_sensor(SENSOR);
+  harness.disconnect(phone);
+  harness.disconnect(sensor);
+
+  assert_eq!(*disconnected.lock().unwrap(), vec![PHONE]);
+}
+
+#[test]
+fn indications_wait_for_the_confirmation() {
+  let (mut harness, _) = harness();
+
+  harness.replay(&[
+    Step::Connect(PHONE),
+    Step::Subscribe(0, EVENT, INDICATE),
+    Step::Run(|server| {
+      server.indicate(&EVENT, &[1]).unwrap();
+      server.indicate(&EVENT, &[2]).unwrap();
+    }),
+    Step::ExpectSent(vec![(0, EVENT, vec![1], true)]),
+    Step::Confirm(0, EVENT),
+    Step::ExpectSent(vec![(0, EVENT, vec![2], true)]),
+    Step::Confirm(0, EVENT),
+    Step::ExpectSent(vec![]),
+  ]);
+}
+
+#[test]
+fn congestion_keeps_the_latest_notification() {
+  let (mut harness, _) = harness();
+
+  harness.replay(&[
+    Step::Connect(PHONE),
+    Step::Subscribe(0, VALUE, NOTIFY),
+    Step::Subscribe(0, EVENT, INDICATE),
+    Step::Congest(0, true),
+    Step::Run(|server| {
+      server.notify(&VALUE, &[1]).unwrap();
+      server.indicate(&EVENT, &[2]).unwrap();
+      server.notify(&VALUE, &[3]).unwrap();
+      assert!(server.is_congested());
+    }),
+    Step::ExpectSent(vec![]),
+    Step::Congest(0, false),
+    Step::ExpectSent(vec![(0, EVENT, vec![2], true), (0, VALUE, vec![3], false)]),
+    Step::Run(|server| assert!(!server.is_congested())),
+  ]);
+}
+
+#[test]
+fn tracks_the_health_of_each_link() {
+  let (mut harness, _) = harness();
+  let phone = harness.connect(PHONE);
+  let tablet = harness.connect(TABLET);
+
+  harness.stack().set_rssi(PHONE, -60);
+  harness.read_rssi();
+  harness.congest(phone, true);
+  harness.congest(phone, true);
+  harness.congest(phone, false);
+  harness.congest(phone, true);
+
+  let links = harness.server().links();
+  let stats = |conn_id| links.iter().find(|(peer, _)| peer.conn_id == conn_id).unwrap().1;
+  assert_eq!((stats(phone).rssi, stats(phone).congestions), (Some(-60), 2));
+  assert_eq!((stats(tablet).rssi, stats(tablet).congestions), (None, 0));
+
+  harness.disconnect_for(phone, DisconnectReason::Timeout);
+  harness.disconnect(tablet);
+
+  let disconnects = harness.server().disconnects();
+  let reasons = disconnects.iter().map(|d| (d.addr, d.reason)).collect::<Vec<_>>();
+  assert_eq!(
+    reasons,
+    [
+      (PHONE, DisconnectReason::Timeout),
+      (TABLET, DisconnectReason::RemoteUser)
+    ]
+  );
+  assert!(disconnects[0].reason.is_link_loss());
+  assert_eq!(disconnects[0].stats.rssi, Some(-60));
+}
+
+#[test]
+fn counts_the_failed_indications() {
+  let (mut harness, _) = harness();
+  let phone = harness.connect(PHONE);
+  harness.subscribe(phone, &EVENT, INDICATE);
+
+  harness.server().indicate(&EVENT, &[1]).unwrap();
+  harness.stack().pump();
+  let handle = harness.handle(&EVENT);
+  harness.stack().push_gatts(GattsEvent::Confirm {
+    status: GattStatus::Error,
+    conn_id: phone,
+    handle,
+  });
+  harness.stack().pump();
+
+  assert_eq!(harness.server().links()[0].1.failed_sends, 1);
+}
+
+#[test]
+fn sends_to_a_single_peer() {
+  let (mut harness, _) = harness();
+  let phone = harness.connect(PHONE);
+  let tablet = harness.connect(TABLET);
+  harness.subscribe(phone, &VALUE, NOTIFY);
+  harness.subscribe(tablet, &VALUE, NOTIFY);
+
+  harness.server().notify_peer(tablet, &VALUE, &[7]).unwrap();
+  harness.stack().pump();
+
+  let sent = harness.take_sent();
+  assert_eq!(sent.len(), 1);
+  assert_eq!((sent[0].conn_id, sent[0].data.as_slice()), (tablet, &[7][..]));
+}
+
+#[test]
+fn fragments_for_the_mtu_of_each_peer() {
+  let (mut harness, _) = harness();
+  let phone = harness.connect(PHONE);
+  let tablet = harness.connect(TABLET);
+  harness.exchange_mtu(tablet, 247);
+  harness.subscribe(phone, &VALUE, NOTIFY);
+  harness.subscribe(tablet, &VALUE, NOTIFY);
+
+  let message = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+  harness
+    .server()
+    .notify_fragmented(&VALUE, |mtu| fragment(&message, 1, mtu))
+    .unwrap();
+  harness.stack().pump();
+
+  let sent = harness.take_sent();
+  for (conn_id, mtu) in [(phone, DEFAULT_MTU), (tablet, 247)] {
+    let mut reassembler = Reassembler::default();
+    let fragments = sent.iter().filter(|sent| sent.conn_id == conn_id);
+    let mut reassembled = None;
+    for sent in fragments {
+      assert!(sent.data.len() <= mtu as usize - 3);
+      reassembled = reassembler.push(&sent.data).unwrap();
+    }
+    assert_eq!(reassembled.as_ref(), Some(&message));
+  }
+}
+
+#[test]
+fn limits_the_connections() {
+  let (mut harness, _) = harness();
+
+  harness.replay(&[
+    Step::Connect(PHONE),
+    // keeps advertising for another peer
+    Step::ExpectAdvertising(true),
+    Step::Connect(TABLET),
+    Step::ExpectConnections(MAX_CONNECTIONS),
+    Step::ExpectAdvertising(false),
+    Step::Connect(WATCH),
+    Step::ExpectConnections(MAX_CONNECTIONS),
+    Step::Disconnect(0),
+    Step::ExpectAdvertising(true),
+  ]);
+}
+
+#[test]
+fn ignores_links_to_sensors() {
+  let (mut harness, _) = harness();
+
+  let sensor = harness.connect_sensor(SENSOR);
+  assert_eq!(harness.server().connection_count(), 0);
+
+  harness.disconnect(sensor);
+  assert_eq!(harness.server().connection_count(), 0);
+  assert!(harness.server().disconnects().is_empty());
+}
+
+#[test]
+fn advertises_directed_to_the_bonded_peer() {
+  let bond = Bond {
+    addr: PHONE,
+    addr_type: 0,
+  };
+  let written = Arc::new(Mutex::new(Vec::new()));
+  let config = AdvertisingConfig {
+    directed_window: Some(Duration::from_secs(5)),
+    ..Default::default()
+  };
+  let harness = Harness::start_with(server(written).advertising(config), FakeStack::with_bonds(&[bond]));
+
+  let advertising = harness.stack().advertising().unwrap();
+  assert_eq!(advertising.directed, Some(bond));
+}
+
+#[test]
+fn advertises_fast_to_a_bonded_peer_by_default() {
+  let bond = Bond {
+    addr: PHONE,
+    addr_type: 0,
+  };
+  let written = Arc::new(Mutex::new(Vec::new()));
+  let harness = Harness::start_with(server(written), FakeStack::with_bonds(&[bond]));
+
+  let advertising = harness.stack().advertising().unwrap();
+  assert_eq!(advertising.directed, None);
+  assert_eq!(advertising.interval, AdvertisingConfig::default().fast_interval);
+}
+
+#[test]
+fn reports_pairing() {
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let pairing = events.clone();
+  let builder = server(Arc::default()).on_pairing(move |event| pairing.lock().unwrap().push(event));
+  let harness = Harness::start(builder);
+
+  harness.stack().push_gap(GapEvent::PasskeyNotification {
+    addr: PHONE,
+    passkey: 123456,
+  });
+  harness.stack().push_gap(GapEvent::AuthenticationComplete {
+    addr: PHONE,
+    success: true,
+  });
+  harness.stack().pump();
+
+  assert_eq!(
+    *events.lock().unwrap(),
+    vec![
+      PairingEvent::Passkey {
+        addr: PHONE,
+        passkey: 123456
+      },
+      PairingEvent::Complete {
+        addr: PHONE,
+        success: true
+      },
+    ]
+  );
+}
+
+#[test]
+fn renames() {
+  let (harness, _) = harness();
+
+  harness.server().set_name("Renamed").unwrap();
+  harness.stack().pump();
+
+  let (name, _) = harness.stack().advertising_data().unwrap();
+  assert_eq!(name, "Renamed");
+  assert!(harness.stack().advertising().is_some());
+}
@@ -0,0 +1,181 @@
+/*!
+ * Firmware update: commands and progress on the control characteristic, image chunks on the data characteristic.
+ */
+use alloc::vec::Vec;
+
+use thiserror::Error;
+
+const CMD_START: u8 = 1;
+const CMD_ABORT: u8 = 2;
+const CMD_FINISH: u8 = 3;
+
+pub const STATE_LEN: usize = 10;
+/** Offset in front of the image bytes of a chunk */
+pub const CHUNK_HEADER_LEN: usize = 4;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuError {
+  #[error("truncated command")]
+  Truncated,
+  #[error("unknown command")]
+  UnknownCommand,
+  #[error("no update in progress")]
+  NotStarted,
+  #[error("image does not fit in the partition")]
+  TooLarge,
+  #[error("unexpected chunk offset")]
+  UnexpectedOffset,
+  #[error("image size mismatch")]
+  SizeMismatch,
+  #[error("image hash mismatch")]
+  HashMismatch,
+  #[error("invalid signature")]
+  InvalidSignature,
+  #[error("no signing key configured")]
+  NoPublicKey,
+  #[error("flash error")]
+  Flash,
+}
+
+impl DfuError {
+  pub fn code(self) -> u8 {
+    match self {
+      Self::Truncated => 1,
+      Self::UnknownCommand => 2,
+      Self::NotStarted => 3,
+      Self::TooLarge => 4,
+      Self::UnexpectedOffset => 5,
+      Self::SizeMismatch => 6,
+      Self::HashMismatch => 7,
+      Self::InvalidSignature => 8,
+      Self::NoPublicKey => 9,
+      Self::Flash => 10,
+    }
+  }
+  pub fn from_code(code: u8) -> Option<Self> {
+    Some(match code {
+      1 => Self::Truncated,
+      2 => Self::UnknownCommand,
+      3 => Self::NotStarted,
+      4 => Self::TooLarge,
+      5 => Self::UnexpectedOffset,
+      6 => Self::SizeMismatch,
+      7 => Self::HashMismatch,
+      8 => Self::InvalidSignature,
+      9 => Self::NoPublicKey,
+      10 => Self::Flash,
+      _ => return None,
+    })
+  }
+}
+
+/**
+Written to the control characteristic:
+- `[1, size: u32, sha256: [u8; 32], signature...]` starts an update, the signature is DER encoded
+- `[2]` aborts it
+- `[3]` verifies the image and reboots into it
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+  Start {
+    size: u32,
+    hash: [u8; 32],
+    signature: Vec<u8>,
+  },
+  Abort,
+  Finish,
+}
+
+impl Command {
+  pub fn encode(&self) -> Vec<u8> {
+    match self {
+      Self::Start { size, hash, signature } => {
+        let mut data = Vec::with_capacity(1 + 4 + 32 + signature.len());
+        data.push(CMD_START);
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend_from_slice(hash);
+        data.extend_from_slice(signature);
+        data
+      }
+      Self::Abort => [CMD_ABORT].into(),
+      Self::Finish => [CMD_FINISH].into(),
+    }
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, DfuError> {
+    match *data.first().ok_or(DfuError::Truncated)? {
+      CMD_START => {
+        if data.len() < 1 + 4 + 32 + 1 {
+          return Err(DfuError::Truncated);
+        }
+        Ok(Self::Start {
+          size: u32::from_le_bytes([data[1], data[2], data[3], data[4]]),
+          hash: data[5..37].try_into().unwrap(),
+          signature: data[37..].into(),
+        })
+      }
+      CMD_ABORT => Ok(Self::Abort),
+      CMD_FINISH => Ok(Self::Finish),
+      _ => Err(DfuError::UnknownCommand),
+    }
+  }
+}
+
+/** `[offset: u32, data...]` written to the data characteristic */
+pub fn encode_chunk(offset: u32, data: &[u8]) -> Vec<u8> {
+  let mut chunk = Vec::with_capacity(CHUNK_HEADER_LEN + data.len());
+  chunk.extend_from_slice(&offset.to_le_bytes());
+  chunk.extend_from_slice(data);
+  chunk
+}
+
+/** Offset and image bytes of a chunk */
+pub fn decode_chunk(chunk: &[u8]) -> Result<(u32, &[u8]), DfuError> {
+  let (offset, data) = chunk
+    .split_first_chunk::<CHUNK_HEADER_LEN>()
+    .ok_or(DfuError::Truncated)?;
+  Ok((u32::from_le_bytes(*offset), data))
+}
+
+/** Progress reported through the control characteristic */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DfuState {
+  #[default]
+  Idle,
+  Receiving {
+    received: u32,
+    size: u32,
+  },
+  Done,
+  Failed(DfuError),
+}
+
+impl DfuState {
+  /** `[state, error, received: u32, size: u32]`, the counters are 0 unless receiving */
+  pub fn encode(self) -> [u8; STATE_LEN] {
+    let (state, error, received, size) = match self {
+      Self::Idle => (0, 0, 0, 0),
+      Self::Receiving { received, size } => (1, 0, received, size),
+      Self::Done => (2, 0, 0, 0),
+      Self::Failed(e) => (3, e.code(), 0, 0),
+    };
+    let mut data = [state, error, 0, 0, 0, 0, 0, 0, 0, 0];
+    data[2..6].copy_from_slice(&received.to_le_bytes());
+    data[6..].copy_from_slice(&size.to_le_bytes());
+    data
+  }
+
+  /** `None` for an unknown state or error */
+  pub fn decode(data: &[u8]) -> Option<Self> {
+    let data: &[u8; STATE_LEN] = data.get(..STATE_LEN)?.try_into().ok()?;
+    let received = u32::from_le_bytes([data[2], data[3], data[4], data[5]]);
+    let size = u32::from_le_bytes([data[6], data[7], data[8], data[9]]);
+    Some(match data[0] {
+      0 => Self::Idle,
+      1 => Self::Receiving { received, size },
+      2 => Self::Done,
+      3 => Self::Failed(DfuError::from_code(data[1])?),
+      _ => return None,
+    })
+  }
+}
@@ -0,0 +1,204 @@
+/*!
+ * Bitmaps pushed by the phone for content the firmware cannot render itself, such as map snapshots or QR codes.
+ *
+ * The pixels use the run-length encoding of `badapple`: alternating runs of dark and bright pixels in row-major order,
+ * starting with a possibly empty dark run. A run is one byte up to 250, or 251 followed by a u16 LE, or 252 followed
+ * by a u32 LE.
+ */
+use alloc::vec;
+use alloc::vec::Vec;
+
+use thiserror::Error;
+
+pub const VERSION: u8 = 1;
+/** Upper bound of a reassembled image message, detailed full screen images may not fit */
+pub const MAX_MESSAGE_LEN: usize = 8192;
+/** Size of the display the images are placed on */
+pub const SCREEN_WIDTH: u32 = 200;
+pub const SCREEN_HEIGHT: u32 = 200;
+const HEADER_LEN: usize = 5;
+
+const MAX_SHORT_RUN: u32 = 250;
+const U16_RUN: u8 = 251;
+const U32_RUN: u8 = 252;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ImageError {
+  #[error("message is truncated")]
+  Truncated,
+  #[error("unsupported version {0}")]
+  UnsupportedVersion(u8),
+  #[error("invalid run marker {0}")]
+  InvalidMarker(u8),
+  #[error("image does not fit the display")]
+  OutOfBounds,
+  #[error("runs cover {0} pixels instead of {1}")]
+  PixelCount(u64, usize),
+}
+
+/** 1bpp image placed on the display */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+  x: u8,
+  y: u8,
+  width: u8,
+  height: u8,
+  /** Row-major, MSB first, set bits are bright */
+  pixels: Vec<u8>,
+}
+
+impl Bitmap {
+  /** A dark bitmap, fails if it is empty or does not fit the display */
+  pub fn new(x: u8, y: u8, width: u8, height: u8) -> Result<Self, ImageError> {
+    if width == 0 || height == 0 || x as u32 + width as u32 > SCREEN_WIDTH || y as u32 + height as u32 > SCREEN_HEIGHT {
+      return Err(ImageError::OutOfBounds);
+    }
+    let len = width as usize * height as usize;
+    Ok(Self {
+      x,
+      y,
+      width,
+      height,
+      pixels: vec![0; len.div_ceil(8)],
+    })
+  }
+
+  /** Top left corner on the display */
+  pub fn position(&self) -> (u8, u8) {
+    (self.x, self.y)
+  }
+  pub fn size(&self) -> (u8, u8) {
+    (self.width, self.height)
+  }
+  /** Number of pixels */
+  pub fn len(&self) -> usize {
+    self.width as usize * self.height as usize
+  }
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /** Whether the pixel at row-major `index` is bright */
+  pub fn pixel(&self, index: usize) -> bool {
+    self.pixels[index / 8] & (0x80 >> (index % 8)) != 0
+  }
+  pub fn set_pixel(&mut self, index: usize, bright: bool) {
+    if bright {
+      self.pixels[index / 8] |= 0x80 >> (index % 8);
+    } else {
+      self.pixels[index / 8] &= !(0x80 >> (index % 8));
+    }
+  }
+  /** Pixels in row-major order, true for bright */
+  pub fn pixels(&self) -> impl Iterator<Item = bool> + '_ {
+    (0..self.len()).map(|i| self.pixel(i))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageMessage {
+  Show(Bitmap),
+  /** Sent as an empty rectangle */
+  Clear,
+}
+
+impl ImageMessage {
+  /**
+   * `[version, x, y, width, height, runs...]`
+   *
+   * The runs must cover exactly `width * height` pixels.
+   */
+  pub fn encode(&self) -> Vec<u8> {
+    match self {
+      Self::Show(bitmap) => {
+        let mut data = vec![VERSION, bitmap.x, bitmap.y, bitmap.width, bitmap.height];
+        data.extend_from_slice(&encode_runs(&run_lengths(bitmap.pixels())));
+        data
+      }
+      Self::Clear => vec![VERSION, 0, 0, 0, 0],
+    }
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, ImageError> {
+    let (&[version, x, y, width, height], mut data) =
+      data.split_first_chunk::<HEADER_LEN>().ok_or(ImageError::Truncated)?;
+    if version != VERSION {
+      return Err(ImageError::UnsupportedVersion(version));
+    }
+    if width == 0 || height == 0 {
+      return Ok(Self::Clear);
+    }
+
+    let mut bitmap = Bitmap::new(x, y, width, height)?;
+    let len = bitmap.len();
+    let mut position = 0u64;
+    let mut bright = false;
+    while !data.is_empty() {
+      let end = position + read_run(&mut data)? as u64;
+      if bright {
+        for i in position as usize..end.min(len as u64) as usize {
+          bitmap.set_pixel(i, true);
+        }
+      }
+      position = end;
+      bright = !bright;
+    }
+    if position != len as u64 {
+      return Err(ImageError::PixelCount(position, len));
+    }
+
+    Ok(Self::Show(bitmap))
+  }
+}
+
+/** Lengths of the alternating runs, starting with a possibly empty dark run */
+pub fn run_lengths(pixels: impl IntoIterator<Item = bool>) -> Vec<u32> {
+  let mut runs = Vec::new();
+  let mut bright = false;
+  let mut count = 0;
+  for pixel in pixels {
+    if pixel != bright {
+      runs.push(count);
+      bright = pixel;
+      count = 0;
+    }
+    count += 1;
+  }
+  runs.push(count);
+  runs
+}
+
+pub fn encode_runs(runs: &[u32]) -> Vec<u8> {
+  let mut data = Vec::with_capacity(runs.len());
+  for &run in runs {
+    if run <= MAX_SHORT_RUN {
+      data.push(run as u8);
+    } else if let Ok(run) = u16::try_from(run) {
+      data.push(U16_RUN);
+      data.extend_from_slice(&run.to_le_bytes());
+    } else {
+      data.push(U32_RUN);
+      data.extend_from_slice(&run.to_le_bytes());
+    }
+  }
+  data
+}
+
+fn read_run(data: &mut &[u8]) -> Result<u32, ImageError> {
+  let (&marker, rest) = data.split_first().ok_or(ImageError::Truncated)?;
+  *data = rest;
+  match marker {
+    0..=250 => Ok(marker as u32),
+    U16_RUN => {
+      let (bytes, rest) = data.split_first_chunk::<2>().ok_or(ImageError::Truncated)?;
+      *data = rest;
+      Ok(u16::from_le_bytes(*bytes) as u32)
+    }
+    U32_RUN => {
+      let (bytes, rest) = data.split_first_chunk::<4>().ok_or(ImageError::Truncated)?;
+      *data = rest;
+      Ok(u32::from_le_bytes(*bytes))
+    }
+    marker => Err(ImageError::InvalidMarker(marker)),
+  }
+}
@@ -0,0 +1,18 @@
+/*!
+ * Wire formats shared by the firmware and the host tools.
+ *
+ * Everything the phone and the device exchange over BLE is encoded and decoded here, so that it can be tested on the
+ * host. All multi-byte fields are little endian.
+ */
+#![no_std]
+
+extern crate alloc;
+
+pub mod dfu;
+pub mod image;
+pub mod map;
+pub mod movement;
+pub mod navigation;
+pub mod ride;
+pub mod settings;
+pub mod status;
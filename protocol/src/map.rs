@@ -0,0 +1,163 @@
+/*!
+ * Route geometry and position pushed by the phone for the mini-map.
+ */
+use alloc::vec;
+use alloc::vec::Vec;
+
+use thiserror::Error;
+
+const ROUTE_MESSAGE: u8 = 0x01;
+const POSITION_MESSAGE: u8 = 0x02;
+const NONE_U16: u16 = 0xffff;
+
+/** Largest latitude in microdegrees */
+pub const MAX_LAT: i32 = 90_000_000;
+/** Largest longitude in microdegrees */
+pub const MAX_LON: i32 = 180_000_000;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MapError {
+  #[error("message is empty")]
+  Empty,
+  #[error("unknown message type: {0:#04x}")]
+  UnknownType(u8),
+  #[error("message is truncated")]
+  Truncated,
+  #[error("route has no points")]
+  EmptyRoute,
+  #[error("consecutive route points are too far apart")]
+  GapTooLarge,
+  #[error("point is outside the valid coordinates")]
+  OutOfRange,
+}
+
+/** Latitude and longitude in microdegrees */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GeoPoint {
+  pub lat: i32,
+  pub lon: i32,
+}
+
+impl GeoPoint {
+  /** Whether the latitude is within ±90° and the longitude within ±180° */
+  pub fn is_valid(self) -> bool {
+    (-MAX_LAT..=MAX_LAT).contains(&self.lat) && (-MAX_LON..=MAX_LON).contains(&self.lon)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapMessage {
+  Route {
+    points: Vec<GeoPoint>,
+    maneuver: Option<usize>,
+  },
+  Position {
+    position: GeoPoint,
+    /** Degrees clockwise from north */
+    heading: Option<f32>,
+  },
+}
+
+impl MapMessage {
+  /**
+   * Wire format, little endian:
+   * - route   : 0x01, lat: i32, lon: i32, maneuver: u16, n x (dlat: i16, dlon: i16)
+   * - position: 0x02, lat: i32, lon: i32, heading: u16
+   *
+   * The first route point is absolute and the following ones are deltas from the previous point.
+   * `maneuver` is an index into the route points and `heading` is in 0.01 degrees, 0xffff means none.
+   * A maneuver index which does not fit is sent as none, the phone splits the route before that happens.
+   *
+   * Points outside ±90° latitude or ±180° longitude are rejected when decoding.
+   */
+  pub fn encode(&self) -> Result<Vec<u8>, MapError> {
+    match self {
+      Self::Route { points, maneuver } => {
+        let (first, rest) = points.split_first().ok_or(MapError::EmptyRoute)?;
+        let maneuver = maneuver.and_then(|i| u16::try_from(i).ok()).unwrap_or(NONE_U16);
+        let mut data = Vec::with_capacity(1 + 8 + 2 + rest.len() * 4);
+        data.push(ROUTE_MESSAGE);
+        write_point(&mut data, *first);
+        data.extend_from_slice(&maneuver.to_le_bytes());
+        let mut previous = *first;
+        for &point in rest {
+          let dlat = i16::try_from(point.lat as i64 - previous.lat as i64).map_err(|_| MapError::GapTooLarge)?;
+          let dlon = i16::try_from(point.lon as i64 - previous.lon as i64).map_err(|_| MapError::GapTooLarge)?;
+          data.extend_from_slice(&dlat.to_le_bytes());
+          data.extend_from_slice(&dlon.to_le_bytes());
+          previous = point;
+        }
+        Ok(data)
+      }
+      Self::Position { position, heading } => {
+        let heading = heading.map_or(NONE_U16, |heading| {
+          let heading = heading % 360.0;
+          let heading = if heading < 0.0 { heading + 360.0 } else { heading };
+          (heading * 100.0 + 0.5) as u16
+        });
+        let mut data = Vec::with_capacity(1 + 8 + 2);
+        data.push(POSITION_MESSAGE);
+        write_point(&mut data, *position);
+        data.extend_from_slice(&heading.to_le_bytes());
+        Ok(data)
+      }
+    }
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, MapError> {
+    let (&kind, mut data) = data.split_first().ok_or(MapError::Empty)?;
+    match kind {
+      ROUTE_MESSAGE => {
+        let mut point = check_point(read_point(&mut data)?)?;
+        let maneuver = read_u16(&mut data)?;
+        let mut points = vec![point];
+        while !data.is_empty() {
+          let dlat = read_u16(&mut data)? as i16 as i32;
+          let dlon = read_u16(&mut data)? as i16 as i32;
+          point = GeoPoint {
+            lat: point.lat.checked_add(dlat).ok_or(MapError::OutOfRange)?,
+            lon: point.lon.checked_add(dlon).ok_or(MapError::OutOfRange)?,
+          };
+          points.push(check_point(point)?);
+        }
+        Ok(Self::Route {
+          points,
+          maneuver: (maneuver != NONE_U16).then_some(maneuver as usize),
+        })
+      }
+      POSITION_MESSAGE => {
+        let position = check_point(read_point(&mut data)?)?;
+        let heading = read_u16(&mut data)?;
+        Ok(Self::Position {
+          position,
+          heading: (heading != NONE_U16).then_some(heading as f32 / 100.0),
+        })
+      }
+      kind => Err(MapError::UnknownType(kind)),
+    }
+  }
+}
+
+fn read_u16(data: &mut &[u8]) -> Result<u16, MapError> {
+  let (bytes, rest) = data.split_first_chunk::<2>().ok_or(MapError::Truncated)?;
+  *data = rest;
+  Ok(u16::from_le_bytes(*bytes))
+}
+
+fn read_point(data: &mut &[u8]) -> Result<GeoPoint, MapError> {
+  let (bytes, rest) = data.split_first_chunk::<8>().ok_or(MapError::Truncated)?;
+  *data = rest;
+  Ok(GeoPoint {
+    lat: i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    lon: i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+  })
+}
+
+fn check_point(point: GeoPoint) -> Result<GeoPoint, MapError> {
+  point.is_valid().then_some(point).ok_or(MapError::OutOfRange)
+}
+
+fn write_point(data: &mut Vec<u8>, point: GeoPoint) {
+  data.extend_from_slice(&point.lat.to_le_bytes());
+  data.extend_from_slice(&point.lon.to_le_bytes());
+}
@@ -0,0 +1,112 @@
+/*!
+ * Movement service: IMU samples notified at a period chosen by the phone.
+ */
+use core::time::Duration;
+
+use thiserror::Error;
+
+pub const GYRO_Z: u16 = 1 << 0;
+pub const GYRO_Y: u16 = 1 << 1;
+pub const GYRO_X: u16 = 1 << 2;
+pub const ACC_Z: u16 = 1 << 3;
+pub const ACC_Y: u16 = 1 << 4;
+pub const ACC_X: u16 = 1 << 5;
+pub const MAG: u16 = 1 << 6;
+const ALL_AXES: u16 = GYRO_X | GYRO_Y | GYRO_Z | ACC_X | ACC_Y | ACC_Z | MAG;
+
+pub const SAMPLE_LEN: usize = 18;
+
+/** 1 s */
+pub const DEFAULT_PERIOD: u8 = 100;
+/** 100 ms, the lower bound accepted by the app */
+pub const MIN_PERIOD: u8 = 10;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MovementError {
+  #[error("sample is truncated")]
+  Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MovementConfig {
+  pub enabled: u16,
+  /** In 10 ms units */
+  pub period: u8,
+}
+
+impl Default for MovementConfig {
+  fn default() -> Self {
+    Self {
+      enabled: 0,
+      period: DEFAULT_PERIOD,
+    }
+  }
+}
+
+impl MovementConfig {
+  pub fn is_enabled(&self) -> bool {
+    self.enabled & ALL_AXES != 0
+  }
+  pub fn period(&self) -> Duration {
+    Duration::from_millis(self.period as u64 * 10)
+  }
+
+  /** Apply a write to the config characteristic, returns false if the value is malformed */
+  pub fn write_enabled(&mut self, data: &[u8]) -> bool {
+    match *data {
+      [lo] => self.enabled = lo as u16,
+      [lo, hi] => self.enabled = u16::from_le_bytes([lo, hi]),
+      _ => return false,
+    }
+    true
+  }
+  /** Apply a write to the period characteristic, too short periods are clamped */
+  pub fn write_period(&mut self, data: &[u8]) -> bool {
+    let [period] = *data else {
+      return false;
+    };
+    self.period = period.max(MIN_PERIOD);
+    true
+  }
+}
+
+/**
+ * One sample in raw sensor units: 131 LSB per °/s for the gyroscope, 16384 LSB per g for the accelerometer and
+ * 1090 LSB per gauss for the magnetometer. Disabled axes are 0.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MovementSample {
+  /** x, y, z */
+  pub gyro: [i16; 3],
+  pub acc: [i16; 3],
+  pub mag: [i16; 3],
+}
+
+impl MovementSample {
+  /** `[gyro x, y, z, acc x, y, z, mag x, y, z]`, each an i16 */
+  pub fn encode(&self) -> [u8; SAMPLE_LEN] {
+    let mut data = [0; SAMPLE_LEN];
+    let values = self.gyro.iter().chain(&self.acc).chain(&self.mag);
+    for (chunk, value) in data.chunks_exact_mut(2).zip(values) {
+      chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    data
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, MovementError> {
+    let data: &[u8; SAMPLE_LEN] = data
+      .get(..SAMPLE_LEN)
+      .and_then(|data| data.try_into().ok())
+      .ok_or(MovementError::Truncated)?;
+    let mut values = [0; 9];
+    for (value, chunk) in values.iter_mut().zip(data.chunks_exact(2)) {
+      *value = i16::from_le_bytes([chunk[0], chunk[1]]);
+    }
+    let axes = |i: usize| [values[i], values[i + 1], values[i + 2]];
+    Ok(Self {
+      gyro: axes(0),
+      acc: axes(3),
+      mag: axes(6),
+    })
+  }
+}
@@ -0,0 +1,275 @@
+/*!
+ * Turn-by-turn instructions pushed by the phone, and the fragmentation used for messages longer than the ATT_MTU.
+ */
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+
+use thiserror::Error;
+
+/** Version of the instruction encoding, a newer version may only append fields */
+pub const VERSION: u8 = 1;
+/** Upper bound of a reassembled message */
+pub const MAX_MESSAGE_LEN: usize = 512;
+/** ATT_MTU before the MTU exchange */
+pub const DEFAULT_MTU: u16 = 23;
+/** Opcode and handle of a Write Request */
+const ATT_WRITE_HEADER: usize = 3;
+
+const FIRST_FRAGMENT: u8 = 0x80;
+const LAST_FRAGMENT: u8 = 0x40;
+const MESSAGE_ID_MASK: u8 = 0x3f;
+const NONE_U32: u32 = 0xffff_ffff;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NavigationError {
+  #[error("fragment is empty")]
+  Empty,
+  #[error("fragment of message {0} arrived without its first fragment")]
+  OutOfOrder(u8),
+  #[error("message is too large")]
+  TooLarge,
+  #[error("unsupported version: {0}")]
+  UnsupportedVersion(u8),
+  #[error("unknown maneuver: {0}")]
+  UnknownManeuver(u8),
+  #[error("unknown modifier: {0}")]
+  UnknownModifier(u8),
+  #[error("street name is not valid UTF-8")]
+  InvalidStreet,
+  #[error("message is truncated")]
+  Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Maneuver {
+  Depart,
+  Arrive,
+  Continue,
+  Turn,
+  Fork,
+  Merge,
+  OnRamp,
+  OffRamp,
+  EndOfRoad,
+  Roundabout,
+}
+
+impl Maneuver {
+  pub fn to_u8(self) -> u8 {
+    self as u8
+  }
+  pub fn from_u8(value: u8) -> Option<Self> {
+    Some(match value {
+      0 => Self::Depart,
+      1 => Self::Arrive,
+      2 => Self::Continue,
+      3 => Self::Turn,
+      4 => Self::Fork,
+      5 => Self::Merge,
+      6 => Self::OnRamp,
+      7 => Self::OffRamp,
+      8 => Self::EndOfRoad,
+      9 => Self::Roundabout,
+      _ => return None,
+    })
+  }
+}
+
+/** Direction of the maneuver */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+  None,
+  Straight,
+  SlightLeft,
+  Left,
+  SharpLeft,
+  SlightRight,
+  Right,
+  SharpRight,
+  UTurn,
+}
+
+impl Modifier {
+  pub fn to_u8(self) -> u8 {
+    self as u8
+  }
+  pub fn from_u8(value: u8) -> Option<Self> {
+    Some(match value {
+      0 => Self::None,
+      1 => Self::Straight,
+      2 => Self::SlightLeft,
+      3 => Self::Left,
+      4 => Self::SharpLeft,
+      5 => Self::SlightRight,
+      6 => Self::Right,
+      7 => Self::SharpRight,
+      8 => Self::UTurn,
+      _ => return None,
+    })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+  pub maneuver: Maneuver,
+  pub modifier: Modifier,
+  /** Meters to the maneuver */
+  pub distance: u32,
+  /** Street after the maneuver, may be empty */
+  pub street: String,
+  /** Meters to the destination */
+  pub remaining: u32,
+  /** Seconds to the destination */
+  pub eta: Option<u32>,
+}
+
+impl Instruction {
+  /**
+   * Wire format, little endian:
+   * version: u8, maneuver: u8, modifier: u8, distance: u32, remaining: u32, eta: u32, street_len: u8, street: utf-8
+   *
+   * `eta` is 0xffffffff when unknown. Fields appended by a newer encoder with the same version are ignored.
+   */
+  pub fn encode(&self) -> Vec<u8> {
+    let street = truncate_utf8(&self.street, u8::MAX as usize);
+    let mut data = Vec::with_capacity(16 + street.len());
+    data.extend_from_slice(&[VERSION, self.maneuver.to_u8(), self.modifier.to_u8()]);
+    data.extend_from_slice(&self.distance.to_le_bytes());
+    data.extend_from_slice(&self.remaining.to_le_bytes());
+    data.extend_from_slice(&self.eta.unwrap_or(NONE_U32).to_le_bytes());
+    data.push(street.len() as u8);
+    data.extend_from_slice(street.as_bytes());
+    data
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, NavigationError> {
+    let mut data = data;
+    let [version, maneuver, modifier] = *read::<3>(&mut data)?;
+    if version != VERSION {
+      return Err(NavigationError::UnsupportedVersion(version));
+    }
+    let maneuver = Maneuver::from_u8(maneuver).ok_or(NavigationError::UnknownManeuver(maneuver))?;
+    let modifier = Modifier::from_u8(modifier).ok_or(NavigationError::UnknownModifier(modifier))?;
+    let distance = u32::from_le_bytes(*read(&mut data)?);
+    let remaining = u32::from_le_bytes(*read(&mut data)?);
+    let eta = u32::from_le_bytes(*read(&mut data)?);
+    let [street_len] = *read::<1>(&mut data)?;
+    let street = data.get(..street_len as usize).ok_or(NavigationError::Truncated)?;
+    let street = core::str::from_utf8(street).map_err(|_| NavigationError::InvalidStreet)?;
+
+    Ok(Self {
+      maneuver,
+      modifier,
+      distance,
+      street: street.to_string(),
+      remaining,
+      eta: (eta != NONE_U32).then_some(eta),
+    })
+  }
+}
+
+fn read<'a, const N: usize>(data: &mut &'a [u8]) -> Result<&'a [u8; N], NavigationError> {
+  let (bytes, rest) = data.split_first_chunk::<N>().ok_or(NavigationError::Truncated)?;
+  *data = rest;
+  Ok(bytes)
+}
+
+fn truncate_utf8(text: &str, max_len: usize) -> &str {
+  let mut end = text.len().min(max_len);
+  while !text.is_char_boundary(end) {
+    end -= 1;
+  }
+  &text[..end]
+}
+
+/**
+ * Split a message into writes that fit the negotiated ATT_MTU.
+ *
+ * Each fragment starts with a header byte: bit 7 marks the first fragment, bit 6 the last one and
+ * bits 0-5 carry the message id, so that fragments of an interrupted message are not mixed with the next one.
+ */
+pub fn fragment(message: &[u8], id: u8, mtu: u16) -> Vec<Vec<u8>> {
+  let chunk_len = (mtu as usize).saturating_sub(ATT_WRITE_HEADER + 1).max(1);
+  let chunks: Vec<&[u8]> = if message.is_empty() {
+    vec![&[]]
+  } else {
+    message.chunks(chunk_len).collect()
+  };
+  let count = chunks.len();
+
+  chunks
+    .into_iter()
+    .enumerate()
+    .map(|(i, chunk)| {
+      let mut header = id & MESSAGE_ID_MASK;
+      if i == 0 {
+        header |= FIRST_FRAGMENT;
+      }
+      if i == count - 1 {
+        header |= LAST_FRAGMENT;
+      }
+      let mut fragment = Vec::with_capacity(chunk.len() + 1);
+      fragment.push(header);
+      fragment.extend_from_slice(chunk);
+      fragment
+    })
+    .collect()
+}
+
+/** Collects the fragments written by one peer */
+#[derive(Debug, Clone)]
+pub struct Reassembler {
+  id: Option<u8>,
+  buffer: Vec<u8>,
+  limit: usize,
+}
+
+impl Default for Reassembler {
+  fn default() -> Self {
+    Self::with_limit(MAX_MESSAGE_LEN)
+  }
+}
+
+impl Reassembler {
+  /** Reassembler for messages up to `limit` bytes */
+  pub fn with_limit(limit: usize) -> Self {
+    Self {
+      id: None,
+      buffer: Vec::new(),
+      limit,
+    }
+  }
+
+  /** Feed one fragment, returns the message once its last fragment arrived */
+  pub fn push(&mut self, fragment: &[u8]) -> Result<Option<Vec<u8>>, NavigationError> {
+    let (&header, chunk) = fragment.split_first().ok_or(NavigationError::Empty)?;
+    let id = header & MESSAGE_ID_MASK;
+
+    if header & FIRST_FRAGMENT != 0 {
+      self.id = Some(id);
+      self.buffer.clear();
+    } else if self.id != Some(id) {
+      self.reset();
+      return Err(NavigationError::OutOfOrder(id));
+    }
+
+    if self.buffer.len() + chunk.len() > self.limit {
+      self.reset();
+      return Err(NavigationError::TooLarge);
+    }
+    self.buffer.extend_from_slice(chunk);
+
+    if header & LAST_FRAGMENT != 0 {
+      self.id = None;
+      return Ok(Some(mem::take(&mut self.buffer)));
+    }
+    Ok(None)
+  }
+
+  pub fn reset(&mut self) {
+    self.id = None;
+    self.buffer.clear();
+  }
+}
@@ -0,0 +1,88 @@
+/*!
+ * Ride log: the cycling sensor values sampled at a fixed interval during a ride.
+ */
+use alloc::vec::Vec;
+
+use thiserror::Error;
+
+pub const SAMPLE_LEN: usize = 10;
+const NONE_U8: u8 = 0xff;
+const NONE_U16: u16 = 0xffff;
+const NONE_I16: i16 = i16::MIN;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RideLogError {
+  #[error("log is truncated")]
+  Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RideSample {
+  /** Unix time, `None` until the phone set the clock */
+  pub time: Option<u32>,
+  /** Centimeters per second */
+  pub speed: Option<u16>,
+  /** Crank revolutions per minute */
+  pub cadence: Option<u8>,
+  /** Beats per minute */
+  pub heart_rate: Option<u8>,
+  /** Watts */
+  pub power: Option<i16>,
+}
+
+impl RideSample {
+  /** Whether no sensor reported anything, such samples are not worth logging */
+  pub fn is_empty(&self) -> bool {
+    self.speed.is_none() && self.cadence.is_none() && self.heart_rate.is_none() && self.power.is_none()
+  }
+
+  /**
+   * Wire format, little endian:
+   * time: u32 (0: unknown), speed: u16 (0xffff: none), cadence and heart rate: u8 (0xff: none),
+   * power: i16 (i16::MIN: none)
+   *
+   * Values which collide with the none markers are clamped below them.
+   */
+  pub fn encode(&self) -> [u8; SAMPLE_LEN] {
+    let speed = self.speed.map_or(NONE_U16, |speed| speed.min(NONE_U16 - 1));
+    let power = self.power.map_or(NONE_I16, |power| power.max(NONE_I16 + 1));
+    let mut data = [0; SAMPLE_LEN];
+    data[..4].copy_from_slice(&self.time.unwrap_or(0).to_le_bytes());
+    data[4..6].copy_from_slice(&speed.to_le_bytes());
+    data[6] = self.cadence.map_or(NONE_U8, |cadence| cadence.min(NONE_U8 - 1));
+    data[7] = self
+      .heart_rate
+      .map_or(NONE_U8, |heart_rate| heart_rate.min(NONE_U8 - 1));
+    data[8..].copy_from_slice(&power.to_le_bytes());
+    data
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, RideLogError> {
+    let data: &[u8; SAMPLE_LEN] = data
+      .get(..SAMPLE_LEN)
+      .and_then(|data| data.try_into().ok())
+      .ok_or(RideLogError::Truncated)?;
+    let time = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    let speed = u16::from_le_bytes([data[4], data[5]]);
+    let power = i16::from_le_bytes([data[8], data[9]]);
+    Ok(Self {
+      time: (time != 0).then_some(time),
+      speed: (speed != NONE_U16).then_some(speed),
+      cadence: (data[6] != NONE_U8).then_some(data[6]),
+      heart_rate: (data[7] != NONE_U8).then_some(data[7]),
+      power: (power != NONE_I16).then_some(power),
+    })
+  }
+}
+
+/** The samples back to back, oldest first */
+pub fn encode_log<'a>(samples: impl IntoIterator<Item = &'a RideSample>) -> Vec<u8> {
+  samples.into_iter().flat_map(|sample| sample.encode()).collect()
+}
+
+pub fn decode_log(data: &[u8]) -> Result<Vec<RideSample>, RideLogError> {
+  if data.len() % SAMPLE_LEN != 0 {
+    return Err(RideLogError::Truncated);
+  }
+  data.chunks_exact(SAMPLE_LEN).map(RideSample::decode).collect()
+}
@@ -0,0 +1,141 @@
+/*!
+ * Display, audio and sensor settings, written by the phone and persisted by the device as the same record.
+ */
+use thiserror::Error;
+
+use crate::movement::{DEFAULT_PERIOD, MIN_PERIOD};
+
+pub const VERSION: u8 = 1;
+pub const RECORD_LEN: usize = 8;
+const MUTED: u8 = 1 << 0;
+
+/** MPU6050 digital low pass filter setting: 0 is 260 Hz, 5 is 10 Hz, 6 is 5 Hz */
+pub const DEFAULT_LOW_PASS: u8 = 5;
+pub const MAX_LOW_PASS: u8 = 6;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SettingsError {
+  #[error("record is truncated")]
+  Truncated,
+  #[error("unsupported version {0}")]
+  UnsupportedVersion(u8),
+  #[error("invalid {0}")]
+  Invalid(&'static str),
+}
+
+/** Waveform used when a page is redrawn */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lut {
+  /** Quick but leaves some ghosting */
+  #[default]
+  Fast,
+  /** Cleaner but blinks */
+  Normal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Units {
+  #[default]
+  Metric,
+  Imperial,
+}
+
+/**
+`[version, lut, full_refresh_every, volume, flags, imu_period, imu_low_pass, units]`
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+  pub lut: Lut,
+  /** Full refresh after this many page redraws to clear the ghosting, 0 to never */
+  pub full_refresh_every: u8,
+  /** Alert cue volume in percent */
+  pub volume: u8,
+  pub muted: bool,
+  /** Movement sampling period in 10 ms units, also written through the movement period characteristic */
+  pub imu_period: u8,
+  /** MPU6050 digital low pass filter setting, higher is smoother */
+  pub imu_low_pass: u8,
+  pub units: Units,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Self {
+      lut: Lut::Fast,
+      full_refresh_every: 0,
+      volume: 100,
+      muted: false,
+      imu_period: DEFAULT_PERIOD,
+      imu_low_pass: DEFAULT_LOW_PASS,
+      units: Units::Metric,
+    }
+  }
+}
+
+impl Settings {
+  pub fn encode(&self) -> [u8; RECORD_LEN] {
+    [
+      VERSION,
+      match self.lut {
+        Lut::Fast => 0,
+        Lut::Normal => 1,
+      },
+      self.full_refresh_every,
+      self.volume,
+      if self.muted { MUTED } else { 0 },
+      self.imu_period,
+      self.imu_low_pass,
+      match self.units {
+        Units::Metric => 0,
+        Units::Imperial => 1,
+      },
+    ]
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, SettingsError> {
+    let &version = data.first().ok_or(SettingsError::Truncated)?;
+    if version != VERSION {
+      return Err(SettingsError::UnsupportedVersion(version));
+    }
+    let data: [u8; RECORD_LEN] = data
+      .get(..RECORD_LEN)
+      .and_then(|data| data.try_into().ok())
+      .ok_or(SettingsError::Truncated)?;
+
+    Ok(Self {
+      lut: match data[1] {
+        0 => Lut::Fast,
+        1 => Lut::Normal,
+        _ => return Err(SettingsError::Invalid("lut")),
+      },
+      full_refresh_every: data[2],
+      volume: match data[3] {
+        volume @ 0..=100 => volume,
+        _ => return Err(SettingsError::Invalid("volume")),
+      },
+      muted: data[4] & MUTED != 0,
+      imu_period: match data[5] {
+        period if period >= MIN_PERIOD => period,
+        _ => return Err(SettingsError::Invalid("imu period")),
+      },
+      imu_low_pass: match data[6] {
+        low_pass @ 0..=MAX_LOW_PASS => low_pass,
+        _ => return Err(SettingsError::Invalid("imu low pass")),
+      },
+      units: match data[7] {
+        0 => Units::Metric,
+        1 => Units::Imperial,
+        _ => return Err(SettingsError::Invalid("units")),
+      },
+    })
+  }
+
+  /** Volume to play the alert cues at, 0 if muted */
+  pub fn effective_volume(&self) -> u8 {
+    if self.muted {
+      0
+    } else {
+      self.volume
+    }
+  }
+}
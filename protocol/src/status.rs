@@ -0,0 +1,71 @@
+/*!
+ * Phone status shown in the status bar: battery, GPS fix and the time the device clock is set from.
+ */
+use thiserror::Error;
+
+pub const STATUS_LEN: usize = 8;
+const NONE_U8: u8 = 0xff;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PhoneStatusError {
+  #[error("message is truncated")]
+  Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GpsFix {
+  #[default]
+  Unknown,
+  NoFix,
+  Fix,
+}
+
+/** Status reported by the phone */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PhoneStatus {
+  /** Percent */
+  pub battery: Option<u8>,
+  pub gps_fix: GpsFix,
+  /** Unix time and UTC offset in minutes */
+  pub time: Option<(i64, i32)>,
+}
+
+impl PhoneStatus {
+  /**
+   * Wire format, little endian:
+   * battery: u8 (percent, 0xff: unknown), gps fix: u8 (0: unknown, 1: no fix, 2: fix),
+   * time: u32 (unix time, 0: unknown), utc offset: i16 (minutes)
+   *
+   * The time must fit the fields, it is truncated otherwise.
+   */
+  pub fn encode(&self) -> [u8; STATUS_LEN] {
+    let gps_fix = match self.gps_fix {
+      GpsFix::Unknown => 0,
+      GpsFix::NoFix => 1,
+      GpsFix::Fix => 2,
+    };
+    let (time, utc_offset) = self.time.map_or((0, 0), |(time, offset)| (time as u32, offset as i16));
+    let mut data = [self.battery.unwrap_or(NONE_U8), gps_fix, 0, 0, 0, 0, 0, 0];
+    data[2..6].copy_from_slice(&time.to_le_bytes());
+    data[6..].copy_from_slice(&utc_offset.to_le_bytes());
+    data
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, PhoneStatusError> {
+    let data: &[u8; STATUS_LEN] = data
+      .get(..STATUS_LEN)
+      .and_then(|data| data.try_into().ok())
+      .ok_or(PhoneStatusError::Truncated)?;
+    let time = u32::from_le_bytes([data[2], data[3], data[4], data[5]]);
+    let utc_offset = i16::from_le_bytes([data[6], data[7]]);
+    Ok(Self {
+      battery: (data[0] != NONE_U8).then_some(data[0].min(100)),
+      gps_fix: match data[1] {
+        1 => GpsFix::NoFix,
+        2 => GpsFix::Fix,
+        _ => GpsFix::Unknown,
+      },
+      time: (time != 0).then_some((time as i64, utc_offset as i32)),
+    })
+  }
+}
@@ -0,0 +1,453 @@
+use navelo_protocol::dfu::{self, Command, DfuError, DfuState};
+use navelo_protocol::image::{self, Bitmap, ImageError, ImageMessage, SCREEN_HEIGHT, SCREEN_WIDTH};
+use navelo_protocol::map::{GeoPoint, MapError, MapMessage, MAX_LAT, MAX_LON};
+use navelo_protocol::movement::{MovementSample, SAMPLE_LEN};
+use navelo_protocol::navigation::{
+  fragment, Instruction, Maneuver, Modifier, NavigationError, Reassembler, DEFAULT_MTU, MAX_MESSAGE_LEN,
+};
+use navelo_protocol::ride::{self, RideLogError, RideSample};
+use navelo_protocol::settings::{Lut, Settings, SettingsError, Units, MAX_LOW_PASS, RECORD_LEN};
+use navelo_protocol::status::{GpsFix, PhoneStatus, STATUS_LEN};
+use proptest::prelude::*;
+
+fn maneuver() -> impl Strategy<Value = Maneuver> {
+  (0..=9u8).prop_map(|value| Maneuver::from_u8(value).unwrap())
+}
+
+fn modifier() -> impl Strategy<Value = Modifier> {
+  (0..=8u8).prop_map(|value| Modifier::from_u8(value).unwrap())
+}
+
+fn instruction() -> impl Strategy<Value = Instruction> {
+  (
+    maneuver(),
+    modifier(),
+    any::<u32>(),
+    "\\PC{0,60}",
+    any::<u32>(),
+    prop::option::of(0..u32::MAX),
+  )
+    .prop_map(|(maneuver, modifier, distance, street, remaining, eta)| Instruction {
+      maneuver,
+      modifier,
+      distance,
+      street,
+      remaining,
+      eta,
+    })
+}
+
+fn settings() -> impl Strategy<Value = Settings> {
+  (
+    any::<bool>(),
+    any::<u8>(),
+    0..=100u8,
+    any::<bool>(),
+    10..=u8::MAX,
+    0..=MAX_LOW_PASS,
+    any::<bool>(),
+  )
+    .prop_map(
+      |(normal, full_refresh_every, volume, muted, imu_period, imu_low_pass, imperial)| Settings {
+        lut: if normal { Lut::Normal } else { Lut::Fast },
+        full_refresh_every,
+        volume,
+        muted,
+        imu_period,
+        imu_low_pass,
+        units: if imperial { Units::Imperial } else { Units::Metric },
+      },
+    )
+}
+
+fn bitmap() -> impl Strategy<Value = Bitmap> {
+  (0..SCREEN_WIDTH as u8, 0..SCREEN_HEIGHT as u8)
+    .prop_flat_map(|(x, y)| {
+      (
+        Just(x),
+        Just(y),
+        1..=SCREEN_WIDTH as u8 - x,
+        1..=SCREEN_HEIGHT as u8 - y,
+      )
+    })
+    .prop_flat_map(|(x, y, width, height)| {
+      let len = width as usize * height as usize;
+      // Long runs exercise the u16 marker, noise the single byte runs
+      let pixels = prop_oneof![
+        prop::collection::vec(any::<bool>(), len),
+        (0..=len).prop_map(move |split| (0..len).map(|i| i >= split).collect()),
+      ];
+      (Just(x), Just(y), Just(width), Just(height), pixels)
+    })
+    .prop_map(|(x, y, width, height, pixels)| {
+      let mut bitmap = Bitmap::new(x, y, width, height).unwrap();
+      for (i, bright) in pixels.into_iter().enumerate() {
+        bitmap.set_pixel(i, bright);
+      }
+      bitmap
+    })
+}
+
+fn geo_point() -> impl Strategy<Value = GeoPoint> {
+  (-MAX_LAT..=MAX_LAT, -MAX_LON..=MAX_LON).prop_map(|(lat, lon)| GeoPoint { lat, lon })
+}
+
+fn map_message() -> impl Strategy<Value = MapMessage> {
+  prop_oneof![
+    (
+      geo_point(),
+      prop::collection::vec((any::<i16>(), any::<i16>()), 0..64),
+      prop::option::of(0..u16::MAX as usize),
+    )
+      .prop_filter_map("route leaves the valid coordinates", |(first, deltas, maneuver)| {
+        let mut point = first;
+        let mut points = vec![first];
+        for (dlat, dlon) in deltas {
+          point = GeoPoint {
+            lat: point.lat + dlat as i32,
+            lon: point.lon + dlon as i32,
+          };
+          points.push(point);
+        }
+        points
+          .iter()
+          .all(|point| point.is_valid())
+          .then_some(MapMessage::Route { points, maneuver })
+      }),
+    (geo_point(), prop::option::of(0..36000u16)).prop_map(|(position, heading)| MapMessage::Position {
+      position,
+      heading: heading.map(|heading| heading as f32 / 100.0),
+    }),
+  ]
+}
+
+fn phone_status() -> impl Strategy<Value = PhoneStatus> {
+  (
+    prop::option::of(0..=100u8),
+    prop_oneof![Just(GpsFix::Unknown), Just(GpsFix::NoFix), Just(GpsFix::Fix)],
+    prop::option::of((1..=u32::MAX, any::<i16>())),
+  )
+    .prop_map(|(battery, gps_fix, time)| PhoneStatus {
+      battery,
+      gps_fix,
+      time: time.map(|(time, offset)| (time as i64, offset as i32)),
+    })
+}
+
+fn ride_sample() -> impl Strategy<Value = RideSample> {
+  (
+    prop::option::of(1..=u32::MAX),
+    prop::option::of(0..0xffffu16),
+    prop::option::of(0..0xffu8),
+    prop::option::of(0..0xffu8),
+    prop::option::of(i16::MIN + 1..=i16::MAX),
+  )
+    .prop_map(|(time, speed, cadence, heart_rate, power)| RideSample {
+      time,
+      speed,
+      cadence,
+      heart_rate,
+      power,
+    })
+}
+
+fn dfu_error() -> impl Strategy<Value = DfuError> {
+  (1..=10u8).prop_map(|code| DfuError::from_code(code).unwrap())
+}
+
+fn dfu_command() -> impl Strategy<Value = Command> {
+  prop_oneof![
+    (
+      any::<u32>(),
+      any::<[u8; 32]>(),
+      prop::collection::vec(any::<u8>(), 1..80)
+    )
+      .prop_map(|(size, hash, signature)| Command::Start { size, hash, signature }),
+    Just(Command::Abort),
+    Just(Command::Finish),
+  ]
+}
+
+fn dfu_state() -> impl Strategy<Value = DfuState> {
+  prop_oneof![
+    Just(DfuState::Idle),
+    (any::<u32>(), any::<u32>()).prop_map(|(received, size)| DfuState::Receiving { received, size }),
+    Just(DfuState::Done),
+    dfu_error().prop_map(DfuState::Failed),
+  ]
+}
+
+proptest! {
+  #[test]
+  fn instruction_roundtrip(instruction in instruction()) {
+    prop_assert_eq!(Instruction::decode(&instruction.encode()), Ok(instruction));
+  }
+
+  #[test]
+  fn instruction_ignores_appended_fields(instruction in instruction(), extra in prop::collection::vec(any::<u8>(), 0..16)) {
+    let mut data = instruction.encode();
+    data.extend_from_slice(&extra);
+    prop_assert_eq!(Instruction::decode(&data), Ok(instruction));
+  }
+
+  #[test]
+  fn instruction_truncated(instruction in instruction(), cut in any::<prop::sample::Index>()) {
+    let data = instruction.encode();
+    let data = &data[..cut.index(data.len())];
+    prop_assert_eq!(Instruction::decode(data), Err(NavigationError::Truncated));
+  }
+
+  #[test]
+  fn instruction_long_street_is_truncated_on_char_boundary(street in "\\PC{200,400}") {
+    let instruction = Instruction {
+      maneuver: Maneuver::Turn,
+      modifier: Modifier::Left,
+      distance: 0,
+      street: street.clone(),
+      remaining: 0,
+      eta: None,
+    };
+    let decoded = Instruction::decode(&instruction.encode()).unwrap();
+    prop_assert!(decoded.street.len() <= u8::MAX as usize);
+    prop_assert!(street.starts_with(&decoded.street));
+  }
+
+  #[test]
+  fn fragments_reassemble(
+    message in prop::collection::vec(any::<u8>(), 0..=MAX_MESSAGE_LEN),
+    id in any::<u8>(),
+    mtu in DEFAULT_MTU..=517,
+  ) {
+    let fragments = fragment(&message, id, mtu);
+    let mut reassembler = Reassembler::default();
+    for (i, fragment) in fragments.iter().enumerate() {
+      prop_assert!(fragment.len() <= mtu as usize - 3);
+      let result = reassembler.push(fragment).unwrap();
+      if i == fragments.len() - 1 {
+        prop_assert_eq!(result.as_deref(), Some(&message[..]));
+      } else {
+        prop_assert_eq!(result, None);
+      }
+    }
+  }
+
+  #[test]
+  fn interrupted_message_is_replaced(
+    first in prop::collection::vec(any::<u8>(), 100..300),
+    second in prop::collection::vec(any::<u8>(), 0..300),
+  ) {
+    let mut reassembler = Reassembler::default();
+    let interrupted = fragment(&first, 1, DEFAULT_MTU);
+    reassembler.push(&interrupted[0]).unwrap();
+    let mut result = None;
+    for fragment in fragment(&second, 2, DEFAULT_MTU) {
+      result = reassembler.push(&fragment).unwrap();
+    }
+    prop_assert_eq!(result, Some(second));
+    prop_assert_eq!(reassembler.push(&interrupted[1]), Err(NavigationError::OutOfOrder(1)));
+  }
+
+  #[test]
+  fn settings_roundtrip(settings in settings()) {
+    prop_assert_eq!(Settings::decode(&settings.encode()), Ok(settings));
+  }
+
+  #[test]
+  fn settings_decode_never_panics(data in prop::collection::vec(any::<u8>(), 0..16)) {
+    if let Ok(settings) = Settings::decode(&data) {
+      prop_assert_eq!(&settings.encode()[..], &data[..RECORD_LEN]);
+    }
+  }
+
+  #[test]
+  fn movement_sample_roundtrip(gyro in any::<[i16; 3]>(), acc in any::<[i16; 3]>(), mag in any::<[i16; 3]>()) {
+    let sample = MovementSample { gyro, acc, mag };
+    prop_assert_eq!(MovementSample::decode(&sample.encode()), Ok(sample));
+  }
+
+  #[test]
+  fn movement_sample_truncated(data in prop::collection::vec(any::<u8>(), 0..SAMPLE_LEN)) {
+    prop_assert!(MovementSample::decode(&data).is_err());
+  }
+
+  #[test]
+  fn ride_sample_roundtrip(sample in ride_sample()) {
+    prop_assert_eq!(RideSample::decode(&sample.encode()), Ok(sample));
+  }
+
+  #[test]
+  fn ride_log_roundtrip(samples in prop::collection::vec(ride_sample(), 0..32)) {
+    prop_assert_eq!(ride::decode_log(&ride::encode_log(&samples)), Ok(samples));
+  }
+
+  #[test]
+  fn ride_log_truncated(samples in prop::collection::vec(ride_sample(), 1..32), cut in 1..ride::SAMPLE_LEN) {
+    let data = ride::encode_log(&samples);
+    prop_assert_eq!(ride::decode_log(&data[..data.len() - cut]), Err(RideLogError::Truncated));
+  }
+
+  #[test]
+  fn image_roundtrip(bitmap in bitmap()) {
+    let message = ImageMessage::Show(bitmap);
+    prop_assert_eq!(ImageMessage::decode(&message.encode()), Ok(message));
+  }
+
+  #[test]
+  fn runs_roundtrip(runs in prop::collection::vec(any::<u32>(), 0..64)) {
+    let pixels: u64 = runs.iter().map(|&run| run as u64).sum();
+    let mut data = vec![image::VERSION, 0, 0, 1, 1];
+    data.extend_from_slice(&image::encode_runs(&runs));
+    // Only a single pixel fits, so any other total is reported with the decoded count
+    let expected = if pixels == 1 { None } else { Some(ImageError::PixelCount(pixels, 1)) };
+    prop_assert_eq!(ImageMessage::decode(&data).err(), expected);
+  }
+
+  #[test]
+  fn image_decode_never_panics(data in prop::collection::vec(any::<u8>(), 0..64)) {
+    let _ = ImageMessage::decode(&data);
+  }
+
+  #[test]
+  fn map_message_roundtrip(message in map_message()) {
+    prop_assert_eq!(MapMessage::decode(&message.encode().unwrap()), Ok(message));
+  }
+
+  #[test]
+  fn map_message_decode_never_panics(data in prop::collection::vec(any::<u8>(), 0..64)) {
+    let _ = MapMessage::decode(&data);
+  }
+
+  #[test]
+  fn phone_status_roundtrip(status in phone_status()) {
+    prop_assert_eq!(PhoneStatus::decode(&status.encode()), Ok(status));
+  }
+
+  #[test]
+  fn phone_status_truncated(data in prop::collection::vec(any::<u8>(), 0..STATUS_LEN)) {
+    prop_assert!(PhoneStatus::decode(&data).is_err());
+  }
+
+  #[test]
+  fn dfu_command_roundtrip(command in dfu_command()) {
+    prop_assert_eq!(Command::decode(&command.encode()), Ok(command));
+  }
+
+  #[test]
+  fn dfu_chunk_roundtrip(offset in any::<u32>(), data in prop::collection::vec(any::<u8>(), 0..256)) {
+    let chunk = dfu::encode_chunk(offset, &data);
+    prop_assert_eq!(dfu::decode_chunk(&chunk), Ok((offset, &data[..])));
+  }
+
+  #[test]
+  fn dfu_state_roundtrip(state in dfu_state()) {
+    prop_assert_eq!(DfuState::decode(&state.encode()), Some(state));
+  }
+
+  #[test]
+  fn dfu_decode_never_panics(data in prop::collection::vec(any::<u8>(), 0..64)) {
+    let _ = Command::decode(&data);
+    if let Some(state) = DfuState::decode(&data) {
+      prop_assert_eq!(state.encode()[0], data[0]);
+    }
+  }
+}
+
+#[test]
+fn image_clear_roundtrip() {
+  assert_eq!(
+    ImageMessage::decode(&ImageMessage::Clear.encode()),
+    Ok(ImageMessage::Clear)
+  );
+}
+
+#[test]
+fn image_out_of_bounds() {
+  assert_eq!(Bitmap::new(150, 0, 51, 10), Err(ImageError::OutOfBounds));
+  assert_eq!(
+    ImageMessage::decode(&[image::VERSION, 0, 199, 1, 2, 2]),
+    Err(ImageError::OutOfBounds)
+  );
+}
+
+#[test]
+fn run_lengths_start_dark() {
+  assert_eq!(image::run_lengths([true, true, false]), [0, 2, 1]);
+  assert_eq!(image::run_lengths([false, false]), [2]);
+}
+
+#[test]
+fn settings_reject_invalid_fields() {
+  let mut data = Settings::default().encode();
+  data[3] = 101;
+  assert_eq!(Settings::decode(&data), Err(SettingsError::Invalid("volume")));
+  assert_eq!(Settings::decode(&[2]), Err(SettingsError::UnsupportedVersion(2)));
+}
+
+#[test]
+fn map_route_gap_too_large() {
+  let points = vec![GeoPoint { lat: 0, lon: 0 }, GeoPoint { lat: 40_000, lon: 0 }];
+  let message = MapMessage::Route { points, maneuver: None };
+  assert_eq!(message.encode(), Err(MapError::GapTooLarge));
+}
+
+#[test]
+fn dfu_start_needs_a_signature() {
+  let mut data = Command::Start {
+    size: 1,
+    hash: [0; 32],
+    signature: vec![0x30],
+  }
+  .encode();
+  data.pop();
+  assert_eq!(Command::decode(&data), Err(DfuError::Truncated));
+  assert_eq!(Command::decode(&[9]), Err(DfuError::UnknownCommand));
+}
+
+#[test]
+fn map_rejects_points_out_of_range() {
+  let mut data = vec![0x02];
+  data.extend_from_slice(&(MAX_LAT + 1).to_le_bytes());
+  data.extend_from_slice(&0i32.to_le_bytes());
+  data.extend_from_slice(&0xffffu16.to_le_bytes());
+  assert_eq!(MapMessage::decode(&data), Err(MapError::OutOfRange));
+
+  // a delta that would overflow the accumulated latitude
+  let mut data = vec![0x01];
+  data.extend_from_slice(&i32::MAX.to_le_bytes());
+  data.extend_from_slice(&0i32.to_le_bytes());
+  data.extend_from_slice(&0xffffu16.to_le_bytes());
+  data.extend_from_slice(&i16::MAX.to_le_bytes());
+  data.extend_from_slice(&0i16.to_le_bytes());
+  assert_eq!(MapMessage::decode(&data), Err(MapError::OutOfRange));
+
+  let mut data = vec![0x01];
+  data.extend_from_slice(&MAX_LAT.to_le_bytes());
+  data.extend_from_slice(&0i32.to_le_bytes());
+  data.extend_from_slice(&0xffffu16.to_le_bytes());
+  data.extend_from_slice(&1i16.to_le_bytes());
+  data.extend_from_slice(&0i16.to_le_bytes());
+  assert_eq!(MapMessage::decode(&data), Err(MapError::OutOfRange));
+}
+
+#[test]
+fn ride_sample_clamps_values_to_the_none_markers() {
+  let sample = RideSample {
+    time: Some(1),
+    speed: Some(u16::MAX),
+    cadence: Some(u8::MAX),
+    heart_rate: Some(u8::MAX),
+    power: Some(i16::MIN),
+  };
+  assert_eq!(
+    RideSample::decode(&sample.encode()),
+    Ok(RideSample {
+      time: Some(1),
+      speed: Some(u16::MAX - 1),
+      cadence: Some(u8::MAX - 1),
+      heart_rate: Some(u8::MAX - 1),
+      power: Some(i16::MIN + 1),
+    })
+  );
+  assert!(RideSample::default().is_empty());
+  assert!(!sample.is_empty());
+}
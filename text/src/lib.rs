@@ -0,0 +1,242 @@
+/*!
+ * Text layout for the on-device screens: line wrapping with kinsoku, per-paragraph bidi and ellipsis truncation.
+ *
+ * It only needs the width of each character, so that it can be tested on the host without the bitmap fonts.
+ */
+#![no_std]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const ELLIPSIS: char = '\u{2026}';
+
+/** Characters which should not be placed at the start of a line (kinsoku) */
+const NO_LINE_START: &[char] = &[
+  '、', '。', '，', '．', '・', '：', '；', '？', '！', 'ー', '）', '」', '』', '】', '〕', 'ぁ', 'ぃ', 'ぅ', 'ぇ',
+  'ぉ', 'っ', 'ゃ', 'ゅ', 'ょ', 'ァ', 'ィ', 'ゥ', 'ェ', 'ォ', 'ッ', 'ャ', 'ュ', 'ョ', ',', '.', ':', ';', '!', '?',
+  ')', ']',
+];
+
+/** Advance widths of a font */
+pub trait Metrics {
+  /** Width in pixels, missing glyphs included */
+  fn char_width(&self, c: char) -> u32;
+
+  fn text_width(&self, text: &str) -> u32 {
+    text.chars().map(|c| self.char_width(c)).sum()
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wrap {
+  /** Break at spaces and between CJK characters, falls back to `Char` for long words */
+  Word,
+  /** Break anywhere */
+  Char,
+  /** Single line per paragraph, truncated with an ellipsis */
+  None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+  Ltr,
+  Rtl,
+}
+
+/** A laid out line, in visual order */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+  pub text: String,
+  pub width: u32,
+  pub direction: Direction,
+}
+
+/**
+ * Break `text` into lines no wider than `max_width`, honoring `\n`, and reorder them for display.
+ * Lines past `max_lines` are dropped, and the last kept line gets an ellipsis.
+ */
+pub fn layout(text: &str, max_width: u32, metrics: &impl Metrics, wrap: Wrap, max_lines: Option<usize>) -> Vec<Line> {
+  // each paragraph has its own direction, e.g. a Hebrew street name under an English instruction
+  let mut lines = Vec::new();
+  let mut directions = Vec::new();
+  for paragraph in text.split('\n') {
+    let chars: Vec<char> = paragraph.chars().collect();
+    wrap_paragraph(metrics, &chars, max_width, wrap, &mut lines);
+    directions.resize(lines.len(), base_direction(paragraph));
+  }
+
+  if let Some(max_lines) = max_lines {
+    if lines.len() > max_lines {
+      lines.truncate(max_lines);
+      if let Some(last) = lines.last_mut() {
+        *last = truncate(metrics, last, max_width, true);
+      }
+    }
+  }
+  if wrap == Wrap::None {
+    for line in lines.iter_mut() {
+      *line = truncate(metrics, line, max_width, false);
+    }
+  }
+
+  lines
+    .into_iter()
+    .zip(directions)
+    .map(|(line, direction)| Line {
+      width: metrics.text_width(&line),
+      text: reorder(&line, direction),
+      direction,
+    })
+    .collect()
+}
+
+fn wrap_paragraph(metrics: &impl Metrics, chars: &[char], max_width: u32, wrap: Wrap, lines: &mut Vec<String>) {
+  if chars.is_empty() {
+    lines.push(String::new());
+    return;
+  }
+  let mut start = 0;
+  while start < chars.len() {
+    let end = break_line(metrics, chars, start, max_width, wrap);
+    let line: String = chars[start..end].iter().collect();
+    lines.push(line.trim_end().into());
+    start = end;
+    while start < chars.len() && chars[start] == ' ' {
+      start += 1;
+    }
+  }
+}
+
+/** Returns the end of the line starting at `start` */
+fn break_line(metrics: &impl Metrics, chars: &[char], start: usize, max_width: u32, wrap: Wrap) -> usize {
+  if wrap == Wrap::None {
+    return chars.len();
+  }
+
+  let mut width = 0;
+  let mut fit = start;
+  while fit < chars.len() {
+    let w = metrics.char_width(chars[fit]);
+    if width + w > max_width && fit > start && chars[fit] != ' ' {
+      break;
+    }
+    width += w;
+    fit += 1;
+  }
+  if fit == chars.len() {
+    return fit;
+  }
+
+  let mut end = fit;
+  if wrap == Wrap::Word {
+    if let Some(i) = (start + 1..=fit).rev().find(|&i| can_break(chars, i)) {
+      end = i;
+    }
+  }
+  while end > start + 1 && NO_LINE_START.contains(&chars[end]) {
+    end -= 1;
+  }
+  end
+}
+
+/** Whether a line can be broken between `chars[i - 1]` and `chars[i]` */
+fn can_break(chars: &[char], i: usize) -> bool {
+  chars[i - 1] == ' ' || chars[i] == ' ' || is_cjk(chars[i - 1]) || is_cjk(chars[i])
+}
+
+/** Fit `line` into `max_width` with a trailing ellipsis. If `force`, the ellipsis is always added. */
+fn truncate(metrics: &impl Metrics, line: &str, max_width: u32, force: bool) -> String {
+  if !force && metrics.text_width(line) <= max_width {
+    return line.into();
+  }
+  let mut line = String::from(line.trim_end());
+  let ellipsis = metrics.char_width(ELLIPSIS);
+  while !line.is_empty() && metrics.text_width(&line) + ellipsis > max_width {
+    line.pop();
+  }
+  line.truncate(line.trim_end().len());
+  line.push(ELLIPSIS);
+  line
+}
+
+fn is_cjk(c: char) -> bool {
+  matches!(c as u32,
+    0x2e80..=0x9fff // CJK radicals, punctuation, kana, unified ideographs
+    | 0xac00..=0xd7af // Hangul
+    | 0xf900..=0xfaff // CJK compatibility ideographs
+    | 0xff00..=0xffef // Fullwidth and halfwidth forms
+  )
+}
+
+fn is_rtl(c: char) -> bool {
+  matches!(c as u32,
+    0x0590..=0x08ff // Hebrew, Arabic, Syriac, Thaana, ...
+    | 0xfb1d..=0xfdff // Hebrew and Arabic presentation forms
+    | 0xfe70..=0xfeff
+  )
+}
+
+/** Strong direction of a character, `None` for neutrals (spaces and punctuation) */
+fn char_direction(c: char) -> Option<Direction> {
+  if is_rtl(c) {
+    Some(Direction::Rtl)
+  } else if c.is_alphanumeric() {
+    Some(Direction::Ltr)
+  } else {
+    None
+  }
+}
+
+/** Paragraph direction, taken from the first strong character */
+pub fn base_direction(text: &str) -> Direction {
+  text.chars().find_map(char_direction).unwrap_or(Direction::Ltr)
+}
+
+/**
+ * Reorder a line from logical to visual order.
+ * This is a simplified bidi algorithm: neutrals take the direction of the surrounding text if
+ * both sides agree and the base direction otherwise, and there are no embedding levels.
+ */
+pub fn reorder(line: &str, base: Direction) -> String {
+  if !line.chars().any(is_rtl) {
+    return line.into();
+  }
+
+  let chars: Vec<char> = line.chars().collect();
+  let strong: Vec<Option<Direction>> = chars.iter().map(|&c| char_direction(c)).collect();
+  let resolved: Vec<Direction> = (0..chars.len())
+    .map(|i| {
+      strong[i].unwrap_or_else(|| {
+        let prev = strong[..i].iter().rev().find_map(|d| *d);
+        let next = strong[i + 1..].iter().find_map(|d| *d);
+        match (prev, next) {
+          (Some(prev), Some(next)) if prev == next => prev,
+          _ => base,
+        }
+      })
+    })
+    .collect();
+
+  let mut runs: Vec<(Direction, Vec<char>)> = Vec::new();
+  for (&c, &direction) in chars.iter().zip(&resolved) {
+    match runs.last_mut() {
+      Some((d, run)) if *d == direction => run.push(c),
+      _ => runs.push((direction, vec![c])),
+    }
+  }
+  if base == Direction::Rtl {
+    runs.reverse();
+  }
+  runs
+    .into_iter()
+    .flat_map(|(direction, mut run)| {
+      if direction == Direction::Rtl {
+        run.reverse();
+      }
+      run
+    })
+    .collect()
+}
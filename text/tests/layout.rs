@@ -0,0 +1,129 @@
+use navelo_text::{base_direction, layout, reorder, Direction, Metrics, Wrap};
+
+/** Half-width characters are 6 px and CJK characters 12 px, like the 12 px font */
+struct Fixed;
+
+impl Metrics for Fixed {
+  fn char_width(&self, c: char) -> u32 {
+    if c.is_ascii() || ('\u{0590}'..='\u{05ff}').contains(&c) || c == '\u{2026}' {
+      6
+    } else {
+      12
+    }
+  }
+}
+
+fn texts(text: &str, max_width: u32, wrap: Wrap, max_lines: Option<usize>) -> Vec<String> {
+  layout(text, max_width, &Fixed, wrap, max_lines)
+    .into_iter()
+    .map(|line| line.text)
+    .collect()
+}
+
+#[test]
+fn wraps_japanese_between_any_characters() {
+  assert_eq!(
+    texts("次の交差点を右折です", 48, Wrap::Word, None),
+    ["次の交差", "点を右折", "です"]
+  );
+}
+
+#[test]
+fn wraps_latin_at_spaces() {
+  assert_eq!(
+    texts("Turn right onto Main Street", 60, Wrap::Word, None),
+    ["Turn right", "onto Main", "Street"]
+  );
+}
+
+#[test]
+fn breaks_long_words_anywhere() {
+  assert_eq!(
+    texts("Donaudampfschiff", 48, Wrap::Word, None),
+    ["Donaudam", "pfschiff"]
+  );
+  assert_eq!(texts("Turn right", 36, Wrap::Char, None), ["Turn r", "ight"]);
+}
+
+#[test]
+fn keeps_punctuation_and_small_kana_off_the_line_start() {
+  // 。 would start the second line, so 線 moves down with it
+  assert_eq!(texts("山手線。渋谷", 36, Wrap::Word, None), ["山手", "線。渋", "谷"]);
+  // so does a small kana
+  assert_eq!(
+    texts("キャンプ場", 12, Wrap::Word, None),
+    ["キ", "ャ", "ン", "プ", "場"]
+  );
+  assert_eq!(texts("ゴールキャンプ", 48, Wrap::Word, None), ["ゴール", "キャンプ"]);
+}
+
+#[test]
+fn mixes_japanese_and_latin() {
+  assert_eq!(texts("国道1号 Route 1", 60, Wrap::Word, None), ["国道1号", "Route 1"]);
+}
+
+#[test]
+fn honors_newlines() {
+  assert_eq!(texts("右折\n\n300 m", 100, Wrap::Word, None), ["右折", "", "300 m"]);
+}
+
+#[test]
+fn truncates_extra_lines_with_an_ellipsis() {
+  assert_eq!(
+    texts("次の交差点を右折です", 48, Wrap::Word, Some(2)),
+    ["次の交差", "点を右\u{2026}"]
+  );
+  assert_eq!(
+    texts("Turn right onto Main Street", 60, Wrap::Word, Some(1)),
+    ["Turn righ\u{2026}"]
+  );
+}
+
+#[test]
+fn truncates_unwrapped_lines() {
+  assert_eq!(texts("新宿駅西口", 48, Wrap::None, None), ["新宿駅\u{2026}"]);
+  assert_eq!(texts("新宿駅", 48, Wrap::None, None), ["新宿駅"]);
+  assert_eq!(
+    texts("新宿駅西口\n渋谷", 48, Wrap::None, None),
+    ["新宿駅\u{2026}", "渋谷"]
+  );
+}
+
+#[test]
+fn measures_lines_in_logical_order() {
+  let lines = layout("渋谷 Shibuya", 200, &Fixed, Wrap::Word, None);
+  assert_eq!(lines[0].width, 12 * 2 + 6 * 8);
+}
+
+#[test]
+fn detects_the_paragraph_direction() {
+  assert_eq!(base_direction("שלום"), Direction::Rtl);
+  assert_eq!(base_direction("123 שלום"), Direction::Ltr);
+  assert_eq!(base_direction("(שלום)"), Direction::Rtl);
+  assert_eq!(base_direction("東京"), Direction::Ltr);
+  assert_eq!(base_direction(""), Direction::Ltr);
+}
+
+#[test]
+fn reverses_right_to_left_text() {
+  assert_eq!(reorder("רחוב הרצל", Direction::Rtl), "לצרה בוחר");
+  assert_eq!(reorder("Turn onto רחוב הרצל", Direction::Ltr), "Turn onto לצרה בוחר");
+  assert_eq!(reorder("רחוב Main St", Direction::Rtl), "Main St בוחר");
+  assert_eq!(reorder("渋谷駅", Direction::Ltr), "渋谷駅");
+}
+
+#[test]
+fn lays_out_each_paragraph_in_its_own_direction() {
+  let lines = layout("Turn right\nרחוב הרצל", 200, &Fixed, Wrap::Word, None);
+  assert_eq!(lines[0].text, "Turn right");
+  assert_eq!(lines[0].direction, Direction::Ltr);
+  assert_eq!(lines[1].text, "לצרה בוחר");
+  assert_eq!(lines[1].direction, Direction::Rtl);
+}
+
+#[test]
+fn wraps_right_to_left_text_before_reordering() {
+  // the first logical words stay on the first line, which is then read from the right
+  let lines = texts("רחוב הרצל תל אביב", 60, Wrap::Word, None);
+  assert_eq!(lines, ["לצרה בוחר", "ביבא לת"]);
+}